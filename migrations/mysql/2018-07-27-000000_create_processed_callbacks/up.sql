@@ -0,0 +1,5 @@
+CREATE TABLE mpesa_processed_callbacks (
+    callback_key VARCHAR(191) NOT NULL PRIMARY KEY,
+    received_at DATETIME NOT NULL,
+    INDEX mpesa_processed_callbacks_received_at (received_at)
+);
@@ -0,0 +1,2 @@
+DROP TABLE mpesa_callbacks;
+DROP TABLE mpesa_transactions;
@@ -0,0 +1,31 @@
+CREATE TABLE mpesa_transactions (
+    id VARCHAR(32) NOT NULL PRIMARY KEY,
+    product VARCHAR(32) NOT NULL,
+    request TEXT NOT NULL,
+    conversation_id VARCHAR(64),
+    originator_conversation_id VARCHAR(64),
+    checkout_request_id VARCHAR(64),
+    merchant_request_id VARCHAR(64),
+    state VARCHAR(32) NOT NULL,
+    result_code BIGINT,
+    result_desc TEXT,
+    receipt VARCHAR(32),
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    INDEX mpesa_transactions_conversation_id (conversation_id),
+    INDEX mpesa_transactions_originator_conversation_id (originator_conversation_id),
+    INDEX mpesa_transactions_checkout_request_id (checkout_request_id),
+    INDEX mpesa_transactions_merchant_request_id (merchant_request_id),
+    INDEX mpesa_transactions_state (state, updated_at)
+);
+
+CREATE TABLE mpesa_callbacks (
+    id VARCHAR(32) NOT NULL PRIMARY KEY,
+    transaction_id VARCHAR(32),
+    kind VARCHAR(32) NOT NULL,
+    reference VARCHAR(64) NOT NULL,
+    body TEXT NOT NULL,
+    received_at DATETIME NOT NULL,
+    INDEX mpesa_callbacks_transaction_id (transaction_id, received_at),
+    INDEX mpesa_callbacks_reference (reference)
+);
@@ -0,0 +1 @@
+CREATE INDEX mpesa_transactions_receipt ON mpesa_transactions (receipt);
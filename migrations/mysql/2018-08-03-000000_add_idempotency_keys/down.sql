@@ -0,0 +1,4 @@
+ALTER TABLE mpesa_transactions
+    DROP INDEX mpesa_transactions_idempotency_key,
+    DROP COLUMN acknowledgement,
+    DROP COLUMN idempotency_key;
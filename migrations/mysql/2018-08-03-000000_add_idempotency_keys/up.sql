@@ -0,0 +1,4 @@
+ALTER TABLE mpesa_transactions
+    ADD COLUMN idempotency_key VARCHAR(64),
+    ADD COLUMN acknowledgement TEXT,
+    ADD UNIQUE INDEX mpesa_transactions_idempotency_key (idempotency_key);
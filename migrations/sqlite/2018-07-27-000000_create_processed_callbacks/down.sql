@@ -0,0 +1 @@
+DROP TABLE mpesa_processed_callbacks;
@@ -0,0 +1,6 @@
+CREATE TABLE mpesa_processed_callbacks (
+    callback_key VARCHAR(191) NOT NULL PRIMARY KEY,
+    received_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX mpesa_processed_callbacks_received_at ON mpesa_processed_callbacks (received_at);
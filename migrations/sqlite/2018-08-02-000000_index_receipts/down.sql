@@ -0,0 +1 @@
+DROP INDEX mpesa_transactions_receipt;
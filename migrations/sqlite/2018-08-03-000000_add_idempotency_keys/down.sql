@@ -0,0 +1,33 @@
+-- SQLite cannot drop columns, so the table is rebuilt without them
+DROP INDEX mpesa_transactions_idempotency_key;
+
+CREATE TABLE mpesa_transactions_without_keys (
+    id VARCHAR(32) NOT NULL PRIMARY KEY,
+    product VARCHAR(32) NOT NULL,
+    request TEXT NOT NULL,
+    conversation_id VARCHAR(64),
+    originator_conversation_id VARCHAR(64),
+    checkout_request_id VARCHAR(64),
+    merchant_request_id VARCHAR(64),
+    state VARCHAR(32) NOT NULL,
+    result_code BIGINT,
+    result_desc TEXT,
+    receipt VARCHAR(32),
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO mpesa_transactions_without_keys
+    SELECT id, product, request, conversation_id, originator_conversation_id, checkout_request_id, merchant_request_id,
+           state, result_code, result_desc, receipt, created_at, updated_at
+    FROM mpesa_transactions;
+
+DROP TABLE mpesa_transactions;
+ALTER TABLE mpesa_transactions_without_keys RENAME TO mpesa_transactions;
+
+CREATE INDEX mpesa_transactions_conversation_id ON mpesa_transactions (conversation_id);
+CREATE INDEX mpesa_transactions_originator_conversation_id ON mpesa_transactions (originator_conversation_id);
+CREATE INDEX mpesa_transactions_checkout_request_id ON mpesa_transactions (checkout_request_id);
+CREATE INDEX mpesa_transactions_merchant_request_id ON mpesa_transactions (merchant_request_id);
+CREATE INDEX mpesa_transactions_state ON mpesa_transactions (state, updated_at);
+CREATE INDEX mpesa_transactions_receipt ON mpesa_transactions (receipt);
@@ -0,0 +1,4 @@
+ALTER TABLE mpesa_transactions ADD COLUMN idempotency_key VARCHAR(64);
+ALTER TABLE mpesa_transactions ADD COLUMN acknowledgement TEXT;
+
+CREATE UNIQUE INDEX mpesa_transactions_idempotency_key ON mpesa_transactions (idempotency_key);
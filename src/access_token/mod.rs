@@ -30,24 +30,28 @@
 
 use reqwest;
 use base64;
-use hyper::header::{Basic, Authorization, Headers};
-use actix_web::{client, HttpMessage, HttpRequest, HttpResponse, FutureResponse, AsyncResponder};
+use actix_web::{client, HttpMessage, HttpResponse, FutureResponse, AsyncResponder};
 use bytes::Bytes;
 use futures::future::Future;
 use std::sync::mpsc;
+use std::sync::Arc;
 use actix;
 use futures;
 
 use std::time::{Instant, Duration};
 use std::fmt::{self, Display};
 use std::convert::From;
-use std::str::FromStr;
 use serde_json;
 use std::error::Error;
 
+use client::transport::{HttpRequest, HttpTransport, Transport, TransportError};
+
 /// Constant representing time after which to get a new access token.
 const ACCEESS_TOKEN_EXPIRATION: Duration = Duration::from_secs(60 * 55);
 
+/// Path of the OAuth api relative to the api base url
+pub const ENDPOINT: &str = "/oauth/v1/generate?grant_type=client_credentials";
+
 
 
 
@@ -58,6 +62,8 @@ pub struct AccessToken {
    token: Option<String>,
    last_retrieved: Option<Instant>,
    credentials: String,
+   url: String,
+   transport: Arc<dyn Transport>,
 } 
 
 /// Definition of possible errors when dealing with the access token.
@@ -91,11 +97,32 @@ impl AccessToken {
         AccessToken {
             token: None,
             last_retrieved: None,
-            credentials: base64::encode(&credentials_str)
-            
+            credentials: base64::encode(&credentials_str),
+            url: String::from("http://localhost/accessToken"),
+            transport: Arc::new(HttpTransport::new()),
         } 
     }
 
+    /// Retrieves tokens from the API at `base_url`, e.g. `client::SANDBOX_URL`
+    ///
+    /// # Example
+    /// ```
+    /// # use mpesa::access_token::AccessToken;
+    /// # use mpesa::client::SANDBOX_URL;
+    ///
+    /// let access_token = AccessToken::new(String::from("foo"), String::from("bar")).base_url(SANDBOX_URL);
+    /// ```
+    pub fn base_url(mut self, base_url: &str) -> AccessToken {
+        self.url = format!("{}{}", base_url.trim_end_matches('/'), ENDPOINT);
+        self
+    }
+
+    /// Retrieves tokens through `transport` instead of the network, see `client::transport`
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> AccessToken {
+        self.transport = transport;
+        self
+    }
+
     /// Public function to get the access token
     /// 
     /// The function itself implements a solution to check whether a valid token exists and whether it is valid
@@ -148,18 +175,20 @@ impl AccessToken {
         Ok(token_to_return)
     }
    
+    /// The request retrieving a fresh access token
+    pub fn request(&self) -> HttpRequest {
+        HttpRequest::get(&self.url).header("Authorization", &format!("Basic {}", self.credentials))
+    }
+
     /// A private function called to retieve a fresh access token from the Mpesa API server
      fn get_token(&mut self) -> Result<String, MpesaAccessTokenError>{
-        let url = String::from("http://localhost/accessToken");
         let mut token_json = String::new();
-        let mut response = reqwest::Client::new().get(&url)
-                        .header(Authorization(Basic::from_str(&self.credentials).unwrap()))
-                        .send()?;
+        let response = self.transport.send(&self.request())?;
         
-        if response.status().is_success() {
-            token_json = response.text()?;
+        if response.status >= 200 && response.status < 300 {
+            token_json = response.body;
         } else {
-            return Err(MpesaAccessTokenError::RetrievalInvalidResponseError(format!("{}", response.status())));
+            return Err(MpesaAccessTokenError::RetrievalInvalidResponseError(format!("{}", response.status)));
         }                
                                     
         let token_json: serde_json::Value = serde_json::from_str(&token_json)?;
@@ -182,6 +211,15 @@ impl From<reqwest::Error> for MpesaAccessTokenError {
     }
 }
 
+impl From<TransportError> for MpesaAccessTokenError {
+    fn from(error: TransportError) -> Self {
+        match error {
+            TransportError::Connection(error) => MpesaAccessTokenError::RetrievalConnectionError(error),
+            error => MpesaAccessTokenError::RetrievalInvalidResponseError(format!("{}", error)),
+        }
+    }
+}
+
 impl From<serde_json::Error> for MpesaAccessTokenError {
      fn from(error: serde_json::Error) -> Self {
         MpesaAccessTokenError::InvalidAccessToken(format!("{}", error))
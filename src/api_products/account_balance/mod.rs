@@ -5,27 +5,91 @@
 //! test url: POST https://sandbox.safaricom.co.ke/mpesa/accountbalance/v1/query
 
 use super::super::parameters::*;
+use super::Product;
 
+/// Path of the account balance api relative to the api base url
+pub const ENDPOINT: &str = "/mpesa/accountbalance/v1/query";
 
 /// A strcut holding request parameters for account balance api
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct AccountBalance {
     /// This is the credential/username used to authenticate the transaction request
-    initiator: String,
+    pub Initiator: String,
     /// Base64 encoded string of the Security Credential, which is encrypted using M-Pesa public key and validates the transaction on M-Pesa Core system.
-    security_credential: String,
+    pub SecurityCredential: String,
     /// A unique command passed to the M-Pesa system
-    command_id: CommandIds,
-    /// The shortcode of the organisation receiving the transaction.
-    party_b: String,
-    /// Type of the organisation receiving the transaction.
-    ReceiverIdentifierType: Identifiers,
+    pub CommandID: String,
+    /// The shortcode of the organisation whose balance is queried.
+    pub PartyA: Party,
+    /// Type of the organisation whose balance is queried.
+    pub IdentifierType: Identifiers,
     /// Comments that are sent along with the transaction.
-    Remarks: String,
+    pub Remarks: String,
     /// The timeout end-point that receives a timeout message.
-    QueueTimeOutURL: String,
+    pub QueueTimeOutURL: String,
     /// The end-point that receives a successful transaction.
-    ResultURL: String,
-    /// Organisation receiving the funds.
-    AccountType: String,
-}
\ No newline at end of file
+    pub ResultURL: String,
+}
+
+impl AccountBalance {
+    /// Creates a query for the balance of `party`, deriving its identifier type
+    pub fn new<P: Into<Party>>(party: P) -> AccountBalance {
+        let party = party.into();
+        AccountBalance {
+            Initiator: String::new(),
+            SecurityCredential: String::new(),
+            CommandID: CommandIds::AccountBalance.to_string(),
+            IdentifierType: party.identifier(),
+            PartyA: party,
+            Remarks: String::new(),
+            QueueTimeOutURL: String::new(),
+            ResultURL: String::new(),
+        }
+    }
+
+    /// The initiator making the query and its security credential
+    pub fn initiator(mut self, initiator: &str, security_credential: &str) -> AccountBalance {
+        self.Initiator = initiator.to_string();
+        self.SecurityCredential = security_credential.to_string();
+        self
+    }
+
+    /// The urls the balance and timeouts are posted to
+    pub fn urls(mut self, result_url: &str, queue_timeout_url: &str) -> AccountBalance {
+        self.ResultURL = result_url.to_string();
+        self.QueueTimeOutURL = queue_timeout_url.to_string();
+        self
+    }
+
+    /// Comments that are sent along with the query
+    pub fn remarks(mut self, remarks: &str) -> AccountBalance {
+        self.Remarks = remarks.to_string();
+        self
+    }
+
+    /// Checks the request before it is sent
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        CommandIds::check(&self.CommandID, Product::AccountBalance)?;
+        self.PartyA.check(self.IdentifierType)?;
+        Ok(())
+    }
+
+    /// Checks the callback urls of the request against the rules of `environment`
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        check_callback_urls(&[("ResultURL", &self.ResultURL), ("QueueTimeOutURL", &self.QueueTimeOutURL)], environment)
+    }
+}
+
+/// Representation of the acknowledgement returned by an account balance api call.
+/// The balance itself is posted to the `ResultURL`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountBalanceResponse {
+    /// A unique numeric code generated by the M-Pesa system of the response to a request.
+    pub ConversationID: String,
+    /// A unique numeric code generated by the M-Pesa system of the request.
+    pub OriginatorConversationID: String,
+    /// Status code of the submission. `0` means the request was accepted
+    pub ResponseCode: String,
+    /// A response message from the M-Pesa system accompanying the response to a request.
+    pub ResponseDescription: String,
+}
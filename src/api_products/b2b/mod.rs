@@ -6,46 +6,131 @@
 //! 
 //! testing url: POST https://sandbox.safaricom.co.ke/mpesa/b2b/v1/paymentrequest
 
+use parameters::{check_callback_urls, Amount, CommandIds, Environment, Identifiers, ParameterError, Party};
+use super::Product;
+
+/// Path of the B2B api relative to the api base url
+pub const ENDPOINT: &str = "/mpesa/b2b/v1/paymentrequest";
+
 /// Struct holding B2B request parameters
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct B2B {
     /// This is the credential/username used to authenticate the transaction request
-    Initiator: String,
+    pub Initiator: String,
     /// Base64 encoded string of the Security Credential, which is encrypted using M-Pesa public key and validates the transaction on M-Pesa Core system.
-    SecurityCredential: String,
+    pub SecurityCredential: String,
     /// Unique command for each transaction type, possible values are: BusinessPayBill, MerchantToMerchantTransfer, MerchantTransferFromMerchantToWorking, MerchantServicesMMFAccountTransfer, AgencyFloatAdvance
-    CommandID: String,
+    pub CommandID: String,
     /// The amount being transacted
-    Amount: String,
+    pub Amount: Amount,
     /// Organization’s short code initiating the transaction
-    PartyA: String,
+    pub PartyA: Party,
     /// Type of organization sending the transaction
-    SenderIdentifier: String,
+    pub SenderIdentifierType: Identifiers,
     /// Organization’s short code receiving the funds being transacted
-    PartyB: String,
+    pub PartyB: Party,
     /// Type of organization receiving the funds being transacted.
-    RecieverIdentifierType: String,
+    pub RecieverIdentifierType: Identifiers,
     /// Comments that are sent along with the transaction
-    Remarks: String,
+    pub Remarks: String,
     /// The path that stores information of time out transactions.it should be properly
     ///validated to make sure that it contains the port, URI and domain name or publicly
     ///available IP.
-    QueueTimeOutURL: String,
+    pub QueueTimeOutURL: String,
     /// The path that receives results from M-Pesa it should be properly validated to make
     /// sure that it contains the port, URI and domain name or publicly available IP.
-    ResultURL: String,
+    pub ResultURL: String,
     /// Account Reference mandatory for “BusinessPaybill” CommandID
-    AccountReference: String,
+    pub AccountReference: String,
+}
+
+impl B2B {
+    /// Creates a payment of `amount` from `sender` to `receiver`, deriving their identifier types
+    ///
+    /// # Example
+    /// ```
+    /// # use mpesa::api_products::b2b::B2B;
+    /// # use mpesa::parameters::*;
+    /// let request = B2B::new(CommandIds::BusinessBuyGoods, Amount::shillings(500),
+    ///                        ShortCode::new("600000").unwrap(), TillNumber::new("5123456").unwrap())
+    ///     .account_reference("INV-1");
+    ///
+    /// assert_eq!(request.SenderIdentifierType, Identifiers::ShortCode);
+    /// assert_eq!(request.RecieverIdentifierType, Identifiers::TillNumber);
+    /// ```
+    pub fn new<S: Into<Party>, R: Into<Party>>(command_id: CommandIds, amount: Amount, sender: S, receiver: R) -> B2B {
+        let sender = sender.into();
+        let receiver = receiver.into();
+        B2B {
+            Initiator: String::new(),
+            SecurityCredential: String::new(),
+            CommandID: command_id.to_string(),
+            Amount: amount,
+            SenderIdentifierType: sender.identifier(),
+            PartyA: sender,
+            RecieverIdentifierType: receiver.identifier(),
+            PartyB: receiver,
+            Remarks: String::new(),
+            QueueTimeOutURL: String::new(),
+            ResultURL: String::new(),
+            AccountReference: String::new(),
+        }
+    }
+
+    /// The initiator sending the payment and its security credential
+    pub fn initiator(mut self, initiator: &str, security_credential: &str) -> B2B {
+        self.Initiator = initiator.to_string();
+        self.SecurityCredential = security_credential.to_string();
+        self
+    }
+
+    /// The urls the result and timeouts are posted to
+    pub fn urls(mut self, result_url: &str, queue_timeout_url: &str) -> B2B {
+        self.ResultURL = result_url.to_string();
+        self.QueueTimeOutURL = queue_timeout_url.to_string();
+        self
+    }
+
+    /// Comments that are sent along with the payment
+    pub fn remarks(mut self, remarks: &str) -> B2B {
+        self.Remarks = remarks.to_string();
+        self
+    }
+
+    /// The account the payment is made to, for paybills
+    pub fn account_reference(mut self, account_reference: &str) -> B2B {
+        self.AccountReference = account_reference.to_string();
+        self
+    }
+
+    /// Checks the request before it is sent
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        CommandIds::check(&self.CommandID, Product::B2B)?;
+        self.Amount.check(Product::B2B)?;
+        for &(party, identifier) in &[(&self.PartyA, self.SenderIdentifierType), (&self.PartyB, self.RecieverIdentifierType)] {
+            if !party.is_business() {
+                return Err(ParameterError::Party { party: party.to_string(), identifier: identifier });
+            }
+            party.check(identifier)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the callback urls of the request against the rules of `environment`
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        check_callback_urls(&[("ResultURL", &self.ResultURL), ("QueueTimeOutURL", &self.QueueTimeOutURL)], environment)
+    }
 }
 
-/// Representation of responses expected from a B2B api call
-#[derive(Debug)]
-pub enum B2BResponse {
+/// Representation of the acknowledgement returned by a B2B api call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct B2BResponse {
     /// A unique numeric code generated by the M-Pesa system of the response to a request.
-    ConversationID,
+    pub ConversationID: String,
     /// A unique numeric code generated by the M-Pesa system of the request.
-    OriginatorConversationID,
+    pub OriginatorConversationID: String,
+    /// Status code of the submission. `0` means the request was accepted
+    pub ResponseCode: String,
     /// A response message from the M-Pesa system accompanying the response to a request.
-    ResponseDescription,
-
-}
\ No newline at end of file
+    pub ResponseDescription: String,
+}
@@ -3,29 +3,69 @@
 //! This API enables Business to Customer (B2C) transactions between a company and customers who are the end users of
 //! its products or services. Use of this API requires a valid and verified B2C M-Pesa Short code.
 //! 
-//! testing url: POST https://sandbox.safaricom.co.ke/mpesa/b2c/v1/paymentrequest
- 
+//! testing url: POST https://sandbox.safaricom.co.ke/mpesa/b2c/v3/paymentrequest
+//!
+//! Payments are sent to version 3 of the API, the only version that takes the `OriginatorConversationID` of the
+//! request from the caller. The client relies on it to look a payment up when its outcome is unknown.
+
+use parameters::{check_callback_urls, Amount, CommandIds, Environment, Msisdn, ParameterError, ShortCode};
+use super::Product;
+
+/// Path of the B2C api relative to the api base url
+pub const ENDPOINT: &str = "/mpesa/b2c/v3/paymentrequest";
+
 /// A struct holding B2C request parameters 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct B2C {
     /// This is the credential/username used to authenticate the transaction request
-    InitiatorName: String,
+    pub InitiatorName: String,
     /// Base64 encoded string of the Security Credential, which is encrypted using M-Pesa public key and validates the transaction on M-Pesa Core system.
-    SecurityCredential: String,
+    pub SecurityCredential: String,
     /// Unique command for each transaction type e.g. SalaryPayment, BusinessPayment, PromotionPayment
-    CommandID: String,
+    pub CommandID: String,
     /// The amount being transacted
-    Amount: String,
+    pub Amount: Amount,
     /// Organization’s shortcode initiating the transaction.
-    PartyA: String,
+    pub PartyA: ShortCode,
     /// Phone number receiving the transaction
-    PartyB: String,
+    pub PartyB: Msisdn,
     /// Comments that are sent along with the transaction.
-    Remarks: String,
+    pub Remarks: String,
     /// The timeout end-point that receives a timeout response
-    QueueTimeOutURL: String,
+    pub QueueTimeOutURL: String,
     /// The end-point that receives the response of the transaction
-    ResultURL: String,
+    pub ResultURL: String,
     /// Optional
-    Occasion: String,
+    pub Occasion: String,
+    /// Unique id of the request, generated by the client when left empty. M-Pesa reports it back in the
+    /// acknowledgement and the result, and it is used to look the payment up when its outcome is unknown
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub OriginatorConversationID: String,
+}
+
+impl B2C {
+    /// Checks the request before it is sent
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        CommandIds::check(&self.CommandID, Product::B2C)?;
+        self.Amount.check(Product::B2C)?;
+        Ok(())
+    }
+
+    /// Checks the callback urls of the request against the rules of `environment`
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        check_callback_urls(&[("ResultURL", &self.ResultURL), ("QueueTimeOutURL", &self.QueueTimeOutURL)], environment)
+    }
+}
+
+/// Representation of the acknowledgement returned by a B2C api call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct B2CResponse {
+    /// A unique numeric code generated by the M-Pesa system of the response to a request.
+    pub ConversationID: String,
+    /// A unique numeric code generated by the M-Pesa system of the request.
+    pub OriginatorConversationID: String,
+    /// Status code of the submission. `0` means the request was accepted
+    pub ResponseCode: String,
+    /// A response message from the M-Pesa system accompanying the response to a request.
+    pub ResponseDescription: String,
 }
\ No newline at end of file
@@ -17,53 +17,121 @@
 //! 
 //! testing url: POST [https://sandbox.safaricom.co.ke/mpesa/c2b/v1/simulate](#) 
 
+use parameters::{check_callback_urls, Amount, CommandIds, Environment, Msisdn, ParameterError, Party, ShortCode};
+use super::Product;
+
+/// Path of the register url api relative to the api base url
+pub const REGISTER_URL_ENDPOINT: &str = "/mpesa/c2b/v1/registerurl";
+
+/// Path of the simulate transaction api relative to the api base url
+pub const SIMULATE_ENDPOINT: &str = "/mpesa/c2b/v1/simulate";
 
 /// A struct holding RegisterUrl request parameters
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct RegisterUrl {
     /// Validation URL for the client.
-    ValidationURL: String,
+    pub ValidationURL: String,
     /// Confirmation URL for the client
-    ConfirmationURL: String,
+    pub ConfirmationURL: String,
     /// Default response type for timeout.
-    ResponseType: String,
+    pub ResponseType: String,
     /// The short code of the organization.
-    ShortCode: String,
+    pub ShortCode: ShortCode,
+}
+
+impl RegisterUrl {
+    /// Registers `validation_url` and `confirmation_url` for `short_code`.
+    /// `response_type`, `Completed` or `Cancelled`, decides what happens to payments when the validation url cannot be reached
+    pub fn new(short_code: ShortCode, validation_url: &str, confirmation_url: &str, response_type: &str) -> RegisterUrl {
+        RegisterUrl {
+            ValidationURL: validation_url.to_string(),
+            ConfirmationURL: confirmation_url.to_string(),
+            ResponseType: response_type.to_string(),
+            ShortCode: short_code,
+        }
+    }
+
+    /// Checks the urls to be registered against the rules of `environment`
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        check_callback_urls(&[("ValidationURL", &self.ValidationURL), ("ConfirmationURL", &self.ConfirmationURL)], environment)
+    }
 }
 
 /// Representation of responses expected from a register url api call
-#[derive(Debug)]
-pub enum RegisterUrlResponse {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisterUrlResponse {
     /// A unique numeric code generated by the M-Pesa system of the response to a request
-    ConversationID,
+    #[serde(default)]
+    pub ConversationID: String,
     /// A unique numeric code generated by the M-Pesa system of the request
-    OriginatorConversationID,
+    #[serde(default)]
+    pub OriginatorConversationID: String,
+    /// Status code of the registration. `0` means the urls were registered
+    #[serde(default)]
+    pub ResponseCode: String,
     /// A response message from the M-Pesa system accompanying the response to a request.
-    ResponseDescription
+    pub ResponseDescription: String,
 }
 
 /// A struct Simulate Transaction request parameters
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SimulateTransaction {
     /// Unique command for each transaction type
-    CommandID: String,
+    pub CommandID: String,
     /// The amount been transacted.
-    Amount: String,
+    pub Amount: Amount,
     /// MSISDN (phone number) sending the transaction, start with country code without the plus(+) sign
-    MSISDN: String,
+    pub Msisdn: Msisdn,
     /// Bill Reference Number (Optional)
-    BillRefNumber: String,
+    pub BillRefNumber: String,
     /// 6 digit M-Pesa Till Number or PayBill Number
-    ShortCode: String,
+    pub ShortCode: Party,
+}
+
+impl SimulateTransaction {
+    /// Simulates a payment of `amount` from `msisdn` to `short_code`.
+    /// Payments to till numbers are made with `CustomerBuyGoodsOnline`, all others with `CustomerPayBillOnline`
+    pub fn new<P: Into<Party>>(short_code: P, msisdn: Msisdn, amount: Amount) -> SimulateTransaction {
+        let short_code = short_code.into();
+        let command_id = match short_code {
+            Party::TillNumber(_) => CommandIds::CustomerBuyGoodsOnline,
+            _ => CommandIds::CustomerPayBillOnline,
+        };
+        SimulateTransaction {
+            CommandID: command_id.to_string(),
+            Amount: amount,
+            Msisdn: msisdn,
+            BillRefNumber: String::new(),
+            ShortCode: short_code,
+        }
+    }
+
+    /// The account number the customer pays to
+    pub fn bill_ref_number(mut self, bill_ref_number: &str) -> SimulateTransaction {
+        self.BillRefNumber = bill_ref_number.to_string();
+        self
+    }
+
+    /// Checks the request before it is sent
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        CommandIds::check(&self.CommandID, Product::C2BSimulate)?;
+        self.Amount.check(Product::C2BSimulate)?;
+        if !self.ShortCode.is_business() {
+            return Err(ParameterError::Party { party: self.ShortCode.to_string(), identifier: self.ShortCode.identifier() });
+        }
+        Ok(())
+    }
 }
 
 /// Represenattion of responses expected from a simulare traansaction api call
-#[derive(Debug)]
-pub enum SimulateTransactionResponse {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SimulateTransactionResponse {
     /// A unique numeric code generated by the M-Pesa system of the response to a request
-    ConversationID,
+    #[serde(default)]
+    pub ConversationID: String,
     /// A unique numeric code generated by the M-Pesa system of the request.
-    OriginatorConversationID,
+    #[serde(default)]
+    pub OriginatorConversationID: String,
     /// A response message from the M-Pesa system accompanying the response to a request
-    ResponseDescription,
-}
\ No newline at end of file
+    pub ResponseDescription: String,
+}
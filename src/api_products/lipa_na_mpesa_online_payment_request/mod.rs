@@ -5,47 +5,91 @@
 //! 
 //! test url: POST https://sandbox.safaricom.co.ke/mpesa/stkpush/v1/processrequest
 
+use base64;
+use chrono::{FixedOffset, Utc};
+
+use parameters::{check_callback_urls, Amount, CommandIds, Environment, Msisdn, ParameterError, Party, ShortCode};
+use super::Product;
+
+/// Path of the lipa na mpesa online api relative to the api base url
+pub const ENDPOINT: &str = "/mpesa/stkpush/v1/processrequest";
+
+/// Offset of East Africa Time, the timezone M-Pesa expects timestamps in
+const EAT_OFFSET_SECS: i32 = 3 * 60 * 60;
+
 /// A struct holding request parameters for the lipa na mpesa online api
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct LipaNaMpesaOnlinePaymentRequest {
     /// The organization shortcode used to receive the transaction
-    BusinessShortCode: String,
+    pub BusinessShortCode: ShortCode,
     /// The password for encrypting the request. This is generated by base64 encoding BusinessShortcode , Passkey and Timestamp
-    Password: String,
+    pub Password: String,
     /// The timestamp of the transaction in the format **yyyymmddhhiiss** .
-    Timestamp: String,
-    /// The transaction type to be used for this request. Only `CustomerPayBillOnline` is supported.
-    TransactionType: String,
+    pub Timestamp: String,
+    /// The transaction type to be used for this request. `CustomerPayBillOnline` for paybills or `CustomerBuyGoodsOnline` for till numbers.
+    pub TransactionType: String,
     /// The amount to be transacted.
-    Amount: String,
+    pub Amount: Amount,
     /// The MSISDN sending the funds.
-    PartyA: String,
+    pub PartyA: Msisdn,
     /// The organization shortcode receiving the funds
-    PartyB: String,
+    pub PartyB: Party,
     /// The MSISDN sending the funds.
-    PhoneNumber: String,
+    pub PhoneNumber: Msisdn,
     /// The url to where responses from M-Pesa will be sent to.
-    CallBackURL: String,
+    pub CallBackURL: String,
     /// Used with M-Pesa PayBills
-    AccountReference: String,
+    pub AccountReference: String,
     /// A description of the transaction
-    TransactionDesc: String,
+    pub TransactionDesc: String,
     
 }
 
-/// Represenattion ofresponses expected form a lipn ana mpesa online mpesa api call
-#[derive(Debug)]
-pub enum LipaNaMpesaOnlinePaymentResponse {
+impl LipaNaMpesaOnlinePaymentRequest {
+    /// Checks the request before it is sent
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        CommandIds::check(&self.TransactionType, Product::StkPush)?;
+        self.Amount.check(Product::StkPush)?;
+        if !self.PartyB.is_business() {
+            return Err(ParameterError::Party { party: self.PartyB.to_string(), identifier: self.PartyB.identifier() });
+        }
+        Ok(())
+    }
+
+    /// Checks the callback urls of the request against the rules of `environment`
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        check_callback_urls(&[("CallBackURL", &self.CallBackURL)], environment)
+    }
+}
+
+/// Representation of the acknowledgement returned by a lipa na mpesa online api call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LipaNaMpesaOnlinePaymentResponse {
     /// Merchant Request ID
-    MerchantRequestID,
+    pub MerchantRequestID: String,
     /// Check out Request ID
-    CheckoutRequestID,
-    /// Response Code
-    ResponseCode,
-    /// Result Desc
-    ResultDesc,
+    pub CheckoutRequestID: String,
+    /// Response Code. `0` means the request was accepted
+    pub ResponseCode: String,
     /// Response Description message
-    ResponseDescription,
-    /// Result Code
-    ResultCode,
+    pub ResponseDescription: String,
+    /// Message that can be shown to the customer
+    #[serde(default)]
+    pub CustomerMessage: String,
+}
+
+/// The current time in the format **yyyymmddhhiiss** as expected in the `Timestamp` of STK requests
+pub fn timestamp() -> String {
+    Utc::now().with_timezone(&FixedOffset::east(EAT_OFFSET_SECS)).format("%Y%m%d%H%M%S").to_string()
+}
+
+/// Generates the `Password` of STK requests by base64 encoding the shortcode, passkey and timestamp
+///
+/// # Example
+/// ```
+/// # use mpesa::api_products::lipa_na_mpesa_online_payment_request::password;
+/// assert_eq!(password("174379", "passkey", "20180101120000"), "MTc0Mzc5cGFzc2tleTIwMTgwMTAxMTIwMDAw");
+/// ```
+pub fn password(business_short_code: &str, passkey: &str, timestamp: &str) -> String {
+    base64::encode(&format!("{}{}{}", business_short_code, passkey, timestamp))
 }
\ No newline at end of file
@@ -2,34 +2,54 @@
 //! 
 //! test url: POST https://sandbox.safaricom.co.ke/mpesa/stkpushquery/v1/query
 
+use super::lipa_na_mpesa_online_payment_request::{password, timestamp};
+
+/// Path of the lipa na mpesa online query api relative to the api base url
+pub const ENDPOINT: &str = "/mpesa/stkpushquery/v1/query";
 
 /// A struct containing requesr parametrs for the lipa na mpesa online query request api
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct LipaNaMpesaOnlineQueryRequest {
     /// Business Short Code
-    BusinessShortCode: String,
+    pub BusinessShortCode: String,
     /// Password
-    Password: String,
+    pub Password: String,
     /// Timestamp
-    Timestamp: String,
+    pub Timestamp: String,
     /// Checkout RequestID
-    CheckoutRequestID: String,
+    pub CheckoutRequestID: String,
     
 }
 
+impl LipaNaMpesaOnlineQueryRequest {
+    /// Creates a query for the STK push identified by `checkout_request_id`, generating the timestamp and password
+    pub fn new(business_short_code: &str, passkey: &str, checkout_request_id: &str) -> LipaNaMpesaOnlineQueryRequest {
+        let timestamp = timestamp();
+        LipaNaMpesaOnlineQueryRequest {
+            BusinessShortCode: business_short_code.to_string(),
+            Password: password(business_short_code, passkey, &timestamp),
+            Timestamp: timestamp,
+            CheckoutRequestID: checkout_request_id.to_string(),
+        }
+    }
+}
+
 /// Represention of responses expected from lipa na mpesa online query request api call
-#[derive(Debug)]
-pub enum LipaNaMpesaOnlineQueryRequestResponse {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LipaNaMpesaOnlineQueryRequestResponse {
     /// Merchant Request ID
-    MerchantRequestID,
+    pub MerchantRequestID: String,
     /// Check out Request ID
-    CheckoutRequestID,
+    pub CheckoutRequestID: String,
     /// Response Code
-    ResponseCode,
+    pub ResponseCode: String,
     /// Result Desc
-    ResultDesc,
+    pub ResultDesc: String,
     /// Response Description message
-    ResponseDescription,
-    /// Result Code
-    ResultCode,
+    pub ResponseDescription: String,
+    /// Result Code. `0` means the customer paid
+    pub ResultCode: String,
+    /// The M-Pesa receipt of the payment. Only sent by some versions of the API
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub MpesaReceiptNumber: String,
 }
\ No newline at end of file
@@ -7,4 +7,31 @@ pub mod account_balance;
 pub mod reversal;
 pub mod transaction_status;
 pub mod lipa_na_mpesa_online_payment_request;
-pub mod lipa_na_mpesa_online_query_request;
\ No newline at end of file
+pub mod lipa_na_mpesa_online_query_request;
+
+/// An API product requests are made with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Product {
+    B2C,
+    B2B,
+    StkPush,
+    Reversal,
+    TransactionStatus,
+    AccountBalance,
+    C2BSimulate,
+}
+
+impl Product {
+    /// The name the product is recorded under
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Product::B2C => "b2c",
+            &Product::B2B => "b2b",
+            &Product::StkPush => "stk_push",
+            &Product::Reversal => "reversal",
+            &Product::TransactionStatus => "transaction_status",
+            &Product::AccountBalance => "account_balance",
+            &Product::C2BSimulate => "c2b_simulate",
+        }
+    }
+}
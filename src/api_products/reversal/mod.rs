@@ -4,28 +4,98 @@
 //! 
 //! test url: POST https://sandbox.safaricom.co.ke/mpesa/reversal/v1/request
 
+use parameters::{check_callback_urls, CommandIds, Environment, Identifiers, ParameterError, Party};
+use super::Product;
+
+/// Path of the reversal api relative to the api base url
+pub const ENDPOINT: &str = "/mpesa/reversal/v1/request";
 
 /// A struct holding request parameters for the Reversal Api
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Reversal {
     /// This is the credential/username used to authenticate the transaction request.
-    Initiator: String,
+    pub Initiator: String,
     /// Base64 encoded string of the Security Credential, which is encrypted using M-Pesa public key and validates the transaction on M-Pesa Core system.
-    SecurityCredential: String,
+    pub SecurityCredential: String,
     /// Unique command for each transaction type, possible values are: TransactionReversal
-    CommandID: String,
-    /// Organization/MSISDN sending the transaction
-    PartyA: String,
+    pub CommandID: String,
+    /// Organization receiving the transaction
+    pub ReceiverParty: Party,
     /// Type of organization receiving the transaction
-    RecieverIdentifierType: String,
+    pub RecieverIdentifierType: Identifiers,
     /// Comments that are sent along with the transaction.
-    Remarks: String,
+    pub Remarks: String,
     /// The path that stores information of time out transaction
-    QueueTimeOutURL: String,
+    pub QueueTimeOutURL: String,
     /// The path that stores information of transaction.
-    ResultURL: String,
+    pub ResultURL: String,
     /// Organization Receiving the funds
-    TransactionID: String,
+    pub TransactionID: String,
     /// Optional.
-    Occasion: String,
-}
\ No newline at end of file
+    pub Occasion: String,
+}
+
+impl Reversal {
+    /// Creates the reversal of the transaction with receipt `transaction_id` made to `party`,
+    /// deriving its identifier type
+    pub fn new<P: Into<Party>>(transaction_id: &str, party: P) -> Reversal {
+        let party = party.into();
+        Reversal {
+            Initiator: String::new(),
+            SecurityCredential: String::new(),
+            CommandID: CommandIds::TransactionReversal.to_string(),
+            RecieverIdentifierType: party.identifier(),
+            ReceiverParty: party,
+            Remarks: String::new(),
+            QueueTimeOutURL: String::new(),
+            ResultURL: String::new(),
+            TransactionID: transaction_id.to_string(),
+            Occasion: String::new(),
+        }
+    }
+
+    /// The initiator requesting the reversal and its security credential
+    pub fn initiator(mut self, initiator: &str, security_credential: &str) -> Reversal {
+        self.Initiator = initiator.to_string();
+        self.SecurityCredential = security_credential.to_string();
+        self
+    }
+
+    /// The urls the result and timeouts are posted to
+    pub fn urls(mut self, result_url: &str, queue_timeout_url: &str) -> Reversal {
+        self.ResultURL = result_url.to_string();
+        self.QueueTimeOutURL = queue_timeout_url.to_string();
+        self
+    }
+
+    /// Comments that are sent along with the reversal
+    pub fn remarks(mut self, remarks: &str) -> Reversal {
+        self.Remarks = remarks.to_string();
+        self
+    }
+
+    /// Checks the request before it is sent
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        CommandIds::check(&self.CommandID, Product::Reversal)?;
+        self.ReceiverParty.check(self.RecieverIdentifierType)?;
+        Ok(())
+    }
+
+    /// Checks the callback urls of the request against the rules of `environment`
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        check_callback_urls(&[("ResultURL", &self.ResultURL), ("QueueTimeOutURL", &self.QueueTimeOutURL)], environment)
+    }
+}
+
+/// Representation of the acknowledgement returned by a Reversal api call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReversalResponse {
+    /// A unique numeric code generated by the M-Pesa system of the response to a request.
+    pub ConversationID: String,
+    /// A unique numeric code generated by the M-Pesa system of the request.
+    pub OriginatorConversationID: String,
+    /// Status code of the submission. `0` means the request was accepted
+    pub ResponseCode: String,
+    /// A response message from the M-Pesa system accompanying the response to a request.
+    pub ResponseDescription: String,
+}
@@ -4,28 +4,109 @@
 //! 
 //! test url: POST https://sandbox.safaricom.co.ke/mpesa/transactionstatus/v1/query
 
+use parameters::{check_callback_urls, CommandIds, Environment, Identifiers, ParameterError, Party};
+use super::Product;
+
+/// Path of the transaction status api relative to the api base url
+pub const ENDPOINT: &str = "/mpesa/transactionstatus/v1/query";
 
 /// struct holding Transaction status reqest parameters
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TransactionSatus {
     /// Unique command for each transaction type, possible values are:TransactionStatusQuery
-    CommandID: String,
+    pub CommandID: String,
     /// Organization /MSISDN sending the transaction
-    ShortCode: String,
+    pub PartyA: Party,
     /// Type of organization receiving the transaction
-    IdentifierType: String,
+    pub IdentifierType: Identifiers,
     /// Comments that are sent along with the transaction.
-    Remarks: String,
+    pub Remarks: String,
     /// The name of Initiator to initiating the request
-    Initiator: String,
+    pub Initiator: String,
     /// Base64 encoded string of the Security Credential, which is encrypted using M-Pesa public key and validates the transaction on M-Pesa Core system.
-    SecurityCredential: String,
+    pub SecurityCredential: String,
     /// The path that stores information of time out transaction.
-    QueueTimeOutURL: String,
+    pub QueueTimeOutURL: String,
     /// The path that stores information of transaction.
-    ResultURL: String,
+    pub ResultURL: String,
     /// Organization Receiving the funds
-    TransactionID: String,
+    pub TransactionID: String,
+    /// `OriginatorConversationID` of the transaction. Used to look up transactions whose receipt is not known
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub OriginalConversationID: String,
     /// Optional
-    Occasion: String,
+    pub Occasion: String,
+}
+
+impl TransactionSatus {
+    /// Creates a query for the transaction with receipt `transaction_id` made by or to `party`,
+    /// deriving its identifier type
+    pub fn new<P: Into<Party>>(transaction_id: &str, party: P) -> TransactionSatus {
+        let party = party.into();
+        TransactionSatus {
+            CommandID: CommandIds::TransactionStatusQuery.to_string(),
+            IdentifierType: party.identifier(),
+            PartyA: party,
+            Remarks: String::new(),
+            Initiator: String::new(),
+            SecurityCredential: String::new(),
+            QueueTimeOutURL: String::new(),
+            ResultURL: String::new(),
+            TransactionID: transaction_id.to_string(),
+            OriginalConversationID: String::new(),
+            Occasion: String::new(),
+        }
+    }
+
+    /// Looks the transaction up by the `OriginatorConversationID` it was sent with, for when the receipt is not known
+    pub fn original_conversation_id(mut self, originator_conversation_id: &str) -> TransactionSatus {
+        self.OriginalConversationID = originator_conversation_id.to_string();
+        self
+    }
+
+    /// The initiator making the query and its security credential
+    pub fn initiator(mut self, initiator: &str, security_credential: &str) -> TransactionSatus {
+        self.Initiator = initiator.to_string();
+        self.SecurityCredential = security_credential.to_string();
+        self
+    }
+
+    /// The urls the status and timeouts are posted to
+    pub fn urls(mut self, result_url: &str, queue_timeout_url: &str) -> TransactionSatus {
+        self.ResultURL = result_url.to_string();
+        self.QueueTimeOutURL = queue_timeout_url.to_string();
+        self
+    }
+
+    /// Comments that are sent along with the query
+    pub fn remarks(mut self, remarks: &str) -> TransactionSatus {
+        self.Remarks = remarks.to_string();
+        self
+    }
+
+    /// Checks the request before it is sent
+    pub fn validate(&self) -> Result<(), ParameterError> {
+        CommandIds::check(&self.CommandID, Product::TransactionStatus)?;
+        self.PartyA.check(self.IdentifierType)?;
+        Ok(())
+    }
+
+    /// Checks the callback urls of the request against the rules of `environment`
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        check_callback_urls(&[("ResultURL", &self.ResultURL), ("QueueTimeOutURL", &self.QueueTimeOutURL)], environment)
+    }
+}
+
+/// Representation of the acknowledgement returned by a transaction status api call.
+/// The status itself is posted to the `ResultURL`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionStatusResponse {
+    /// A unique numeric code generated by the M-Pesa system of the response to a request.
+    pub ConversationID: String,
+    /// A unique numeric code generated by the M-Pesa system of the request.
+    pub OriginatorConversationID: String,
+    /// Status code of the submission. `0` means the request was accepted
+    pub ResponseCode: String,
+    /// A response message from the M-Pesa system accompanying the response to a request.
+    pub ResponseDescription: String,
 }
\ No newline at end of file
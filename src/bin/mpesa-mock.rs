@@ -0,0 +1,54 @@
+//! Runs the mock Daraja API, see `mpesa::mock`
+//!
+//! ```text
+//! mpesa-mock --port 8000 --callback-delay 2
+//! MPESA_BASE_URL=http://127.0.0.1:8000 mpesa b2c --phone 254708374149 --amount 100
+//! curl -X POST http://127.0.0.1:8000/mock/scenarios -H 'Content-Type: application/json' -d '{"scenario": "timeout"}'
+//! ```
+
+extern crate clap;
+extern crate mpesa;
+
+use std::process;
+use std::time::Duration;
+
+use clap::{App, Arg};
+
+use mpesa::mock::MockServer;
+
+fn main() {
+    let matches = App::new("mpesa-mock")
+        .about("An offline mock of the M-Pesa Daraja API")
+        .arg(Arg::with_name("host").long("host").takes_value(true).default_value("127.0.0.1").help("Address to listen on"))
+        .arg(Arg::with_name("port").long("port").takes_value(true).default_value("8000").help("Port to listen on"))
+        .arg(Arg::with_name("callback-delay").long("callback-delay").takes_value(true).default_value("2")
+             .help("Seconds between acknowledging a request and posting its callbacks"))
+        .arg(Arg::with_name("consumer-key").long("consumer-key").takes_value(true).requires("consumer-secret")
+             .help("Only issue access tokens for this consumer key"))
+        .arg(Arg::with_name("consumer-secret").long("consumer-secret").takes_value(true).requires("consumer-key")
+             .help("Only issue access tokens for this consumer secret"))
+        .get_matches();
+
+    let port: u16 = matches.value_of("port").unwrap_or_default().parse().unwrap_or_else(|_| usage("--port must be a port number"));
+    let delay: f64 = matches.value_of("callback-delay").unwrap_or_default().parse().unwrap_or_else(|_| usage("--callback-delay must be a number of seconds"));
+    if delay < 0.0 {
+        usage("--callback-delay must not be negative");
+    }
+    let host = matches.value_of("host").unwrap_or_default();
+
+    let mut server = MockServer::new().callback_delay(Duration::from_millis((delay * 1000.0) as u64));
+    if let (Some(consumer_key), Some(consumer_secret)) = (matches.value_of("consumer-key"), matches.value_of("consumer-secret")) {
+        server = server.credentials(consumer_key, consumer_secret);
+    }
+
+    eprintln!("mock Daraja API listening on http://{}:{}", host, port);
+    if let Err(error) = server.run((host, port)) {
+        eprintln!("unable to run the mock server: {}", error);
+        process::exit(71);
+    }
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(64)
+}
@@ -0,0 +1,704 @@
+//! Paying many customers at once from a CSV file
+//!
+//! The file has a header and one payout per row:
+//!
+//! ```text
+//! msisdn,amount,command_id,remarks
+//! 0708374149,1500,SalaryPayment,June salary
+//! 254708374150,200,,Airtime refund
+//! ```
+//!
+//! `command_id` defaults to `BusinessPayment` when empty. Every row is validated before anything is sent, and all the
+//! invalid rows are reported at once.
+//!
+//! A `BulkPayout` sends one `B2C` request per row, with at most `concurrency` requests in flight and at most
+//! `rate_limit` requests per second. The state of every row is appended to a state file, one JSON object per line,
+//! and flushed to disk before its request is sent. A run that was interrupted is resumed by running the same file
+//! with the same state file: only rows that were never sent are sent.
+//! Rows whose request may or may not have reached M-Pesa are left `unknown` and must be resolved, see
+//! `client::resolution`; so are rows a crashed run was sending, when the state file is opened again.
+//! Rows that failed are not sent again, pay them in a new batch.
+//!
+//! Requests refused with `429 Too Many Requests` or `503 Service Unavailable` are sent again after a growing pause,
+//! see `retries()`. Rows M-Pesa never processed, because no access token could be retrieved or it kept refusing them,
+//! are put back to `created` and the run stops, so they are sent when it is resumed.
+//!
+//! Install `results()` on your callback server to record the results posted to the `ResultURL`, `wait()` for them
+//! and write them out with `write_results()`. A result arriving before the acknowledgement of its payment is
+//! recorded is held back until it is.
+//!
+//! # Example
+//! ```no_run
+//! # use std::fs::File;
+//! # use std::path::Path;
+//! # use std::time::Duration;
+//! # use mpesa::api_products::b2c::B2C;
+//! # use mpesa::bulk::{read_payouts, BulkPayout};
+//! # use mpesa::config::Config;
+//! # let template: B2C = unimplemented!();
+//! let config = Config::load(None).unwrap();
+//! let payouts = read_payouts(File::open("payouts.csv").unwrap()).unwrap();
+//! let bulk = BulkPayout::new(template, payouts, Path::new("payouts.csv.state")).unwrap()
+//!     .concurrency(4)
+//!     .rate_limit(10)
+//!     .environment(config.environment);
+//! // hand `bulk.results()` to your `CallbackServer` here
+//!
+//! bulk.run(|| config.client().unwrap());
+//! bulk.wait(Duration::from_secs(5 * 60));
+//! bulk.write_results(File::create("payouts.results.csv").unwrap()).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam;
+use csv;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use serde::{Deserialize, Deserializer, Serializer};
+use serde_json;
+
+use api_products::b2c::{B2C, B2CResponse};
+use callbacks::{CallbackHandler, TransactionResult};
+use client::{MpesaClient, MpesaClientError};
+use parameters::{Amount, CommandIds, Environment, MpesaRequestError, Msisdn, ParameterError};
+use store::{Product, TransactionState};
+
+/// Command used for rows without one
+const DEFAULT_COMMAND: CommandIds = CommandIds::BusinessPayment;
+
+/// Length of generated `OriginatorConversationID`s
+const ORIGINATOR_CONVERSATION_ID_LENGTH: usize = 24;
+
+/// How often `wait()` looks for outstanding results
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How many times a throttled request is sent again by default
+const RETRIES: u32 = 3;
+
+/// The pause before the first retry of a throttled request by default, doubled before every next one
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// A payout read from the CSV file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payout {
+    /// The line of the file the payout is on, which identifies it in the state file
+    pub line: usize,
+    /// The customer to pay
+    pub msisdn: Msisdn,
+    /// The amount to pay
+    pub amount: Amount,
+    /// The command to pay with
+    pub command_id: CommandIds,
+    /// Remarks sent along with the payment
+    pub remarks: String,
+}
+
+/// The state of a payout, as recorded in the state file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PayoutState {
+    /// The line of the payout in the CSV file
+    pub line: usize,
+    /// The customer paid, to recognize a state file that belongs to another CSV file
+    pub msisdn: Msisdn,
+    /// The amount paid, to recognize a state file that belongs to another CSV file
+    pub amount: Amount,
+    /// Where the payment is in its lifecycle, see `store::lifecycle`
+    #[serde(serialize_with = "serialize_state", deserialize_with = "deserialize_state")]
+    pub state: TransactionState,
+    /// The id the payment was sent with, or the one M-Pesa acknowledged it with
+    pub originator_conversation_id: Option<String>,
+    /// `ConversationID` of the acknowledgement
+    pub conversation_id: Option<String>,
+    /// `ResultCode` of the result
+    pub result_code: Option<i64>,
+    /// `ResultDesc` of the result, or why the request failed
+    pub result_desc: Option<String>,
+    /// M-Pesa receipt number of the payment
+    pub receipt: Option<String>,
+}
+
+/// A row of the results file
+#[derive(Debug, Serialize)]
+struct ResultRow<'a> {
+    line: usize,
+    msisdn: &'a Msisdn,
+    amount: Amount,
+    command_id: CommandIds,
+    remarks: &'a str,
+    state: &'static str,
+    originator_conversation_id: Option<&'a str>,
+    conversation_id: Option<&'a str>,
+    result_code: Option<i64>,
+    result_desc: Option<&'a str>,
+    receipt: Option<&'a str>,
+}
+
+/// Sends the payments of a `BulkPayout`. Implemented by `MpesaClient`
+pub trait PaymentSender {
+    /// Sends one payment and returns the acknowledgement
+    fn send_b2c(&mut self, request: &B2C) -> Result<B2CResponse, MpesaClientError>;
+}
+
+impl PaymentSender for MpesaClient {
+    fn send_b2c(&mut self, request: &B2C) -> Result<B2CResponse, MpesaClientError> {
+        MpesaClient::send_b2c(self, request)
+    }
+}
+
+/// Definition of possible errors when making a bulk payout
+#[derive(Debug)]
+pub enum BulkError {
+    /// The CSV file could not be read
+    Csv(String),
+    /// Rows of the CSV file are invalid. Holds the line and description of each
+    InvalidRows(Vec<(usize, String)>),
+    /// The state file could not be read or written
+    State(io::Error),
+    /// The state file has a line that is not a `PayoutState`
+    CorruptState(String),
+    /// The state file belongs to another CSV file. Holds the line that differs
+    StateMismatch(usize),
+    /// The fields shared by all payments would be rejected, e.g. a callback url
+    Template(ParameterError),
+}
+
+/// Pays the rows of a CSV file, see the module documentation
+pub struct BulkPayout {
+    template: B2C,
+    payouts: Vec<Payout>,
+    ledger: Arc<Ledger>,
+    concurrency: usize,
+    interval: Option<Duration>,
+    environment: Environment,
+    retries: u32,
+    backoff: Duration,
+}
+
+/// Records the results of a `BulkPayout` as they are posted to the `ResultURL`
+pub struct BulkResults {
+    ledger: Arc<Ledger>,
+}
+
+/// The state file of a `BulkPayout`
+struct Ledger {
+    file: Mutex<File>,
+    states: Mutex<HashMap<usize, PayoutState>>,
+    /// Results that arrived while their payment was being sent, with the state they move it to
+    held: Mutex<Vec<(TransactionResult, TransactionState)>>,
+}
+
+/// Hands out the times requests may be sent at
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+/// Reads and validates the payouts of a CSV file, reporting every invalid row
+pub fn read_payouts<R: Read>(reader: R) -> Result<Vec<Payout>, BulkError> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+    let headers: Vec<String> = reader.headers()?.iter().map(|header| header.to_lowercase()).collect();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let (msisdn, amount) = match (column("msisdn"), column("amount")) {
+        (Some(msisdn), Some(amount)) => (msisdn, amount),
+        _ => return Err(BulkError::Csv(String::from("the file must have msisdn and amount columns"))),
+    };
+    let (command_id, remarks) = (column("command_id"), column("remarks"));
+
+    let mut payouts = Vec::new();
+    let mut invalid = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map(|position| position.line() as usize).unwrap_or(0);
+        let field = |index: Option<usize>| index.and_then(|index| record.get(index)).unwrap_or("");
+        match payout(line, field(Some(msisdn)), field(Some(amount)), field(command_id), field(remarks)) {
+            Ok(payout) => payouts.push(payout),
+            Err(error) => invalid.push((line, error)),
+        }
+    }
+
+    if !invalid.is_empty() {
+        return Err(BulkError::InvalidRows(invalid));
+    }
+    Ok(payouts)
+}
+
+/// Validates a row of the CSV file
+fn payout(line: usize, msisdn: &str, amount: &str, command_id: &str, remarks: &str) -> Result<Payout, String> {
+    let msisdn: Msisdn = msisdn.parse().map_err(|error| format!("{}", error))?;
+    let amount: Amount = amount.parse().map_err(|error| format!("{}", error))?;
+    amount.check(Product::B2C).map_err(|error| format!("{}", error))?;
+    let command_id = match command_id {
+        "" => DEFAULT_COMMAND,
+        command_id => CommandIds::check(command_id, Product::B2C).map_err(|error| format!("{}", error))?,
+    };
+    Ok(Payout { line: line, msisdn: msisdn, amount: amount, command_id: command_id, remarks: remarks.to_string() })
+}
+
+impl BulkPayout {
+    /// Prepares the payment of `payouts`, recording their state in the file at `state_path`.
+    /// `template` holds the fields shared by all payments: initiator, security credential, `PartyA`, urls and occasion.
+    pub fn new(template: B2C, payouts: Vec<Payout>, state_path: &Path) -> Result<BulkPayout, BulkError> {
+        let ledger = Ledger::open(state_path)?;
+        {
+            let states = ledger.states.lock().unwrap();
+            for payout in &payouts {
+                if let Some(state) = states.get(&payout.line) {
+                    if state.msisdn != payout.msisdn || state.amount != payout.amount {
+                        return Err(BulkError::StateMismatch(payout.line));
+                    }
+                }
+            }
+        }
+        Ok(BulkPayout {
+            template: template,
+            payouts: payouts,
+            ledger: Arc::new(ledger),
+            concurrency: 1,
+            interval: None,
+            environment: Environment::Sandbox,
+            retries: RETRIES,
+            backoff: RETRY_BACKOFF,
+        })
+    }
+
+    /// Sends at most `concurrency` requests at a time
+    pub fn concurrency(mut self, concurrency: usize) -> BulkPayout {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sends at most `per_second` requests per second
+    pub fn rate_limit(mut self, per_second: u32) -> BulkPayout {
+        self.interval = if per_second == 0 { None } else { Some(Duration::from_secs(1) / per_second) };
+        self
+    }
+
+    /// Checks the urls of the payments against the rules of `environment`, the sandbox by default
+    pub fn environment(mut self, environment: Environment) -> BulkPayout {
+        self.environment = environment;
+        self
+    }
+
+    /// Sends a request refused with 429 or 503 up to `retries` more times, pausing `backoff` before the first retry
+    /// and twice as long before every next one. 3 retries after 1 second by default
+    pub fn retries(mut self, retries: u32, backoff: Duration) -> BulkPayout {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sends every payout that was not sent before, with senders made by `sender`, one per concurrent request.
+    /// Returns the state of every payout once all requests have been acknowledged or failed.
+    /// Sending stops early when no access token can be retrieved or M-Pesa keeps refusing requests; the payouts left
+    /// are sent when the run is resumed. Nothing is sent when a payment would be rejected before reaching M-Pesa.
+    pub fn run<S, F>(&self, sender: F) -> Result<Vec<PayoutState>, BulkError>
+        where S: PaymentSender, F: Fn() -> S + Sync
+    {
+        let pending: Vec<&Payout> = self.payouts.iter()
+            .filter(|payout| self.ledger.state(payout.line).map(|state| state.state == TransactionState::Created).unwrap_or(true))
+            .collect();
+        self.check(&pending)?;
+        let next = AtomicUsize::new(0);
+        let stop = AtomicBool::new(false);
+        let failure = Mutex::new(None);
+        let limiter = self.interval.map(RateLimiter::new);
+
+        crossbeam::scope(|scope| {
+            for _ in 0..self.concurrency.min(pending.len()) {
+                scope.spawn(|| {
+                    let mut sender = sender();
+                    while !stop.load(Ordering::SeqCst) {
+                        let payout = match pending.get(next.fetch_add(1, Ordering::SeqCst)) {
+                            Some(payout) => *payout,
+                            None => break,
+                        };
+                        if let Some(ref limiter) = limiter {
+                            limiter.wait();
+                        }
+                        match self.pay(&mut sender, payout) {
+                            Ok(true) => (),
+                            Ok(false) => stop.store(true, Ordering::SeqCst),
+                            Err(error) => {
+                                stop.store(true, Ordering::SeqCst);
+                                *failure.lock().unwrap() = Some(error);
+                            },
+                        }
+                    }
+                });
+            }
+        });
+
+        match failure.into_inner().unwrap() {
+            Some(error) => Err(error),
+            None => Ok(self.states()),
+        }
+    }
+
+    /// Checks the urls of the template and the requests of `payouts` as the client would before sending them
+    fn check(&self, payouts: &[&Payout]) -> Result<(), BulkError> {
+        self.template.check_urls(self.environment).map_err(BulkError::Template)?;
+        let invalid: Vec<(usize, String)> = payouts.iter()
+            .filter_map(|payout| self.request(payout, String::new()).validate().err().map(|error| (payout.line, format!("{}", error))))
+            .collect();
+        if !invalid.is_empty() {
+            return Err(BulkError::InvalidRows(invalid));
+        }
+        Ok(())
+    }
+
+    /// The request paying `payout`
+    fn request(&self, payout: &Payout, originator_conversation_id: String) -> B2C {
+        B2C {
+            CommandID: payout.command_id.to_string(),
+            Amount: payout.amount,
+            PartyB: payout.msisdn.clone(),
+            Remarks: payout.remarks.clone(),
+            OriginatorConversationID: originator_conversation_id,
+            ..self.template.clone()
+        }
+    }
+
+    /// Sends one payout. Returns whether the batch can go on
+    fn pay<S: PaymentSender>(&self, sender: &mut S, payout: &Payout) -> Result<bool, BulkError> {
+        let originator_conversation_id: String = thread_rng().sample_iter(&Alphanumeric).take(ORIGINATOR_CONVERSATION_ID_LENGTH).collect();
+        let request = self.request(payout, originator_conversation_id.clone());
+
+        // recorded before sending, so a crash while sending never leads to paying twice
+        self.ledger.update(payout, |state| {
+            state.state = TransactionState::Submitted;
+            state.originator_conversation_id = Some(originator_conversation_id);
+        })?;
+
+        let result = self.send(sender, &request);
+        let go_on = match result {
+            Err(ref error) => !never_sent(error),
+            Ok(_) => true,
+        };
+        self.ledger.update(payout, |state| match result {
+            Ok(ref response) if response.ResponseCode.trim() == "0" => {
+                state.state = TransactionState::Acknowledged;
+                state.conversation_id = Some(response.ConversationID.clone());
+                if !response.OriginatorConversationID.is_empty() {
+                    state.originator_conversation_id = Some(response.OriginatorConversationID.clone());
+                }
+            },
+            Ok(ref response) => {
+                state.state = TransactionState::Failed;
+                state.result_desc = Some(format!("{} {}", response.ResponseCode, response.ResponseDescription));
+            },
+            Err(MpesaClientError::OutcomeUnknown { ref cause, .. }) => {
+                state.state = TransactionState::Unknown;
+                state.result_desc = Some(cause.clone());
+            },
+            Err(ref error) if never_sent(error) => {
+                state.state = TransactionState::Created;
+                state.originator_conversation_id = None;
+                state.result_desc = Some(format!("{}", error));
+            },
+            Err(ref error) => {
+                state.state = TransactionState::Failed;
+                state.result_desc = Some(format!("{}", error));
+            },
+        })?;
+        Ok(go_on)
+    }
+
+    /// Sends `request`, again after a pause while M-Pesa refuses it with 429 or 503
+    fn send<S: PaymentSender>(&self, sender: &mut S, request: &B2C) -> Result<B2CResponse, MpesaClientError> {
+        let mut backoff = self.backoff;
+        for _ in 0..self.retries {
+            match sender.send_b2c(request) {
+                Err(ref error) if throttled(error) => {
+                    warn!("{} was refused, sending it again in {:?}: {}", request.OriginatorConversationID, backoff, error);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                },
+                result => return result,
+            }
+        }
+        sender.send_b2c(request)
+    }
+
+    /// A `CallbackHandler` recording the results of the payouts
+    pub fn results(&self) -> BulkResults {
+        BulkResults { ledger: self.ledger.clone() }
+    }
+
+    /// Waits until the results of all acknowledged payouts have arrived, at most for `timeout`.
+    /// Returns whether they all arrived
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let outstanding = self.states().iter().any(|state| state.state == TransactionState::Acknowledged);
+            if !outstanding {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(WAIT_POLL_INTERVAL);
+        }
+    }
+
+    /// The state of every payout, in the order of the CSV file
+    pub fn states(&self) -> Vec<PayoutState> {
+        self.payouts.iter().map(|payout| self.ledger.state(payout.line).unwrap_or_else(|| PayoutState::new(payout))).collect()
+    }
+
+    /// Writes every payout with its state as CSV
+    pub fn write_results<W: Write>(&self, writer: W) -> Result<(), BulkError> {
+        let mut writer = csv::Writer::from_writer(writer);
+        for (payout, state) in self.payouts.iter().zip(self.states()) {
+            writer.serialize(ResultRow {
+                line: payout.line,
+                msisdn: &payout.msisdn,
+                amount: payout.amount,
+                command_id: payout.command_id,
+                remarks: &payout.remarks,
+                state: state.state.as_str(),
+                originator_conversation_id: state.originator_conversation_id.as_ref().map(String::as_str),
+                conversation_id: state.conversation_id.as_ref().map(String::as_str),
+                result_code: state.result_code,
+                result_desc: state.result_desc.as_ref().map(String::as_str),
+                receipt: state.receipt.as_ref().map(String::as_str),
+            })?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl PayoutState {
+    /// The state of a payout that has not been sent
+    fn new(payout: &Payout) -> PayoutState {
+        PayoutState {
+            line: payout.line,
+            msisdn: payout.msisdn.clone(),
+            amount: payout.amount,
+            state: TransactionState::Created,
+            originator_conversation_id: None,
+            conversation_id: None,
+            result_code: None,
+            result_desc: None,
+            receipt: None,
+        }
+    }
+}
+
+impl Ledger {
+    /// Opens the state file at `path`, creating it if needed, and reads the latest state of every payout in it.
+    /// Payouts a crashed run was sending may or may not have been paid, so they are marked `unknown`
+    fn open(path: &Path) -> Result<Ledger, BulkError> {
+        let mut states = HashMap::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let state: PayoutState = serde_json::from_str(&line).map_err(|error| BulkError::CorruptState(format!("{}", error)))?;
+                states.insert(state.line, state);
+            }
+        }
+        let ledger = Ledger {
+            file: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?),
+            states: Mutex::new(HashMap::new()),
+            held: Mutex::new(Vec::new()),
+        };
+        for state in states.values_mut().filter(|state| state.state == TransactionState::Submitted) {
+            state.state = TransactionState::Unknown;
+            state.result_desc = Some(String::from("the run was interrupted while the payment was being sent"));
+            ledger.append(state)?;
+        }
+        *ledger.states.lock().unwrap() = states;
+        Ok(ledger)
+    }
+
+    /// The latest state of the payout on `line`
+    fn state(&self, line: usize) -> Option<PayoutState> {
+        self.states.lock().unwrap().get(&line).cloned()
+    }
+
+    /// Changes the state of `payout` with `change` and appends it to the file, then records the results held back
+    /// for it
+    fn update<F: FnOnce(&mut PayoutState)>(&self, payout: &Payout, change: F) -> Result<(), BulkError> {
+        let mut states = self.states.lock().unwrap();
+        let mut state = states.get(&payout.line).cloned().unwrap_or_else(|| PayoutState::new(payout));
+        change(&mut state);
+        self.append(&state)?;
+        states.insert(state.line, state);
+        self.release(&mut states)
+    }
+
+    /// Records the result of the payout sent with the ids of `result`, if it is one of them.
+    /// The result is held back while its payout, or any payout when it matches none, is still being sent
+    fn conclude(&self, result: TransactionResult, next: TransactionState) -> Result<(), BulkError> {
+        let mut states = self.states.lock().unwrap();
+        self.held.lock().unwrap().push((result, next));
+        self.release(&mut states)
+    }
+
+    /// Records the held results whose payout has been sent. Results matching no payout are dropped once no payout is
+    /// being sent anymore
+    fn release(&self, states: &mut HashMap<usize, PayoutState>) -> Result<(), BulkError> {
+        let mut held = self.held.lock().unwrap();
+        let sending = states.values().any(|state| state.state == TransactionState::Submitted);
+        let mut outcome = Ok(());
+        for (result, next) in held.drain(..).collect::<Vec<_>>() {
+            let found = states.values()
+                .find(|state| state.originator_conversation_id.as_ref() == Some(&result.OriginatorConversationID)
+                    || state.conversation_id.as_ref() == Some(&result.ConversationID))
+                .cloned();
+            match found {
+                Some(ref state) if state.state == TransactionState::Submitted => held.push((result, next)),
+                Some(state) => {
+                    if let Err(error) = self.record(states, state, &result, next) {
+                        outcome = Err(error);
+                    }
+                },
+                None if sending => held.push((result, next)),
+                None => (),
+            }
+        }
+        outcome
+    }
+
+    /// Moves `state` to `next` with `result`, unless its lifecycle forbids it
+    fn record(&self, states: &mut HashMap<usize, PayoutState>, mut state: PayoutState, result: &TransactionResult,
+              next: TransactionState) -> Result<(), BulkError> {
+        if !state.state.can_become(next) {
+            return Ok(());
+        }
+        state.state = next;
+        state.conversation_id = Some(result.ConversationID.clone());
+        state.result_code = Some(result.ResultCode);
+        state.result_desc = Some(result.ResultDesc.clone());
+        if !result.TransactionID.is_empty() {
+            state.receipt = Some(result.TransactionID.clone());
+        }
+        self.append(&state)?;
+        states.insert(state.line, state);
+        Ok(())
+    }
+
+    /// Appends `state` to the file and makes sure it is on disk
+    fn append(&self, state: &PayoutState) -> Result<(), BulkError> {
+        let mut file = self.file.lock().unwrap();
+        let line = serde_json::to_string(state).map_err(|error| BulkError::CorruptState(format!("{}", error)))?;
+        writeln!(file, "{}", line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+impl CallbackHandler for BulkResults {
+    fn on_result(&self, result: TransactionResult) {
+        let next = if result.ResultCode == 0 { TransactionState::Completed } else { TransactionState::Failed };
+        let id = result.OriginatorConversationID.clone();
+        if let Err(error) = self.ledger.conclude(result, next) {
+            error!("unable to record the result of {}: {}", id, error);
+        }
+    }
+
+    fn on_timeout(&self, result: TransactionResult) {
+        let id = result.OriginatorConversationID.clone();
+        if let Err(error) = self.ledger.conclude(result, TransactionState::TimedOut) {
+            error!("unable to record the timeout of {}: {}", id, error);
+        }
+    }
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> RateLimiter {
+        RateLimiter { interval: interval, next: Mutex::new(Instant::now()) }
+    }
+
+    /// Blocks until the next request may be sent
+    fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        let now = Instant::now();
+        if slot > now {
+            thread::sleep(slot - now);
+        }
+    }
+}
+
+/// Whether M-Pesa refused the request for now, without processing it
+fn throttled(error: &MpesaClientError) -> bool {
+    match error {
+        &MpesaClientError::Request(MpesaRequestError::TooManyRequests, _) => true,
+        &MpesaClientError::Request(MpesaRequestError::ServiceUnavailable, _) => true,
+        _ => false,
+    }
+}
+
+/// Whether the request was not processed by M-Pesa, so the payout can be sent when the run is resumed
+fn never_sent(error: &MpesaClientError) -> bool {
+    match error {
+        &MpesaClientError::AccessToken(_) | &MpesaClientError::InvalidRequest(_) => true,
+        error => throttled(error),
+    }
+}
+
+fn serialize_state<S: Serializer>(state: &TransactionState, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(state.as_str())
+}
+
+fn deserialize_state<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TransactionState, D::Error> {
+    use serde::de::Error;
+    String::deserialize(deserializer)?.parse().map_err(|error| D::Error::custom(format!("{}", error)))
+}
+
+impl From<csv::Error> for BulkError {
+    fn from(error: csv::Error) -> Self {
+        BulkError::Csv(format!("{}", error))
+    }
+}
+
+impl From<io::Error> for BulkError {
+    fn from(error: io::Error) -> Self {
+        BulkError::State(error)
+    }
+}
+
+impl Display for BulkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BulkError::Csv(ref description) => write!(f, "BulkError::Csv -- {}", description),
+            &BulkError::InvalidRows(ref rows) => {
+                write!(f, "BulkError::InvalidRows -- {} invalid rows", rows.len())?;
+                for &(line, ref description) in rows {
+                    write!(f, "\n  line {}: {}", line, description)?;
+                }
+                Ok(())
+            },
+            &BulkError::State(ref error) => write!(f, "BulkError::State -- {}", error),
+            &BulkError::CorruptState(ref description) => write!(f, "BulkError::CorruptState -- {}", description),
+            &BulkError::StateMismatch(line) => {
+                write!(f, "BulkError::StateMismatch -- line {} differs from the state file, which belongs to another batch", line)
+            },
+            &BulkError::Template(ref error) => write!(f, "BulkError::Template -- {}", error),
+        }
+    }
+}
+
+impl Error for BulkError {
+    fn description(&self) -> &str {
+        "failed to make a bulk payout"
+    }
+}
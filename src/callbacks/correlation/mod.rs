@@ -0,0 +1,405 @@
+//! Matching of incoming callbacks to the requests that caused them
+//!
+//! When the client submits a request it registers the ids returned in the acknowledgement
+//! (`ConversationID`/`OriginatorConversationID` or `CheckoutRequestID`) with a `Correlator` and gets back
+//! a `PendingResult`, a future that resolves once the matching callback arrives or the wait times out.
+//!
+//! The `Correlator` is itself a `CallbackHandler` so it is placed in front of your own handler on the callback server.
+//! Callbacks that nobody is waiting for are passed on to that (fallback) handler untouched.
+//!
+//! A callback may arrive before the acknowledgement of its request, so before its ids are known. The client takes
+//! an `InFlight` from the correlator before sending a request and registers the ids through it. While requests are in
+//! flight, callbacks nobody is waiting for are held back and handed to the request registering their ids, or to the
+//! fallback handler once no request is in flight anymore, or after `HOLD_LIMIT` at the latest.
+//!
+//! # Example
+//! ```
+//! # extern crate mpesa;
+//! # extern crate futures;
+//! # extern crate serde_json;
+//! # use mpesa::callbacks::*;
+//! # use mpesa::callbacks::correlation::*;
+//! # use futures::Future;
+//! # use std::time::Duration;
+//! # fn main() {
+//! struct Unmatched;
+//! impl CallbackHandler for Unmatched {}
+//!
+//! let correlator = Correlator::new(Unmatched);
+//! let pending: PendingResult<TransactionResult> = correlator.register(&["AG_20180101_1234"], Duration::from_secs(60));
+//!
+//! // normally done by the callback server
+//! let callback: ResultCallback = serde_json::from_str(r#"{"Result": {
+//!     "ResultType": 0, "ResultCode": 0, "ResultDesc": "The service request is processed successfully.",
+//!     "OriginatorConversationID": "19455-424535-1", "ConversationID": "AG_20180101_1234",
+//!     "TransactionID": "LGR219G3EY"}}"#).unwrap();
+//! correlator.on_result(callback.Result);
+//!
+//! assert_eq!(pending.wait().unwrap().TransactionID, "LGR219G3EY");
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::error::Error;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::marker::PhantomData;
+
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+
+use super::{CallbackHandler, C2BTransaction, CallbackResponse, StkCallback, TransactionResult};
+use super::verify::SecurityEvent;
+
+/// How often waits that have run past their deadline are checked for
+const SWEEP_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest a callback nobody is waiting for is held back while requests are in flight
+pub const HOLD_LIMIT: Duration = Duration::from_secs(30);
+
+/// A callback delivered to a waiting request
+#[derive(Debug)]
+pub enum Delivery {
+    /// Posted to the `ResultURL`
+    Result(TransactionResult),
+    /// Posted to the `QueueTimeOutURL`
+    QueueTimeout(TransactionResult),
+    /// Posted to the `CallBackURL` of an STK push
+    Stk(StkCallback),
+}
+
+/// Reasons a `PendingResult` may fail to resolve to a result
+#[derive(Debug)]
+pub enum AwaitError {
+    /// No callback arrived before the wait timed out
+    TimedOut,
+    /// M-Pesa posted to the `QueueTimeOutURL` instead of the `ResultURL`
+    QueueTimeout(TransactionResult),
+    /// The correlator was dropped while waiting
+    Cancelled,
+    /// A callback of a different kind than expected was matched e.g. an STK callback for a B2C request
+    UnexpectedCallback(Delivery),
+}
+
+impl Delivery {
+    /// The ids the callback can be matched on
+    fn keys(&self) -> Vec<String> {
+        match self {
+            &Delivery::Result(ref result) | &Delivery::QueueTimeout(ref result) => {
+                vec![result.ConversationID.clone(), result.OriginatorConversationID.clone()]
+            },
+            &Delivery::Stk(ref callback) => vec![callback.CheckoutRequestID.clone(), callback.MerchantRequestID.clone()],
+        }
+    }
+
+    /// Passes the callback on to `handler`
+    fn hand_to(self, handler: &dyn CallbackHandler) {
+        match self {
+            Delivery::Result(result) => handler.on_result(result),
+            Delivery::QueueTimeout(result) => handler.on_timeout(result),
+            Delivery::Stk(callback) => handler.on_stk_callback(callback),
+        }
+    }
+}
+
+/// Callback types a `PendingResult` can resolve to
+pub trait Awaitable: Sized {
+    /// Extracts the typed result from a matched callback
+    fn from_delivery(delivery: Delivery) -> Result<Self, AwaitError>;
+}
+
+impl Awaitable for TransactionResult {
+    fn from_delivery(delivery: Delivery) -> Result<Self, AwaitError> {
+        match delivery {
+            Delivery::Result(result) => Ok(result),
+            Delivery::QueueTimeout(result) => Err(AwaitError::QueueTimeout(result)),
+            other => Err(AwaitError::UnexpectedCallback(other)),
+        }
+    }
+}
+
+impl Awaitable for StkCallback {
+    fn from_delivery(delivery: Delivery) -> Result<Self, AwaitError> {
+        match delivery {
+            Delivery::Stk(callback) => Ok(callback),
+            other => Err(AwaitError::UnexpectedCallback(other)),
+        }
+    }
+}
+
+/// A future resolving to the outcome of a submitted request
+#[derive(Debug)]
+pub struct PendingResult<T> {
+    id: String,
+    receiver: oneshot::Receiver<Result<Delivery, AwaitError>>,
+    result: PhantomData<T>,
+}
+
+impl<T> PendingResult<T> {
+    /// The id the result is being awaited under, e.g. the `ConversationID`
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+impl<T: Awaitable> Future for PendingResult<T> {
+    type Item = T;
+    type Error = AwaitError;
+
+    fn poll(&mut self) -> Poll<T, AwaitError> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Ok(delivery))) => T::from_delivery(delivery).map(Async::Ready),
+            Ok(Async::Ready(Err(error))) => Err(error),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(oneshot::Canceled) => Err(AwaitError::Cancelled),
+        }
+    }
+}
+
+struct Waiter {
+    keys: Vec<String>,
+    deadline: Instant,
+    sender: oneshot::Sender<Result<Delivery, AwaitError>>,
+}
+
+/// Held callbacks paired with their waiters, and those to pass on to the fallback handler
+type Released = (Vec<(Waiter, Delivery)>, Vec<Delivery>);
+
+struct Inner {
+    next_id: u64,
+    keys: HashMap<String, u64>,
+    waiters: HashMap<u64, Waiter>,
+    in_flight: usize,
+    held: Vec<(Instant, Delivery)>,
+}
+
+impl Inner {
+    fn take(&mut self, keys: &[String]) -> Option<Waiter> {
+        let id = keys.iter().filter_map(|key| self.keys.get(key)).next().cloned()?;
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            self.keys.remove(key);
+        }
+        Some(waiter)
+    }
+
+    fn take_overdue(&mut self, now: Instant) -> Vec<Waiter> {
+        let overdue: Vec<u64> = self.waiters.iter()
+            .filter(|&(_, waiter)| waiter.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        overdue.into_iter().filter_map(|id| {
+            let waiter = self.waiters.remove(&id)?;
+            for key in &waiter.keys {
+                self.keys.remove(key);
+            }
+            Some(waiter)
+        }).collect()
+    }
+
+    /// Pairs held callbacks with the requests now waiting for them. Returns the pairs, and the callbacks that are no
+    /// longer to be held back: all of them if no request is in flight, else those held for `HOLD_LIMIT` by `now`
+    fn release(&mut self, now: Instant) -> Released {
+        let mut matched = Vec::new();
+        let mut unmatched = Vec::new();
+        for (until, delivery) in self.held.drain(..).collect::<Vec<_>>() {
+            match self.take(&delivery.keys()) {
+                Some(waiter) => matched.push((waiter, delivery)),
+                None if self.in_flight == 0 || until <= now => unmatched.push(delivery),
+                None => self.held.push((until, delivery)),
+            }
+        }
+        (matched, unmatched)
+    }
+}
+
+/// A request being sent whose callback may arrive before its acknowledgement.
+/// Dropping it without registering any ids lets the callbacks held back for it go to the fallback handler
+pub struct InFlight {
+    correlator: Correlator,
+}
+
+impl InFlight {
+    /// Starts waiting for a callback carrying any of `ids`, which may have arrived already, see `Correlator::register()`
+    pub fn register<T: Awaitable>(self, ids: &[&str], timeout: Duration) -> PendingResult<T> {
+        self.correlator.register(ids, timeout)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let released = {
+            let mut inner = self.correlator.inner.lock().unwrap();
+            inner.in_flight -= 1;
+            inner.release(Instant::now())
+        };
+        hand_over(released, &*self.correlator.fallback);
+    }
+}
+
+/// Matches incoming callbacks to the requests waiting for them.
+///
+/// Cloning a `Correlator` is cheap and all clones share the same waits, so one clone can be handed to the client
+/// and another to the callback server.
+#[derive(Clone)]
+pub struct Correlator {
+    inner: Arc<Mutex<Inner>>,
+    fallback: Arc<dyn CallbackHandler>,
+}
+
+impl Correlator {
+    /// Creates a new correlator that passes callbacks nobody is waiting for to `fallback`
+    pub fn new<H: CallbackHandler + 'static>(fallback: H) -> Correlator {
+        let inner = Arc::new(Mutex::new(Inner {
+            next_id: 0,
+            keys: HashMap::new(),
+            waiters: HashMap::new(),
+            in_flight: 0,
+            held: Vec::new(),
+        }));
+
+        let correlator = Correlator {
+            inner: inner,
+            fallback: Arc::new(fallback),
+        };
+        let weak = Arc::downgrade(&correlator.inner);
+        let fallback = correlator.fallback.clone();
+        thread::spawn(move || sweep(weak, fallback));
+        correlator
+    }
+
+    /// Marks a request as being sent, holding back callbacks nobody is waiting for until its ids are registered
+    pub fn in_flight(&self) -> InFlight {
+        self.inner.lock().unwrap().in_flight += 1;
+        InFlight { correlator: self.clone() }
+    }
+
+    /// Starts waiting for a callback carrying any of `ids`.
+    ///
+    /// The returned future fails with `AwaitError::TimedOut` if nothing arrives within `timeout`.
+    pub fn register<T: Awaitable>(&self, ids: &[&str], timeout: Duration) -> PendingResult<T> {
+        let (sender, receiver) = oneshot::channel();
+        let keys: Vec<String> = ids.iter().filter(|id| !id.is_empty()).map(|id| id.to_string()).collect();
+
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        for key in &keys {
+            inner.keys.insert(key.clone(), id);
+        }
+        inner.waiters.insert(id, Waiter {
+            keys: keys.clone(),
+            deadline: Instant::now() + timeout,
+            sender: sender,
+        });
+
+        PendingResult {
+            id: keys.into_iter().next().unwrap_or_default(),
+            receiver: receiver,
+            result: PhantomData,
+        }
+    }
+
+    /// The number of requests currently waiting for a callback
+    pub fn pending(&self) -> usize {
+        self.inner.lock().unwrap().waiters.len()
+    }
+
+    /// Hands `delivery` to whoever is waiting for it, holds it back if its request may still be in flight,
+    /// or passes it on to the fallback handler
+    fn deliver(&self, delivery: Delivery) {
+        let waiter = {
+            let mut inner = self.inner.lock().unwrap();
+            match inner.take(&delivery.keys()) {
+                Some(waiter) => waiter,
+                None if inner.in_flight > 0 => return inner.held.push((Instant::now() + HOLD_LIMIT, delivery)),
+                None => return delivery.hand_to(&*self.fallback),
+            }
+        };
+        send(waiter, delivery, &*self.fallback);
+    }
+}
+
+/// Hands `delivery` to `waiter`, or to `fallback` if it stopped waiting
+fn send(waiter: Waiter, delivery: Delivery, fallback: &dyn CallbackHandler) {
+    if let Err(Ok(delivery)) = waiter.sender.send(Ok(delivery)) {
+        delivery.hand_to(fallback);
+    }
+}
+
+/// Delivers the callbacks released by `Inner::release()`
+fn hand_over((matched, unmatched): Released, fallback: &dyn CallbackHandler) {
+    for (waiter, delivery) in matched {
+        send(waiter, delivery, fallback);
+    }
+    for delivery in unmatched {
+        delivery.hand_to(fallback);
+    }
+}
+
+impl CallbackHandler for Correlator {
+    fn on_result(&self, result: TransactionResult) {
+        self.deliver(Delivery::Result(result))
+    }
+
+    fn on_timeout(&self, result: TransactionResult) {
+        self.deliver(Delivery::QueueTimeout(result))
+    }
+
+    fn on_stk_callback(&self, callback: StkCallback) {
+        self.deliver(Delivery::Stk(callback))
+    }
+
+    fn on_c2b_validation(&self, transaction: C2BTransaction) -> CallbackResponse {
+        self.fallback.on_c2b_validation(transaction)
+    }
+
+    fn on_c2b_confirmation(&self, transaction: C2BTransaction) {
+        self.fallback.on_c2b_confirmation(transaction)
+    }
+
+    fn on_security_event(&self, event: SecurityEvent) {
+        self.fallback.on_security_event(event)
+    }
+}
+
+/// Fails waits that have run past their deadline and releases callbacks held back for too long,
+/// until the correlator is dropped
+fn sweep(inner: Weak<Mutex<Inner>>, fallback: Arc<dyn CallbackHandler>) {
+    loop {
+        thread::sleep(SWEEP_INTERVAL);
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let now = Instant::now();
+        let (overdue, released) = {
+            let mut inner = inner.lock().unwrap();
+            (inner.take_overdue(now), inner.release(now))
+        };
+        for waiter in overdue {
+            let _ = waiter.sender.send(Err(AwaitError::TimedOut));
+        }
+        hand_over(released, &*fallback);
+    }
+}
+
+impl Display for AwaitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &AwaitError::TimedOut => write!(f, "AwaitError::TimedOut -- no callback arrived in time"),
+            &AwaitError::QueueTimeout(ref result) => write!(f, "AwaitError::QueueTimeout -- {}", result.ResultDesc),
+            &AwaitError::Cancelled => write!(f, "AwaitError::Cancelled -- the correlator was dropped"),
+            &AwaitError::UnexpectedCallback(ref delivery) => write!(f, "AwaitError::UnexpectedCallback -- {:?}", delivery),
+        }
+    }
+}
+
+impl Error for AwaitError {
+    fn description(&self) -> &str {
+        "failed to await the result of a request"
+    }
+}
@@ -0,0 +1,354 @@
+//! Suppression of callbacks M-Pesa delivers more than once
+//!
+//! Safaricom sometimes posts the same C2B confirmation (same `TransID`) or STK callback several times.
+//! A `Deduplicator` placed in front of your `CallbackHandler` remembers every callback it has passed on in a
+//! `DedupStore` and acknowledges repeats without invoking your handler again.
+//!
+//! Callbacks are identified as follows:
+//! * C2B confirmations by their `TransID`
+//! * STK callbacks by their `CheckoutRequestID`
+//! * `ResultURL` and `QueueTimeOutURL` callbacks by their `ConversationID`
+//!
+//! Besides the in-memory `MemoryStore`, `MysqlStore` and `SqliteStore` are available with the `mysql` and `sqlite`
+//! features. They create their `mpesa_processed_callbacks` table through the migrations in the `migrations` directory
+//! when opened.
+//!
+//! C2B validation requests are always passed on since M-Pesa expects a decision for every one of them.
+//!
+//! If the store cannot be reached the callback is passed on, so a store outage may let a duplicate through
+//! but never loses a callback. Likewise a callback is forgotten again if your handler panics on it, so that its
+//! redelivery is passed on.
+//!
+//! # Example
+//! ```
+//! # use mpesa::callbacks::CallbackHandler;
+//! # use mpesa::callbacks::dedup::{Deduplicator, MemoryStore};
+//! # use std::time::Duration;
+//! struct Handler;
+//! impl CallbackHandler for Handler {}
+//!
+//! let handler = Deduplicator::new(Handler, MemoryStore::new(10000, Duration::from_secs(60 * 60 * 24)));
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{CallbackHandler, C2BTransaction, CallbackResponse, StkCallback, TransactionResult};
+use super::verify::SecurityEvent;
+
+#[cfg(feature = "mysql")]
+pub use self::sql::MysqlStore;
+#[cfg(feature = "sqlite")]
+pub use self::sql::SqliteStore;
+
+/// Definition of possible errors when accessing a `DedupStore`
+#[derive(Debug)]
+pub enum DedupError {
+    /// The store could not be reached or failed to record the key
+    Store(String),
+}
+
+/// Storage of the keys of callbacks that have already been handled
+pub trait DedupStore: Send + Sync {
+    /// Records `key` as seen. Returns `true` if this is the first time it was seen.
+    ///
+    /// Checking and recording must happen atomically so that two concurrent deliveries of the same
+    /// callback do not both get `true`.
+    fn first_seen(&self, key: &str) -> Result<bool, DedupError>;
+
+    /// Forgets `key`, so that it is seen for the first time again. Used when the callback could not be handled
+    fn forget(&self, key: &str) -> Result<(), DedupError>;
+}
+
+/// An in-memory `DedupStore` that remembers up to `capacity` keys for `ttl` each.
+/// When full, the least recently seen key is forgotten first.
+pub struct MemoryStore {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<MemoryState>,
+}
+
+struct MemoryState {
+    generation: u64,
+    /// key to the last time it was seen and the generation of its entry in `order`
+    seen: HashMap<String, (Instant, u64)>,
+    /// keys from the least to the most recently seen. Entries whose generation is stale are skipped
+    order: VecDeque<(String, u64)>,
+}
+
+impl MemoryStore {
+    /// Creates a new store remembering up to `capacity` keys for `ttl` each
+    pub fn new(capacity: usize, ttl: Duration) -> MemoryStore {
+        MemoryStore {
+            capacity: capacity,
+            ttl: ttl,
+            state: Mutex::new(MemoryState {
+                generation: 0,
+                seen: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl MemoryState {
+    fn touch(&mut self, key: &str, now: Instant) {
+        self.generation += 1;
+        self.seen.insert(key.to_string(), (now, self.generation));
+        self.order.push_back((key.to_string(), self.generation));
+
+        // a key seen over and over leaves stale entries behind a live one at the front, which eviction never reaches
+        if self.order.len() > 2 * self.seen.len() + 16 {
+            let seen = &self.seen;
+            self.order.retain(|&(ref key, generation)| seen.get(key).map_or(false, |&(_, current)| current == generation));
+        }
+    }
+
+    /// Forgets expired keys and, if still over `capacity`, the least recently seen ones
+    fn evict(&mut self, capacity: usize, ttl: Duration, now: Instant) {
+        while let Some((key, generation)) = self.order.pop_front() {
+            let current = match self.seen.get(&key) {
+                Some(&(last_seen, current)) if current == generation => Some(last_seen),
+                _ => None,
+            };
+            match current {
+                // a stale entry, the key has been seen again since
+                None => continue,
+                Some(last_seen) => {
+                    if self.seen.len() > capacity || now.duration_since(last_seen) >= ttl {
+                        self.seen.remove(&key);
+                    } else {
+                        self.order.push_front((key, generation));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl DedupStore for MemoryStore {
+    fn first_seen(&self, key: &str) -> Result<bool, DedupError> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.evict(self.capacity, self.ttl, now);
+
+        let first = !state.seen.contains_key(key);
+        state.touch(key, now);
+        state.evict(self.capacity, self.ttl, now);
+
+        Ok(first)
+    }
+
+    fn forget(&self, key: &str) -> Result<(), DedupError> {
+        // its entry in `order` is skipped from now on
+        self.state.lock().unwrap().seen.remove(key);
+        Ok(())
+    }
+}
+
+/// A `CallbackHandler` that passes each callback on to `handler` only the first time it is delivered
+pub struct Deduplicator<H, S> {
+    handler: H,
+    store: S,
+}
+
+impl<H: CallbackHandler, S: DedupStore> Deduplicator<H, S> {
+    /// Creates a new deduplicator in front of `handler`, remembering callbacks in `store`
+    pub fn new(handler: H, store: S) -> Deduplicator<H, S> {
+        Deduplicator {
+            handler: handler,
+            store: store,
+        }
+    }
+
+    fn first_seen(&self, key: &str) -> bool {
+        match self.store.first_seen(key) {
+            Ok(first) => {
+                if !first {
+                    info!("suppressed duplicate callback {}", key);
+                }
+                first
+            },
+            Err(error) => {
+                error!("unable to check callback {} for duplicates: {}", key, error);
+                true
+            },
+        }
+    }
+
+    /// Passes a callback on to the handler with `handle` if it is delivered for the first time.
+    /// If the handler panics the callback is forgotten, so that its redelivery is passed on
+    fn handle_once<F: FnOnce(&H)>(&self, key: &str, handle: F) {
+        if !self.first_seen(key) {
+            return;
+        }
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| handle(&self.handler))) {
+            if let Err(error) = self.store.forget(key) {
+                error!("unable to forget callback {} after its handler failed: {}", key, error);
+            }
+            panic::resume_unwind(panic);
+        }
+    }
+}
+
+impl<H: CallbackHandler, S: DedupStore> CallbackHandler for Deduplicator<H, S> {
+    fn on_result(&self, result: TransactionResult) {
+        self.handle_once(&format!("result:{}", result.ConversationID), |handler| handler.on_result(result))
+    }
+
+    fn on_timeout(&self, result: TransactionResult) {
+        self.handle_once(&format!("timeout:{}", result.ConversationID), |handler| handler.on_timeout(result))
+    }
+
+    fn on_stk_callback(&self, callback: StkCallback) {
+        self.handle_once(&format!("stk:{}", callback.CheckoutRequestID), |handler| handler.on_stk_callback(callback))
+    }
+
+    fn on_c2b_validation(&self, transaction: C2BTransaction) -> CallbackResponse {
+        self.handler.on_c2b_validation(transaction)
+    }
+
+    fn on_c2b_confirmation(&self, transaction: C2BTransaction) {
+        self.handle_once(&format!("c2b:{}", transaction.TransID), |handler| handler.on_c2b_confirmation(transaction))
+    }
+
+    fn on_security_event(&self, event: SecurityEvent) {
+        self.handler.on_security_event(event)
+    }
+}
+
+#[cfg(any(feature = "mysql", feature = "sqlite"))]
+mod sql {
+    use diesel;
+
+    use super::DedupError;
+
+    table! {
+        mpesa_processed_callbacks (callback_key) {
+            callback_key -> Varchar,
+            received_at -> Timestamp,
+        }
+    }
+
+    impl From<diesel::result::Error> for DedupError {
+        fn from(error: diesel::result::Error) -> Self {
+            DedupError::Store(format!("{}", error))
+        }
+    }
+
+    /// Implements a `DedupStore` over a Diesel connection type.
+    /// Expects the `embedded_migrations` of the database to be in scope.
+    macro_rules! diesel_dedup_store {
+        ($(#[$attr:meta])* $store:ident, $connection:ty) => {
+            $(#[$attr])*
+            pub struct $store {
+                connection: Mutex<$connection>,
+            }
+
+            impl $store {
+                /// Connects to the database at `database_url` and brings its tables up to date
+                pub fn new(database_url: &str) -> Result<$store, DedupError> {
+                    let connection = <$connection>::establish(database_url)
+                        .map_err(|error| DedupError::Store(format!("{}", error)))?;
+                    embedded_migrations::run(&connection)
+                        .map_err(|error| DedupError::Store(format!("{}", error)))?;
+
+                    Ok($store {
+                        connection: Mutex::new(connection),
+                    })
+                }
+
+                /// Forgets callbacks received more than `days` days ago
+                pub fn purge(&self, days: u32) -> Result<usize, DedupError> {
+                    let connection = self.connection.lock().unwrap();
+                    let received_before = (Utc::now() - Duration::days(days as i64)).naive_utc();
+                    Ok(diesel::delete(mpesa_processed_callbacks::table.filter(mpesa_processed_callbacks::received_at.lt(received_before)))
+                        .execute(&*connection)?)
+                }
+            }
+
+            impl DedupStore for $store {
+                fn first_seen(&self, key: &str) -> Result<bool, DedupError> {
+                    let connection = self.connection.lock().unwrap();
+                    let inserted = diesel::insert_or_ignore_into(mpesa_processed_callbacks::table)
+                        .values((mpesa_processed_callbacks::callback_key.eq(key),
+                                 mpesa_processed_callbacks::received_at.eq(Utc::now().naive_utc())))
+                        .execute(&*connection)?;
+
+                    Ok(inserted == 1)
+                }
+
+                fn forget(&self, key: &str) -> Result<(), DedupError> {
+                    let connection = self.connection.lock().unwrap();
+                    diesel::delete(mpesa_processed_callbacks::table.find(key)).execute(&*connection)?;
+                    Ok(())
+                }
+            }
+        };
+    }
+
+    #[cfg(feature = "mysql")]
+    pub use self::mysql::MysqlStore;
+    #[cfg(feature = "sqlite")]
+    pub use self::sqlite::SqliteStore;
+
+    #[cfg(feature = "mysql")]
+    mod mysql {
+        use std::sync::Mutex;
+
+        use chrono::{Duration, Utc};
+        use diesel;
+        use diesel::prelude::*;
+        use diesel::mysql::MysqlConnection;
+
+        use super::mpesa_processed_callbacks;
+        use super::super::{DedupError, DedupStore};
+
+        embed_migrations!("migrations/mysql");
+
+        diesel_dedup_store! {
+            /// A `DedupStore` backed by a MySQL table, so duplicates are recognised across restarts and instances
+            MysqlStore, MysqlConnection
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod sqlite {
+        use std::sync::Mutex;
+
+        use chrono::{Duration, Utc};
+        use diesel;
+        use diesel::prelude::*;
+        use diesel::sqlite::SqliteConnection;
+
+        use super::mpesa_processed_callbacks;
+        use super::super::{DedupError, DedupStore};
+
+        embed_migrations!("migrations/sqlite");
+
+        diesel_dedup_store! {
+            /// A `DedupStore` backed by a SQLite table, so duplicates are recognised across restarts.
+            /// `database_url` is the path of the database file.
+            SqliteStore, SqliteConnection
+        }
+    }
+}
+
+impl Display for DedupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DedupError::Store(ref description) => write!(f, "DedupError::Store -- {}", description),
+        }
+    }
+}
+
+impl Error for DedupError {
+    fn description(&self) -> &str {
+        "failed to check a callback for duplicates"
+    }
+}
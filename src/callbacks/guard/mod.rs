@@ -0,0 +1,419 @@
+//! Protection of the callback endpoints against callbacks not sent by M-Pesa
+//!
+//! Callback urls are public, so anyone who learns them could post fake payments to them.
+//! A `CallbackGuard` installed on the callback server offers two defences:
+//! * **Source allowlist** - only callbacks from known Safaricom addresses are accepted. `X-Forwarded-For` is only
+//!   consulted when the connection comes from one of your own trusted proxies.
+//! * **Path tokens** - the urls handed to M-Pesa carry an unguessable token as their last path segment,
+//!   e.g. `https://example.com/callbacks/result/8Jk2...`. Callbacks to a url with a token that was never issued are rejected.
+//!
+//! Every rejected callback is logged under the `mpesa::audit` target.
+//!
+//! # Example
+//! ```
+//! # use mpesa::callbacks::guard::*;
+//! # use mpesa::callbacks::server::CallbackPaths;
+//! # use mpesa::parameters::Environment;
+//! let tokens = TokenRegistry::new();
+//! let guard = CallbackGuard::new()
+//!     .allowlist(SourceAllowlist::safaricom().trusted_proxy("10.0.0.0/8".parse().unwrap()))
+//!     .tokens(tokens.clone());
+//!
+//! // urls to put in the `ResultURL`, `QueueTimeOutURL` and `CallBackURL` of your requests
+//! let urls = CallbackUrls::new("https://example.com", CallbackPaths::default(), tokens);
+//! // a url M-Pesa would drop callbacks to is better found at startup
+//! urls.check_urls(Environment::Production).unwrap();
+//! let urls = urls.issue();
+//! assert!(urls.result_url.starts_with("https://example.com/callbacks/result/"));
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::{self, Display};
+use std::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use parameters::{check_callback_urls, Environment, ParameterError};
+use parameters::callback_url::FORBIDDEN_KEYWORDS;
+use super::server::CallbackPaths;
+
+/// Target under which rejected callbacks are logged
+pub const AUDIT_TARGET: &str = "mpesa::audit";
+
+/// Addresses Safaricom sends callbacks from, as published on the Daraja portal
+pub const SAFARICOM_ADDRESSES: &[&str] = &[
+    "196.201.214.200/32",
+    "196.201.214.206/32",
+    "196.201.213.114/32",
+    "196.201.214.207/32",
+    "196.201.214.208/32",
+    "196.201.213.44/32",
+    "196.201.212.127/32",
+    "196.201.212.138/32",
+    "196.201.212.129/32",
+    "196.201.212.136/32",
+    "196.201.212.74/32",
+    "196.201.212.69/32",
+];
+
+/// Length of generated path tokens
+const TOKEN_LENGTH: usize = 32;
+
+/// A block of IP addresses e.g. `196.201.214.0/24`. A lone address is treated as a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+/// Definition of possible errors when parsing a `Cidr`
+#[derive(Debug, PartialEq)]
+pub enum CidrParseError {
+    /// The address part is not an IP address
+    InvalidAddress(String),
+    /// The prefix length is not a number or too long for the address
+    InvalidPrefix(String),
+}
+
+impl Cidr {
+    /// Whether `ip` falls within the block
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, *ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            },
+            (IpAddr::V4(_), IpAddr::V6(ip)) => {
+                // IPv4-mapped addresses as reported by dual stack sockets
+                let segments = ip.segments();
+                if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+                    ip.to_ipv4().map_or(false, |ip| self.contains(&IpAddr::V4(ip)))
+                } else {
+                    false
+                }
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` bits of `network` and `ip` are equal
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    let remaining_bits = prefix % 8;
+
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = CidrParseError;
+
+    fn from_str(s: &str) -> Result<Cidr, CidrParseError> {
+        let mut parts = s.trim().splitn(2, '/');
+        let address = parts.next().unwrap_or("");
+        let address: IpAddr = address.parse()
+            .map_err(|_| CidrParseError::InvalidAddress(address.to_string()))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match parts.next() {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(CidrParseError::InvalidPrefix(prefix.to_string())),
+            },
+            None => max_prefix,
+        };
+
+        Ok(Cidr { address: address, prefix: prefix })
+    }
+}
+
+/// The addresses callbacks are accepted from
+#[derive(Debug, Clone, Default)]
+pub struct SourceAllowlist {
+    allowed: Vec<Cidr>,
+    trusted_proxies: Vec<Cidr>,
+}
+
+impl SourceAllowlist {
+    /// Creates an empty allowlist, rejecting everything
+    pub fn new() -> SourceAllowlist {
+        SourceAllowlist::default()
+    }
+
+    /// Creates an allowlist of the `SAFARICOM_ADDRESSES`
+    pub fn safaricom() -> SourceAllowlist {
+        SAFARICOM_ADDRESSES.iter()
+            .fold(SourceAllowlist::new(), |allowlist, cidr| allowlist.allow(cidr.parse().unwrap()))
+    }
+
+    /// Accepts callbacks from `cidr`
+    pub fn allow(mut self, cidr: Cidr) -> SourceAllowlist {
+        self.allowed.push(cidr);
+        self
+    }
+
+    /// Trusts the `X-Forwarded-For` header on connections from `cidr`, e.g. your load balancer
+    pub fn trusted_proxy(mut self, cidr: Cidr) -> SourceAllowlist {
+        self.trusted_proxies.push(cidr);
+        self
+    }
+
+    fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Works out the address the callback originates from.
+    ///
+    /// `X-Forwarded-For` is read from right to left, skipping trusted proxies, but only if `peer` is itself
+    /// a trusted proxy. Anything to the left of the first untrusted address could have been forged by the sender.
+    pub fn source(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        if !self.is_trusted_proxy(&peer) {
+            return peer;
+        }
+
+        let mut source = peer;
+        if let Some(forwarded_for) = forwarded_for {
+            for hop in forwarded_for.rsplit(',') {
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(ip) => {
+                        source = ip;
+                        if !self.is_trusted_proxy(&ip) {
+                            break;
+                        }
+                    },
+                    Err(_) => break,
+                }
+            }
+        }
+        source
+    }
+
+    /// Whether callbacks from `ip` are accepted
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        self.allowed.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// The path tokens that have been issued and are accepted by the callback server.
+///
+/// Cloning a `TokenRegistry` is cheap and all clones share the same tokens.
+#[derive(Debug, Clone, Default)]
+pub struct TokenRegistry {
+    tokens: Arc<Mutex<HashSet<String>>>,
+}
+
+impl TokenRegistry {
+    /// Creates a registry with no tokens
+    pub fn new() -> TokenRegistry {
+        TokenRegistry::default()
+    }
+
+    /// Generates and records a new random token
+    pub fn issue(&self) -> String {
+        // M-Pesa drops callbacks to urls containing certain keywords, which a random token may spell out
+        let token = loop {
+            let token: String = thread_rng().sample_iter(&Alphanumeric).take(TOKEN_LENGTH).collect();
+            let lowercase = token.to_lowercase();
+            if !FORBIDDEN_KEYWORDS.iter().any(|keyword| lowercase.contains(keyword)) {
+                break token;
+            }
+        };
+        self.insert(&token);
+        token
+    }
+
+    /// Accepts a previously issued token, e.g. one loaded from your own storage after a restart
+    pub fn insert(&self, token: &str) {
+        self.tokens.lock().unwrap().insert(token.to_string());
+    }
+
+    /// Stops accepting `token`
+    pub fn revoke(&self, token: &str) {
+        self.tokens.lock().unwrap().remove(token);
+    }
+
+    /// Whether `token` has been issued and not revoked
+    pub fn accepts(&self, token: &str) -> bool {
+        self.tokens.lock().unwrap().contains(token)
+    }
+}
+
+/// Callback urls carrying a freshly issued path token
+#[derive(Debug, Clone)]
+pub struct CallbackUrlSet {
+    /// The token embedded in all the urls
+    pub token: String,
+    /// Url to use as `ResultURL`
+    pub result_url: String,
+    /// Url to use as `QueueTimeOutURL`
+    pub timeout_url: String,
+    /// Url to use as the `CallBackURL` of an STK push
+    pub stk_url: String,
+    /// Url to use as the C2B `ValidationURL`
+    pub c2b_validation_url: String,
+    /// Url to use as the C2B `ConfirmationURL`
+    pub c2b_confirmation_url: String,
+}
+
+/// Generates callback urls pointing at a guarded callback server
+#[derive(Debug, Clone)]
+pub struct CallbackUrls {
+    base_url: String,
+    paths: CallbackPaths,
+    tokens: TokenRegistry,
+}
+
+impl CallbackUrls {
+    /// Creates a generator of urls under `base_url`, e.g. `https://example.com`, for a server listening on `paths`.
+    /// `tokens` must be shared with the server's `CallbackGuard`.
+    pub fn new(base_url: &str, paths: CallbackPaths, tokens: TokenRegistry) -> CallbackUrls {
+        CallbackUrls {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            paths: paths,
+            tokens: tokens,
+        }
+    }
+
+    /// Issues a new token and returns the urls carrying it.
+    /// Call this once per C2B url registration or per request you want to be able to tell apart.
+    pub fn issue(&self) -> CallbackUrlSet {
+        self.urls(self.tokens.issue())
+    }
+
+    /// Checks the urls this generator issues against the rules of `environment`, e.g. at startup
+    pub fn check_urls(&self, environment: Environment) -> Result<(), ParameterError> {
+        let urls = self.urls(String::from("token"));
+        check_callback_urls(&[
+            ("ResultURL", &urls.result_url),
+            ("QueueTimeOutURL", &urls.timeout_url),
+            ("CallBackURL", &urls.stk_url),
+            ("ValidationURL", &urls.c2b_validation_url),
+            ("ConfirmationURL", &urls.c2b_confirmation_url),
+        ], environment)
+    }
+
+    fn urls(&self, token: String) -> CallbackUrlSet {
+        let url = |path: &str| format!("{}{}/{}", self.base_url, path, token);
+
+        CallbackUrlSet {
+            result_url: url(&self.paths.result),
+            timeout_url: url(&self.paths.timeout),
+            stk_url: url(&self.paths.stk),
+            c2b_validation_url: url(&self.paths.c2b_validation),
+            c2b_confirmation_url: url(&self.paths.c2b_confirmation),
+            token: token,
+        }
+    }
+}
+
+/// Reasons a callback may be rejected by the `CallbackGuard`
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    /// The source address could not be determined
+    UnknownSource,
+    /// The callback came from an address that is not on the allowlist
+    SourceNotAllowed(IpAddr),
+    /// The url carried no token or one that was never issued
+    InvalidToken,
+}
+
+/// Decides whether callbacks reaching the callback server are accepted
+#[derive(Debug, Clone, Default)]
+pub struct CallbackGuard {
+    allowlist: Option<SourceAllowlist>,
+    tokens: Option<TokenRegistry>,
+}
+
+impl CallbackGuard {
+    /// Creates a guard that accepts everything until an allowlist or token registry is added
+    pub fn new() -> CallbackGuard {
+        CallbackGuard::default()
+    }
+
+    /// Only accepts callbacks from addresses on `allowlist`
+    pub fn allowlist(mut self, allowlist: SourceAllowlist) -> CallbackGuard {
+        self.allowlist = Some(allowlist);
+        self
+    }
+
+    /// Only accepts callbacks to urls carrying a token issued by `tokens`
+    pub fn tokens(mut self, tokens: TokenRegistry) -> CallbackGuard {
+        self.tokens = Some(tokens);
+        self
+    }
+
+    /// Whether the callback server expects a token as the last path segment
+    pub fn requires_token(&self) -> bool {
+        self.tokens.is_some()
+    }
+
+    /// Checks a callback received on `path` from `peer`. Rejections are logged under `AUDIT_TARGET`.
+    pub fn check(&self, path: &str, peer: Option<IpAddr>, forwarded_for: Option<&str>, token: Option<&str>) -> Result<(), Rejection> {
+        let result = self.evaluate(peer, forwarded_for, token);
+        if let Err(ref rejection) = result {
+            warn!(target: AUDIT_TARGET, "rejected callback to {} from {} (X-Forwarded-For: {}): {}",
+                  path,
+                  peer.map(|peer| peer.to_string()).unwrap_or_else(|| String::from("unknown")),
+                  forwarded_for.unwrap_or("-"),
+                  rejection);
+        }
+        result
+    }
+
+    fn evaluate(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>, token: Option<&str>) -> Result<(), Rejection> {
+        if let Some(ref allowlist) = self.allowlist {
+            let peer = peer.ok_or(Rejection::UnknownSource)?;
+            let source = allowlist.source(peer, forwarded_for);
+            if !allowlist.allows(&source) {
+                return Err(Rejection::SourceNotAllowed(source));
+            }
+        }
+
+        if let Some(ref tokens) = self.tokens {
+            match token {
+                Some(token) if tokens.accepts(token) => (),
+                _ => return Err(Rejection::InvalidToken),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for CidrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CidrParseError::InvalidAddress(ref address) => write!(f, "CidrParseError::InvalidAddress -- {}", address),
+            &CidrParseError::InvalidPrefix(ref prefix) => write!(f, "CidrParseError::InvalidPrefix -- {}", prefix),
+        }
+    }
+}
+
+impl Error for CidrParseError {
+    fn description(&self) -> &str {
+        "invalid CIDR block"
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Rejection::UnknownSource => write!(f, "Rejection::UnknownSource -- the source address is unknown"),
+            &Rejection::SourceNotAllowed(ref ip) => write!(f, "Rejection::SourceNotAllowed -- {} is not on the allowlist", ip),
+            &Rejection::InvalidToken => write!(f, "Rejection::InvalidToken -- the path token is missing or was never issued"),
+        }
+    }
+}
@@ -0,0 +1,335 @@
+//! Representation of the callbacks M-Pesa posts back to your endpoints once a request has been processed
+//!
+//! Most Mpesa API products only return an acknowledgement when called. The actual outcome of the transaction
+//! is delivered later as a POST to one of the urls supplied in the request:
+//! * **ResultURL** - results of B2C, B2B, Reversal, Transaction Status and Account Balance requests
+//! * **QueueTimeOutURL** - requests that timed out while waiting in the M-Pesa queue
+//! * **CallBackURL** - results of Lipa na Mpesa Online (STK Push) requests
+//! * **ValidationURL/ConfirmationURL** - C2B payments made to a registered shortcode
+//!
+//! This module contains typed representations of those payloads and the `CallbackHandler` trait through which
+//! they are delivered to your application. Handlers can be layered, e.g. a `Correlator` placed in front of your own
+//! handler hands results that the client is waiting on back to the client and lets everything else fall through.
+
+pub mod correlation;
+pub mod dedup;
+pub mod guard;
+pub mod server;
+pub mod verify;
+
+use std::sync::Arc;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_json::{Number, Value};
+use parameters::{Amount, ResponseCodes};
+use self::verify::SecurityEvent;
+
+/// Body posted to the `ResultURL` and `QueueTimeOutURL` of B2C, B2B, Reversal, Transaction Status and Account Balance requests
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResultCallback {
+    /// The result of the transaction
+    pub Result: TransactionResult,
+}
+
+/// The result of a transaction as reported by M-Pesa
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionResult {
+    /// Status code indicating whether the transaction was already sent to your listener. Usually 0
+    #[serde(deserialize_with = "number_or_string")]
+    pub ResultType: i64,
+    /// Numeric status code indicating the status of the transaction processing. 0 means success
+    #[serde(deserialize_with = "number_or_string")]
+    pub ResultCode: i64,
+    /// Message explaining the result of the transaction
+    pub ResultDesc: String,
+    /// The unique identifier of the request as returned in the acknowledgement
+    pub OriginatorConversationID: String,
+    /// The unique identifier generated by M-Pesa for the request
+    pub ConversationID: String,
+    /// The M-Pesa receipt number of the transaction
+    #[serde(default)]
+    pub TransactionID: String,
+    /// Additional details of the transaction, only present on some successful results
+    #[serde(default)]
+    pub ResultParameters: Option<ResultParameters>,
+    /// Data echoed back by M-Pesa, such as the `QueueTimeoutURL`
+    #[serde(default)]
+    pub ReferenceData: Option<Value>,
+}
+
+/// Container of the key/value pairs describing a transaction result
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultParameters {
+    /// The parameters. M-Pesa sends a lone object instead of a list when there is only one
+    #[serde(deserialize_with = "one_or_many")]
+    pub ResultParameter: Vec<ResultParameter>,
+}
+
+/// A single result parameter e.g. `TransactionAmount`, `ReceiverPartyPublicName`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultParameter {
+    /// Name of the parameter
+    pub Key: String,
+    /// Value of the parameter. Numbers and strings are both used by M-Pesa
+    #[serde(default)]
+    pub Value: Option<Value>,
+}
+
+/// Body posted to the `CallBackURL` of a Lipa na Mpesa Online (STK Push) request
+#[derive(Debug, Clone, Deserialize)]
+pub struct StkCallbackBody {
+    /// Wrapper around the callback
+    pub Body: StkCallbackEnvelope,
+}
+
+/// Wrapper around the STK callback
+#[derive(Debug, Clone, Deserialize)]
+pub struct StkCallbackEnvelope {
+    /// The callback itself
+    #[serde(rename = "stkCallback")]
+    pub stk_callback: StkCallback,
+}
+
+/// The result of a Lipa na Mpesa Online (STK Push) request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StkCallback {
+    /// Merchant Request ID as returned in the acknowledgement
+    pub MerchantRequestID: String,
+    /// Check out Request ID as returned in the acknowledgement
+    pub CheckoutRequestID: String,
+    /// Numeric status code. 0 means the customer paid
+    #[serde(deserialize_with = "number_or_string")]
+    pub ResultCode: i64,
+    /// Message explaining the result
+    pub ResultDesc: String,
+    /// Details of the payment. Absent when the payment failed
+    #[serde(default)]
+    pub CallbackMetadata: Option<CallbackMetadata>,
+}
+
+/// Details of a successful STK payment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallbackMetadata {
+    /// The items e.g. `Amount`, `MpesaReceiptNumber`, `TransactionDate`, `PhoneNumber`
+    #[serde(deserialize_with = "one_or_many")]
+    pub Item: Vec<CallbackItem>,
+}
+
+/// A single item of the STK callback metadata
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallbackItem {
+    /// Name of the item
+    pub Name: String,
+    /// Value of the item. `Balance` is usually sent without one
+    #[serde(default)]
+    pub Value: Option<Value>,
+}
+
+/// Body posted to the C2B `ValidationURL` and `ConfirmationURL`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct C2BTransaction {
+    /// e.g. `Pay Bill` or `Buy Goods`
+    #[serde(default)]
+    pub TransactionType: String,
+    /// The M-Pesa receipt number of the payment
+    pub TransID: String,
+    /// Time of the payment in the format **yyyymmddhhiiss**
+    #[serde(deserialize_with = "string_or_number")]
+    pub TransTime: String,
+    /// The amount paid
+    #[serde(deserialize_with = "string_or_number")]
+    pub TransAmount: String,
+    /// The shortcode that received the payment
+    #[serde(deserialize_with = "string_or_number")]
+    pub BusinessShortCode: String,
+    /// The account number entered by the customer
+    #[serde(default)]
+    pub BillRefNumber: String,
+    /// Invoice number, if any
+    #[serde(default)]
+    pub InvoiceNumber: String,
+    /// Balance of the receiving shortcode after the payment. Only sent on confirmation
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub OrgAccountBalance: String,
+    /// An id you can echo back during validation
+    #[serde(default)]
+    pub ThirdPartyTransID: String,
+    /// The phone number that made the payment
+    #[serde(deserialize_with = "string_or_number")]
+    pub MSISDN: String,
+    /// First name of the customer
+    #[serde(default)]
+    pub FirstName: String,
+    /// Middle name of the customer
+    #[serde(default)]
+    pub MiddleName: String,
+    /// Last name of the customer
+    #[serde(default)]
+    pub LastName: String,
+}
+
+/// The response sent back to M-Pesa after receiving a callback.
+/// For C2B validation it decides whether the payment goes through.
+#[derive(Debug, Clone, Serialize)]
+pub struct CallbackResponse {
+    /// `0` to accept, anything else to reject
+    pub ResultCode: String,
+    /// A description of the decision
+    pub ResultDesc: String,
+}
+
+impl CallbackResponse {
+    /// Acknowledges a callback, or accepts a C2B payment during validation
+    pub fn accept() -> CallbackResponse {
+        CallbackResponse {
+            ResultCode: ResponseCodes::SuccessC2B.to_string(),
+            ResultDesc: String::from("Accepted"),
+        }
+    }
+
+    /// Rejects a C2B payment during validation
+    pub fn reject() -> CallbackResponse {
+        CallbackResponse {
+            ResultCode: ResponseCodes::RejectTranscation.to_string(),
+            ResultDesc: String::from("Rejected"),
+        }
+    }
+}
+
+impl TransactionResult {
+    /// Looks up one of the `ResultParameters` by its key
+    pub fn parameter(&self, key: &str) -> Option<&Value> {
+        self.ResultParameters.as_ref()
+            .and_then(|params| params.ResultParameter.iter().find(|param| param.Key == key))
+            .and_then(|param| param.Value.as_ref())
+    }
+
+    /// The `TransactionAmount` of the result, if it has one
+    pub fn amount(&self) -> Option<Amount> {
+        self.parameter("TransactionAmount").and_then(Amount::from_value)
+    }
+}
+
+impl StkCallback {
+    /// Looks up one of the `CallbackMetadata` items by its name
+    pub fn item(&self, name: &str) -> Option<&Value> {
+        self.CallbackMetadata.as_ref()
+            .and_then(|metadata| metadata.Item.iter().find(|item| item.Name == name))
+            .and_then(|item| item.Value.as_ref())
+    }
+
+    /// The `Amount` paid, if the payment went through
+    pub fn amount(&self) -> Option<Amount> {
+        self.item("Amount").and_then(Amount::from_value)
+    }
+}
+
+impl C2BTransaction {
+    /// The `TransAmount` paid, if it can be read
+    pub fn amount(&self) -> Option<Amount> {
+        self.TransAmount.parse().ok()
+    }
+}
+
+/// Implemented by whatever should act on incoming callbacks.
+///
+/// All methods have default implementations that ignore the callback (and accept C2B payments),
+/// so you only need to implement the ones you are interested in.
+pub trait CallbackHandler: Send + Sync {
+    /// Called with the results posted to a `ResultURL`
+    fn on_result(&self, _result: TransactionResult) {}
+
+    /// Called with the results posted to a `QueueTimeOutURL`
+    fn on_timeout(&self, _result: TransactionResult) {}
+
+    /// Called with the results posted to the `CallBackURL` of an STK push
+    fn on_stk_callback(&self, _callback: StkCallback) {}
+
+    /// Called when M-Pesa asks whether a C2B payment should be accepted
+    fn on_c2b_validation(&self, _transaction: C2BTransaction) -> CallbackResponse {
+        CallbackResponse::accept()
+    }
+
+    /// Called when a C2B payment has been completed
+    fn on_c2b_confirmation(&self, _transaction: C2BTransaction) {}
+
+    /// Called when a callback could not be verified against the API, see the `verify` module
+    fn on_security_event(&self, _event: SecurityEvent) {}
+}
+
+impl<H: CallbackHandler + ?Sized> CallbackHandler for Arc<H> {
+    fn on_result(&self, result: TransactionResult) {
+        (**self).on_result(result)
+    }
+
+    fn on_timeout(&self, result: TransactionResult) {
+        (**self).on_timeout(result)
+    }
+
+    fn on_stk_callback(&self, callback: StkCallback) {
+        (**self).on_stk_callback(callback)
+    }
+
+    fn on_c2b_validation(&self, transaction: C2BTransaction) -> CallbackResponse {
+        (**self).on_c2b_validation(transaction)
+    }
+
+    fn on_c2b_confirmation(&self, transaction: C2BTransaction) {
+        (**self).on_c2b_confirmation(transaction)
+    }
+
+    fn on_security_event(&self, event: SecurityEvent) {
+        (**self).on_security_event(event)
+    }
+}
+
+/// M-Pesa sends a lone object instead of a list when a list has a single element
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(item) => Ok(vec![item]),
+        OneOrMany::Many(items) => Ok(items),
+    }
+}
+
+/// M-Pesa sends the codes of some callbacks as strings, e.g. `"ResultCode": "0"`
+fn number_or_string<'de, D>(deserializer: D) -> Result<i64, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(i64),
+        String(String),
+    }
+
+    match NumberOrString::deserialize(deserializer)? {
+        NumberOrString::Number(number) => Ok(number),
+        NumberOrString::String(text) => text.trim().parse()
+            .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&text), &"an integer")),
+    }
+}
+
+/// M-Pesa sends amounts, phone numbers and times of some C2B callbacks as numbers, e.g. `"TransAmount": 10`
+fn string_or_number<'de, D>(deserializer: D) -> Result<String, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(Number),
+    }
+
+    match StringOrNumber::deserialize(deserializer)? {
+        StringOrNumber::String(text) => Ok(text),
+        StringOrNumber::Number(number) => Ok(number.to_string()),
+    }
+}
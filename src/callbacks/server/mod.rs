@@ -0,0 +1,207 @@
+//! An HTTP server receiving the callbacks posted by M-Pesa
+//!
+//! Each callback is decoded into its typed representation and handed to a `CallbackHandler`.
+//! M-Pesa is always acknowledged with the response expected by the API.
+//!
+//! A `CallbackGuard` can be installed to reject callbacks that do not come from M-Pesa, see the `guard` module.
+//!
+//! # Example
+//! ```no_run
+//! # use mpesa::callbacks::CallbackHandler;
+//! # use mpesa::callbacks::server::CallbackServer;
+//! struct Handler;
+//! impl CallbackHandler for Handler {}
+//!
+//! CallbackServer::new(Handler).run("0.0.0.0:8080").expect("unable to start the callback server");
+//! ```
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+
+use actix_web::{server, App, AsyncResponder, FutureResponse, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::http::Method;
+use futures::future::{self, Future};
+
+use super::{CallbackHandler, CallbackResponse, C2BTransaction, ResultCallback, StkCallbackBody};
+use super::guard::CallbackGuard;
+
+/// The paths the callback server listens on.
+/// The urls given to M-Pesa e.g. the `ResultURL` should point to these.
+/// They must not contain keywords M-Pesa rejects urls for, such as `mpesa`, see `parameters::callback_url`.
+#[derive(Debug, Clone)]
+pub struct CallbackPaths {
+    /// Path receiving `ResultURL` callbacks
+    pub result: String,
+    /// Path receiving `QueueTimeOutURL` callbacks
+    pub timeout: String,
+    /// Path receiving STK push `CallBackURL` callbacks
+    pub stk: String,
+    /// Path receiving C2B validation requests
+    pub c2b_validation: String,
+    /// Path receiving C2B confirmations
+    pub c2b_confirmation: String,
+}
+
+impl Default for CallbackPaths {
+    fn default() -> CallbackPaths {
+        CallbackPaths {
+            result: String::from("/callbacks/result"),
+            timeout: String::from("/callbacks/timeout"),
+            stk: String::from("/callbacks/stk"),
+            c2b_validation: String::from("/callbacks/c2b/validation"),
+            c2b_confirmation: String::from("/callbacks/c2b/confirmation"),
+        }
+    }
+}
+
+/// A server dispatching M-Pesa callbacks to a `CallbackHandler`
+pub struct CallbackServer {
+    handler: Arc<dyn CallbackHandler>,
+    paths: CallbackPaths,
+    guard: Option<Arc<CallbackGuard>>,
+}
+
+/// State shared by the request handlers
+struct ServerState {
+    handler: Arc<dyn CallbackHandler>,
+    guard: Option<Arc<CallbackGuard>>,
+}
+
+impl CallbackServer {
+    /// Creates a new callback server listening on the default `CallbackPaths`
+    pub fn new<H: CallbackHandler + 'static>(handler: H) -> CallbackServer {
+        CallbackServer {
+            handler: Arc::new(handler),
+            paths: CallbackPaths::default(),
+            guard: None,
+        }
+    }
+
+    /// Listens on `paths` instead of the default ones
+    pub fn paths(mut self, paths: CallbackPaths) -> CallbackServer {
+        self.paths = paths;
+        self
+    }
+
+    /// Rejects callbacks that do not pass `guard`.
+    /// If the guard requires path tokens, every path is expected to be followed by a `/{token}` segment.
+    pub fn guard(mut self, guard: CallbackGuard) -> CallbackServer {
+        self.guard = Some(Arc::new(guard));
+        self
+    }
+
+    /// Starts listening on `address`. Blocks until the server is stopped.
+    pub fn run<A: ToSocketAddrs>(self, address: A) -> io::Result<()> {
+        let handler = self.handler;
+        let guard = self.guard;
+        let suffix = match guard {
+            Some(ref guard) if guard.requires_token() => "/{token}",
+            _ => "",
+        };
+        let paths = CallbackPaths {
+            result: format!("{}{}", self.paths.result, suffix),
+            timeout: format!("{}{}", self.paths.timeout, suffix),
+            stk: format!("{}{}", self.paths.stk, suffix),
+            c2b_validation: format!("{}{}", self.paths.c2b_validation, suffix),
+            c2b_confirmation: format!("{}{}", self.paths.c2b_confirmation, suffix),
+        };
+
+        server::new(move || {
+                App::with_state(ServerState { handler: handler.clone(), guard: guard.clone() })
+                    .resource(&paths.result, |r| r.method(Method::POST).a(result))
+                    .resource(&paths.timeout, |r| r.method(Method::POST).a(timeout))
+                    .resource(&paths.stk, |r| r.method(Method::POST).a(stk))
+                    .resource(&paths.c2b_validation, |r| r.method(Method::POST).a(c2b_validation))
+                    .resource(&paths.c2b_confirmation, |r| r.method(Method::POST).a(c2b_confirmation))
+            })
+            .bind(address)?
+            .run();
+
+        Ok(())
+    }
+}
+
+/// Runs the callback past the guard, if any. Returns the response to send if it is rejected.
+fn rejection(req: &HttpRequest<ServerState>) -> Option<HttpResponse> {
+    let guard = match req.state().guard {
+        Some(ref guard) => guard,
+        None => return None,
+    };
+    let forwarded_for = req.headers().get("x-forwarded-for").and_then(|value| value.to_str().ok());
+    let peer = req.peer_addr().map(|address| address.ip());
+
+    match guard.check(req.path(), peer, forwarded_for, req.match_info().get("token")) {
+        Ok(()) => None,
+        Err(_) => Some(HttpResponse::Forbidden().finish()),
+    }
+}
+
+fn result(req: HttpRequest<ServerState>) -> FutureResponse<HttpResponse> {
+    if let Some(response) = rejection(&req) {
+        return Box::new(future::ok(response));
+    }
+    let handler = req.state().handler.clone();
+    req.json()
+        .from_err()
+        .and_then(move |callback: ResultCallback| {
+            handler.on_result(callback.Result);
+            Ok(HttpResponse::Ok().json(CallbackResponse::accept()))
+        })
+        .responder()
+}
+
+fn timeout(req: HttpRequest<ServerState>) -> FutureResponse<HttpResponse> {
+    if let Some(response) = rejection(&req) {
+        return Box::new(future::ok(response));
+    }
+    let handler = req.state().handler.clone();
+    req.json()
+        .from_err()
+        .and_then(move |callback: ResultCallback| {
+            handler.on_timeout(callback.Result);
+            Ok(HttpResponse::Ok().json(CallbackResponse::accept()))
+        })
+        .responder()
+}
+
+fn stk(req: HttpRequest<ServerState>) -> FutureResponse<HttpResponse> {
+    if let Some(response) = rejection(&req) {
+        return Box::new(future::ok(response));
+    }
+    let handler = req.state().handler.clone();
+    req.json()
+        .from_err()
+        .and_then(move |callback: StkCallbackBody| {
+            handler.on_stk_callback(callback.Body.stk_callback);
+            Ok(HttpResponse::Ok().json(CallbackResponse::accept()))
+        })
+        .responder()
+}
+
+fn c2b_validation(req: HttpRequest<ServerState>) -> FutureResponse<HttpResponse> {
+    if let Some(response) = rejection(&req) {
+        return Box::new(future::ok(response));
+    }
+    let handler = req.state().handler.clone();
+    req.json()
+        .from_err()
+        .and_then(move |transaction: C2BTransaction| {
+            Ok(HttpResponse::Ok().json(handler.on_c2b_validation(transaction)))
+        })
+        .responder()
+}
+
+fn c2b_confirmation(req: HttpRequest<ServerState>) -> FutureResponse<HttpResponse> {
+    if let Some(response) = rejection(&req) {
+        return Box::new(future::ok(response));
+    }
+    let handler = req.state().handler.clone();
+    req.json()
+        .from_err()
+        .and_then(move |transaction: C2BTransaction| {
+            handler.on_c2b_confirmation(transaction);
+            Ok(HttpResponse::Ok().json(CallbackResponse::accept()))
+        })
+        .responder()
+}
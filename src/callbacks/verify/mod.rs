@@ -0,0 +1,337 @@
+//! Verification of callbacks against the API before they are trusted
+//!
+//! Even with a `CallbackGuard` in place you may not want to release goods on the word of a callback alone.
+//! A `Verifier` placed in front of your `CallbackHandler` looks every payment up before passing it on:
+//! * **C2B confirmations** are looked up with a Transaction Status query on their `TransID`
+//! * **Successful STK callbacks** are checked with an STK query on their `CheckoutRequestID`, then looked up with a
+//!   Transaction Status query on their `MpesaReceiptNumber`
+//!
+//! The payment is only passed on if the receipt, amount and MSISDN reported by the API match the callback
+//! and the transaction is reported as completed. Otherwise your handler receives a `SecurityEvent` instead.
+//!
+//! Verification happens in the background so M-Pesa is acknowledged straight away. `WORKERS` threads verify the
+//! callbacks one after the other; if more than `QUEUE_LENGTH` are waiting, the callback is not verified and your
+//! handler receives a `SecurityEvent::VerificationFailed` for it instead.
+//! The Transaction Status results arrive on your `ResultURL`, so the client given to the `Verifier` must have a
+//! `Correlator` which sits in front of the `Verifier` on the callback server:
+//!
+//! ```text
+//! CallbackServer -> Correlator -> Verifier -> your handler
+//! ```
+
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+use futures::Future;
+use serde_json::Value;
+
+use api_products::lipa_na_mpesa_online_query_request::LipaNaMpesaOnlineQueryRequest;
+use api_products::transaction_status::TransactionSatus;
+use client::MpesaClient;
+use parameters::{Amount, Party};
+use super::{CallbackHandler, C2BTransaction, CallbackResponse, StkCallback, TransactionResult};
+
+/// Remarks sent along with verification queries
+const REMARKS: &str = "Callback verification";
+
+/// Number of threads verifying callbacks
+pub const WORKERS: usize = 4;
+
+/// Most callbacks waiting to be verified
+pub const QUEUE_LENGTH: usize = 256;
+
+/// The details needed to look transactions up
+#[derive(Debug, Clone)]
+pub struct VerificationConfig {
+    /// The name of the initiator of Transaction Status queries
+    pub initiator: String,
+    /// The security credential of the initiator
+    pub security_credential: String,
+    /// The shortcode, paybill or till payments are received on. Its identifier type is derived from it
+    pub short_code: Party,
+    /// The `ResultURL` for Transaction Status queries. Must reach the `Correlator` of the client
+    pub result_url: String,
+    /// The `QueueTimeOutURL` for Transaction Status queries
+    pub timeout_url: String,
+    /// The shortcode STK pushes are made to
+    pub stk_short_code: String,
+    /// The Lipa na Mpesa Online passkey of `stk_short_code`
+    pub stk_passkey: String,
+}
+
+impl VerificationConfig {
+    /// A Transaction Status query for the transaction with `receipt`,
+    /// or with `originator_conversation_id` when the receipt is not known
+    pub fn transaction_status(&self, receipt: &str, originator_conversation_id: &str, remarks: &str) -> TransactionSatus {
+        TransactionSatus::new(receipt, self.short_code.clone())
+            .original_conversation_id(originator_conversation_id)
+            .initiator(&self.initiator, &self.security_credential)
+            .urls(&self.result_url, &self.timeout_url)
+            .remarks(remarks)
+    }
+}
+
+/// A detail of a payment that can disagree between a callback and the API
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    /// The M-Pesa receipt number
+    Receipt,
+    /// The amount paid
+    Amount,
+    /// The phone number that paid
+    Msisdn,
+    /// Whether the transaction completed
+    Status,
+}
+
+/// A detail on which a callback and the API disagree
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    /// The detail in question
+    pub field: Field,
+    /// The value claimed by the callback
+    pub claimed: String,
+    /// The value reported by the API
+    pub reported: String,
+}
+
+/// Raised instead of passing a payment on when its callback could not be verified.
+/// `reference` is the `TransID` of C2B confirmations and the `CheckoutRequestID` of STK callbacks.
+#[derive(Debug, Clone)]
+pub enum SecurityEvent {
+    /// The callback disagrees with the transaction reported by the API, a sign of a forged callback
+    Discrepancy { reference: String, discrepancies: Vec<Discrepancy> },
+    /// The API reports that no such payment went through
+    Unconfirmed { reference: String, description: String },
+    /// The payment could not be looked up, e.g. because the API could not be reached
+    VerificationFailed { reference: String, description: String },
+}
+
+/// A `CallbackHandler` that only passes on payments confirmed by the API
+pub struct Verifier<H> {
+    inner: Arc<Inner<H>>,
+    queue: Mutex<SyncSender<Job>>,
+}
+
+/// A callback waiting to be verified
+enum Job {
+    Stk(StkCallback),
+    C2B(C2BTransaction),
+}
+
+struct Inner<H> {
+    handler: H,
+    client: Mutex<MpesaClient>,
+    config: VerificationConfig,
+}
+
+impl<H: CallbackHandler + 'static> Verifier<H> {
+    /// Creates a new verifier in front of `handler` looking payments up with `client`
+    pub fn new(handler: H, client: MpesaClient, config: VerificationConfig) -> Verifier<H> {
+        let inner = Arc::new(Inner {
+            handler: handler,
+            client: Mutex::new(client),
+            config: config,
+        });
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..WORKERS {
+            let (inner, receiver) = (inner.clone(), receiver.clone());
+            thread::spawn(move || work(&inner, &receiver));
+        }
+
+        Verifier {
+            inner: inner,
+            queue: Mutex::new(sender),
+        }
+    }
+
+    /// Queues a callback for verification, raising a security event for it if the queue is full
+    fn enqueue(&self, job: Job) {
+        let result = self.queue.lock().unwrap().try_send(job);
+        let (job, description) = match result {
+            Ok(()) => return,
+            Err(TrySendError::Full(job)) => (job, "too many callbacks are waiting to be verified"),
+            Err(TrySendError::Disconnected(job)) => (job, "no worker is left to verify callbacks"),
+        };
+        let reference = match job {
+            Job::Stk(ref callback) => callback.CheckoutRequestID.clone(),
+            Job::C2B(ref transaction) => transaction.TransID.clone(),
+        };
+        self.inner.handler.on_security_event(failed(&reference, description));
+    }
+}
+
+/// Verifies queued callbacks until the `Verifier` is dropped
+fn work<H: CallbackHandler>(inner: &Inner<H>, queue: &Mutex<Receiver<Job>>) {
+    loop {
+        // the lock is released before verifying so other workers can take the next callback
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        match job {
+            Job::Stk(callback) => match inner.verify_stk(&callback) {
+                Ok(()) => inner.handler.on_stk_callback(callback),
+                Err(event) => inner.handler.on_security_event(event),
+            },
+            Job::C2B(transaction) => match inner.verify_c2b(&transaction) {
+                Ok(()) => inner.handler.on_c2b_confirmation(transaction),
+                Err(event) => inner.handler.on_security_event(event),
+            },
+        }
+    }
+}
+
+impl<H: CallbackHandler> Inner<H> {
+    /// Looks the transaction identified by `receipt` up with a Transaction Status query
+    fn transaction_status(&self, reference: &str, receipt: &str) -> Result<TransactionResult, SecurityEvent> {
+        let request = self.config.transaction_status(receipt, "", REMARKS);
+
+        // the lock is released before waiting so other verifications can be submitted meanwhile
+        let pending = self.client.lock().unwrap().transaction_status(&request)
+            .map_err(|error| failed(reference, error))?;
+        let result = pending.wait().map_err(|error| failed(reference, error))?;
+
+        if result.ResultCode != 0 {
+            return Err(SecurityEvent::Unconfirmed {
+                reference: reference.to_string(),
+                description: result.ResultDesc,
+            });
+        }
+        Ok(result)
+    }
+
+    fn verify_c2b(&self, transaction: &C2BTransaction) -> Result<(), SecurityEvent> {
+        let status = self.transaction_status(&transaction.TransID, &transaction.TransID)?;
+        let discrepancies = compare(&status, &transaction.TransID, &transaction.TransAmount, &transaction.MSISDN);
+        check(&transaction.TransID, discrepancies)
+    }
+
+    fn verify_stk(&self, callback: &StkCallback) -> Result<(), SecurityEvent> {
+        let reference = &callback.CheckoutRequestID;
+        let query = LipaNaMpesaOnlineQueryRequest::new(&self.config.stk_short_code, &self.config.stk_passkey, reference);
+        let response = self.client.lock().unwrap().stk_query(&query)
+            .map_err(|error| failed(reference, error))?;
+
+        if response.ResultCode != "0" {
+            return Err(SecurityEvent::Unconfirmed {
+                reference: reference.clone(),
+                description: response.ResultDesc,
+            });
+        }
+
+        let receipt = callback.item("MpesaReceiptNumber").map(value_string).unwrap_or_default();
+        let amount = callback.item("Amount").map(value_string).unwrap_or_default();
+        let msisdn = callback.item("PhoneNumber").map(value_string).unwrap_or_default();
+
+        let status = self.transaction_status(reference, &receipt)?;
+        check(reference, compare(&status, &receipt, &amount, &msisdn))
+    }
+}
+
+/// Compares the receipt, amount and MSISDN claimed by a callback to the Transaction Status result
+fn compare(status: &TransactionResult, receipt: &str, amount: &str, msisdn: &str) -> Vec<Discrepancy> {
+    let reported = |key: &str| status.parameter(key).map(value_string).unwrap_or_default();
+    let mut discrepancies = Vec::new();
+
+    let reported_receipt = reported("ReceiptNo");
+    if reported_receipt != receipt {
+        discrepancies.push(Discrepancy { field: Field::Receipt, claimed: receipt.to_string(), reported: reported_receipt });
+    }
+
+    let reported_amount = reported("Amount");
+    let parsed_amount = reported_amount.parse::<Amount>().ok();
+    if parsed_amount.is_none() || parsed_amount != amount.parse().ok() {
+        discrepancies.push(Discrepancy { field: Field::Amount, claimed: amount.to_string(), reported: reported_amount });
+    }
+
+    // reported as e.g. "254708374149 - John Doe"
+    let debit_party = reported("DebitPartyName");
+    let reported_msisdn: String = debit_party.trim().chars().take_while(|c| c.is_digit(10)).collect();
+    if reported_msisdn.is_empty() || reported_msisdn != msisdn.trim() {
+        discrepancies.push(Discrepancy { field: Field::Msisdn, claimed: msisdn.to_string(), reported: debit_party });
+    }
+
+    let reported_status = reported("TransactionStatus");
+    if reported_status != "Completed" {
+        discrepancies.push(Discrepancy { field: Field::Status, claimed: String::from("Completed"), reported: reported_status });
+    }
+
+    discrepancies
+}
+
+fn check(reference: &str, discrepancies: Vec<Discrepancy>) -> Result<(), SecurityEvent> {
+    if discrepancies.is_empty() {
+        Ok(())
+    } else {
+        Err(SecurityEvent::Discrepancy {
+            reference: reference.to_string(),
+            discrepancies: discrepancies,
+        })
+    }
+}
+
+fn failed<E: Display>(reference: &str, error: E) -> SecurityEvent {
+    SecurityEvent::VerificationFailed {
+        reference: reference.to_string(),
+        description: format!("{}", error),
+    }
+}
+
+/// M-Pesa sends numbers and strings interchangeably
+fn value_string(value: &Value) -> String {
+    match value {
+        &Value::String(ref string) => string.clone(),
+        other => other.to_string(),
+    }
+}
+
+impl<H: CallbackHandler + 'static> CallbackHandler for Verifier<H> {
+    fn on_result(&self, result: TransactionResult) {
+        self.inner.handler.on_result(result)
+    }
+
+    fn on_timeout(&self, result: TransactionResult) {
+        self.inner.handler.on_timeout(result)
+    }
+
+    fn on_stk_callback(&self, callback: StkCallback) {
+        // only successful payments need to be confirmed before they are trusted
+        if callback.ResultCode != 0 {
+            return self.inner.handler.on_stk_callback(callback);
+        }
+        self.enqueue(Job::Stk(callback))
+    }
+
+    fn on_c2b_validation(&self, transaction: C2BTransaction) -> CallbackResponse {
+        self.inner.handler.on_c2b_validation(transaction)
+    }
+
+    fn on_c2b_confirmation(&self, transaction: C2BTransaction) {
+        self.enqueue(Job::C2B(transaction))
+    }
+
+    fn on_security_event(&self, event: SecurityEvent) {
+        self.inner.handler.on_security_event(event)
+    }
+}
+
+impl Display for SecurityEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SecurityEvent::Discrepancy { ref reference, ref discrepancies } => {
+                write!(f, "SecurityEvent::Discrepancy -- {}:", reference)?;
+                for discrepancy in discrepancies {
+                    write!(f, " {:?} claimed {} but reported {};", discrepancy.field, discrepancy.claimed, discrepancy.reported)?;
+                }
+                Ok(())
+            },
+            &SecurityEvent::Unconfirmed { ref reference, ref description } => write!(f, "SecurityEvent::Unconfirmed -- {}: {}", reference, description),
+            &SecurityEvent::VerificationFailed { ref reference, ref description } => write!(f, "SecurityEvent::VerificationFailed -- {}: {}", reference, description),
+        }
+    }
+}
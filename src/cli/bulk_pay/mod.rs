@@ -0,0 +1,106 @@
+//! The `bulk-pay` subcommand, paying the rows of a CSV file, see `mpesa::bulk`
+//!
+//! With `--port`, the callback server runs alongside to record the results of the payments, which are waited for
+//! before the results file is written.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use clap::ArgMatches;
+
+use mpesa::api_products::b2c::B2C;
+use mpesa::bulk::{read_payouts, BulkPayout, PayoutState};
+use mpesa::callbacks::server::CallbackServer;
+use mpesa::parameters::Amount;
+use mpesa::store::TransactionState;
+
+use super::output::{self, Format};
+use super::{required, CliError, Context, EXIT_OK, EXIT_REJECTED, EXIT_TEMPFAIL};
+
+/// Pays the rows of the file and returns the exit code matching their states
+pub fn run(matches: &ArgMatches, context: &Context) -> Result<i32, CliError> {
+    let path = required(matches, "file")?;
+    let state_path = matches.value_of("state").map(String::from).unwrap_or_else(|| format!("{}.state", path));
+    let results_path = matches.value_of("results").map(String::from).unwrap_or_else(|| format!("{}.results.csv", path));
+    let concurrency = number(matches, "concurrency")?;
+    let rate = number(matches, "rate")?;
+    let wait = Duration::from_secs(number(matches, "wait")? as u64);
+
+    let payouts = read_payouts(File::open(path)?)?;
+    let first = payouts.first().ok_or_else(|| CliError::Usage(format!("{} has no rows", path)))?.msisdn.clone();
+    let (initiator, security_credential) = context.initiator()?;
+    let (result_url, timeout_url) = context.urls()?;
+    let template = B2C {
+        InitiatorName: initiator.to_string(),
+        SecurityCredential: security_credential,
+        CommandID: String::new(),
+        Amount: Amount::shillings(0),
+        PartyA: context.short_code()?,
+        // replaced by the customer of each row
+        PartyB: first,
+        Remarks: String::new(),
+        QueueTimeOutURL: timeout_url.to_string(),
+        ResultURL: result_url.to_string(),
+        Occasion: matches.value_of("occasion").unwrap_or_default().to_string(),
+        OriginatorConversationID: String::new(),
+    };
+    // fail before anything is recorded when the urls would be rejected or the token cannot be had
+    context.config.check_urls()?;
+    context.config.access_token()?.token()?;
+
+    let bulk = BulkPayout::new(template, payouts, Path::new(&state_path))?
+        .concurrency(concurrency)
+        .rate_limit(rate as u32)
+        .environment(context.config.environment);
+
+    if let Some(port) = matches.value_of("port") {
+        let port: u16 = port.parse().map_err(|_| CliError::Usage(String::from("--port must be a port number")))?;
+        let host = matches.value_of("host").unwrap_or("0.0.0.0").to_string();
+        let server = CallbackServer::new(bulk.results());
+        thread::spawn(move || {
+            if let Err(error) = server.run((host.as_str(), port)) {
+                eprintln!("unable to run the callback server: {}", error);
+            }
+        });
+    }
+
+    let config = context.config.clone();
+    bulk.run(|| config.client().expect("the credentials were checked above"))?;
+    eprintln!("sent the payouts, state recorded in {}", state_path);
+    if matches.is_present("port") && !bulk.wait(wait) {
+        eprintln!("gave up waiting for results after {}s", wait.as_secs());
+    }
+    let states = bulk.states();
+
+    bulk.write_results(File::create(&results_path)?)?;
+    eprintln!("results written to {}", results_path);
+    print_summary(&states, context.format)?;
+    Ok(exit_code(&states))
+}
+
+/// Prints how many payouts are in each state
+fn print_summary(states: &[PayoutState], format: Format) -> Result<(), CliError> {
+    let mut counts = BTreeMap::new();
+    for state in states {
+        *counts.entry(state.state.as_str()).or_insert(0) += 1;
+    }
+    output::print(&json!(counts), format)
+}
+
+/// `EXIT_OK` when every payout completed, `EXIT_TEMPFAIL` when some are still open and `EXIT_REJECTED` when some failed
+fn exit_code(states: &[PayoutState]) -> i32 {
+    if states.iter().any(|state| state.state == TransactionState::Failed) {
+        EXIT_REJECTED
+    } else if states.iter().all(|state| state.state == TransactionState::Completed) {
+        EXIT_OK
+    } else {
+        EXIT_TEMPFAIL
+    }
+}
+
+fn number(matches: &ArgMatches, name: &str) -> Result<usize, CliError> {
+    required(matches, name)?.parse().map_err(|_| CliError::Usage(format!("--{} must be a number", name)))
+}
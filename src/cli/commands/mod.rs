@@ -0,0 +1,227 @@
+//! Building and sending the request of each subcommand
+
+use clap::ArgMatches;
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use mpesa::api_products::account_balance::AccountBalance;
+use mpesa::api_products::b2b::B2B;
+use mpesa::api_products::b2c::B2C;
+use mpesa::api_products::c2b::{RegisterUrl, SimulateTransaction};
+use mpesa::api_products::lipa_na_mpesa_online_payment_request::{password, timestamp, LipaNaMpesaOnlinePaymentRequest};
+use mpesa::api_products::lipa_na_mpesa_online_query_request::LipaNaMpesaOnlineQueryRequest;
+use mpesa::api_products::reversal::Reversal;
+use mpesa::api_products::transaction_status::TransactionSatus;
+use mpesa::client::{DryRun, MpesaClient, MpesaClientError};
+use mpesa::client::transport::HttpRequest;
+use mpesa::config::required as setting;
+use mpesa::parameters::{Amount, CommandIds, Msisdn, Party, Paybill, TillNumber};
+
+use super::{required, CliError, Context};
+
+/// Runs the subcommand `name` and returns the acknowledgement of its request, or with `--dry-run` the request
+pub fn run(name: &str, matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    match name {
+        "token" => token(context),
+        "stk-push" => stk_push(matches, context),
+        "stk-query" => stk_query(matches, context),
+        "b2c" => b2c(matches, context),
+        "b2b" => b2b(matches, context),
+        "balance" => balance(matches, context),
+        "status" => status(matches, context),
+        "reverse" => reverse(matches, context),
+        "register-url" => register_url(matches, context),
+        "simulate" => simulate(matches, context),
+        _ => Err(CliError::Usage(format!("{} is not a subcommand", name))),
+    }
+}
+
+fn token(context: &Context) -> Result<Value, CliError> {
+    let mut access_token = context.config.access_token()?;
+    if context.dry_run {
+        return Ok(access_token.request().redacted().to_value());
+    }
+    let token = access_token.token()?;
+    Ok(json!({ "access_token": token }))
+}
+
+fn stk_push(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &stk_push_request(matches, context)?, |client, request| client.send_stk_push(request), |dry_run, request| dry_run.render_stk_push(request))
+}
+
+/// The request `stk-push` sends
+pub fn stk_push_request(matches: &ArgMatches, context: &Context) -> Result<LipaNaMpesaOnlinePaymentRequest, CliError> {
+    let short_code = context.short_code()?;
+    let (receiver, command) = match matches.value_of("till") {
+        Some(till) => (Party::from(TillNumber::new(till)?), CommandIds::CustomerBuyGoodsOnline),
+        None => (Party::from(short_code.clone()), CommandIds::CustomerPayBillOnline),
+    };
+    let phone = phone(matches)?;
+    let timestamp = timestamp();
+    Ok(LipaNaMpesaOnlinePaymentRequest {
+        Password: password(short_code.as_str(), setting(&context.config.passkey, "passkey")?, &timestamp),
+        BusinessShortCode: short_code,
+        Timestamp: timestamp,
+        TransactionType: command.to_string(),
+        Amount: amount(matches)?,
+        PartyA: phone.clone(),
+        PartyB: receiver,
+        PhoneNumber: phone,
+        CallBackURL: setting(&context.config.callback_url, "callback_url")?.to_string(),
+        AccountReference: matches.value_of("reference").unwrap_or_default().to_string(),
+        TransactionDesc: matches.value_of("description").unwrap_or_default().to_string(),
+    })
+}
+
+fn stk_query(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &stk_query_request(matches, context)?, |client, request| client.stk_query(request), |dry_run, request| dry_run.render_stk_query(request))
+}
+
+/// The request `stk-query` sends
+pub fn stk_query_request(matches: &ArgMatches, context: &Context) -> Result<LipaNaMpesaOnlineQueryRequest, CliError> {
+    Ok(LipaNaMpesaOnlineQueryRequest::new(context.short_code()?.as_str(), setting(&context.config.passkey, "passkey")?,
+                                          required(matches, "checkout-request-id")?))
+}
+
+fn b2c(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &b2c_request(matches, context)?, |client, request| client.send_b2c(request), |dry_run, request| dry_run.render_b2c(request))
+}
+
+/// The request `b2c` sends
+pub fn b2c_request(matches: &ArgMatches, context: &Context) -> Result<B2C, CliError> {
+    let (initiator, security_credential) = context.initiator()?;
+    let (result_url, timeout_url) = context.urls()?;
+    Ok(B2C {
+        InitiatorName: initiator.to_string(),
+        SecurityCredential: security_credential,
+        CommandID: matches.value_of("command").unwrap_or_default().to_string(),
+        Amount: amount(matches)?,
+        PartyA: context.short_code()?,
+        PartyB: phone(matches)?,
+        Remarks: matches.value_of("remarks").unwrap_or_default().to_string(),
+        QueueTimeOutURL: timeout_url.to_string(),
+        ResultURL: result_url.to_string(),
+        Occasion: matches.value_of("occasion").unwrap_or_default().to_string(),
+        OriginatorConversationID: String::new(),
+    })
+}
+
+fn b2b(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &b2b_request(matches, context)?, |client, request| client.send_b2b(request), |dry_run, request| dry_run.render_b2b(request))
+}
+
+/// The request `b2b` sends
+pub fn b2b_request(matches: &ArgMatches, context: &Context) -> Result<B2B, CliError> {
+    let (initiator, security_credential) = context.initiator()?;
+    let (result_url, timeout_url) = context.urls()?;
+    let (receiver, command) = match matches.value_of("till") {
+        Some(till) => (Party::from(TillNumber::new(till)?), CommandIds::BusinessBuyGoods),
+        None => (Party::from(Paybill::new(required(matches, "paybill")?)?), CommandIds::BusinessPayBill),
+    };
+    let command = match matches.value_of("command") {
+        Some(command) => command.parse()?,
+        None => command,
+    };
+    let mut request = B2B::new(command, amount(matches)?, context.short_code()?, receiver)
+        .initiator(initiator, &security_credential)
+        .urls(result_url, timeout_url)
+        .remarks(matches.value_of("remarks").unwrap_or_default());
+    if let Some(account_reference) = matches.value_of("account-reference") {
+        request = request.account_reference(account_reference);
+    }
+    Ok(request)
+}
+
+fn balance(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &balance_request(matches, context)?, |client, request| client.send_account_balance(request), |dry_run, request| dry_run.render_account_balance(request))
+}
+
+/// The request `balance` sends
+pub fn balance_request(matches: &ArgMatches, context: &Context) -> Result<AccountBalance, CliError> {
+    let (initiator, security_credential) = context.initiator()?;
+    let (result_url, timeout_url) = context.urls()?;
+    Ok(AccountBalance::new(context.short_code()?)
+        .initiator(initiator, &security_credential)
+        .urls(result_url, timeout_url)
+        .remarks(matches.value_of("remarks").unwrap_or_default()))
+}
+
+fn status(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &status_request(matches, context)?, |client, request| client.send_transaction_status(request), |dry_run, request| dry_run.render_transaction_status(request))
+}
+
+/// The request `status` sends
+pub fn status_request(matches: &ArgMatches, context: &Context) -> Result<TransactionSatus, CliError> {
+    let (initiator, security_credential) = context.initiator()?;
+    let (result_url, timeout_url) = context.urls()?;
+    let mut request = TransactionSatus::new(required(matches, "transaction-id")?, context.short_code()?)
+        .initiator(initiator, &security_credential)
+        .urls(result_url, timeout_url)
+        .remarks(matches.value_of("remarks").unwrap_or_default());
+    if let Some(original_conversation_id) = matches.value_of("original-conversation-id") {
+        request = request.original_conversation_id(original_conversation_id);
+    }
+    Ok(request)
+}
+
+fn reverse(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &reverse_request(matches, context)?, |client, request| client.send_reversal(request), |dry_run, request| dry_run.render_reversal(request))
+}
+
+/// The request `reverse` sends
+pub fn reverse_request(matches: &ArgMatches, context: &Context) -> Result<Reversal, CliError> {
+    let (initiator, security_credential) = context.initiator()?;
+    let (result_url, timeout_url) = context.urls()?;
+    Ok(Reversal::new(required(matches, "transaction-id")?, context.short_code()?)
+        .initiator(initiator, &security_credential)
+        .urls(result_url, timeout_url)
+        .remarks(matches.value_of("remarks").unwrap_or_default()))
+}
+
+fn register_url(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &register_url_request(matches, context)?, |client, request| client.register_urls(request), |dry_run, request| dry_run.render_register_urls(request))
+}
+
+/// The request `register-url` sends
+pub fn register_url_request(matches: &ArgMatches, context: &Context) -> Result<RegisterUrl, CliError> {
+    Ok(RegisterUrl::new(context.short_code()?, required(matches, "validation-url")?,
+                        required(matches, "confirmation-url")?, matches.value_of("response-type").unwrap_or("Completed")))
+}
+
+fn simulate(matches: &ArgMatches, context: &Context) -> Result<Value, CliError> {
+    submit(context, &simulate_request(matches, context)?, |client, request| client.simulate_c2b(request), |dry_run, request| dry_run.render_simulate_c2b(request))
+}
+
+/// The request `simulate` sends
+pub fn simulate_request(matches: &ArgMatches, context: &Context) -> Result<SimulateTransaction, CliError> {
+    let receiver = match matches.value_of("till") {
+        Some(till) => Party::from(TillNumber::new(till)?),
+        None => Party::from(context.short_code()?),
+    };
+    let mut request = SimulateTransaction::new(receiver, phone(matches)?, amount(matches)?);
+    if let Some(bill_ref) = matches.value_of("bill-ref") {
+        request = request.bill_ref_number(bill_ref);
+    }
+    Ok(request)
+}
+
+fn phone(matches: &ArgMatches) -> Result<Msisdn, CliError> {
+    Ok(required(matches, "phone")?.parse()?)
+}
+
+fn amount(matches: &ArgMatches) -> Result<Amount, CliError> {
+    Ok(required(matches, "amount")?.parse()?)
+}
+
+/// Sends `request` with `send` and returns the acknowledgement, or with `--dry-run` the request `render` builds
+fn submit<Q, A, S, R>(context: &Context, request: &Q, send: S, render: R) -> Result<Value, CliError>
+    where A: Serialize,
+          S: FnOnce(&mut MpesaClient, &Q) -> Result<A, MpesaClientError>,
+          R: FnOnce(&mut DryRun, &Q) -> Result<HttpRequest, MpesaClientError>
+{
+    let mut client = context.client()?;
+    if context.dry_run {
+        return Ok(render(&mut client.dry_run(), request)?.to_value());
+    }
+    Ok(serde_json::to_value(send(&mut client, request)?)?)
+}
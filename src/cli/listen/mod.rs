@@ -0,0 +1,105 @@
+//! The `listen` subcommand, running the callback server for local debugging
+//!
+//! Every callback is printed decoded into its typed representation, or as JSON with `--output json`, and appended
+//! to the `--log` file as a line of JSON when one is given:
+//!
+//! ```text
+//! {"received_at":"2018-07-01T12:00:00+00:00","kind":"stk","callback":{"MerchantRequestID":"...",...}}
+//! ```
+
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use clap::ArgMatches;
+use serde::Serialize;
+
+use mpesa::callbacks::{CallbackHandler, CallbackResponse, C2BTransaction, StkCallback, TransactionResult};
+use mpesa::callbacks::server::{CallbackPaths, CallbackServer};
+
+use super::output::Format;
+use super::{CliError, Context};
+
+/// Prints the callbacks it receives
+pub struct Listener {
+    format: Format,
+    log: Option<Mutex<File>>,
+    accept_c2b: bool,
+}
+
+/// Runs the callback server until it is stopped
+pub fn run(matches: &ArgMatches, context: &Context) -> Result<(), CliError> {
+    let port: u16 = matches.value_of("port").unwrap_or("8080").parse()
+        .map_err(|_| CliError::Usage(String::from("--port must be a port number")))?;
+    let host = matches.value_of("host").unwrap_or("0.0.0.0");
+    let listener = listener(matches, context)?;
+
+    let paths = CallbackPaths::default();
+    eprintln!("listening on {}:{}", host, port);
+    for path in &[&paths.result, &paths.timeout, &paths.stk, &paths.c2b_validation, &paths.c2b_confirmation] {
+        eprintln!("  POST {}", path);
+    }
+    CallbackServer::new(listener).paths(paths).run((host, port))?;
+    Ok(())
+}
+
+/// The handler printing and logging the callbacks as asked for by the flags
+pub fn listener(matches: &ArgMatches, context: &Context) -> Result<Listener, CliError> {
+    let log = match matches.value_of("log") {
+        Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+    Ok(Listener {
+        format: context.format,
+        log: log,
+        accept_c2b: matches.value_of("c2b-validation") != Some("reject"),
+    })
+}
+
+impl Listener {
+    /// Prints a callback of `kind` and appends it to the log
+    fn event<T: Serialize + Debug>(&self, kind: &str, callback: &T) {
+        let received_at = Utc::now().to_rfc3339();
+        let event = json!({ "received_at": received_at, "kind": kind, "callback": callback });
+
+        match self.format {
+            Format::Table => println!("[{}] {}\n{:#?}", received_at, kind, callback),
+            Format::Json => println!("{}", event),
+        }
+
+        if let Some(ref log) = self.log {
+            let mut log = log.lock().expect("the log lock is poisoned");
+            if let Err(error) = writeln!(log, "{}", event) {
+                eprintln!("unable to append to the log: {}", error);
+            }
+        }
+    }
+}
+
+impl CallbackHandler for Listener {
+    fn on_result(&self, result: TransactionResult) {
+        self.event("result", &result);
+    }
+
+    fn on_timeout(&self, result: TransactionResult) {
+        self.event("timeout", &result);
+    }
+
+    fn on_stk_callback(&self, callback: StkCallback) {
+        self.event("stk", &callback);
+    }
+
+    fn on_c2b_validation(&self, transaction: C2BTransaction) -> CallbackResponse {
+        self.event("c2b_validation", &transaction);
+        let response = if self.accept_c2b { CallbackResponse::accept() } else { CallbackResponse::reject() };
+        eprintln!("answered {} with {}", transaction.TransID, response.ResultDesc);
+        response
+    }
+
+    fn on_c2b_confirmation(&self, transaction: C2BTransaction) {
+        self.event("c2b_confirmation", &transaction);
+    }
+}
+
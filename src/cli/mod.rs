@@ -0,0 +1,408 @@
+//! The `mpesa` command-line tool
+//!
+//! Every subcommand builds the matching `api_products` request from its flags, sends it and prints the
+//! acknowledgement as a table or, with `--output json`, as JSON. `listen` instead runs the callback server and prints
+//! the callbacks it receives, see the `listen` module.
+//!
+//! With `--dry-run` the request is checked and built but not sent: its url, headers and body are printed instead,
+//! credentials redacted. No access token is retrieved, so nothing leaves the machine.
+//!
+//! Credentials and settings shared by the subcommands are loaded with `config::Config` from `.env`, environment
+//! variables and a profile of `mpesa.toml`, chosen with `--profile` and `--config`. The global flags, e.g.
+//! `--consumer-key`, override them.
+//!
+//! The tool exits with
+//!
+//! | code | meaning                                                                  |
+//! |------|--------------------------------------------------------------------------|
+//! | 0    | the request was accepted, or printed with `--dry-run`                    |
+//! | 1    | M-Pesa rejected the request, or some payouts of a bulk payout failed     |
+//! | 64   | the command line is wrong                                                |
+//! | 65   | a parameter is invalid, so the request was not sent                      |
+//! | 69   | M-Pesa could not be reached                                              |
+//! | 70   | the response could not be understood, or the tool failed otherwise       |
+//! | 71   | the callback server could not be started, or a file not opened           |
+//! | 75   | the payment may or may not have been made, or results are still awaited  |
+//! | 77   | no access token could be retrieved                                       |
+//! | 78   | the configuration could not be loaded                                    |
+
+mod bulk_pay;
+mod commands;
+mod listen;
+mod output;
+#[cfg(test)]
+mod tests;
+
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::Path;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde_json;
+
+use mpesa::access_token::MpesaAccessTokenError;
+use mpesa::client::{MpesaClient, MpesaClientError};
+use mpesa::config::{required as setting, Config, ConfigError};
+use mpesa::bulk::BulkError;
+use mpesa::parameters::{ParameterError, ShortCode};
+
+use self::output::Format;
+
+/// The request was accepted
+const EXIT_OK: i32 = 0;
+/// M-Pesa rejected the request
+const EXIT_REJECTED: i32 = 1;
+/// The command line is wrong
+const EXIT_USAGE: i32 = 64;
+/// A parameter is invalid
+const EXIT_DATA: i32 = 65;
+/// M-Pesa could not be reached
+const EXIT_UNAVAILABLE: i32 = 69;
+/// Any other failure
+const EXIT_SOFTWARE: i32 = 70;
+/// A local resource failed, e.g. the port is taken
+const EXIT_OSERR: i32 = 71;
+/// Whether the request was processed is not known
+const EXIT_TEMPFAIL: i32 = 75;
+/// No access token could be retrieved
+const EXIT_NOPERM: i32 = 77;
+/// The configuration could not be loaded
+const EXIT_CONFIG: i32 = 78;
+
+/// Global flags overriding the setting of the same name
+const SETTING_FLAGS: &[&str] = &[
+    "consumer-key", "consumer-secret", "base-url", "short-code", "passkey", "initiator-name", "security-credential",
+    "result-url", "timeout-url", "callback-url",
+];
+
+/// Definition of possible errors when running a subcommand
+#[derive(Debug)]
+pub enum CliError {
+    /// A flag is missing or malformed
+    Usage(String),
+    /// A flag is not a valid parameter of the request
+    Parameter(ParameterError),
+    /// The configuration could not be loaded, or lacks a setting
+    Config(ConfigError),
+    /// No access token could be retrieved
+    AccessToken(MpesaAccessTokenError),
+    /// The request failed
+    Client(MpesaClientError),
+    /// The acknowledgement could not be printed
+    Output(String),
+    /// The callback server could not be started, or a file not opened
+    Io(io::Error),
+    /// A bulk payout could not be made
+    Bulk(BulkError),
+}
+
+/// Settings shared by all subcommands
+pub struct Context {
+    config: Config,
+    format: Format,
+    dry_run: bool,
+}
+
+impl Context {
+    /// Loads the configuration and overrides it with the global flags
+    fn from_matches(matches: &ArgMatches) -> Result<Context, CliError> {
+        let profile = matches.value_of("profile");
+        let mut config = match matches.value_of("config") {
+            Some(path) => Config::load_from(Path::new(path), profile)?,
+            None => Config::load(profile)?,
+        };
+        for flag in SETTING_FLAGS {
+            if let Some(value) = matches.value_of(flag) {
+                config.set(&flag.replace('-', "_"), value)?;
+            }
+        }
+        if matches.is_present("production") {
+            config.set("environment", "production")?;
+        }
+        Ok(Context {
+            config: config,
+            format: matches.value_of("output").unwrap_or("table").parse().map_err(CliError::Usage)?,
+            dry_run: matches.is_present("dry-run"),
+        })
+    }
+
+    /// A client for the configured API
+    fn client(&self) -> Result<MpesaClient, CliError> {
+        Ok(self.config.client()?)
+    }
+
+    /// The shortcode of the organization
+    fn short_code(&self) -> Result<ShortCode, CliError> {
+        Ok(setting(&self.config.short_code, "short_code")?.clone())
+    }
+
+    /// The name and security credential of the API operator
+    fn initiator(&self) -> Result<(&str, String), CliError> {
+        Ok((setting(&self.config.initiator_name, "initiator_name")?, self.config.credential()?))
+    }
+
+    /// The urls results and queue timeouts are posted to
+    fn urls(&self) -> Result<(&str, &str), CliError> {
+        Ok((setting(&self.config.result_url, "result_url")?, setting(&self.config.timeout_url, "timeout_url")?))
+    }
+}
+
+/// Runs the tool with the arguments it was started with and returns its exit code
+pub fn run() -> i32 {
+    let matches = match app().get_matches_from_safe(env::args_os()) {
+        Ok(matches) => matches,
+        Err(error) => {
+            if error.use_stderr() {
+                eprintln!("{}", error.message);
+                return EXIT_USAGE;
+            }
+            // --help and --version
+            println!("{}", error.message);
+            return EXIT_OK;
+        },
+    };
+
+    let (name, subcommand) = matches.subcommand();
+    let subcommand = match subcommand {
+        Some(subcommand) => subcommand,
+        None => return fail(CliError::Usage(String::from("no subcommand given, see --help"))),
+    };
+
+    // global flags are propagated to the subcommand, wherever they were given
+    let context = match Context::from_matches(subcommand) {
+        Ok(context) => context,
+        Err(error) => return fail(error),
+    };
+
+    if context.dry_run && (name == "bulk-pay" || name == "listen") {
+        return fail(CliError::Usage(format!("{} does not support --dry-run", name)));
+    }
+
+    if name == "bulk-pay" {
+        return match bulk_pay::run(subcommand, &context) {
+            Ok(code) => code,
+            Err(error) => fail(error),
+        };
+    }
+
+    if name == "listen" {
+        return match listen::run(subcommand, &context) {
+            Ok(()) => EXIT_OK,
+            Err(error) => fail(error),
+        };
+    }
+
+    match commands::run(name, subcommand, &context) {
+        Ok(acknowledgement) => match output::print(&acknowledgement, context.format) {
+            Ok(()) => if output::is_accepted(&acknowledgement) { EXIT_OK } else { EXIT_REJECTED },
+            Err(error) => fail(error),
+        },
+        Err(error) => fail(error),
+    }
+}
+
+/// Reports `error` and returns the exit code matching it
+fn fail(error: CliError) -> i32 {
+    eprintln!("{}", error);
+    error.exit_code()
+}
+
+/// The value of a flag of a subcommand that is only required in some cases
+fn required<'a>(matches: &'a ArgMatches, name: &str) -> Result<&'a str, CliError> {
+    matches.value_of(name).ok_or_else(|| CliError::Usage(format!("--{} is required", name)))
+}
+
+/// The definition of the command line
+fn app() -> App<'static, 'static> {
+    App::new("mpesa")
+        .about("Calls the M-Pesa (Daraja) API")
+        .version(env!("CARGO_PKG_VERSION"))
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(global("profile", "Profile of the config file, sandbox by default"))
+        .arg(global("config", "Config file, mpesa.toml by default"))
+        .arg(global("consumer-key", "Consumer key of the app"))
+        .arg(global("consumer-secret", "Consumer secret of the app"))
+        .arg(global("base-url", "Base url of the API, the default url of the environment by default"))
+        .arg(Arg::with_name("production").long("production").global(true).help("Calls the production API"))
+        .arg(Arg::with_name("dry-run").long("dry-run").global(true).help("Prints the request instead of sending it"))
+        .arg(Arg::with_name("output").long("output").short("o").takes_value(true).global(true)
+            .possible_values(&["table", "json"]).default_value("table").help("How to print the acknowledgement"))
+        .arg(global("short-code", "Shortcode of the organization"))
+        .arg(global("initiator-name", "Username of the API operator"))
+        .arg(global("security-credential", "Encrypted password of the API operator"))
+        .arg(global("result-url", "Url results are posted to"))
+        .arg(global("timeout-url", "Url requests that timed out in the queue are posted to"))
+        .arg(global("passkey", "Lipa na M-Pesa online passkey"))
+        .arg(global("callback-url", "Url the outcome of STK pushes is posted to"))
+        .subcommand(SubCommand::with_name("token")
+            .about("Retrieves an access token"))
+        .subcommand(SubCommand::with_name("stk-push")
+            .about("Prompts a customer to pay")
+            .arg(value("phone", "Phone number of the customer").required(true))
+            .arg(value("amount", "Amount in shillings").required(true))
+            .arg(value("till", "Till number to pay to instead of the shortcode"))
+            .arg(value("reference", "Account reference shown to the customer").default_value("mpesa"))
+            .arg(value("description", "Description of the payment").default_value("payment")))
+        .subcommand(SubCommand::with_name("stk-query")
+            .about("Queries the outcome of an STK push")
+            .arg(value("checkout-request-id", "CheckoutRequestID of the push").required(true)))
+        .subcommand(SubCommand::with_name("b2c")
+            .about("Pays a customer")
+            .arg(value("phone", "Phone number of the customer").required(true))
+            .arg(value("amount", "Amount in shillings").required(true))
+            .arg(value("command", "Command id").default_value("BusinessPayment"))
+            .arg(value("remarks", "Remarks sent along with the payment").default_value("payment"))
+            .arg(value("occasion", "Occasion sent along with the payment")))
+        .subcommand(SubCommand::with_name("b2b")
+            .about("Pays another business")
+            .arg(value("paybill", "Paybill number to pay to").required_unless("till").conflicts_with("till"))
+            .arg(value("till", "Till number to pay to"))
+            .arg(value("amount", "Amount in shillings").required(true))
+            .arg(value("command", "Command id, derived from the receiver by default"))
+            .arg(value("account-reference", "Account number at the receiver"))
+            .arg(value("remarks", "Remarks sent along with the payment").default_value("payment")))
+        .subcommand(SubCommand::with_name("balance")
+            .about("Queries the balance of the shortcode")
+            .arg(value("remarks", "Remarks sent along with the query").default_value("balance")))
+        .subcommand(SubCommand::with_name("status")
+            .about("Queries the status of a transaction")
+            .arg(value("transaction-id", "M-Pesa receipt of the transaction").required(true))
+            .arg(value("original-conversation-id", "OriginatorConversationID the transaction was sent with"))
+            .arg(value("remarks", "Remarks sent along with the query").default_value("status")))
+        .subcommand(SubCommand::with_name("reverse")
+            .about("Reverses a transaction")
+            .arg(value("transaction-id", "M-Pesa receipt of the transaction").required(true))
+            .arg(value("remarks", "Remarks sent along with the reversal").default_value("reversal")))
+        .subcommand(SubCommand::with_name("register-url")
+            .about("Registers the C2B validation and confirmation urls of the shortcode")
+            .arg(value("validation-url", "Url payments are validated at").required(true))
+            .arg(value("confirmation-url", "Url payments are confirmed at").required(true))
+            .arg(value("response-type", "What happens to payments when the validation url cannot be reached")
+                .possible_values(&["Completed", "Cancelled"]).default_value("Completed")))
+        .subcommand(SubCommand::with_name("simulate")
+            .about("Simulates a C2B payment, sandbox only")
+            .arg(value("phone", "Phone number of the customer").required(true))
+            .arg(value("amount", "Amount in shillings").required(true))
+            .arg(value("till", "Till number to pay to instead of the shortcode"))
+            .arg(value("bill-ref", "Account number the customer pays to")))
+        .subcommand(SubCommand::with_name("bulk-pay")
+            .about("Pays the customers listed in a CSV file, resuming an interrupted run")
+            .arg(Arg::with_name("file").required(true).help("CSV file with msisdn, amount, command_id and remarks columns"))
+            .arg(value("state", "State file recording the progress, <file>.state by default"))
+            .arg(value("results", "Results file, <file>.results.csv by default"))
+            .arg(value("concurrency", "Payments sent at a time").default_value("4"))
+            .arg(value("rate", "Payments sent per second at most, 0 for no limit").default_value("5"))
+            .arg(value("port", "Port to receive results on; results are not awaited without it"))
+            .arg(value("host", "Address to receive results on").default_value("0.0.0.0"))
+            .arg(value("wait", "Seconds to wait for results").default_value("300"))
+            .arg(value("occasion", "Occasion sent along with every payment")))
+        .subcommand(SubCommand::with_name("listen")
+            .about("Runs the callback server and prints the callbacks it receives")
+            .arg(value("port", "Port to listen on").default_value("8080"))
+            .arg(value("host", "Address to listen on").default_value("0.0.0.0"))
+            .arg(value("log", "File to append the callbacks to, one JSON object per line"))
+            .arg(value("c2b-validation", "How to answer C2B validation requests")
+                .possible_values(&["accept", "reject"]).default_value("accept")))
+}
+
+/// A global flag taking a value
+fn global(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name).long(name).takes_value(true).global(true).help(help)
+}
+
+/// A flag of a subcommand taking a value
+fn value(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name).long(name).takes_value(true).help(help)
+}
+
+impl CliError {
+    /// The code the tool exits with when failing with this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            &CliError::Usage(_) => EXIT_USAGE,
+            &CliError::Parameter(_) => EXIT_DATA,
+            &CliError::Config(ConfigError::Missing(_)) => EXIT_USAGE,
+            &CliError::Config(_) => EXIT_CONFIG,
+            &CliError::AccessToken(_) => EXIT_NOPERM,
+            &CliError::Output(_) => EXIT_SOFTWARE,
+            &CliError::Io(_) => EXIT_OSERR,
+            &CliError::Bulk(BulkError::State(_)) => EXIT_OSERR,
+            &CliError::Bulk(_) => EXIT_DATA,
+            &CliError::Client(ref error) => match error {
+                &MpesaClientError::AccessToken(_) => EXIT_NOPERM,
+                &MpesaClientError::InvalidRequest(_) => EXIT_DATA,
+                &MpesaClientError::Connection(_) => EXIT_UNAVAILABLE,
+                &MpesaClientError::Request(..) => EXIT_REJECTED,
+                &MpesaClientError::OutcomeUnknown { .. } => EXIT_TEMPFAIL,
+                &MpesaClientError::SecurityCredential(_) => EXIT_CONFIG,
+                _ => EXIT_SOFTWARE,
+            },
+        }
+    }
+}
+
+impl From<ParameterError> for CliError {
+    fn from(error: ParameterError) -> Self {
+        CliError::Parameter(error)
+    }
+}
+
+impl From<ConfigError> for CliError {
+    fn from(error: ConfigError) -> Self {
+        CliError::Config(error)
+    }
+}
+
+impl From<MpesaAccessTokenError> for CliError {
+    fn from(error: MpesaAccessTokenError) -> Self {
+        CliError::AccessToken(error)
+    }
+}
+
+impl From<MpesaClientError> for CliError {
+    fn from(error: MpesaClientError) -> Self {
+        CliError::Client(error)
+    }
+}
+
+impl From<BulkError> for CliError {
+    fn from(error: BulkError) -> Self {
+        CliError::Bulk(error)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for CliError {
+    fn from(error: serde_json::Error) -> Self {
+        CliError::Output(format!("{}", error))
+    }
+}
+
+impl Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CliError::Usage(ref description) => write!(f, "CliError::Usage -- {}", description),
+            &CliError::Parameter(ref error) => write!(f, "CliError::Parameter -- {}", error),
+            &CliError::Config(ref error) => write!(f, "CliError::Config -- {}", error),
+            &CliError::AccessToken(ref error) => write!(f, "CliError::AccessToken -- {}", error),
+            &CliError::Client(ref error) => write!(f, "CliError::Client -- {}", error),
+            &CliError::Output(ref description) => write!(f, "CliError::Output -- {}", description),
+            &CliError::Io(ref error) => write!(f, "CliError::Io -- {}", error),
+            &CliError::Bulk(ref error) => write!(f, "CliError::Bulk -- {}", error),
+        }
+    }
+}
+
+impl Error for CliError {
+    fn description(&self) -> &str {
+        "failed to run an mpesa command"
+    }
+}
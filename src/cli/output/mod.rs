@@ -0,0 +1,73 @@
+//! Printing acknowledgements
+
+use std::str::FromStr;
+
+use serde_json::{self, Value};
+
+use super::CliError;
+
+/// How acknowledgements are printed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// One aligned `field  value` row per field
+    Table,
+    /// Pretty printed JSON
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("{} is not an output format, expected table or json", s)),
+        }
+    }
+}
+
+/// Prints `acknowledgement` to stdout in `format`
+pub fn print(acknowledgement: &Value, format: Format) -> Result<(), CliError> {
+    print!("{}", render(acknowledgement, format)?);
+    Ok(())
+}
+
+/// Renders `acknowledgement` in `format`, ending in a newline
+pub fn render(acknowledgement: &Value, format: Format) -> Result<String, CliError> {
+    Ok(match format {
+        Format::Json => format!("{}\n", serde_json::to_string_pretty(acknowledgement)?),
+        Format::Table => table(acknowledgement),
+    })
+}
+
+/// Renders the fields of an object as aligned rows, and anything else as is
+fn table(value: &Value) -> String {
+    let fields = match value.as_object() {
+        Some(fields) => fields,
+        None => return format!("{}\n", cell(value)),
+    };
+    let width = fields.keys().map(|key| key.len()).max().unwrap_or(0);
+    fields.iter()
+        .map(|(key, value)| format!("{:width$}  {}\n", key, cell(value), width = width))
+        .collect()
+}
+
+/// Strings without their quotes, everything else as JSON
+fn cell(value: &Value) -> String {
+    match value {
+        &Value::String(ref s) => s.clone(),
+        &Value::Null => String::new(),
+        value => value.to_string(),
+    }
+}
+
+/// Whether M-Pesa accepted the request the acknowledgement answers.
+/// STK queries also report whether the customer paid in their `ResultCode`
+pub fn is_accepted(acknowledgement: &Value) -> bool {
+    ["ResponseCode", "ResultCode"].iter().all(|field| match acknowledgement.get(field) {
+        Some(&Value::String(ref code)) => code.trim() == "0",
+        Some(&Value::Number(ref code)) => code.as_i64() == Some(0),
+        _ => true,
+    })
+}
@@ -0,0 +1,205 @@
+//! Tests of the subcommands, run without sending anything
+
+use std::env;
+use std::fs;
+
+use chrono::DateTime;
+use clap::ArgMatches;
+use serde_json::{self, Value};
+
+use mpesa::callbacks::{CallbackHandler, C2BTransaction, StkCallbackBody};
+use mpesa::testing;
+
+use mpesa::access_token::MpesaAccessTokenError;
+use mpesa::bulk::BulkError;
+use mpesa::client::MpesaClientError;
+use mpesa::config::{Config, ConfigError};
+use mpesa::parameters::{MpesaRequestError, ParameterError};
+
+use super::{app, commands, listen, output, CliError, Context, EXIT_CONFIG, EXIT_DATA, EXIT_NOPERM, EXIT_OSERR, EXIT_REJECTED,
+            EXIT_SOFTWARE, EXIT_TEMPFAIL, EXIT_USAGE};
+use super::output::Format;
+
+/// A context with every setting the subcommands need
+fn context() -> Context {
+    let mut config = Config::default();
+    for &(name, value) in &[("short_code", "600000"), ("passkey", "passkey"), ("initiator_name", "testapi"),
+                            ("security_credential", "credential"), ("result_url", "https://example.com/result"),
+                            ("timeout_url", "https://example.com/timeout"), ("callback_url", "https://example.com/stk")] {
+        config.set(name, value).unwrap();
+    }
+    Context { config: config, format: Format::Table, dry_run: false }
+}
+
+/// Parses `args` and returns the matches of the subcommand
+fn subcommand(args: &[&str]) -> ArgMatches<'static> {
+    let matches = app().get_matches_from_safe(Some("mpesa").into_iter().chain(args.iter().cloned())).unwrap();
+    matches.subcommand().1.unwrap().clone()
+}
+
+fn json<T: ::serde::Serialize>(request: &T) -> Value {
+    serde_json::to_value(request).unwrap()
+}
+
+#[test]
+fn test_b2c_request_is_built_from_flags_and_settings() {
+    let matches = subcommand(&["b2c", "--phone", "254708374149", "--amount", "100", "--occasion", "bonus"]);
+    let request = json(&commands::b2c_request(&matches, &context()).unwrap());
+
+    assert_eq!(request["InitiatorName"], "testapi");
+    assert_eq!(request["SecurityCredential"], "credential");
+    assert_eq!(request["CommandID"], "BusinessPayment");
+    assert_eq!(request["Amount"], json(&"100".parse::<::mpesa::parameters::Amount>().unwrap()));
+    assert_eq!(request["PartyA"], "600000");
+    assert_eq!(request["PartyB"], "254708374149");
+    assert_eq!(request["Remarks"], "payment");
+    assert_eq!(request["Occasion"], "bonus");
+    assert_eq!(request["ResultURL"], "https://example.com/result");
+    assert_eq!(request["QueueTimeOutURL"], "https://example.com/timeout");
+}
+
+#[test]
+fn test_b2b_and_stk_push_requests_pay_a_till_with_buy_goods() {
+    let b2b = json(&commands::b2b_request(&subcommand(&["b2b", "--till", "123456", "--amount", "10"]), &context()).unwrap());
+    assert_eq!(b2b["CommandID"], "BusinessBuyGoods");
+    assert_eq!(b2b["PartyB"], "123456");
+
+    let b2b = json(&commands::b2b_request(&subcommand(&["b2b", "--paybill", "600111", "--amount", "10",
+                                                         "--account-reference", "invoice-7"]), &context()).unwrap());
+    assert_eq!(b2b["CommandID"], "BusinessPayBill");
+    assert_eq!(b2b["AccountReference"], "invoice-7");
+
+    let push = json(&commands::stk_push_request(&subcommand(&["stk-push", "--phone", "254708374149", "--amount", "1",
+                                                               "--till", "123456"]), &context()).unwrap());
+    assert_eq!(push["TransactionType"], "CustomerBuyGoodsOnline");
+    assert_eq!(push["BusinessShortCode"], "600000");
+    assert_eq!(push["PartyB"], "123456");
+    assert_eq!(push["CallBackURL"], "https://example.com/stk");
+    assert_eq!(push["AccountReference"], "mpesa");
+}
+
+#[test]
+fn test_status_and_simulate_requests_take_their_optional_flags() {
+    let status = json(&commands::status_request(&subcommand(&["status", "--transaction-id", "LKXXXX1234",
+                                                               "--original-conversation-id", "AG_1"]), &context()).unwrap());
+    assert_eq!(status["TransactionID"], "LKXXXX1234");
+    assert_eq!(status["OriginalConversationID"], "AG_1");
+
+    let simulate = json(&commands::simulate_request(&subcommand(&["simulate", "--phone", "254708374149", "--amount", "5",
+                                                                   "--bill-ref", "account-1"]), &context()).unwrap());
+    assert_eq!(simulate["ShortCode"], "600000");
+    assert_eq!(simulate["BillRefNumber"], "account-1");
+}
+
+#[test]
+fn test_invalid_flags_and_missing_settings_fail_before_sending() {
+    let error = commands::b2c_request(&subcommand(&["b2c", "--phone", "0700", "--amount", "100"]), &context()).unwrap_err();
+    assert_eq!(error.exit_code(), EXIT_DATA);
+
+    let mut context = context();
+    context.config.initiator_name = None;
+    let error = commands::b2c_request(&subcommand(&["b2c", "--phone", "254708374149", "--amount", "100"]), &context).unwrap_err();
+    match error {
+        CliError::Config(ConfigError::Missing("initiator_name")) => (),
+        error => panic!("expected the missing initiator name, got {}", error),
+    }
+    assert_eq!(error.exit_code(), EXIT_USAGE);
+}
+
+#[test]
+fn test_exit_codes_match_the_error() {
+    assert_eq!(CliError::Usage(String::new()).exit_code(), EXIT_USAGE);
+    assert_eq!(CliError::Parameter(ParameterError::Msisdn(String::new())).exit_code(), EXIT_DATA);
+    assert_eq!(CliError::Config(ConfigError::Missing("passkey")).exit_code(), EXIT_USAGE);
+    assert_eq!(CliError::Config(ConfigError::UnknownProfile(String::new())).exit_code(), EXIT_CONFIG);
+    assert_eq!(CliError::AccessToken(MpesaAccessTokenError::EmptyAccessToken).exit_code(), EXIT_NOPERM);
+    assert_eq!(CliError::Output(String::new()).exit_code(), EXIT_SOFTWARE);
+    assert_eq!(CliError::Io(::std::io::ErrorKind::AddrInUse.into()).exit_code(), EXIT_OSERR);
+    assert_eq!(CliError::Bulk(BulkError::State(::std::io::ErrorKind::NotFound.into())).exit_code(), EXIT_OSERR);
+    assert_eq!(CliError::Bulk(BulkError::Csv(String::new())).exit_code(), EXIT_DATA);
+
+    let client = |error| CliError::Client(error).exit_code();
+    assert_eq!(client(MpesaClientError::AccessToken(MpesaAccessTokenError::EmptyAccessToken)), EXIT_NOPERM);
+    assert_eq!(client(MpesaClientError::InvalidRequest(ParameterError::Amount(String::new()))), EXIT_DATA);
+    assert_eq!(client(MpesaClientError::Request(MpesaRequestError::BadRequest, String::new())), EXIT_REJECTED);
+    assert_eq!(client(MpesaClientError::OutcomeUnknown { originator_conversation_id: None, cause: String::new() }),
+               EXIT_TEMPFAIL);
+    assert_eq!(client(MpesaClientError::InvalidResponse(String::new())), EXIT_SOFTWARE);
+}
+
+#[test]
+fn test_acknowledgements_are_accepted_only_with_zero_codes() {
+    assert!(output::is_accepted(&json!({ "ResponseCode": "0", "ResponseDescription": "Success" })));
+    assert!(output::is_accepted(&json!({ "ResponseCode": " 0 ", "ResultCode": 0 })));
+    assert!(output::is_accepted(&json!({ "access_token": "token" })));
+    assert!(!output::is_accepted(&json!({ "ResponseCode": "1" })));
+    // the STK query was accepted but the customer cancelled
+    assert!(!output::is_accepted(&json!({ "ResponseCode": "0", "ResultCode": "1032" })));
+    assert!(!output::is_accepted(&json!({ "ResponseCode": 0, "ResultCode": 2001 })));
+}
+
+#[test]
+fn test_acknowledgements_are_rendered_as_tables_or_json() {
+    let acknowledgement = json!({ "ConversationID": "AG_1", "ResponseCode": "0", "Amount": 10, "Occasion": null });
+
+    assert_eq!(output::render(&acknowledgement, Format::Table).unwrap(),
+               "Amount          10\nConversationID  AG_1\nOccasion        \nResponseCode    0\n");
+    assert_eq!(output::render(&json!("plain"), Format::Table).unwrap(), "plain\n");
+
+    let rendered = output::render(&acknowledgement, Format::Json).unwrap();
+    assert!(rendered.ends_with("}\n"));
+    assert_eq!(serde_json::from_str::<Value>(&rendered).unwrap(), acknowledgement);
+
+    assert_eq!("json".parse::<Format>(), Ok(Format::Json));
+    assert!("yaml".parse::<Format>().is_err());
+}
+
+#[test]
+fn test_listen_logs_one_line_per_callback_and_rejects_validations_when_asked() {
+    let log = env::temp_dir().join(format!("mpesa-listen-{}.jsonl", ::std::process::id()));
+    let _ = fs::remove_file(&log);
+    let matches = subcommand(&["listen", "--log", log.to_str().unwrap(), "--c2b-validation", "reject"]);
+    let listener = listen::listener(&matches, &context()).unwrap();
+
+    let validation: C2BTransaction = serde_json::from_str(testing::C2B_VALIDATION).unwrap();
+    let response = listener.on_c2b_validation(validation.clone());
+    assert_eq!(response.ResultCode, "1");
+    assert_eq!(response.ResultDesc, "Rejected");
+    listener.on_stk_callback(serde_json::from_str::<StkCallbackBody>(testing::STK_CALLBACK_PAID).unwrap().Body.stk_callback);
+
+    let lines: Vec<Value> = fs::read_to_string(&log).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    fs::remove_file(&log).unwrap();
+    assert_eq!(lines.len(), 2);
+    for line in &lines {
+        assert_eq!(line.as_object().unwrap().len(), 3);
+        assert!(DateTime::parse_from_rfc3339(line["received_at"].as_str().unwrap()).is_ok());
+    }
+    assert_eq!(lines[0]["kind"], "c2b_validation");
+    assert_eq!(lines[0]["callback"]["TransID"], Value::String(validation.TransID));
+    assert_eq!(lines[1]["kind"], "stk");
+    assert_eq!(lines[1]["callback"]["CheckoutRequestID"], "ws_CO_191220191020363925");
+
+    // validations are accepted by default
+    let listener = listen::listener(&subcommand(&["listen"]), &context()).unwrap();
+    let validation: C2BTransaction = serde_json::from_str(testing::C2B_VALIDATION).unwrap();
+    assert_eq!(listener.on_c2b_validation(validation).ResultCode, "0");
+}
+
+#[test]
+fn test_dry_run_returns_the_request_instead_of_sending_it() {
+    let mut context = context();
+    context.config.set("consumer_key", "key").unwrap();
+    context.config.set("consumer_secret", "secret").unwrap();
+    context.dry_run = true;
+
+    let request = commands::run("b2c", &subcommand(&["b2c", "--phone", "254708374149", "--amount", "100"]), &context).unwrap();
+    assert_eq!(request["method"], "POST");
+    assert_eq!(request["url"], "https://sandbox.safaricom.co.ke/mpesa/b2c/v3/paymentrequest");
+    assert_eq!(request["headers"]["Authorization"], "Bearer [REDACTED]");
+    assert_eq!(request["body"]["PartyB"], "254708374149");
+    assert!(output::is_accepted(&request));
+
+    let token = commands::run("token", &subcommand(&["token"]), &context).unwrap();
+    assert_eq!(token["method"], "GET");
+    assert_eq!(token["headers"]["Authorization"], "Basic [REDACTED]");
+}
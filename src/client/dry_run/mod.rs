@@ -0,0 +1,118 @@
+//! Building requests without sending them
+//!
+//! `MpesaClient::dry_run()` checks, builds and serializes a request as the client would send it and returns it as
+//! an `HttpRequest`, its credentials redacted. Nothing is sent and nothing is recorded in the `TransactionStore`.
+//! No access token is retrieved either, unless asked for with `acquire_token(true)`, which checks the credentials.
+//!
+//! The `SecurityCredential` of a request is generated as it would be for sending when the client was given the
+//! password of the API operator, and is rendered redacted.
+//!
+//! # Example
+//! ```no_run
+//! # use mpesa::access_token::AccessToken;
+//! # use mpesa::api_products::b2c::B2C;
+//! # use mpesa::client::{MpesaClient, SANDBOX_URL};
+//! # let request: B2C = unimplemented!();
+//! let access_token = AccessToken::new(String::from("foo"), String::from("bar"));
+//! let mut client = MpesaClient::new(access_token, SANDBOX_URL);
+//!
+//! let request = client.dry_run().render_b2c(&request).expect("the request would be rejected");
+//! println!("{} {}\n{:?}\n{}", request.method, request.url, request.headers, request.body.unwrap_or_default());
+//! ```
+
+use serde::Serialize;
+
+use api_products::account_balance::{self, AccountBalance};
+use api_products::b2b::{self, B2B};
+use api_products::b2c::{self, B2C};
+use api_products::c2b::{self, RegisterUrl, SimulateTransaction};
+use api_products::lipa_na_mpesa_online_payment_request::{self, LipaNaMpesaOnlinePaymentRequest};
+use api_products::lipa_na_mpesa_online_query_request::{self, LipaNaMpesaOnlineQueryRequest};
+use api_products::reversal::{self, Reversal};
+use api_products::transaction_status::{self, TransactionSatus};
+use super::{MpesaClient, MpesaClientError};
+use super::transport::{HttpRequest, REDACTED};
+
+/// Renders the requests of a client instead of sending them, see the module documentation
+pub struct DryRun<'a> {
+    client: &'a mut MpesaClient,
+    acquire_token: bool,
+}
+
+impl MpesaClient {
+    /// Renders requests as they would be sent instead of sending them, see the `dry_run` module
+    pub fn dry_run(&mut self) -> DryRun {
+        DryRun {
+            client: self,
+            acquire_token: false,
+        }
+    }
+}
+
+impl<'a> DryRun<'a> {
+    /// Retrieves an access token before rendering a request, which checks the credentials
+    pub fn acquire_token(mut self, acquire_token: bool) -> DryRun<'a> {
+        self.acquire_token = acquire_token;
+        self
+    }
+
+    /// The request `send_b2c()` would send
+    pub fn render_b2c(&mut self, request: &B2C) -> Result<HttpRequest, MpesaClientError> {
+        let request = self.client.prepare_b2c(request)?;
+        self.render(b2c::ENDPOINT, &request)
+    }
+
+    /// The request `send_b2b()` would send
+    pub fn render_b2b(&mut self, request: &B2B) -> Result<HttpRequest, MpesaClientError> {
+        let request = self.client.prepare_b2b(request)?;
+        self.render(b2b::ENDPOINT, &request)
+    }
+
+    /// The request `send_reversal()` would send
+    pub fn render_reversal(&mut self, request: &Reversal) -> Result<HttpRequest, MpesaClientError> {
+        let request = self.client.prepare_reversal(request)?;
+        self.render(reversal::ENDPOINT, &request)
+    }
+
+    /// The request `send_stk_push()` would send
+    pub fn render_stk_push(&mut self, request: &LipaNaMpesaOnlinePaymentRequest) -> Result<HttpRequest, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.client.environment)?;
+        self.render(lipa_na_mpesa_online_payment_request::ENDPOINT, request)
+    }
+
+    /// The request `stk_query()` would send
+    pub fn render_stk_query(&mut self, request: &LipaNaMpesaOnlineQueryRequest) -> Result<HttpRequest, MpesaClientError> {
+        self.render(lipa_na_mpesa_online_query_request::ENDPOINT, request)
+    }
+
+    /// The request `send_transaction_status()` would send
+    pub fn render_transaction_status(&mut self, request: &TransactionSatus) -> Result<HttpRequest, MpesaClientError> {
+        let request = self.client.prepare_transaction_status(request)?;
+        self.render(transaction_status::ENDPOINT, &request)
+    }
+
+    /// The request `send_account_balance()` would send
+    pub fn render_account_balance(&mut self, request: &AccountBalance) -> Result<HttpRequest, MpesaClientError> {
+        let request = self.client.prepare_account_balance(request)?;
+        self.render(account_balance::ENDPOINT, &request)
+    }
+
+    /// The request `register_urls()` would send
+    pub fn render_register_urls(&mut self, request: &RegisterUrl) -> Result<HttpRequest, MpesaClientError> {
+        request.check_urls(self.client.environment)?;
+        self.render(c2b::REGISTER_URL_ENDPOINT, request)
+    }
+
+    /// The request `simulate_c2b()` would send
+    pub fn render_simulate_c2b(&mut self, request: &SimulateTransaction) -> Result<HttpRequest, MpesaClientError> {
+        request.validate()?;
+        self.render(c2b::SIMULATE_ENDPOINT, request)
+    }
+
+    /// The request posting `body` to `endpoint`, redacted
+    fn render<B: Serialize>(&mut self, endpoint: &str, body: &B) -> Result<HttpRequest, MpesaClientError> {
+        let token = if self.acquire_token { self.client.access_token.token()? } else { String::from(REDACTED) };
+        Ok(self.client.http_request(endpoint, body, &token)?.redacted())
+    }
+}
@@ -0,0 +1,659 @@
+//! A client for invoking the Mpesa API products
+//!
+//! The client takes care of attaching a valid access token to every call, serializing the request and decoding the
+//! acknowledgement returned by M-Pesa.
+//!
+//! Most products only acknowledge a request when called; the outcome is posted to your callback urls later.
+//! When the client is given a `Correlator` that is also installed on your callback server, methods such as `b2c()`
+//! return a `PendingResult`, a future resolving to that outcome.
+//!
+//! When the client is given a `TransactionStore`, every request starting a transaction is recorded before it is sent
+//! and moved through its lifecycle as the response comes in, see `store::lifecycle`.
+//!
+//! When the connection drops while a B2C or B2B payment is being sent, or M-Pesa answers it with a server error,
+//! whether the payment was made is not known.
+//! Such failures are reported as `MpesaClientError::OutcomeUnknown` and must be resolved before the payment is sent
+//! again, see the `resolution` module.
+//! B2C payments are sent with an `OriginatorConversationID` generated by the client, so they can be looked up even
+//! when they were never acknowledged. The B2B API does not take one; M-Pesa assigns it in the acknowledgement, so a B2B
+//! payment that was not acknowledged cannot be looked up.
+//!
+//! Where callbacks cannot reach you, `stk_push_and_wait()` polls for the outcome of an STK push instead, see the
+//! `polling` module.
+//!
+//! Requests go over the network unless the client is given another `Transport`, e.g. one recording the exchanges to
+//! a file or replaying them from it, see the `transport` module.
+//!
+//! `dry_run()` checks, builds and serializes requests as the client would send them, without sending them, see the
+//! `dry_run` module.
+//!
+//! When the client is given the password of the API operator and the M-Pesa certificate of the environment with
+//! `initiator_password()`, it generates the `SecurityCredential` of the requests sent without one, see the
+//! `security_credential` module.
+//!
+//! B2C, B2B and STK push requests can be sent with an idempotency key, e.g. `send_b2c_idempotent()`.
+//! The key is recorded with the request in the `TransactionStore`, and a request sent again with the same key is not
+//! sent to M-Pesa: the acknowledgement of the first request is returned instead, or its record if it was not acknowledged.
+//! A request that was never sent, e.g. because no access token could be had, is sent when retried with the same key.
+//! Reusing a key for a different request fails with `MpesaClientError::IdempotencyMismatch`.
+//!
+//! # Example
+//! ```no_run
+//! # extern crate mpesa;
+//! # extern crate futures;
+//! # use mpesa::access_token::AccessToken;
+//! # use mpesa::api_products::b2c::B2C;
+//! # use mpesa::callbacks::CallbackHandler;
+//! # use mpesa::callbacks::correlation::Correlator;
+//! # use mpesa::client::{MpesaClient, SANDBOX_URL};
+//! # use futures::Future;
+//! # fn main() {
+//! # let request: B2C = unimplemented!();
+//! struct Unmatched;
+//! impl CallbackHandler for Unmatched {}
+//!
+//! let correlator = Correlator::new(Unmatched);
+//! // hand `correlator.clone()` to your `CallbackServer` here
+//!
+//! let access_token = AccessToken::new(String::from("foo"), String::from("bar"));
+//! let mut client = MpesaClient::new(access_token, SANDBOX_URL).correlator(correlator);
+//!
+//! let result = client.b2c(&request).expect("request was not accepted").wait();
+//! # }
+//! ```
+
+pub mod dry_run;
+pub mod polling;
+pub mod resolution;
+pub mod transport;
+
+use std::fmt::{self, Display};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use reqwest;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use access_token::{AccessToken, MpesaAccessTokenError};
+use api_products::account_balance::{self, AccountBalance, AccountBalanceResponse};
+use api_products::b2b::{self, B2B, B2BResponse};
+use api_products::b2c::{self, B2C, B2CResponse};
+use api_products::c2b::{self, RegisterUrl, RegisterUrlResponse, SimulateTransaction, SimulateTransactionResponse};
+use api_products::lipa_na_mpesa_online_payment_request::{self, LipaNaMpesaOnlinePaymentRequest, LipaNaMpesaOnlinePaymentResponse};
+use api_products::lipa_na_mpesa_online_query_request::{self, LipaNaMpesaOnlineQueryRequest, LipaNaMpesaOnlineQueryRequestResponse};
+use api_products::reversal::{self, Reversal, ReversalResponse};
+use api_products::transaction_status::{self, TransactionSatus, TransactionStatusResponse};
+use callbacks::{StkCallback, TransactionResult};
+use callbacks::correlation::{Correlator, PendingResult};
+use parameters::{Environment, MpesaRequestError, ParameterError};
+use security_credential::{Certificate, CredentialError};
+use store::{self, Acknowledgement, AcknowledgementIds, Product, StoreError, Transaction, TransactionState, TransactionStore};
+
+pub use self::dry_run::DryRun;
+pub use self::resolution::Resolution;
+use self::transport::{HttpRequest, HttpTransport, Transport, TransportError};
+
+/// Base url of the sandbox (testing) environment
+pub const SANDBOX_URL: &str = "https://sandbox.safaricom.co.ke";
+/// Base url of the production environment
+pub const PRODUCTION_URL: &str = "https://api.safaricom.co.ke";
+
+/// How long to wait for the callback of a request by default
+const DEFAULT_RESULT_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+
+/// Length of generated `OriginatorConversationID`s
+const ORIGINATOR_CONVERSATION_ID_LENGTH: usize = 24;
+
+/// Products whose requests must never be sent twice by accident
+const PAYMENT_PRODUCTS: &[Product] = &[Product::B2C, Product::B2B];
+
+/// Request fields that differ between attempts at the same request, ignored when comparing them
+const ATTEMPT_FIELDS: &[&str] = &["OriginatorConversationID", "Timestamp"];
+
+/// Definition of possible errors when invoking an API product
+#[derive(Debug)]
+pub enum MpesaClientError {
+    /// No valid access token could be retrieved
+    AccessToken(MpesaAccessTokenError),
+    /// The API could not be reached
+    Connection(reqwest::Error),
+    /// The API rejected the request. Holds the body of the response
+    Request(MpesaRequestError, String),
+    /// The API responded with a status code that is not documented. Holds the status code and body of the response
+    UnexpectedStatus(u16, String),
+    /// The response of the API could not be understood
+    InvalidResponse(String),
+    /// A result was to be awaited but the client has no `Correlator`
+    NoCorrelator,
+    /// The request could not be recorded in the `TransactionStore`, so it was not sent
+    Store(StoreError),
+    /// The payment may or may not have been made, e.g. because the connection dropped after it was sent.
+    /// It must be resolved before it is sent again. Holds the `OriginatorConversationID` to look it up with, unless
+    /// it was sent without one and never acknowledged, as B2B payments are
+    OutcomeUnknown { originator_conversation_id: Option<String>, cause: String },
+    /// The payment was made already so it was not sent again. Holds its `OriginatorConversationID`
+    AlreadyProcessed(String),
+    /// A request with an idempotency key was to be sent but the client has no `TransactionStore`
+    NoStore,
+    /// A request with the same idempotency key was sent before but not acknowledged, so it was not sent again.
+    /// Holds the record of the earlier request, whose state tells whether it is still in flight
+    Duplicate(Transaction),
+    /// A different request was sent before with the same idempotency key, so this one was not sent. Holds the key
+    IdempotencyMismatch(String),
+    /// The request is invalid, e.g. its `CommandID` cannot be used with the product or a callback url would be
+    /// rejected, so it was not sent
+    InvalidRequest(ParameterError),
+    /// The exchange could not be recorded or replayed
+    Transport(TransportError),
+    /// The security credential could not be generated, so the request was not sent
+    SecurityCredential(CredentialError),
+}
+
+/// A client for the Mpesa API products
+pub struct MpesaClient {
+    access_token: AccessToken,
+    base_url: String,
+    transport: Arc<dyn Transport>,
+    correlator: Option<Correlator>,
+    store: Option<Arc<dyn TransactionStore>>,
+    result_timeout: Duration,
+    environment: Environment,
+    initiator_password: Option<(String, Certificate)>,
+}
+
+impl MpesaClient {
+    /// Creates a new client calling the API at `base_url`, usually `SANDBOX_URL` or `PRODUCTION_URL`.
+    /// Access tokens are retrieved from the same API
+    pub fn new(access_token: AccessToken, base_url: &str) -> MpesaClient {
+        let base_url = base_url.trim_end_matches('/').to_string();
+        MpesaClient {
+            access_token: access_token.base_url(&base_url),
+            environment: if base_url == PRODUCTION_URL { Environment::Production } else { Environment::Sandbox },
+            base_url: base_url,
+            transport: Arc::new(HttpTransport::new()),
+            correlator: None,
+            store: None,
+            result_timeout: DEFAULT_RESULT_TIMEOUT,
+            initiator_password: None,
+        }
+    }
+
+    /// Registers submitted requests with `correlator` so their results can be awaited
+    pub fn correlator(mut self, correlator: Correlator) -> MpesaClient {
+        self.correlator = Some(correlator);
+        self
+    }
+
+    /// Records every request starting a transaction in `store`
+    pub fn store(mut self, store: Arc<dyn TransactionStore>) -> MpesaClient {
+        self.store = Some(store);
+        self
+    }
+
+    /// Exchanges requests, including those for access tokens, through `transport` instead of the network
+    pub fn transport(self, transport: Arc<dyn Transport>) -> MpesaClient {
+        MpesaClient {
+            access_token: self.access_token.transport(transport.clone()),
+            transport: transport,
+            ..self
+        }
+    }
+
+    /// How long to wait for the callback of a request before giving up
+    pub fn result_timeout(mut self, timeout: Duration) -> MpesaClient {
+        self.result_timeout = timeout;
+        self
+    }
+
+    /// The environment whose rules callback urls are checked against.
+    /// Derived from the base url, so only needed when calling production through another url
+    pub fn environment(mut self, environment: Environment) -> MpesaClient {
+        self.environment = environment;
+        self
+    }
+
+    /// Generates the `SecurityCredential` of requests sent without one by encrypting `password`, the password of the
+    /// API operator, with `certificate`, the M-Pesa certificate of the environment
+    pub fn initiator_password(mut self, password: &str, certificate: Certificate) -> MpesaClient {
+        self.initiator_password = Some((password.to_string(), certificate));
+        self
+    }
+
+    /// Submits a B2C payment and returns the acknowledgement
+    pub fn send_b2c(&mut self, request: &B2C) -> Result<B2CResponse, MpesaClientError> {
+        self.submit_b2c(request, None)
+    }
+
+    /// Submits a B2C payment unless one was submitted with `idempotency_key` before
+    pub fn send_b2c_idempotent(&mut self, idempotency_key: &str, request: &B2C) -> Result<B2CResponse, MpesaClientError> {
+        self.submit_b2c(request, Some(idempotency_key))
+    }
+
+    fn submit_b2c(&mut self, request: &B2C, idempotency_key: Option<&str>) -> Result<B2CResponse, MpesaClientError> {
+        let request = self.prepare_b2c(request)?;
+        let ids = AcknowledgementIds {
+            originator_conversation_id: Some(request.OriginatorConversationID.clone()),
+            ..AcknowledgementIds::default()
+        };
+        self.submit(Product::B2C, b2c::ENDPOINT, &request, ids, idempotency_key)
+    }
+
+    /// Checks a B2C payment and gives it an `OriginatorConversationID` and a `SecurityCredential` unless it has them
+    fn prepare_b2c(&self, request: &B2C) -> Result<B2C, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.environment)?;
+        let mut request = request.clone();
+        if request.OriginatorConversationID.is_empty() {
+            request.OriginatorConversationID = originator_conversation_id();
+        }
+        request.SecurityCredential = self.security_credential(&request.SecurityCredential)?;
+        Ok(request)
+    }
+
+    /// Submits a B2C payment and returns a future resolving to the result posted to the `ResultURL`
+    pub fn b2c(&mut self, request: &B2C) -> Result<PendingResult<TransactionResult>, MpesaClientError> {
+        let correlator = self.correlator.clone().ok_or(MpesaClientError::NoCorrelator)?;
+        let in_flight = correlator.in_flight();
+        let response = self.send_b2c(request)?;
+        Ok(in_flight.register(&[&response.ConversationID, &response.OriginatorConversationID], self.result_timeout))
+    }
+
+    /// Submits a B2B payment and returns the acknowledgement
+    pub fn send_b2b(&mut self, request: &B2B) -> Result<B2BResponse, MpesaClientError> {
+        self.submit_b2b(request, None)
+    }
+
+    /// Submits a B2B payment unless one was submitted with `idempotency_key` before
+    pub fn send_b2b_idempotent(&mut self, idempotency_key: &str, request: &B2B) -> Result<B2BResponse, MpesaClientError> {
+        self.submit_b2b(request, Some(idempotency_key))
+    }
+
+    fn submit_b2b(&mut self, request: &B2B, idempotency_key: Option<&str>) -> Result<B2BResponse, MpesaClientError> {
+        let request = self.prepare_b2b(request)?;
+        // the B2B API does not take an `OriginatorConversationID`, it is recorded from the acknowledgement
+        self.submit(Product::B2B, b2b::ENDPOINT, &request, AcknowledgementIds::default(), idempotency_key)
+    }
+
+    /// Checks a B2B payment and gives it a `SecurityCredential` unless it has one
+    fn prepare_b2b(&self, request: &B2B) -> Result<B2B, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.environment)?;
+        Ok(B2B { SecurityCredential: self.security_credential(&request.SecurityCredential)?, ..request.clone() })
+    }
+
+    /// Submits a B2B payment and returns a future resolving to the result posted to the `ResultURL`
+    pub fn b2b(&mut self, request: &B2B) -> Result<PendingResult<TransactionResult>, MpesaClientError> {
+        let correlator = self.correlator.clone().ok_or(MpesaClientError::NoCorrelator)?;
+        let in_flight = correlator.in_flight();
+        let response = self.send_b2b(request)?;
+        Ok(in_flight.register(&[&response.ConversationID, &response.OriginatorConversationID], self.result_timeout))
+    }
+
+    /// Submits the reversal of a transaction and returns the acknowledgement
+    pub fn send_reversal(&mut self, request: &Reversal) -> Result<ReversalResponse, MpesaClientError> {
+        let request = self.prepare_reversal(request)?;
+        self.submit(Product::Reversal, reversal::ENDPOINT, &request, AcknowledgementIds::default(), None)
+    }
+
+    /// Checks a reversal and gives it a `SecurityCredential` unless it has one
+    fn prepare_reversal(&self, request: &Reversal) -> Result<Reversal, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.environment)?;
+        Ok(Reversal { SecurityCredential: self.security_credential(&request.SecurityCredential)?, ..request.clone() })
+    }
+
+    /// Submits the reversal of a transaction and returns a future resolving to the result posted to the `ResultURL`
+    pub fn reversal(&mut self, request: &Reversal) -> Result<PendingResult<TransactionResult>, MpesaClientError> {
+        let correlator = self.correlator.clone().ok_or(MpesaClientError::NoCorrelator)?;
+        let in_flight = correlator.in_flight();
+        let response = self.send_reversal(request)?;
+        Ok(in_flight.register(&[&response.ConversationID, &response.OriginatorConversationID], self.result_timeout))
+    }
+
+    /// Submits an STK push and returns the acknowledgement
+    pub fn send_stk_push(&mut self, request: &LipaNaMpesaOnlinePaymentRequest) -> Result<LipaNaMpesaOnlinePaymentResponse, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.environment)?;
+        self.submit(Product::StkPush, lipa_na_mpesa_online_payment_request::ENDPOINT, request, AcknowledgementIds::default(), None)
+    }
+
+    /// Submits an STK push unless one was submitted with `idempotency_key` before
+    pub fn send_stk_push_idempotent(&mut self, idempotency_key: &str, request: &LipaNaMpesaOnlinePaymentRequest) -> Result<LipaNaMpesaOnlinePaymentResponse, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.environment)?;
+        self.submit(Product::StkPush, lipa_na_mpesa_online_payment_request::ENDPOINT, request, AcknowledgementIds::default(), Some(idempotency_key))
+    }
+
+    /// Submits an STK push and returns a future resolving to the result posted to the `CallBackURL`
+    pub fn stk_push(&mut self, request: &LipaNaMpesaOnlinePaymentRequest) -> Result<PendingResult<StkCallback>, MpesaClientError> {
+        let correlator = self.correlator.clone().ok_or(MpesaClientError::NoCorrelator)?;
+        let in_flight = correlator.in_flight();
+        let response = self.send_stk_push(request)?;
+        Ok(in_flight.register(&[&response.CheckoutRequestID, &response.MerchantRequestID], self.result_timeout))
+    }
+
+    /// Queries the outcome of an STK push
+    pub fn stk_query(&mut self, request: &LipaNaMpesaOnlineQueryRequest) -> Result<LipaNaMpesaOnlineQueryRequestResponse, MpesaClientError> {
+        self.post(lipa_na_mpesa_online_query_request::ENDPOINT, request)
+    }
+
+    /// Submits a transaction status query and returns the acknowledgement
+    pub fn send_transaction_status(&mut self, request: &TransactionSatus) -> Result<TransactionStatusResponse, MpesaClientError> {
+        let request = self.prepare_transaction_status(request)?;
+        self.submit(Product::TransactionStatus, transaction_status::ENDPOINT, &request, AcknowledgementIds::default(), None)
+    }
+
+    /// Checks a transaction status query and gives it a `SecurityCredential` unless it has one
+    fn prepare_transaction_status(&self, request: &TransactionSatus) -> Result<TransactionSatus, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.environment)?;
+        Ok(TransactionSatus { SecurityCredential: self.security_credential(&request.SecurityCredential)?, ..request.clone() })
+    }
+
+    /// Submits a transaction status query and returns a future resolving to the status posted to the `ResultURL`
+    pub fn transaction_status(&mut self, request: &TransactionSatus) -> Result<PendingResult<TransactionResult>, MpesaClientError> {
+        let correlator = self.correlator.clone().ok_or(MpesaClientError::NoCorrelator)?;
+        let in_flight = correlator.in_flight();
+        let response = self.send_transaction_status(request)?;
+        Ok(in_flight.register(&[&response.ConversationID, &response.OriginatorConversationID], self.result_timeout))
+    }
+
+    /// Submits an account balance query and returns the acknowledgement
+    pub fn send_account_balance(&mut self, request: &AccountBalance) -> Result<AccountBalanceResponse, MpesaClientError> {
+        let request = self.prepare_account_balance(request)?;
+        self.submit(Product::AccountBalance, account_balance::ENDPOINT, &request, AcknowledgementIds::default(), None)
+    }
+
+    /// Checks an account balance query and gives it a `SecurityCredential` unless it has one
+    fn prepare_account_balance(&self, request: &AccountBalance) -> Result<AccountBalance, MpesaClientError> {
+        request.validate()?;
+        request.check_urls(self.environment)?;
+        Ok(AccountBalance { SecurityCredential: self.security_credential(&request.SecurityCredential)?, ..request.clone() })
+    }
+
+    /// `security_credential`, or the one generated from the password of the API operator when it is empty
+    fn security_credential(&self, security_credential: &str) -> Result<String, MpesaClientError> {
+        match self.initiator_password {
+            Some((ref password, ref certificate)) if security_credential.is_empty() => Ok(certificate.encrypt(password)?),
+            _ => Ok(security_credential.to_string()),
+        }
+    }
+
+    /// Submits an account balance query and returns a future resolving to the balance posted to the `ResultURL`
+    pub fn account_balance(&mut self, request: &AccountBalance) -> Result<PendingResult<TransactionResult>, MpesaClientError> {
+        let correlator = self.correlator.clone().ok_or(MpesaClientError::NoCorrelator)?;
+        let in_flight = correlator.in_flight();
+        let response = self.send_account_balance(request)?;
+        Ok(in_flight.register(&[&response.ConversationID, &response.OriginatorConversationID], self.result_timeout))
+    }
+
+    /// Registers the C2B validation and confirmation urls of a shortcode
+    pub fn register_urls(&mut self, request: &RegisterUrl) -> Result<RegisterUrlResponse, MpesaClientError> {
+        request.check_urls(self.environment)?;
+        self.post(c2b::REGISTER_URL_ENDPOINT, request)
+    }
+
+    /// Simulates a payment by a customer, only available in the sandbox
+    pub fn simulate_c2b(&mut self, request: &SimulateTransaction) -> Result<SimulateTransactionResponse, MpesaClientError> {
+        request.validate()?;
+        self.post(c2b::SIMULATE_ENDPOINT, request)
+    }
+
+    /// Posts a request starting a transaction, recording it in the store if there is one.
+    /// `ids` are the ids of the transaction known before it is sent.
+    fn submit<B, R>(&mut self, product: Product, endpoint: &str, body: &B, ids: AcknowledgementIds, idempotency_key: Option<&str>) -> Result<R, MpesaClientError>
+        where B: Serialize, R: Serialize + DeserializeOwned + Acknowledgement
+    {
+        let result = match (self.store.clone(), idempotency_key) {
+            (Some(store), _) => self.post_recorded(store, product, endpoint, body, ids.clone(), idempotency_key),
+            (None, Some(_)) => return Err(MpesaClientError::NoStore),
+            (None, None) => self.post(endpoint, body),
+        };
+
+        match result {
+            Err(ref error) if PAYMENT_PRODUCTS.contains(&product) && is_ambiguous(error) => {
+                Err(MpesaClientError::OutcomeUnknown {
+                    originator_conversation_id: ids.originator_conversation_id,
+                    cause: format!("{}", error),
+                })
+            },
+            result => result,
+        }
+    }
+
+    /// Posts a request after recording it in `store`, then records the response.
+    /// A request with an idempotency key that has been used before is not posted again.
+    fn post_recorded<B, R>(&mut self, store: Arc<dyn TransactionStore>, product: Product, endpoint: &str, body: &B, ids: AcknowledgementIds, idempotency_key: Option<&str>) -> Result<R, MpesaClientError>
+        where B: Serialize, R: Serialize + DeserializeOwned + Acknowledgement
+    {
+        let earlier = match idempotency_key {
+            Some(key) => store.find_by_idempotency_key(key)?,
+            None => None,
+        };
+        if let Some(ref earlier) = earlier {
+            check_payload(earlier, product, body)?;
+            // only a request that was never sent is sent again
+            if earlier.state != TransactionState::Created || earlier.acknowledgement.is_some() {
+                return replay(earlier.clone());
+            }
+        }
+
+        // a request that cannot be authorized is not sent, so it may be sent again with the same key
+        self.access_token.token()?;
+
+        let mut transaction = match earlier {
+            Some(earlier) => earlier,
+            None => {
+                let transaction = Transaction {
+                    idempotency_key: idempotency_key.map(String::from),
+                    ..Transaction::new(product, body)
+                };
+                match store.insert(&transaction) {
+                    Ok(()) => transaction,
+                    // sent concurrently with the same key
+                    Err(StoreError::DuplicateKey(key)) => match store.find_by_idempotency_key(&key)? {
+                        Some(earlier) => {
+                            check_payload(&earlier, product, body)?;
+                            return replay(earlier);
+                        },
+                        None => return Err(MpesaClientError::Store(StoreError::DuplicateKey(key))),
+                    },
+                    Err(error) => return Err(MpesaClientError::Store(error)),
+                }
+            },
+        };
+        transaction.request = store::redacted_json(body);
+        transaction.originator_conversation_id = ids.originator_conversation_id;
+        transaction.transition(TransactionState::Submitted).expect("a created transaction can always be submitted");
+        if !store.update_from(TransactionState::Created, &transaction)? {
+            // sent concurrently with the same key
+            return match store.get(&transaction.id)? {
+                Some(earlier) => replay(earlier),
+                None => Err(MpesaClientError::Duplicate(transaction)),
+            };
+        }
+
+        let result = self.post::<B, R>(endpoint, body);
+        // the callback may have concluded the transaction already, so the response is applied to it as it is now
+        let recorded = store::modify(&store, &transaction.id, |transaction| match result {
+            Ok(ref acknowledgement) => {
+                transaction.acknowledgement = serde_json::to_string(acknowledgement).ok();
+                transaction.acknowledge(acknowledgement.ids())
+            },
+            Err(ref error) if PAYMENT_PRODUCTS.contains(&product) && is_ambiguous(error) => {
+                transaction.conclude(TransactionState::Unknown, None, &format!("{}", error), None)
+            },
+            Err(MpesaClientError::Request(ref error, ref text)) => {
+                transaction.conclude(TransactionState::Failed, None, &format!("{} {}", error, text), None)
+            },
+            // the request never left
+            Err(MpesaClientError::AccessToken(ref error)) => {
+                transaction.conclude(TransactionState::Failed, None, &format!("{}", error), None)
+            },
+            // the request may or may not have reached M-Pesa
+            Err(ref error) => transaction.conclude(TransactionState::Unknown, None, &format!("{}", error), None),
+        });
+
+        // the request has been sent, failing now would invite sending it again
+        match recorded {
+            Ok(_) => (),
+            Err(StoreError::IllegalTransition(error)) => warn!("not recording the response to transaction {}: {}", transaction.id, error),
+            Err(error) => error!("unable to record the response to transaction {}: {}", transaction.id, error),
+        }
+        result
+    }
+
+    /// Posts `body` to `endpoint` and decodes the response
+    fn post<B: Serialize, R: DeserializeOwned>(&mut self, endpoint: &str, body: &B) -> Result<R, MpesaClientError> {
+        let token = self.access_token.token()?;
+        let request = self.http_request(endpoint, body, &token)?;
+        let response = self.transport.send(&request)?;
+
+        if response.status < 200 || response.status >= 300 {
+            return Err(match MpesaRequestError::from_status(response.status) {
+                Some(error) => MpesaClientError::Request(error, response.body),
+                None => MpesaClientError::UnexpectedStatus(response.status, response.body),
+            });
+        }
+
+        Ok(serde_json::from_str(&response.body)?)
+    }
+
+    /// The request posting `body` to `endpoint`, authorized with `token`
+    fn http_request<B: Serialize>(&self, endpoint: &str, body: &B, token: &str) -> Result<HttpRequest, MpesaClientError> {
+        let url = format!("{}{}", self.base_url, endpoint);
+        Ok(HttpRequest::post(&url, serde_json::to_string(body)?).header("Authorization", &format!("Bearer {}", token)))
+    }
+}
+
+/// Generates an id to send a payment with
+fn originator_conversation_id() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(ORIGINATOR_CONVERSATION_ID_LENGTH).collect()
+}
+
+/// Fails unless `body` is the request recorded in `earlier`, apart from the fields that differ between attempts
+fn check_payload<B: Serialize>(earlier: &Transaction, product: Product, body: &B) -> Result<(), MpesaClientError> {
+    let payload = |request: &str| {
+        let mut payload: Value = serde_json::from_str(request).unwrap_or(Value::Null);
+        if let Value::Object(ref mut fields) = payload {
+            for field in ATTEMPT_FIELDS {
+                fields.remove(*field);
+            }
+        }
+        payload
+    };
+
+    if earlier.product == product && payload(&earlier.request) == payload(&store::redacted_json(body)) {
+        Ok(())
+    } else {
+        Err(MpesaClientError::IdempotencyMismatch(earlier.idempotency_key.clone().unwrap_or_default()))
+    }
+}
+
+/// The acknowledgement of a request sent before, if it was acknowledged
+fn replay<R: DeserializeOwned>(transaction: Transaction) -> Result<R, MpesaClientError> {
+    match transaction.acknowledgement {
+        Some(ref acknowledgement) => Ok(serde_json::from_str(acknowledgement)?),
+        None => Err(MpesaClientError::Duplicate(transaction)),
+    }
+}
+
+/// Whether a request failing with `error` may have been processed nonetheless
+fn is_ambiguous(error: &MpesaClientError) -> bool {
+    match error {
+        &MpesaClientError::Connection(_) | &MpesaClientError::UnexpectedStatus(..) | &MpesaClientError::InvalidResponse(_) => true,
+        // the request may have been processed before the server failed
+        &MpesaClientError::Request(MpesaRequestError::InternalServerError, _) => true,
+        _ => false,
+    }
+}
+
+impl From<MpesaAccessTokenError> for MpesaClientError {
+    fn from(error: MpesaAccessTokenError) -> Self {
+        MpesaClientError::AccessToken(error)
+    }
+}
+
+impl From<StoreError> for MpesaClientError {
+    fn from(error: StoreError) -> Self {
+        MpesaClientError::Store(error)
+    }
+}
+
+impl From<ParameterError> for MpesaClientError {
+    fn from(error: ParameterError) -> Self {
+        MpesaClientError::InvalidRequest(error)
+    }
+}
+
+impl From<TransportError> for MpesaClientError {
+    fn from(error: TransportError) -> Self {
+        match error {
+            TransportError::Connection(error) => MpesaClientError::Connection(error),
+            error => MpesaClientError::Transport(error),
+        }
+    }
+}
+
+impl From<CredentialError> for MpesaClientError {
+    fn from(error: CredentialError) -> Self {
+        MpesaClientError::SecurityCredential(error)
+    }
+}
+
+impl From<reqwest::Error> for MpesaClientError {
+    fn from(error: reqwest::Error) -> Self {
+        MpesaClientError::Connection(error)
+    }
+}
+
+impl From<serde_json::Error> for MpesaClientError {
+    fn from(error: serde_json::Error) -> Self {
+        MpesaClientError::InvalidResponse(format!("{}", error))
+    }
+}
+
+impl Display for MpesaClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &MpesaClientError::AccessToken(ref error) => write!(f, "MpesaClientError::AccessToken -- {}", error),
+            &MpesaClientError::Connection(ref error) => write!(f, "MpesaClientError::Connection -- {}", error),
+            &MpesaClientError::Request(ref error, ref body) => write!(f, "MpesaClientError::Request -- {} {}", error, body),
+            &MpesaClientError::UnexpectedStatus(status, ref body) => write!(f, "MpesaClientError::UnexpectedStatus -- Status Code: {} {}", status, body),
+            &MpesaClientError::InvalidResponse(ref description) => write!(f, "MpesaClientError::InvalidResponse -- {}", description),
+            &MpesaClientError::NoCorrelator => write!(f, "MpesaClientError::NoCorrelator -- the client has no correlator to await results with"),
+            &MpesaClientError::Store(ref error) => write!(f, "MpesaClientError::Store -- {}", error),
+            &MpesaClientError::OutcomeUnknown { originator_conversation_id: Some(ref id), ref cause } => {
+                write!(f, "MpesaClientError::OutcomeUnknown -- payment {} may or may not have been made: {}", id, cause)
+            },
+            &MpesaClientError::OutcomeUnknown { originator_conversation_id: None, ref cause } => {
+                write!(f, "MpesaClientError::OutcomeUnknown -- the payment may or may not have been made: {}", cause)
+            },
+            &MpesaClientError::AlreadyProcessed(ref originator_conversation_id) => {
+                write!(f, "MpesaClientError::AlreadyProcessed -- payment {} was made already", originator_conversation_id)
+            },
+            &MpesaClientError::NoStore => write!(f, "MpesaClientError::NoStore -- the client has no store to record idempotency keys in"),
+            &MpesaClientError::Duplicate(ref transaction) => {
+                write!(f, "MpesaClientError::Duplicate -- transaction {} was sent with the same idempotency key and is {}",
+                       transaction.id, transaction.state.as_str())
+            },
+            &MpesaClientError::IdempotencyMismatch(ref key) => {
+                write!(f, "MpesaClientError::IdempotencyMismatch -- {} was used for a different request", key)
+            },
+            &MpesaClientError::InvalidRequest(ref error) => write!(f, "MpesaClientError::InvalidRequest -- {}", error),
+            &MpesaClientError::Transport(ref error) => write!(f, "MpesaClientError::Transport -- {}", error),
+            &MpesaClientError::SecurityCredential(ref error) => write!(f, "MpesaClientError::SecurityCredential -- {}", error),
+        }
+    }
+}
+
+impl Error for MpesaClientError {
+    fn description(&self) -> &str {
+        "failed to invoke an Mpesa API product"
+    }
+}
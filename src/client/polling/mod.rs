@@ -0,0 +1,204 @@
+//! Waiting for the outcome of an STK push without callbacks
+//!
+//! The outcome of an STK push is posted to its `CallBackURL`. Where callbacks cannot reach you, e.g. on a developer
+//! machine or behind a firewall, `MpesaClient::stk_push_and_wait()` sends the push and then queries its outcome with
+//! `LipaNaMpesaOnlineQueryRequest`s until it is final.
+//!
+//! M-Pesa refuses queries for pushes the customer has not answered yet with the error `500.001.1001`, which is taken
+//! as pending, as are dropped connections and rate limiting. The delay between queries doubles from the first delay
+//! of `StkPolling` up to its longest, and polling stops once the STK window has passed without a final outcome.
+//!
+//! Only some versions of the API send the M-Pesa receipt of the payment along with the result of a query, see
+//! `StkOutcome::receipt()`. It is always in the callback.
+//!
+//! # Example
+//! ```no_run
+//! # extern crate mpesa;
+//! # use std::time::Duration;
+//! # use mpesa::api_products::lipa_na_mpesa_online_payment_request::LipaNaMpesaOnlinePaymentRequest;
+//! # use mpesa::client::MpesaClient;
+//! # use mpesa::client::polling::{StkOutcome, StkPolling};
+//! # fn main() {
+//! # let mut client: MpesaClient = unimplemented!();
+//! # let request: LipaNaMpesaOnlinePaymentRequest = unimplemented!();
+//! let polling = StkPolling::new().window(Duration::from_secs(90));
+//! match client.stk_push_and_wait(&request, "passkey", &polling) {
+//!     Ok(outcome @ StkOutcome::Paid(_)) => println!("paid, receipt {:?}", outcome.receipt()),
+//!     Ok(StkOutcome::Undetermined(reason)) => eprintln!("check the payment later: {}", reason),
+//!     Ok(outcome) => eprintln!("not paid: {}", outcome.description()),
+//!     Err(error) => eprintln!("the push was not accepted: {}", error),
+//! }
+//! # }
+//! ```
+
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{self, Value};
+
+use api_products::lipa_na_mpesa_online_payment_request::LipaNaMpesaOnlinePaymentRequest;
+use api_products::lipa_na_mpesa_online_query_request::{LipaNaMpesaOnlineQueryRequest, LipaNaMpesaOnlineQueryRequestResponse};
+use parameters::{MpesaRequestError, ResultCodes};
+use super::{MpesaClient, MpesaClientError};
+
+/// Error code of queries for pushes that are still being processed
+pub const STILL_PROCESSING: &str = "500.001.1001";
+
+/// How long after a push is accepted its outcome is awaited by default.
+/// The prompt expires on the phone of the customer well before
+pub const STK_WINDOW: Duration = Duration::from_secs(120);
+
+/// Delay before the first query by default, leaving the customer time to enter their PIN
+const FIRST_DELAY: Duration = Duration::from_secs(5);
+
+/// Longest delay between two queries by default
+const LONGEST_DELAY: Duration = Duration::from_secs(20);
+
+/// When and for how long the outcome of a push is queried
+#[derive(Debug, Clone)]
+pub struct StkPolling {
+    first_delay: Duration,
+    longest_delay: Duration,
+    window: Duration,
+}
+
+/// The final outcome of an STK push, as reported by a query
+#[derive(Debug)]
+pub enum StkOutcome {
+    /// The customer paid
+    Paid(LipaNaMpesaOnlineQueryRequestResponse),
+    /// The customer cancelled the push
+    Cancelled(LipaNaMpesaOnlineQueryRequestResponse),
+    /// The customer did not answer the push in time, or could not be reached
+    Timeout(LipaNaMpesaOnlineQueryRequestResponse),
+    /// The customer cannot afford the payment
+    InsufficientFunds(LipaNaMpesaOnlineQueryRequestResponse),
+    /// The customer entered a wrong PIN
+    WrongPin(LipaNaMpesaOnlineQueryRequestResponse),
+    /// The payment failed for another reason, see its `ResultCode`
+    Failed(LipaNaMpesaOnlineQueryRequestResponse),
+    /// No final outcome was reported before the STK window passed, or the push could not be queried.
+    /// The payment may still go through
+    Undetermined(String),
+}
+
+impl StkPolling {
+    /// Polls after `FIRST_DELAY`, backing off up to `LONGEST_DELAY`, for `STK_WINDOW`
+    pub fn new() -> StkPolling {
+        StkPolling {
+            first_delay: FIRST_DELAY,
+            longest_delay: LONGEST_DELAY,
+            window: STK_WINDOW,
+        }
+    }
+
+    /// Queries `first` after the push is accepted, doubling the delay between queries up to `longest`
+    pub fn delays(mut self, first: Duration, longest: Duration) -> StkPolling {
+        self.first_delay = first;
+        self.longest_delay = cmp::max(first, longest);
+        self
+    }
+
+    /// Gives up once `window` has passed since the push was accepted
+    pub fn window(mut self, window: Duration) -> StkPolling {
+        self.window = window;
+        self
+    }
+}
+
+impl Default for StkPolling {
+    fn default() -> StkPolling {
+        StkPolling::new()
+    }
+}
+
+impl StkOutcome {
+    /// Interprets the result of a query for a push the customer has answered
+    pub fn from_response(response: LipaNaMpesaOnlineQueryRequestResponse) -> StkOutcome {
+        let result_code = match response.ResultCode.parse::<ResultCodes>() {
+            Ok(result_code) => result_code,
+            Err(_) => return StkOutcome::Undetermined(format!("the push has the result code {}", response.ResultCode)),
+        };
+        match result_code {
+            ResultCodes::Success => StkOutcome::Paid(response),
+            ResultCodes::RequestCancelledByUser => StkOutcome::Cancelled(response),
+            ResultCodes::SubscriberUnreachable | ResultCodes::TransactionExpired => StkOutcome::Timeout(response),
+            ResultCodes::InsufficientFunds => StkOutcome::InsufficientFunds(response),
+            ResultCodes::InvalidInitiatorInformation => StkOutcome::WrongPin(response),
+            _ => StkOutcome::Failed(response),
+        }
+    }
+
+    /// Whether the customer paid
+    pub fn is_paid(&self) -> bool {
+        match self {
+            &StkOutcome::Paid(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The M-Pesa receipt of the payment, if the customer paid and the API sent it along
+    pub fn receipt(&self) -> Option<String> {
+        match self {
+            &StkOutcome::Paid(ref response) if !response.MpesaReceiptNumber.is_empty() => Some(response.MpesaReceiptNumber.clone()),
+            _ => None,
+        }
+    }
+
+    /// A description of the outcome
+    pub fn description(&self) -> String {
+        match self {
+            &StkOutcome::Undetermined(ref reason) => reason.clone(),
+            &StkOutcome::Paid(ref response) | &StkOutcome::Cancelled(ref response) | &StkOutcome::Timeout(ref response) |
+            &StkOutcome::InsufficientFunds(ref response) | &StkOutcome::WrongPin(ref response) | &StkOutcome::Failed(ref response) => {
+                format!("{} {}", response.ResultCode, response.ResultDesc)
+            },
+        }
+    }
+}
+
+impl MpesaClient {
+    /// Submits an STK push and polls for its outcome, `passkey` being the passkey of its `BusinessShortCode`.
+    /// Fails only if the push is not accepted
+    pub fn stk_push_and_wait(&mut self, request: &LipaNaMpesaOnlinePaymentRequest, passkey: &str, polling: &StkPolling) -> Result<StkOutcome, MpesaClientError> {
+        let response = self.send_stk_push(request)?;
+        Ok(self.wait_for_stk_push(request.BusinessShortCode.as_str(), passkey, &response.CheckoutRequestID, polling))
+    }
+
+    /// Polls for the outcome of the STK push `checkout_request_id`, accepted just now
+    pub fn wait_for_stk_push(&mut self, business_short_code: &str, passkey: &str, checkout_request_id: &str, polling: &StkPolling) -> StkOutcome {
+        let deadline = Instant::now() + polling.window;
+        let mut delay = polling.first_delay;
+        let mut pending = String::from("the customer has not answered the push");
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return StkOutcome::Undetermined(format!("no outcome of {} within {}s: {}", checkout_request_id, polling.window.as_secs(), pending));
+            }
+            thread::sleep(cmp::min(delay, deadline - now));
+            delay = cmp::min(delay * 2, polling.longest_delay);
+
+            // the password is only valid with a fresh timestamp
+            let query = LipaNaMpesaOnlineQueryRequest::new(business_short_code, passkey, checkout_request_id);
+            match self.stk_query(&query) {
+                Ok(response) => return StkOutcome::from_response(response),
+                Err(ref error) if is_pending(error) => pending = format!("{}", error),
+                Err(error) => return StkOutcome::Undetermined(format!("unable to query {}: {}", checkout_request_id, error)),
+            }
+        }
+    }
+}
+
+/// Whether a query failing with `error` may succeed later
+fn is_pending(error: &MpesaClientError) -> bool {
+    match error {
+        &MpesaClientError::Request(MpesaRequestError::InternalServerError, ref body) => {
+            serde_json::from_str::<Value>(body).ok().map_or(false, |body| body["errorCode"] == STILL_PROCESSING)
+        },
+        &MpesaClientError::Request(MpesaRequestError::TooManyRequests, _) | &MpesaClientError::Request(MpesaRequestError::ServiceUnavailable, _) => true,
+        &MpesaClientError::Connection(_) | &MpesaClientError::UnexpectedStatus(..) => true,
+        _ => false,
+    }
+}
@@ -0,0 +1,188 @@
+//! Resolution of payments whose outcome is unknown
+//!
+//! When the connection drops while a B2C or B2B payment is being sent, the payment may or may not have been made.
+//! The client reports this as `MpesaClientError::OutcomeUnknown`, carrying the `OriginatorConversationID` the payment
+//! was sent with, instead of a plain connection error that invites sending the payment again and paying twice.
+//!
+//! `MpesaClient::resolve()` looks such a payment up with a Transaction Status query and records the outcome in the
+//! `TransactionStore`. `MpesaClient::resend_b2c()` and `resend_b2b()` only send a payment again once it has been
+//! looked up and found to have failed. A lookup that M-Pesa rejects is no proof that the payment failed, since the
+//! query itself may be at fault, so the payment is not sent again and must be settled by hand.
+//!
+//! Only B2C payments carry an `OriginatorConversationID` chosen by the client. B2B payments are known by the one
+//! M-Pesa assigns in the acknowledgement, so a B2B payment whose acknowledgement was lost cannot be looked up and
+//! `OutcomeUnknown` holds no id for it.
+//!
+//! The Transaction Status results arrive on the `ResultURL`, so the client must have a `Correlator`.
+//!
+//! # Example
+//! ```no_run
+//! # extern crate mpesa;
+//! # use mpesa::api_products::b2c::B2C;
+//! # use mpesa::callbacks::verify::VerificationConfig;
+//! # use mpesa::client::{MpesaClient, MpesaClientError};
+//! # fn main() {
+//! # let mut client: MpesaClient = unimplemented!();
+//! # let request: B2C = unimplemented!();
+//! # let lookup: VerificationConfig = unimplemented!();
+//! match client.send_b2c(&request) {
+//!     Err(MpesaClientError::OutcomeUnknown { originator_conversation_id: Some(originator_conversation_id), .. }) => {
+//!         // only sent again if the first attempt is known to have failed
+//!         client.resend_b2c(&request, &originator_conversation_id, &lookup).expect("the payment was not resent");
+//!     },
+//!     other => { other.expect("the payment failed"); },
+//! }
+//! # }
+//! ```
+
+use futures::Future;
+use serde_json::Value;
+
+use api_products::b2b::{B2B, B2BResponse};
+use api_products::b2c::{B2C, B2CResponse};
+use callbacks::TransactionResult;
+use callbacks::verify::VerificationConfig;
+use store::{self, StoreError, TransactionState};
+use super::{MpesaClient, MpesaClientError};
+
+/// Remarks sent along with lookups
+const REMARKS: &str = "Transaction lookup";
+
+/// The outcome of a transaction as reported by a Transaction Status query
+#[derive(Debug)]
+pub enum Resolution {
+    /// The transaction went through. It must not be sent again
+    Completed(TransactionResult),
+    /// The transaction went through but has since been reversed
+    Reversed(TransactionResult),
+    /// The transaction did not go through. It is safe to send it again
+    Failed(TransactionResult),
+    /// The query was rejected, e.g. because M-Pesa knows of no such transaction
+    Rejected(TransactionResult),
+    /// The outcome could not be determined, e.g. because the query could not be made or the transaction is still
+    /// being processed
+    Undetermined(String),
+}
+
+impl Resolution {
+    /// Interprets the result of a Transaction Status query
+    pub fn from_status(result: TransactionResult) -> Resolution {
+        if result.ResultCode != 0 {
+            return Resolution::Rejected(result);
+        }
+
+        let status = result.parameter("TransactionStatus").and_then(Value::as_str).unwrap_or("").to_string();
+        match status.as_str() {
+            "Completed" => Resolution::Completed(result),
+            "Reversed" => Resolution::Reversed(result),
+            "Declined" | "Failed" | "Cancelled" | "Expired" => Resolution::Failed(result),
+            _ => Resolution::Undetermined(format!("the transaction is {}", status)),
+        }
+    }
+
+    /// The state the transaction is resolved to, if it could be resolved
+    pub fn state(&self) -> Option<TransactionState> {
+        match self {
+            &Resolution::Completed(_) => Some(TransactionState::Completed),
+            &Resolution::Reversed(_) => Some(TransactionState::Reversed),
+            &Resolution::Failed(_) => Some(TransactionState::Failed),
+            &Resolution::Rejected(_) | &Resolution::Undetermined(_) => None,
+        }
+    }
+
+    /// The M-Pesa receipt number of the transaction, if it was found
+    pub fn receipt(&self) -> Option<String> {
+        self.result()
+            .and_then(|result| result.parameter("ReceiptNo"))
+            .and_then(Value::as_str)
+            .map(String::from)
+    }
+
+    /// The `ResultCode` of the Transaction Status result, if there was one
+    pub fn result_code(&self) -> Option<i64> {
+        self.result().map(|result| result.ResultCode)
+    }
+
+    /// A description of the outcome
+    pub fn description(&self) -> String {
+        match self {
+            &Resolution::Undetermined(ref description) => description.clone(),
+            other => other.result().map(|result| result.ResultDesc.clone()).unwrap_or_default(),
+        }
+    }
+
+    fn result(&self) -> Option<&TransactionResult> {
+        match self {
+            &Resolution::Completed(ref result) | &Resolution::Reversed(ref result) |
+            &Resolution::Failed(ref result) | &Resolution::Rejected(ref result) => Some(result),
+            &Resolution::Undetermined(_) => None,
+        }
+    }
+}
+
+impl MpesaClient {
+    /// Looks up the transaction with `receipt`, or with `originator_conversation_id` when the receipt is not known.
+    /// `lookup` holds the details needed to make the query.
+    pub fn look_up(&mut self, receipt: &str, originator_conversation_id: &str, lookup: &VerificationConfig) -> Resolution {
+        let request = lookup.transaction_status(receipt, originator_conversation_id, REMARKS);
+        let pending = match self.transaction_status(&request) {
+            Ok(pending) => pending,
+            Err(error) => return Resolution::Undetermined(format!("{}", error)),
+        };
+        match pending.wait() {
+            Ok(result) => Resolution::from_status(result),
+            Err(error) => Resolution::Undetermined(format!("{}", error)),
+        }
+    }
+
+    /// Looks up the payment sent with `originator_conversation_id` and records its outcome in the store, if any
+    pub fn resolve(&mut self, originator_conversation_id: &str, lookup: &VerificationConfig) -> Resolution {
+        let resolution = self.look_up("", originator_conversation_id, lookup);
+        if let (Some(store), Some(state)) = (self.store.clone(), resolution.state()) {
+            let found = store.find_by_reference(originator_conversation_id).and_then(|transaction| match transaction {
+                Some(transaction) => store::modify(&store, &transaction.id, |transaction| {
+                    transaction.conclude(state, resolution.result_code(), &resolution.description(), resolution.receipt())
+                }),
+                None => Ok(None),
+            });
+            match found {
+                Ok(_) => (),
+                Err(StoreError::IllegalTransition(error)) => {
+                    warn!("not recording the resolved outcome of transaction {}: {}", originator_conversation_id, error)
+                },
+                Err(error) => error!("unable to record the outcome of transaction {}: {}", originator_conversation_id, error),
+            }
+        }
+        resolution
+    }
+
+    /// Sends a B2C payment again if the attempt sent with `originator_conversation_id` is found to have failed
+    pub fn resend_b2c(&mut self, request: &B2C, originator_conversation_id: &str, lookup: &VerificationConfig) -> Result<B2CResponse, MpesaClientError> {
+        self.check_resend(originator_conversation_id, lookup)?;
+        let mut request = request.clone();
+        request.OriginatorConversationID = String::new();
+        self.send_b2c(&request)
+    }
+
+    /// Sends a B2B payment again if the attempt acknowledged with `originator_conversation_id` is found to have failed
+    pub fn resend_b2b(&mut self, request: &B2B, originator_conversation_id: &str, lookup: &VerificationConfig) -> Result<B2BResponse, MpesaClientError> {
+        self.check_resend(originator_conversation_id, lookup)?;
+        self.send_b2b(request)
+    }
+
+    /// Resolves an earlier attempt at a payment, failing unless it is known to have failed.
+    /// A rejected lookup leaves the outcome unknown: M-Pesa also rejects queries it cannot make, e.g. for a
+    /// transaction it has not finished recording, so it does not show that the payment was never made.
+    fn check_resend(&mut self, originator_conversation_id: &str, lookup: &VerificationConfig) -> Result<(), MpesaClientError> {
+        match self.resolve(originator_conversation_id, lookup) {
+            Resolution::Failed(_) => Ok(()),
+            Resolution::Completed(_) | Resolution::Reversed(_) => {
+                Err(MpesaClientError::AlreadyProcessed(originator_conversation_id.to_string()))
+            },
+            other => Err(MpesaClientError::OutcomeUnknown {
+                originator_conversation_id: Some(originator_conversation_id.to_string()),
+                cause: other.description(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! The transports carrying the HTTP exchanges of the client and the access token
+//!
+//! `HttpTransport`, the default, sends requests over the network. `RecordingTransport` wraps another transport and
+//! appends every exchange to a JSONL file, one exchange per line, with credentials, tokens and passwords redacted:
+//!
+//! ```text
+//! {"request":{"method":"POST","url":"https://sandbox.safaricom.co.ke/mpesa/b2c/v3/paymentrequest","headers":{"Authorization":"Bearer [REDACTED]",...},"body":{...}},"response":{"status":200,"body":{...}}}
+//! ```
+//!
+//! `ReplayTransport` serves the responses of such a file without touching the network, so a sandbox session recorded
+//! once can be replayed in CI. A request is answered with the first unused exchange of the same method and path,
+//! whatever the host, so the replayed session must send its requests in the order they were recorded.
+//!
+//! # Example
+//! ```no_run
+//! # use std::path::Path;
+//! # use std::sync::Arc;
+//! # use mpesa::access_token::AccessToken;
+//! # use mpesa::client::{MpesaClient, SANDBOX_URL};
+//! # use mpesa::client::transport::{HttpTransport, RecordingTransport, ReplayTransport};
+//! let access_token = AccessToken::new(String::from("foo"), String::from("bar"));
+//!
+//! // capture a session against the sandbox
+//! let recorder = RecordingTransport::create(Path::new("tests/fixtures/session.jsonl"), HttpTransport::new()).unwrap();
+//! let client = MpesaClient::new(access_token, SANDBOX_URL).transport(Arc::new(recorder));
+//!
+//! // and in CI, answer the same requests from the file
+//! # let access_token = AccessToken::new(String::from("foo"), String::from("bar"));
+//! let replay = ReplayTransport::open(Path::new("tests/fixtures/session.jsonl")).unwrap();
+//! let client = MpesaClient::new(access_token, SANDBOX_URL).transport(Arc::new(replay));
+//! ```
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Debug, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use reqwest;
+use reqwest::header::Headers;
+use serde_json::{self, Value};
+
+/// What secrets are replaced with
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Fields of request and response bodies holding secrets
+const SECRET_FIELDS: &[&str] = &["SecurityCredential", "Password", "access_token"];
+
+/// An HTTP request to the API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HttpRequest {
+    /// `GET` or `POST`
+    pub method: String,
+    /// The full url
+    pub url: String,
+    /// The headers, by name
+    pub headers: BTreeMap<String, String>,
+    /// The body, if there is one
+    pub body: Option<String>,
+}
+
+/// The response of the API to an `HttpRequest`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HttpResponse {
+    /// HTTP status code
+    pub status: u16,
+    /// The body
+    pub body: String,
+}
+
+/// Sends requests to the API
+pub trait Transport: Debug + Send + Sync {
+    /// Sends `request` and returns the response, whatever its status
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError>;
+}
+
+/// Definition of possible errors when exchanging a request
+#[derive(Debug)]
+pub enum TransportError {
+    /// The API could not be reached
+    Connection(reqwest::Error),
+    /// The recording could not be read or written
+    Io(io::Error),
+    /// A line of the recording is not an exchange. Holds its number and the reason
+    InvalidRecording(usize, String),
+    /// The recording has no unused exchange for the request. Holds its method and url
+    NotRecorded(String),
+}
+
+/// Sends requests over the network
+#[derive(Debug)]
+pub struct HttpTransport {
+    client: reqwest::Client,
+}
+
+/// Sends requests through another transport and records the exchanges
+#[derive(Debug)]
+pub struct RecordingTransport {
+    inner: Box<dyn Transport>,
+    file: Mutex<File>,
+}
+
+/// Answers requests from recorded exchanges
+#[derive(Debug)]
+pub struct ReplayTransport {
+    exchanges: Mutex<Vec<(Exchange, bool)>>,
+}
+
+/// A line of a recording
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Exchange {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+/// A request as recorded, the body decoded if it is JSON so that the recording is readable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    body: Value,
+}
+
+impl HttpRequest {
+    /// A `GET` of `url`
+    pub fn get(url: &str) -> HttpRequest {
+        HttpRequest { method: String::from("GET"), url: url.to_string(), headers: BTreeMap::new(), body: None }
+    }
+
+    /// A `POST` of the JSON `body` to `url`
+    pub fn post(url: &str, body: String) -> HttpRequest {
+        let mut headers = BTreeMap::new();
+        headers.insert(String::from("Content-Type"), String::from("application/json"));
+        HttpRequest { method: String::from("POST"), url: url.to_string(), headers: headers, body: Some(body) }
+    }
+
+    /// Adds the header `name`
+    pub fn header(mut self, name: &str, value: &str) -> HttpRequest {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// The request with its credentials, tokens and passwords replaced by `REDACTED`
+    pub fn redacted(&self) -> HttpRequest {
+        let mut request = self.clone();
+        if let Some(authorization) = request.headers.get_mut("Authorization") {
+            // keep the scheme, it tells which kind of secret it was
+            let scheme = authorization.split(' ').next().unwrap_or_default().to_string();
+            *authorization = format!("{} {}", scheme, REDACTED);
+        }
+        request.body = request.body.map(|body| redact_text(&body));
+        request
+    }
+
+    /// The request as JSON, its body decoded if it is JSON
+    pub fn to_value(&self) -> Value {
+        json!({
+            "method": self.method,
+            "url": self.url,
+            "headers": self.headers,
+            "body": self.body.as_ref().map(|body| decode(body)),
+        })
+    }
+}
+
+impl HttpTransport {
+    /// Creates a transport with a new HTTP client
+    pub fn new() -> HttpTransport {
+        HttpTransport { client: reqwest::Client::new() }
+    }
+}
+
+impl Default for HttpTransport {
+    fn default() -> HttpTransport {
+        HttpTransport::new()
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        let mut headers = Headers::new();
+        for (name, value) in &request.headers {
+            headers.set_raw(name.clone(), value.clone());
+        }
+        let mut builder = match request.method.as_str() {
+            "GET" => self.client.get(&request.url),
+            _ => self.client.post(&request.url),
+        };
+        builder.headers(headers);
+        if let Some(ref body) = request.body {
+            builder.body(body.clone());
+        }
+
+        let mut response = builder.send()?;
+        Ok(HttpResponse { status: response.status().as_u16(), body: response.text()? })
+    }
+}
+
+impl RecordingTransport {
+    /// Records the exchanges of `inner`, appending them to the file at `path`
+    pub fn create<T: Transport + 'static>(path: &Path, inner: T) -> Result<RecordingTransport, TransportError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RecordingTransport { inner: Box::new(inner), file: Mutex::new(file) })
+    }
+}
+
+impl Transport for RecordingTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        let response = self.inner.send(request)?;
+        let request = request.redacted();
+        let exchange = Exchange {
+            request: RecordedRequest {
+                method: request.method,
+                url: request.url,
+                headers: request.headers,
+                body: request.body.as_ref().map(|body| decode(body)),
+            },
+            response: RecordedResponse { status: response.status, body: decode(&redact_text(&response.body)) },
+        };
+
+        let mut file = self.file.lock().expect("the recording lock is poisoned");
+        writeln!(file, "{}", serde_json::to_string(&exchange).expect("exchanges are serializable"))?;
+        file.flush()?;
+        Ok(response)
+    }
+}
+
+impl ReplayTransport {
+    /// Reads the exchanges recorded in the file at `path`
+    pub fn open(path: &Path) -> Result<ReplayTransport, TransportError> {
+        ReplayTransport::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads recorded exchanges, one per line. Blank lines are skipped
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<ReplayTransport, TransportError> {
+        let mut exchanges = Vec::new();
+        for (index, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let exchange: Exchange = serde_json::from_str(&line)
+                .map_err(|error| TransportError::InvalidRecording(index + 1, format!("{}", error)))?;
+            exchanges.push((exchange, false));
+        }
+        Ok(ReplayTransport { exchanges: Mutex::new(exchanges) })
+    }
+
+    /// How many recorded exchanges have not been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.exchanges.lock().expect("the replay lock is poisoned").iter().filter(|&&(_, used)| !used).count()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        let mut exchanges = self.exchanges.lock().expect("the replay lock is poisoned");
+        let wanted = path(&request.url);
+        let exchange = exchanges.iter_mut()
+            .find(|&&mut (ref exchange, used)| !used && exchange.request.method == request.method && path(&exchange.request.url) == wanted);
+
+        match exchange {
+            Some(&mut (ref exchange, ref mut used)) => {
+                *used = true;
+                let body = match exchange.response.body {
+                    Value::String(ref body) => body.clone(),
+                    ref body => body.to_string(),
+                };
+                Ok(HttpResponse { status: exchange.response.status, body: body })
+            },
+            None => Err(TransportError::NotRecorded(format!("{} {}", request.method, request.url))),
+        }
+    }
+}
+
+/// The path and query of `url`, without the scheme and host
+fn path(url: &str) -> &str {
+    let rest = url.find("://").map_or(url, |start| &url[start + 3..]);
+    rest.find('/').map_or("/", |start| &rest[start..])
+}
+
+/// A body as JSON, or as a JSON string if it is not JSON
+fn decode(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.to_string()))
+}
+
+/// Redacts the secret fields of a body if it is JSON
+fn redact_text(body: &str) -> String {
+    match serde_json::from_str::<Value>(body) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        },
+        Err(_) => body.to_string(),
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        &mut Value::Object(ref mut fields) => {
+            for (name, field) in fields.iter_mut() {
+                if SECRET_FIELDS.contains(&name.as_str()) {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact(field);
+                }
+            }
+        },
+        &mut Value::Array(ref mut items) => {
+            for item in items {
+                redact(item);
+            }
+        },
+        _ => (),
+    }
+}
+
+impl From<reqwest::Error> for TransportError {
+    fn from(error: reqwest::Error) -> Self {
+        TransportError::Connection(error)
+    }
+}
+
+impl From<io::Error> for TransportError {
+    fn from(error: io::Error) -> Self {
+        TransportError::Io(error)
+    }
+}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TransportError::Connection(ref error) => write!(f, "TransportError::Connection -- {}", error),
+            &TransportError::Io(ref error) => write!(f, "TransportError::Io -- {}", error),
+            &TransportError::InvalidRecording(line, ref description) => {
+                write!(f, "TransportError::InvalidRecording -- line {} is not an exchange: {}", line, description)
+            },
+            &TransportError::NotRecorded(ref request) => write!(f, "TransportError::NotRecorded -- no recorded response to {}", request),
+        }
+    }
+}
+
+impl Error for TransportError {
+    fn description(&self) -> &str {
+        "failed to exchange a request"
+    }
+}
@@ -0,0 +1,292 @@
+//! Loading credentials and settings from the environment and config files
+//!
+//! Settings are read from, in increasing order of precedence:
+//! 1. a profile of a TOML file, `mpesa.toml` in the working directory or the file named by `MPESA_CONFIG`
+//! 2. a `.env` file in the working directory or one of its parents
+//! 3. environment variables named after the settings, e.g. `MPESA_CONSUMER_KEY` for `consumer_key`
+//!
+//! The profile is the one asked for, else the one named by `MPESA_PROFILE`, else `sandbox`.
+//! A missing config file is not an error, a missing profile in an existing file is.
+//!
+//! The security credential is the configured `security_credential`, else it is generated by encrypting the
+//! `initiator_password` with the M-Pesa certificate of the environment at `certificate_path`, see `credential()`.
+//!
+//! # Example
+//! ```
+//! # use mpesa::config::Config;
+//! # use mpesa::parameters::Environment;
+//! let file = r#"
+//! [sandbox]
+//! consumer_key = "foo"
+//! consumer_secret = "bar"
+//! short_code = 600000
+//!
+//! [staging]
+//! base_url = "https://daraja.staging.example.com"
+//!
+//! [prod]
+//! environment = "production"
+//! short_code = "654321"
+//! initiator_name = "apiop"
+//! certificate_path = "certs/production.cer"
+//! "#;
+//!
+//! let config = Config::from_toml(file, "prod").unwrap();
+//! assert_eq!(config.environment, Environment::Production);
+//! assert_eq!(config.base_url(), "https://api.safaricom.co.ke");
+//! assert!(config.access_token().is_err());
+//! assert!(config.credential().is_err());
+//! ```
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use dotenv;
+use toml;
+
+use access_token::AccessToken;
+use client::{MpesaClient, PRODUCTION_URL, SANDBOX_URL};
+use parameters::{check_callback_url, Environment, ShortCode};
+use security_credential::Certificate;
+
+/// The file settings are read from by default
+pub const CONFIG_FILE: &str = "mpesa.toml";
+
+/// The profile used when none is asked for
+pub const DEFAULT_PROFILE: &str = "sandbox";
+
+/// Prefix of the environment variables settings are read from
+const ENV_PREFIX: &str = "MPESA_";
+
+/// Credentials and settings for calling the API
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// The profile the settings were read from
+    pub profile: String,
+    /// Consumer key of the app
+    pub consumer_key: Option<String>,
+    /// Consumer secret of the app
+    pub consumer_secret: Option<String>,
+    /// The environment requests are sent to
+    pub environment: Environment,
+    /// Base url of the API, when it is not the default url of the environment
+    pub base_url: Option<String>,
+    /// Shortcode of the organization
+    pub short_code: Option<ShortCode>,
+    /// Lipa na M-Pesa online passkey
+    pub passkey: Option<String>,
+    /// Username of the API operator
+    pub initiator_name: Option<String>,
+    /// Password of the API operator, which is encrypted with the certificate into the security credential
+    pub initiator_password: Option<String>,
+    /// Path of the M-Pesa public key certificate of the environment
+    pub certificate_path: Option<PathBuf>,
+    /// The encrypted password of the API operator, used instead of encrypting `initiator_password`
+    pub security_credential: Option<String>,
+    /// Url results are posted to
+    pub result_url: Option<String>,
+    /// Url requests that timed out in the queue are posted to
+    pub timeout_url: Option<String>,
+    /// Url the outcome of STK pushes is posted to
+    pub callback_url: Option<String>,
+}
+
+/// Definition of possible errors when loading the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read. Holds its path
+    Io(PathBuf, io::Error),
+    /// The config file is not valid TOML, or a setting is not a string
+    Parse(String),
+    /// The config file has no such profile
+    UnknownProfile(String),
+    /// There is no setting with this name
+    UnknownSetting(String),
+    /// A setting is not `sandbox` or `production`
+    Environment(String),
+    /// A setting is not valid. Holds its name and description
+    Invalid(&'static str, String),
+    /// A setting that is needed was not given. Holds its name
+    Missing(&'static str),
+}
+
+impl Config {
+    /// Loads `profile`, or the default profile, from `.env`, the environment and the config file
+    pub fn load(profile: Option<&str>) -> Result<Config, ConfigError> {
+        // `.env` may name the config file
+        dotenv::dotenv().ok();
+        let path = env::var_os(format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from).unwrap_or_else(|| PathBuf::from(CONFIG_FILE));
+        Config::load_from(&path, profile)
+    }
+
+    /// Loads `profile`, or the default profile, from `.env`, the environment and the config file at `path`
+    pub fn load_from(path: &Path, profile: Option<&str>) -> Result<Config, ConfigError> {
+        // a missing .env is fine, the variables may be set already
+        dotenv::dotenv().ok();
+        let profile = match profile {
+            Some(profile) => profile.to_string(),
+            None => env::var(format!("{}PROFILE", ENV_PREFIX)).unwrap_or_else(|_| DEFAULT_PROFILE.to_string()),
+        };
+        let config = match fs::read_to_string(path) {
+            Ok(contents) => Config::from_toml(&contents, &profile)?,
+            Err(ref error) if error.kind() == io::ErrorKind::NotFound => Config { profile: profile, ..Config::default() },
+            Err(error) => return Err(ConfigError::Io(path.to_path_buf(), error)),
+        };
+        config.merge_env()
+    }
+
+    /// Reads `profile` from the contents of a config file
+    pub fn from_toml(contents: &str, profile: &str) -> Result<Config, ConfigError> {
+        let mut profiles: HashMap<String, HashMap<String, toml::Value>> = toml::from_str(contents)
+            .map_err(|error| ConfigError::Parse(format!("{}", error)))?;
+        let settings = profiles.remove(profile).ok_or_else(|| ConfigError::UnknownProfile(profile.to_string()))?;
+        let mut config = Config { profile: profile.to_string(), ..Config::default() };
+        for (name, value) in settings {
+            match value {
+                toml::Value::String(value) => config.set(&name, &value)?,
+                // shortcodes are numbers to the eye
+                toml::Value::Integer(value) => config.set(&name, &value.to_string())?,
+                _ => return Err(ConfigError::Parse(format!("{} must be a string", name))),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overrides settings with the environment variables that are set
+    fn merge_env(mut self) -> Result<Config, ConfigError> {
+        for name in SETTINGS {
+            if let Ok(value) = env::var(format!("{}{}", ENV_PREFIX, name.to_uppercase())) {
+                self.set(name, &value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Sets the setting called `name`, e.g. from a command line flag
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        let value = value.trim();
+        let text = Some(value.to_string());
+        match name {
+            "consumer_key" => self.consumer_key = text,
+            "consumer_secret" => self.consumer_secret = text,
+            "environment" => self.environment = parse_environment(value)?,
+            "base_url" => self.base_url = text,
+            "short_code" => {
+                let short_code = ShortCode::new(value).map_err(|error| ConfigError::Invalid("short_code", format!("{}", error)))?;
+                self.short_code = Some(short_code);
+            },
+            "passkey" => self.passkey = text,
+            "initiator_name" => self.initiator_name = text,
+            "initiator_password" => self.initiator_password = text,
+            "certificate_path" => self.certificate_path = Some(PathBuf::from(value)),
+            "security_credential" => self.security_credential = text,
+            "result_url" => self.result_url = text,
+            "timeout_url" => self.timeout_url = text,
+            "callback_url" => self.callback_url = text,
+            _ => return Err(ConfigError::UnknownSetting(name.to_string())),
+        }
+        Ok(())
+    }
+
+    /// The base url of the API, the default url of the environment unless another one was set
+    pub fn base_url(&self) -> &str {
+        match (&self.base_url, self.environment) {
+            (&Some(ref base_url), _) => base_url,
+            (&None, Environment::Sandbox) => SANDBOX_URL,
+            (&None, Environment::Production) => PRODUCTION_URL,
+        }
+    }
+
+    /// An access token for the configured credentials
+    pub fn access_token(&self) -> Result<AccessToken, ConfigError> {
+        let consumer_key = required(&self.consumer_key, "consumer_key")?;
+        let consumer_secret = required(&self.consumer_secret, "consumer_secret")?;
+        Ok(AccessToken::new(consumer_key.clone(), consumer_secret.clone()).base_url(self.base_url()))
+    }
+
+    /// The M-Pesa certificate at `certificate_path`
+    pub fn certificate(&self) -> Result<Certificate, ConfigError> {
+        let path = required(&self.certificate_path, "certificate_path")?;
+        Certificate::from_file(path).map_err(|error| ConfigError::Invalid("certificate_path", format!("{}", error)))
+    }
+
+    /// The security credential of the API operator: the configured one, else `initiator_password` encrypted with
+    /// the certificate at `certificate_path`
+    pub fn credential(&self) -> Result<String, ConfigError> {
+        if let Some(ref security_credential) = self.security_credential {
+            return Ok(security_credential.clone());
+        }
+        let password = required(&self.initiator_password, "initiator_password")?;
+        self.certificate()?.encrypt(password).map_err(|error| ConfigError::Invalid("initiator_password", format!("{}", error)))
+    }
+
+    /// Checks the configured callback urls against the rules of the environment
+    pub fn check_urls(&self) -> Result<(), ConfigError> {
+        let urls = [("result_url", &self.result_url), ("timeout_url", &self.timeout_url), ("callback_url", &self.callback_url)];
+        for &(name, url) in urls.iter() {
+            if let Some(ref url) = *url {
+                check_callback_url(url, self.environment)
+                    .map_err(|rule| ConfigError::Invalid(name, format!("{} is rejected: {}", url, rule)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A client calling the configured API with the configured credentials, generating security credentials when
+    /// the initiator password and certificate are configured.
+    /// Fails if a configured callback url would be rejected by M-Pesa.
+    pub fn client(&self) -> Result<MpesaClient, ConfigError> {
+        self.check_urls()?;
+        let client = MpesaClient::new(self.access_token()?, self.base_url()).environment(self.environment);
+        match (&self.initiator_password, &self.certificate_path) {
+            (&Some(ref password), &Some(_)) => Ok(client.initiator_password(password, self.certificate()?)),
+            _ => Ok(client),
+        }
+    }
+}
+
+/// The names of all settings
+const SETTINGS: &[&str] = &[
+    "consumer_key", "consumer_secret", "environment", "base_url", "short_code", "passkey", "initiator_name",
+    "initiator_password", "certificate_path", "security_credential", "result_url", "timeout_url", "callback_url",
+];
+
+/// The value of a setting that is needed
+pub fn required<'a, T>(value: &'a Option<T>, name: &'static str) -> Result<&'a T, ConfigError> {
+    value.as_ref().ok_or(ConfigError::Missing(name))
+}
+
+fn parse_environment(value: &str) -> Result<Environment, ConfigError> {
+    match value.to_lowercase().as_str() {
+        "sandbox" => Ok(Environment::Sandbox),
+        "production" => Ok(Environment::Production),
+        _ => Err(ConfigError::Environment(value.to_string())),
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ConfigError::Io(ref path, ref error) => write!(f, "ConfigError::Io -- {}: {}", path.display(), error),
+            &ConfigError::Parse(ref description) => write!(f, "ConfigError::Parse -- {}", description),
+            &ConfigError::UnknownProfile(ref profile) => write!(f, "ConfigError::UnknownProfile -- there is no profile {}", profile),
+            &ConfigError::UnknownSetting(ref name) => write!(f, "ConfigError::UnknownSetting -- there is no setting {}", name),
+            &ConfigError::Environment(ref value) => {
+                write!(f, "ConfigError::Environment -- {} is not an environment, expected sandbox or production", value)
+            },
+            &ConfigError::Invalid(name, ref description) => write!(f, "ConfigError::Invalid -- {}: {}", name, description),
+            &ConfigError::Missing(name) => write!(f, "ConfigError::Missing -- {} is not set", name),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn description(&self) -> &str {
+        "failed to load the configuration"
+    }
+}
@@ -7,14 +7,44 @@
 //! * Call the relevant function with your accesstoken
 
 extern crate reqwest;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate base64;
 extern crate hyper;
+#[macro_use]
 extern crate serde_json;
 extern crate actix_web;
 extern crate actix;
 extern crate tokio;
 extern crate bytes;
 extern crate futures;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate diesel;
+#[cfg(any(feature = "mysql", feature = "sqlite"))]
+#[macro_use]
+extern crate diesel_migrations;
+extern crate rand;
+extern crate chrono;
+extern crate regex;
+#[macro_use]
+extern crate lazy_static;
+extern crate dotenv;
+extern crate toml;
+extern crate crossbeam;
+extern crate csv;
+extern crate openssl;
 pub mod access_token;
 pub mod parameters;
-pub mod api_products;
\ No newline at end of file
+pub mod api_products;
+pub mod callbacks;
+pub mod client;
+pub mod store;
+pub mod reconciliation;
+pub mod config;
+pub mod security_credential;
+pub mod bulk;
+pub mod mock;
+pub mod testing;
\ No newline at end of file
@@ -1,5 +1,14 @@
+extern crate chrono;
+extern crate clap;
 extern crate mpesa;
-use mpesa::access_token::AccessToken;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+
+mod cli;
+
+use std::process;
+
 fn main() {
-    println!("{}", AccessToken::new(String::from("0SKHgBn66azzyz5Y22ZufBhP6m5JwmQT"), String::from("GUm27XVgi697SUfE")).token().expect("unable to retrieve token at this time");
-}
\ No newline at end of file
+    process::exit(cli::run());
+}
@@ -0,0 +1,661 @@
+//! The behaviour of the mock Daraja API, independent of the HTTP server
+//!
+//! `Daraja` answers requests the way the API does: it issues access tokens, checks the token and the mandatory
+//! fields of every request, acknowledges the request and tells which callbacks to post once it is "processed".
+//! How a request is processed can be scripted, see the `scenario` module.
+//!
+//! # Example
+//! ```
+//! # extern crate mpesa;
+//! # #[macro_use] extern crate serde_json;
+//! # use mpesa::mock::daraja::Daraja;
+//! # fn main() {
+//! let mut daraja = Daraja::new();
+//! let token = daraja.token(Some("Basic Zm9vOmJhcg=="), "grant_type=client_credentials");
+//! let token = token.body["access_token"].as_str().unwrap().to_string();
+//!
+//! let reply = daraja.handle("/mpesa/accountbalance/v1/query", Some(&format!("Bearer {}", token)), &json!({
+//!     "Initiator": "apitest", "SecurityCredential": "c2VjcmV0", "CommandID": "AccountBalance",
+//!     "PartyA": "600000", "IdentifierType": "4", "Remarks": "balance",
+//!     "QueueTimeOutURL": "https://example.com/timeout", "ResultURL": "https://example.com/result",
+//! }));
+//! assert_eq!(reply.status, 200);
+//! assert_eq!(reply.body["ResponseCode"], "0");
+//! assert_eq!(reply.callbacks[0].url, "https://example.com/result");
+//!
+//! let reply = daraja.handle("/mpesa/accountbalance/v1/query", Some("Bearer forged"), &json!({}));
+//! assert_eq!(reply.status, 404);
+//! assert_eq!(reply.body["errorCode"], "404.001.03");
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use base64;
+use chrono::{DateTime, FixedOffset, Utc};
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use serde_json::Value;
+
+use api_products::{account_balance, b2b, b2c, c2b, reversal, transaction_status};
+use api_products::lipa_na_mpesa_online_payment_request as stk_push;
+use api_products::lipa_na_mpesa_online_query_request as stk_query;
+use parameters::Amount;
+
+use super::scenario::Scenario;
+
+/// Path of the OAuth endpoint, without its query string
+pub const OAUTH_PATH: &str = "/oauth/v1/generate";
+
+/// Seconds an access token is valid for
+pub const TOKEN_LIFETIME: u64 = 3599;
+
+/// Error code of requests whose access token was not issued or has expired
+pub const INVALID_TOKEN: &str = "404.001.03";
+
+/// Error code of requests missing a mandatory field or with an invalid one
+pub const BAD_REQUEST: &str = "400.002.02";
+
+/// Error code of OAuth requests with wrong credentials
+pub const INVALID_CREDENTIALS: &str = "400.008.01";
+
+/// Error code of STK queries for a push the customer has not answered yet
+pub const STILL_PROCESSING: &str = "500.001.1001";
+
+/// Error code of requests refused while the API is unavailable
+pub const SERVICE_UNAVAILABLE: &str = "503.001.01";
+
+/// Path of the admin endpoint scripting scenarios
+pub const ADMIN_PATH: &str = "/mock/scenarios";
+
+/// `ResultCode` and `ResultDesc` of requests processed successfully
+const SUCCESS: (i64, &str) = (0, "The service request is processed successfully.");
+
+/// The paths of every product endpoint
+pub const ENDPOINTS: &[&str] = &[
+    b2c::ENDPOINT, b2b::ENDPOINT, reversal::ENDPOINT, transaction_status::ENDPOINT, account_balance::ENDPOINT,
+    stk_push::ENDPOINT, stk_query::ENDPOINT, c2b::REGISTER_URL_ENDPOINT, c2b::SIMULATE_ENDPOINT,
+];
+
+/// The mandatory fields of the requests to each endpoint
+const MANDATORY: &[(&str, &[&str])] = &[
+    (b2c::ENDPOINT, &["InitiatorName", "SecurityCredential", "CommandID", "Amount", "PartyA", "PartyB", "QueueTimeOutURL", "ResultURL"]),
+    (b2b::ENDPOINT, &["Initiator", "SecurityCredential", "CommandID", "Amount", "PartyA", "PartyB", "QueueTimeOutURL", "ResultURL"]),
+    (reversal::ENDPOINT, &["Initiator", "SecurityCredential", "CommandID", "TransactionID", "ReceiverParty", "QueueTimeOutURL", "ResultURL"]),
+    // looked up by TransactionID or OriginalConversationID
+    (transaction_status::ENDPOINT, &["Initiator", "SecurityCredential", "CommandID", "PartyA", "QueueTimeOutURL", "ResultURL"]),
+    (account_balance::ENDPOINT, &["Initiator", "SecurityCredential", "CommandID", "PartyA", "QueueTimeOutURL", "ResultURL"]),
+    (stk_push::ENDPOINT, &["BusinessShortCode", "Password", "Timestamp", "TransactionType", "Amount", "PartyA", "PartyB", "PhoneNumber", "CallBackURL", "AccountReference"]),
+    (stk_query::ENDPOINT, &["BusinessShortCode", "Password", "Timestamp", "CheckoutRequestID"]),
+    (c2b::REGISTER_URL_ENDPOINT, &["ShortCode", "ResponseType", "ConfirmationURL", "ValidationURL"]),
+    (c2b::SIMULATE_ENDPOINT, &["ShortCode", "CommandID", "Amount", "Msisdn"]),
+];
+
+/// The answer to a request
+#[derive(Debug, Clone)]
+pub struct Reply {
+    /// HTTP status code
+    pub status: u16,
+    /// JSON body
+    pub body: Value,
+    /// Callbacks to post, in order, once the callback delay has passed
+    pub callbacks: Vec<Callback>,
+    /// How long to wait before posting the callbacks, when it is not the callback delay
+    pub delay: Option<Duration>,
+}
+
+/// A callback to post to the url given in a request
+#[derive(Debug, Clone)]
+pub struct Callback {
+    /// The url, e.g. the `ResultURL` of the request
+    pub url: String,
+    /// JSON body
+    pub body: Value,
+    /// Whether this is a C2B validation request, whose rejection cancels the callbacks after it
+    pub validation: bool,
+}
+
+/// The urls registered for a shortcode
+#[derive(Debug, Clone)]
+struct Registration {
+    validation_url: String,
+    confirmation_url: String,
+}
+
+/// An STK push the customer was prompted for
+#[derive(Debug, Clone)]
+struct Push {
+    merchant_request_id: String,
+    result: (i64, &'static str),
+    /// When the customer answers, never if no callback is posted
+    answered_at: Option<Instant>,
+}
+
+/// A customer payment made through the mock, reported by Transaction Status queries on its receipt
+#[derive(Debug, Clone)]
+struct Payment {
+    amount: Value,
+    msisdn: String,
+}
+
+/// A scenario waiting for the requests it applies to
+#[derive(Debug, Clone)]
+struct Script {
+    scenario: Scenario,
+    msisdn: Option<String>,
+    remaining: usize,
+}
+
+/// The state of the mock API
+#[derive(Debug)]
+pub struct Daraja {
+    credentials: Option<String>,
+    callback_delay: Duration,
+    tokens: HashMap<String, Instant>,
+    registrations: HashMap<String, Registration>,
+    pushes: HashMap<String, Push>,
+    payments: HashMap<String, Payment>,
+    scripts: Vec<Script>,
+}
+
+impl Daraja {
+    /// Creates a mock accepting any credentials and answering STK queries as soon as the push is made
+    pub fn new() -> Daraja {
+        Daraja {
+            credentials: None,
+            callback_delay: Duration::from_secs(0),
+            tokens: HashMap::new(),
+            registrations: HashMap::new(),
+            pushes: HashMap::new(),
+            payments: HashMap::new(),
+            scripts: Vec::new(),
+        }
+    }
+
+    /// Only issues tokens for this consumer key and secret
+    pub fn credentials(mut self, consumer_key: &str, consumer_secret: &str) -> Daraja {
+        self.credentials = Some(base64::encode(&format!("{}:{}", consumer_key, consumer_secret)));
+        self
+    }
+
+    /// STK queries report a push as still processing until `delay` has passed, the time its callback is posted at
+    pub fn callback_delay(mut self, delay: Duration) -> Daraja {
+        self.callback_delay = delay;
+        self
+    }
+
+    /// Applies `scenario` to the next `times` requests, or only to those for the customer `msisdn`
+    pub fn script(&mut self, scenario: Scenario, msisdn: Option<&str>, times: usize) {
+        self.scripts.push(Script {
+            scenario: scenario,
+            msisdn: msisdn.map(last_nine_digits),
+            remaining: times,
+        });
+    }
+
+    /// Drops every scripted scenario
+    pub fn clear_scripts(&mut self) {
+        self.scripts.clear();
+    }
+
+    /// Answers a `POST` to the admin endpoint, scripting the scenario described by `body`
+    pub fn admin(&mut self, body: &Value) -> Reply {
+        let scenario = match body["scenario"].as_str().map(str::parse) {
+            Some(Ok(Scenario::DelayedCallback(delay))) => {
+                Scenario::DelayedCallback(body["delay_ms"].as_u64().map(Duration::from_millis).unwrap_or(delay))
+            },
+            Some(Ok(scenario)) => scenario,
+            Some(Err(unknown)) => return error(400, BAD_REQUEST, &format!("Bad Request - {}", unknown)),
+            None => return error(400, BAD_REQUEST, "Bad Request - Invalid scenario"),
+        };
+        let times = body["times"].as_u64().unwrap_or(1) as usize;
+        self.script(scenario, text(body, "msisdn").as_ref().map(String::as_str), times);
+        reply(json!({ "scenario": scenario.as_str(), "times": times }))
+    }
+
+    /// Answers a `GET` to the OAuth endpoint with the `Authorization` header and query string of the request
+    pub fn token(&mut self, authorization: Option<&str>, query: &str) -> Reply {
+        if !query.split('&').any(|pair| pair == "grant_type=client_credentials") {
+            return error(400, BAD_REQUEST, "Bad Request - Invalid grant_type");
+        }
+        let credentials = match authorization {
+            Some(authorization) if authorization.starts_with("Basic ") => authorization["Basic ".len()..].trim(),
+            _ => return error(400, INVALID_CREDENTIALS, "Invalid Authentication passed"),
+        };
+        if self.credentials.as_ref().map_or(false, |expected| expected != credentials) {
+            return error(400, INVALID_CREDENTIALS, "Invalid Authentication passed");
+        }
+
+        let token = random(28);
+        self.tokens.insert(token.clone(), Instant::now());
+        reply(json!({ "access_token": token, "expires_in": TOKEN_LIFETIME.to_string() }))
+    }
+
+    /// Answers a `POST` of `body` to the product endpoint at `path` with the `Authorization` header of the request
+    pub fn handle(&mut self, path: &str, authorization: Option<&str>, body: &Value) -> Reply {
+        if !self.is_authorized(authorization) {
+            return error(404, INVALID_TOKEN, "Invalid Access Token");
+        }
+        let scenario = self.scenario(body);
+        match scenario {
+            Some(Scenario::TooManyRequests) => return too_many_requests(),
+            Some(Scenario::ServiceUnavailable) => return error(503, SERVICE_UNAVAILABLE, "Service Unavailable"),
+            Some(Scenario::TokenRevoked) => {
+                if let Some(authorization) = authorization {
+                    self.tokens.remove(authorization["Bearer ".len()..].trim());
+                }
+                return error(404, INVALID_TOKEN, "Invalid Access Token");
+            },
+            _ => (),
+        }
+        let mandatory = match MANDATORY.iter().find(|&&(endpoint, _)| endpoint == path) {
+            Some(&(_, fields)) => fields,
+            None => return error(404, "404.001.01", "Resource not found"),
+        };
+        if let Some(field) = mandatory.iter().find(|field| text(body, field).map_or(true, |value| value.is_empty())) {
+            return error(400, BAD_REQUEST, &format!("Bad Request - Invalid {}", field));
+        }
+        if body.get("Amount").is_some() && Amount::from_value(&body["Amount"]).map_or(true, |amount| amount.cents() == 0) {
+            return error(400, BAD_REQUEST, "Bad Request - Invalid Amount");
+        }
+
+        let mut reply = match path {
+            p if p == stk_push::ENDPOINT => self.stk_push(body, scenario),
+            p if p == stk_query::ENDPOINT => self.stk_query(body),
+            p if p == c2b::REGISTER_URL_ENDPOINT => self.register_url(body),
+            p if p == c2b::SIMULATE_ENDPOINT => self.simulate(body),
+            p if p == transaction_status::ENDPOINT => self.transaction_status(body, scenario),
+            _ => result_request(path, body, scenario),
+        };
+        match scenario {
+            Some(Scenario::DuplicateCallback) => {
+                reply.callbacks = reply.callbacks.into_iter().flat_map(|callback| vec![callback.clone(), callback]).collect();
+            },
+            Some(Scenario::DelayedCallback(delay)) => reply.delay = Some(delay),
+            Some(Scenario::NoCallback) => reply.callbacks.clear(),
+            _ => (),
+        }
+        reply
+    }
+
+    /// The scenario applying to a request with `body`: the first one scripted for it, else the one of the conventions
+    fn scenario(&mut self, body: &Value) -> Option<Scenario> {
+        let msisdn = ["PhoneNumber", "Msisdn", "PartyB"].iter().filter_map(|field| text(body, field)).next();
+        let customer = msisdn.as_ref().map(|msisdn| last_nine_digits(msisdn));
+
+        let scripted = self.scripts.iter_mut()
+            .find(|script| script.msisdn.is_none() || script.msisdn == customer)
+            .map(|script| {
+                script.remaining = script.remaining.saturating_sub(1);
+                script.scenario
+            });
+        self.scripts.retain(|script| script.remaining > 0);
+
+        scripted.or_else(|| Scenario::from_convention(msisdn.as_ref().map(String::as_str), Amount::from_value(&body["Amount"])))
+    }
+
+    /// Whether `authorization` carries a token that was issued and has not expired
+    fn is_authorized(&mut self, authorization: Option<&str>) -> bool {
+        let lifetime = Duration::from_secs(TOKEN_LIFETIME);
+        self.tokens.retain(|_, issued_at| issued_at.elapsed() < lifetime);
+        match authorization {
+            Some(authorization) if authorization.starts_with("Bearer ") => {
+                self.tokens.contains_key(authorization["Bearer ".len()..].trim())
+            },
+            _ => false,
+        }
+    }
+
+    fn stk_push(&mut self, body: &Value, scenario: Option<Scenario>) -> Reply {
+        let merchant_request_id = format!("{}-{}-1", digits(5), digits(8));
+        let checkout_request_id = format!("ws_CO_{}{}", now().format("%d%m%Y%H%M%S"), digits(6));
+        let (result_code, result_desc) = scenario.map_or(SUCCESS, |scenario| scenario.result(true));
+        let answered_at = match scenario {
+            Some(Scenario::NoCallback) => None,
+            Some(Scenario::DelayedCallback(delay)) => Some(Instant::now() + delay),
+            _ => Some(Instant::now() + self.callback_delay),
+        };
+        self.pushes.insert(checkout_request_id.clone(), Push {
+            merchant_request_id: merchant_request_id.clone(),
+            result: (result_code, result_desc),
+            answered_at: answered_at,
+        });
+
+        let mut callback = json!({
+            "MerchantRequestID": merchant_request_id,
+            "CheckoutRequestID": checkout_request_id,
+            "ResultCode": result_code,
+            "ResultDesc": result_desc,
+        });
+        // failed payments carry no metadata
+        if result_code == 0 {
+            let receipt = receipt();
+            self.payments.insert(receipt.clone(), Payment {
+                amount: number(&body["Amount"]),
+                msisdn: text(body, "PhoneNumber").unwrap_or_default(),
+            });
+            callback["CallbackMetadata"] = json!({
+                "Item": [
+                    { "Name": "Amount", "Value": number(&body["Amount"]) },
+                    { "Name": "MpesaReceiptNumber", "Value": receipt },
+                    { "Name": "Balance" },
+                    { "Name": "TransactionDate", "Value": now().format("%Y%m%d%H%M%S").to_string().parse::<u64>().unwrap_or(0) },
+                    { "Name": "PhoneNumber", "Value": number(&body["PhoneNumber"]) },
+                ],
+            });
+        }
+        let callback = json!({ "Body": { "stkCallback": callback } });
+
+        Reply {
+            status: 200,
+            body: json!({
+                "MerchantRequestID": merchant_request_id,
+                "CheckoutRequestID": checkout_request_id,
+                "ResponseCode": "0",
+                "ResponseDescription": "Success. Request accepted for processing",
+                "CustomerMessage": "Success. Request accepted for processing",
+            }),
+            callbacks: vec![callback_to(&body["CallBackURL"], callback)],
+            delay: None,
+        }
+    }
+
+    fn stk_query(&mut self, body: &Value) -> Reply {
+        let checkout_request_id = text(body, "CheckoutRequestID").unwrap_or_default();
+        let push = match self.pushes.get(&checkout_request_id) {
+            Some(push) => push,
+            None => return error(400, BAD_REQUEST, "Bad Request - Invalid CheckoutRequestID"),
+        };
+        if push.answered_at.map_or(true, |answered_at| Instant::now() < answered_at) {
+            return error(500, STILL_PROCESSING, "The transaction is being processed");
+        }
+
+        reply(json!({
+            "ResponseCode": "0",
+            "ResponseDescription": "The service request has been accepted successsfully",
+            "MerchantRequestID": push.merchant_request_id,
+            "CheckoutRequestID": checkout_request_id,
+            "ResultCode": push.result.0.to_string(),
+            "ResultDesc": push.result.1,
+        }))
+    }
+
+    fn register_url(&mut self, body: &Value) -> Reply {
+        let short_code = text(body, "ShortCode").unwrap_or_default();
+        self.registrations.insert(short_code, Registration {
+            validation_url: text(body, "ValidationURL").unwrap_or_default(),
+            confirmation_url: text(body, "ConfirmationURL").unwrap_or_default(),
+        });
+
+        // sic, the API misspells the field
+        reply(json!({
+            "OriginatorCoversationID": originator_conversation_id(),
+            "ResponseCode": "0",
+            "ResponseDescription": "success",
+        }))
+    }
+
+    fn simulate(&mut self, body: &Value) -> Reply {
+        let short_code = text(body, "ShortCode").unwrap_or_default();
+        let paybill = text(body, "CommandID").map_or(true, |command| command == "CustomerPayBillOnline");
+        let amount = Amount::from_value(&body["Amount"]).map(|amount| amount.to_string()).unwrap_or_default();
+        let receipt = receipt();
+        self.payments.insert(receipt.clone(), Payment {
+            amount: number(&body["Amount"]),
+            msisdn: text(body, "Msisdn").unwrap_or_default(),
+        });
+        let transaction = json!({
+            "TransactionType": if paybill { "Pay Bill" } else { "Buy Goods" },
+            "TransID": receipt,
+            "TransTime": now().format("%Y%m%d%H%M%S").to_string(),
+            "TransAmount": amount,
+            "BusinessShortCode": short_code,
+            "BillRefNumber": text(body, "BillRefNumber").unwrap_or_default(),
+            "InvoiceNumber": "",
+            "OrgAccountBalance": "",
+            "ThirdPartyTransID": "",
+            "MSISDN": text(body, "Msisdn").unwrap_or_default(),
+            "FirstName": "John",
+            "MiddleName": "",
+            "LastName": "Doe",
+        });
+
+        // payments to a shortcode without registered urls go through unnoticed
+        let callbacks = match self.registrations.get(&short_code) {
+            Some(registration) => vec![
+                Callback { url: registration.validation_url.clone(), body: transaction.clone(), validation: true },
+                Callback { url: registration.confirmation_url.clone(), body: transaction, validation: false },
+            ],
+            None => Vec::new(),
+        };
+
+        Reply {
+            status: 200,
+            body: json!({
+                "OriginatorCoversationID": originator_conversation_id(),
+                "ResponseCode": "0",
+                "ResponseDescription": "Accept the service request successfully.",
+            }),
+            callbacks: callbacks,
+            delay: None,
+        }
+    }
+
+    /// Reports the amount and customer of payments made through the mock, found by their receipt
+    fn transaction_status(&mut self, body: &Value, scenario: Option<Scenario>) -> Reply {
+        if ["TransactionID", "OriginalConversationID"].iter().all(|field| text(body, field).map_or(true, |value| value.is_empty())) {
+            return error(400, BAD_REQUEST, "Bad Request - Invalid TransactionID");
+        }
+        let mut reply = result_request(transaction_status::ENDPOINT, body, scenario);
+        let payment = match text(body, "TransactionID").and_then(|receipt| self.payments.get(&receipt)) {
+            Some(payment) => payment,
+            None => return reply,
+        };
+        for callback in &mut reply.callbacks {
+            if let Some(&mut Value::Array(ref mut parameters)) = callback.body.pointer_mut("/Result/ResultParameters/ResultParameter") {
+                for parameter in parameters {
+                    match parameter["Key"].as_str() {
+                        Some("Amount") => parameter["Value"] = payment.amount.clone(),
+                        Some("DebitPartyName") => parameter["Value"] = json!(format!("{} - John Doe", payment.msisdn)),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        reply
+    }
+}
+
+impl Default for Daraja {
+    fn default() -> Daraja {
+        Daraja::new()
+    }
+}
+
+/// Acknowledges a request answered with a callback to its `ResultURL`, or its `QueueTimeOutURL` when it times out
+fn result_request(path: &str, body: &Value, scenario: Option<Scenario>) -> Reply {
+    // the API assigns the id, except for B2C payments whose version 3 takes it from the caller
+    let originator_conversation_id = match text(body, "OriginatorConversationID") {
+        Some(ref id) if path == b2c::ENDPOINT && !id.is_empty() => id.clone(),
+        _ => originator_conversation_id(),
+    };
+    let conversation_id = format!("AG_{}_{}", now().format("%Y%m%d"), random(20).to_lowercase());
+    let transaction_id = receipt();
+    let (result_code, result_desc) = scenario.map_or(SUCCESS, |scenario| scenario.result(false));
+
+    let mut result = json!({
+        "ResultType": 0,
+        "ResultCode": result_code,
+        "ResultDesc": result_desc,
+        "OriginatorConversationID": originator_conversation_id,
+        "ConversationID": conversation_id,
+        "TransactionID": transaction_id,
+        "ReferenceData": {
+            "ReferenceItem": { "Key": "QueueTimeoutURL", "Value": text(body, "QueueTimeOutURL").unwrap_or_default() },
+        },
+    });
+    // failed requests carry no result parameters
+    if result_code == 0 {
+        result["ResultParameters"] = json!({ "ResultParameter": result_parameters(path, body, &transaction_id) });
+    }
+    let url = match scenario {
+        Some(Scenario::Timeout) => &body["QueueTimeOutURL"],
+        _ => &body["ResultURL"],
+    };
+
+    Reply {
+        status: 200,
+        body: json!({
+            "ConversationID": conversation_id,
+            "OriginatorConversationID": originator_conversation_id,
+            "ResponseCode": "0",
+            "ResponseDescription": "Accept the service request successfully.",
+        }),
+        callbacks: vec![callback_to(url, json!({ "Result": result }))],
+        delay: None,
+    }
+}
+
+/// The `ResultParameters` of the result of a request to `path`
+fn result_parameters(path: &str, body: &Value, transaction_id: &str) -> Value {
+    let completed_at = now().format("%d.%m.%Y %H:%M:%S").to_string();
+    let amount = number(&body["Amount"]);
+    let party_b = text(body, "PartyB").unwrap_or_default();
+    let parameters = match path {
+        p if p == b2c::ENDPOINT => vec![
+            ("TransactionAmount", amount),
+            ("TransactionReceipt", json!(transaction_id)),
+            ("ReceiverPartyPublicName", json!(format!("{} - John Doe", party_b))),
+            ("TransactionCompletedDateTime", json!(completed_at)),
+            ("B2CUtilityAccountAvailableFunds", json!(10116.00)),
+            ("B2CWorkingAccountAvailableFunds", json!(900000.00)),
+            ("B2CRecipientIsRegisteredCustomer", json!("Y")),
+            ("B2CChargesPaidAccountAvailableFunds", json!(0.00)),
+        ],
+        p if p == b2b::ENDPOINT => vec![
+            ("DebitAccountBalance", json!("{Amount={CurrencyCode=KES, MinimumAmount=90000000, BasicAmount=900000.00}}")),
+            ("Amount", amount),
+            ("DebitPartyAffectedAccountBalance", json!("Working Account|KES|900000.00|900000.00|0.00|0.00")),
+            ("TransCompletedTime", json!(now().format("%Y%m%d%H%M%S").to_string())),
+            ("DebitPartyCharges", json!("")),
+            ("ReceiverPartyPublicName", json!(format!("{} - Test Company", party_b))),
+            ("Currency", json!("KES")),
+        ],
+        p if p == reversal::ENDPOINT => vec![
+            ("DebitAccountBalance", json!("Utility Account|KES|10116.00|10116.00|0.00|0.00")),
+            ("Amount", json!(1.00)),
+            ("TransCompletedTime", json!(now().format("%Y%m%d%H%M%S").to_string())),
+            ("OriginalTransactionID", json!(text(body, "TransactionID").unwrap_or_default())),
+            ("Charge", json!(0.00)),
+            ("CreditPartyPublicName", json!("254708374149 - John Doe")),
+            ("DebitPartyPublicName", json!(format!("{} - Test Company", text(body, "ReceiverParty").unwrap_or_default()))),
+        ],
+        p if p == transaction_status::ENDPOINT => vec![
+            ("ReceiptNo", json!(text(body, "TransactionID").unwrap_or_default())),
+            ("ConversationID", json!("")),
+            ("FinalisedTime", json!(now().format("%Y%m%d%H%M%S").to_string())),
+            ("Amount", json!(1.00)),
+            ("TransactionStatus", json!("Completed")),
+            ("ReasonType", json!("Salary Payment via API")),
+            ("TransactionReason", json!("")),
+            ("DebitPartyCharges", json!("")),
+            ("DebitAccountType", json!("Utility Account")),
+            ("InitiatedTime", json!(now().format("%Y%m%d%H%M%S").to_string())),
+            ("OriginatorConversationID", json!("")),
+            ("CreditPartyName", json!("254708374149 - John Doe")),
+            ("DebitPartyName", json!(format!("{} - Test Company", text(body, "PartyA").unwrap_or_default()))),
+        ],
+        _ => vec![
+            ("AccountBalance", json!("Working Account|KES|900000.00|900000.00|0.00|0.00&Utility Account|KES|10116.00|10116.00|0.00|0.00&Charges Paid Account|KES|0.00|0.00|0.00|0.00")),
+            ("BOCompletedTime", json!(now().format("%Y%m%d%H%M%S").to_string().parse::<u64>().unwrap_or(0))),
+        ],
+    };
+    Value::Array(parameters.into_iter().map(|(key, value)| json!({ "Key": key, "Value": value })).collect())
+}
+
+/// A successful reply with `body`
+fn reply(body: Value) -> Reply {
+    Reply { status: 200, body: body, callbacks: Vec::new(), delay: None }
+}
+
+/// An error reply in the format of the API
+pub fn error(status: u16, code: &str, message: &str) -> Reply {
+    Reply {
+        status: status,
+        body: json!({
+            "requestId": format!("{}-{}-1", digits(5), digits(8)),
+            "errorCode": code,
+            "errorMessage": message,
+        }),
+        callbacks: Vec::new(),
+        delay: None,
+    }
+}
+
+/// The answer of the gateway to requests over the rate limit
+fn too_many_requests() -> Reply {
+    Reply {
+        status: 429,
+        body: json!({
+            "fault": {
+                "faultstring": "Spike arrest violation. Allowed rate : 5ps",
+                "detail": { "errorcode": "policies.ratelimit.SpikeArrestViolation" },
+            },
+        }),
+        callbacks: Vec::new(),
+        delay: None,
+    }
+}
+
+fn callback_to(url: &Value, body: Value) -> Callback {
+    Callback { url: url.as_str().unwrap_or_default().to_string(), body: body, validation: false }
+}
+
+/// A field of the request as text, whether it was sent as a string or a number
+fn text(body: &Value, field: &str) -> Option<String> {
+    match body.get(field) {
+        Some(&Value::String(ref value)) => Some(value.clone()),
+        Some(&Value::Number(ref value)) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// A field of the request as a number, the way callbacks carry amounts and phone numbers
+fn number(value: &Value) -> Value {
+    match value {
+        &Value::String(ref value) => value.parse::<u64>().map(Value::from)
+            .or_else(|_| value.parse::<f64>().map(Value::from))
+            .unwrap_or(Value::Null),
+        value => value.clone(),
+    }
+}
+
+/// The time in Nairobi, which the API reports times in
+fn now() -> DateTime<FixedOffset> {
+    Utc::now().with_timezone(&FixedOffset::east(3 * 60 * 60))
+}
+
+/// The last nine digits of a phone number, which identify the customer whatever the prefix
+fn last_nine_digits(msisdn: &str) -> String {
+    let digits: Vec<char> = msisdn.chars().filter(|c| c.is_digit(10)).collect();
+    digits[digits.len().saturating_sub(9)..].iter().collect()
+}
+
+/// A transaction id like `NLJ41HAY6Q`
+fn receipt() -> String {
+    random(10).to_uppercase()
+}
+
+fn originator_conversation_id() -> String {
+    format!("{}-{}-1", digits(5), digits(8))
+}
+
+fn digits(length: usize) -> String {
+    let mut rng = thread_rng();
+    (0..length).map(|_| (b'0' + rng.gen_range(0, 10)) as char).collect()
+}
+
+fn random(length: usize) -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(length).collect()
+}
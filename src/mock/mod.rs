@@ -0,0 +1,224 @@
+//! An offline mock of the Daraja API for integration tests
+//!
+//! `MockServer` serves the OAuth endpoint and every product endpoint under the paths of the real API, so a client
+//! only needs its base url pointed at the mock. Requests are checked and acknowledged the way the API does it,
+//! failures are answered in the API's error format:
+//!
+//! ```text
+//! {"requestId":"11728-2929992-1","errorCode":"404.001.03","errorMessage":"Invalid Access Token"}
+//! ```
+//!
+//! Once a request is acknowledged, its callbacks are posted after the callback delay: the result to the `ResultURL`,
+//! the outcome of an STK push to its `CallBackURL` and C2B simulations to the validation and confirmation urls
+//! registered for the shortcode. The behaviour itself lives in `daraja`, independent of the HTTP server.
+//!
+//! Failures such as insufficient funds, cancelled STK pushes or rate limiting are scripted per request, either up
+//! front, through the admin endpoint `/mock/scenarios` or by sending to test numbers, see the `scenario` module.
+//!
+//! The `mpesa-mock` binary runs the server from the command line.
+//!
+//! # Example
+//! ```no_run
+//! # use std::thread;
+//! # use std::time::Duration;
+//! # use mpesa::mock::MockServer;
+//! # use mpesa::mock::scenario::Scenario;
+//! # use mpesa::config::Config;
+//! thread::spawn(|| {
+//!     MockServer::new()
+//!         .credentials("foo", "bar")
+//!         .callback_delay(Duration::from_millis(500))
+//!         .script(Scenario::InsufficientFunds, Some("254708374149"), 1)
+//!         .run("127.0.0.1:8000")
+//!         .expect("unable to start the mock server");
+//! });
+//!
+//! let mut config = Config::default();
+//! config.set("consumer_key", "foo").unwrap();
+//! config.set("consumer_secret", "bar").unwrap();
+//! config.set("base_url", "http://127.0.0.1:8000").unwrap();
+//! let client = config.client().unwrap();
+//! ```
+
+pub mod daraja;
+pub mod scenario;
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use actix_web::{server, App, AsyncResponder, FutureResponse, HttpMessage, HttpRequest, HttpResponse};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::{Method, StatusCode};
+use futures::future::Future;
+use reqwest;
+use serde_json::Value;
+
+use self::daraja::{Callback, Daraja, Reply, ADMIN_PATH, BAD_REQUEST, ENDPOINTS, OAUTH_PATH};
+use self::scenario::Scenario;
+
+/// How long after the acknowledgement callbacks are posted, by default
+pub const DEFAULT_CALLBACK_DELAY: Duration = Duration::from_secs(2);
+
+/// An HTTP server mocking the Daraja API
+pub struct MockServer {
+    daraja: Daraja,
+    callback_delay: Duration,
+}
+
+/// State shared by the request handlers
+struct MockState {
+    daraja: Arc<Mutex<Daraja>>,
+    callback_delay: Duration,
+}
+
+impl MockServer {
+    /// Creates a mock accepting any credentials and posting callbacks after `DEFAULT_CALLBACK_DELAY`
+    pub fn new() -> MockServer {
+        MockServer {
+            daraja: Daraja::new(),
+            callback_delay: DEFAULT_CALLBACK_DELAY,
+        }
+    }
+
+    /// Only issues access tokens for this consumer key and secret
+    pub fn credentials(mut self, consumer_key: &str, consumer_secret: &str) -> MockServer {
+        self.daraja = self.daraja.credentials(consumer_key, consumer_secret);
+        self
+    }
+
+    /// Posts callbacks `delay` after the acknowledgement of their request
+    pub fn callback_delay(mut self, delay: Duration) -> MockServer {
+        self.callback_delay = delay;
+        self
+    }
+
+    /// Applies `scenario` to the next `times` requests, or only to those for the customer `msisdn`
+    pub fn script(mut self, scenario: Scenario, msisdn: Option<&str>, times: usize) -> MockServer {
+        self.daraja.script(scenario, msisdn, times);
+        self
+    }
+
+    /// Starts listening on `address`. Blocks until the server is stopped.
+    pub fn run<A: ToSocketAddrs>(self, address: A) -> io::Result<()> {
+        let callback_delay = self.callback_delay;
+        let daraja = Arc::new(Mutex::new(self.daraja.callback_delay(callback_delay)));
+
+        server::new(move || {
+                let state = MockState { daraja: daraja.clone(), callback_delay: callback_delay };
+                let mut app = App::with_state(state)
+                    .resource(OAUTH_PATH, |r| r.method(Method::GET).f(token))
+                    .resource(ADMIN_PATH, |r| {
+                        r.method(Method::POST).a(script);
+                        r.method(Method::DELETE).f(clear_scripts);
+                    });
+                for endpoint in ENDPOINTS {
+                    app = app.resource(endpoint, |r| r.method(Method::POST).a(product));
+                }
+                app
+            })
+            .bind(address)?
+            .run();
+
+        Ok(())
+    }
+}
+
+impl Default for MockServer {
+    fn default() -> MockServer {
+        MockServer::new()
+    }
+}
+
+fn token(req: HttpRequest<MockState>) -> HttpResponse {
+    let authorization = header(&req, "authorization");
+    let reply = req.state().daraja.lock().expect("the mock lock is poisoned")
+        .token(authorization.as_ref().map(String::as_str), req.query_string());
+    respond(req.path(), reply, req.state().callback_delay)
+}
+
+fn product(req: HttpRequest<MockState>) -> FutureResponse<HttpResponse> {
+    let daraja = req.state().daraja.clone();
+    let callback_delay = req.state().callback_delay;
+    let path = req.path().to_string();
+    let authorization = header(&req, "authorization");
+    req.json()
+        .then(move |body: Result<Value, JsonPayloadError>| {
+            let reply = match body {
+                Ok(body) => daraja.lock().expect("the mock lock is poisoned")
+                    .handle(&path, authorization.as_ref().map(String::as_str), &body),
+                Err(_) => daraja::error(400, BAD_REQUEST, "Bad Request - Invalid JSON"),
+            };
+            Ok(respond(&path, reply, callback_delay))
+        })
+        .responder()
+}
+
+fn script(req: HttpRequest<MockState>) -> FutureResponse<HttpResponse> {
+    let daraja = req.state().daraja.clone();
+    let callback_delay = req.state().callback_delay;
+    req.json()
+        .then(move |body: Result<Value, JsonPayloadError>| {
+            let reply = match body {
+                Ok(body) => daraja.lock().expect("the mock lock is poisoned").admin(&body),
+                Err(_) => daraja::error(400, BAD_REQUEST, "Bad Request - Invalid JSON"),
+            };
+            Ok(respond(ADMIN_PATH, reply, callback_delay))
+        })
+        .responder()
+}
+
+fn clear_scripts(req: HttpRequest<MockState>) -> HttpResponse {
+    req.state().daraja.lock().expect("the mock lock is poisoned").clear_scripts();
+    HttpResponse::Ok().json(json!({ "scripted": 0 }))
+}
+
+fn header(req: &HttpRequest<MockState>, name: &str) -> Option<String> {
+    req.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from)
+}
+
+/// Turns `reply` into a response and schedules its callbacks
+fn respond(path: &str, reply: Reply, callback_delay: Duration) -> HttpResponse {
+    info!("{} answered with {}", path, reply.status);
+    if !reply.callbacks.is_empty() {
+        post_callbacks(reply.callbacks, reply.delay.unwrap_or(callback_delay));
+    }
+    let status = StatusCode::from_u16(reply.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    HttpResponse::build(status).json(reply.body)
+}
+
+/// Posts `callbacks` in order after `delay`, stopping at a rejected C2B validation
+fn post_callbacks(callbacks: Vec<Callback>, delay: Duration) {
+    thread::spawn(move || {
+        thread::sleep(delay);
+        let client = reqwest::Client::new();
+        for callback in callbacks {
+            let accepted = match client.post(&callback.url).json(&callback.body).send() {
+                Ok(mut response) => response.status().is_success() && (!callback.validation || is_accepted(&response.text().unwrap_or_default())),
+                Err(error) => {
+                    warn!("unable to post the callback to {}: {}", callback.url, error);
+                    false
+                },
+            };
+            if callback.validation && !accepted {
+                info!("{} rejected the C2B payment", callback.url);
+                return;
+            }
+        }
+    });
+}
+
+/// Whether the answer to a C2B validation request accepts the payment
+fn is_accepted(response: &str) -> bool {
+    let response: Value = match ::serde_json::from_str(response) {
+        Ok(response) => response,
+        Err(_) => return false,
+    };
+    match response["ResultCode"] {
+        Value::Number(ref code) => code.as_u64() == Some(0),
+        Value::String(ref code) => code == "0",
+        _ => false,
+    }
+}
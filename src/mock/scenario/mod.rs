@@ -0,0 +1,159 @@
+//! Scripted outcomes of the requests to the mock Daraja API
+//!
+//! Every request succeeds unless a `Scenario` applies to it. A scenario applies when
+//! * it was scripted, through `Daraja::script` or by posting to the admin endpoint `/mock/scenarios`:
+//!   ```text
+//!   {"scenario": "insufficient_funds", "msisdn": "254708374149", "times": 2}
+//!   ```
+//!   `msisdn` limits it to requests for that customer and `times`, 1 by default, to that many requests.
+//!   `delayed_callback` also takes `delay_ms`. A `DELETE` of `/mock/scenarios` drops every scripted scenario.
+//! * or the request follows a convention: the customer is the test number `2547000000NN`, or the amount is
+//!   `99NN` shillings, where `NN` is the code of the scenario.
+//!
+//! | Code | Name                  | Outcome                                                                   |
+//! |------|-----------------------|---------------------------------------------------------------------------|
+//! | 01   | `insufficient_funds`  | the result or STK callback fails with code 1                              |
+//! | 02   | `cancelled`           | the customer cancels the STK push, code 1032. Other requests succeed      |
+//! | 03   | `wrong_pin`           | the STK push or the initiator is refused with code 2001                   |
+//! | 04   | `timeout`             | the request times out: posted to the `QueueTimeOutURL`, STK code 1037     |
+//! | 05   | `duplicate_callback`  | every callback is posted twice                                            |
+//! | 06   | `delayed_callback`    | callbacks are posted after `DELAYED_CALLBACK` instead of the usual delay  |
+//! | 07   | `no_callback`         | no callback is ever posted and STK queries stay pending                   |
+//! | 08   | `too_many_requests`   | the request is refused with `429 Too Many Requests`                       |
+//! | 09   | `service_unavailable` | the request is refused with `503 Service Unavailable`                     |
+//! | 10   | `token_revoked`       | the access token of the request is revoked and the request refused        |
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::time::Duration;
+
+use parameters::Amount;
+
+/// How long callbacks of the `delayed_callback` scenario are held back when selected by convention
+pub const DELAYED_CALLBACK: Duration = Duration::from_secs(30);
+
+/// Amounts from `CONVENTION_AMOUNT + 1` shillings select the scenario with that code
+const CONVENTION_AMOUNT: u64 = 9900;
+
+/// Customers `2547000000NN` select the scenario with code `NN`
+const CONVENTION_MSISDN: &str = "7000000";
+
+/// A scripted outcome of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scenario {
+    /// The customer or the organization cannot afford the payment
+    InsufficientFunds,
+    /// The customer cancels the STK push
+    Cancelled,
+    /// The customer enters a wrong PIN, or the initiator credentials are wrong
+    WrongPin,
+    /// The request times out in the queue
+    Timeout,
+    /// Every callback is posted twice
+    DuplicateCallback,
+    /// Callbacks are posted after the given time instead of the usual delay
+    DelayedCallback(Duration),
+    /// No callback is ever posted
+    NoCallback,
+    /// The request is refused for exceeding the rate limit
+    TooManyRequests,
+    /// The request is refused for the API being unavailable
+    ServiceUnavailable,
+    /// The access token of the request is revoked
+    TokenRevoked,
+}
+
+/// A name that is not the name of a scenario
+#[derive(Debug)]
+pub struct UnknownScenario(pub String);
+
+/// The scenarios, in the order of their codes
+const SCENARIOS: &[Scenario] = &[
+    Scenario::InsufficientFunds, Scenario::Cancelled, Scenario::WrongPin, Scenario::Timeout,
+    Scenario::DuplicateCallback, Scenario::DelayedCallback(DELAYED_CALLBACK), Scenario::NoCallback,
+    Scenario::TooManyRequests, Scenario::ServiceUnavailable, Scenario::TokenRevoked,
+];
+
+impl Scenario {
+    /// The scenario with `code` in the conventions
+    pub fn from_code(code: u64) -> Option<Scenario> {
+        if code == 0 {
+            return None;
+        }
+        SCENARIOS.get(code as usize - 1).cloned()
+    }
+
+    /// The scenario selected by the conventions for a request to `msisdn` or of `amount`, if any
+    pub fn from_convention(msisdn: Option<&str>, amount: Option<Amount>) -> Option<Scenario> {
+        let by_msisdn = msisdn.and_then(|msisdn| {
+            // the last nine digits, whatever the prefix
+            let digits: String = msisdn.chars().filter(|c| c.is_digit(10)).collect();
+            if digits.len() < 9 || !digits[digits.len() - 9..].starts_with(CONVENTION_MSISDN) {
+                return None;
+            }
+            digits[digits.len() - 2..].parse().ok().and_then(Scenario::from_code)
+        });
+        let by_amount = amount.and_then(|amount| {
+            if !amount.is_whole() || amount.whole_shillings() <= CONVENTION_AMOUNT {
+                return None;
+            }
+            Scenario::from_code(amount.whole_shillings() - CONVENTION_AMOUNT)
+        });
+        by_msisdn.or(by_amount)
+    }
+
+    /// The name of the scenario, as accepted by the admin endpoint
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Scenario::InsufficientFunds => "insufficient_funds",
+            &Scenario::Cancelled => "cancelled",
+            &Scenario::WrongPin => "wrong_pin",
+            &Scenario::Timeout => "timeout",
+            &Scenario::DuplicateCallback => "duplicate_callback",
+            &Scenario::DelayedCallback(_) => "delayed_callback",
+            &Scenario::NoCallback => "no_callback",
+            &Scenario::TooManyRequests => "too_many_requests",
+            &Scenario::ServiceUnavailable => "service_unavailable",
+            &Scenario::TokenRevoked => "token_revoked",
+        }
+    }
+
+    /// The `ResultCode` and `ResultDesc` of a request processed under the scenario
+    pub fn result(&self, stk_push: bool) -> (i64, &'static str) {
+        match (self, stk_push) {
+            (&Scenario::InsufficientFunds, _) => (1, "The balance is insufficient for the transaction."),
+            (&Scenario::Cancelled, true) => (1032, "Request cancelled by user"),
+            (&Scenario::WrongPin, _) => (2001, "The initiator information is invalid."),
+            (&Scenario::Timeout, true) => (1037, "DS timeout user cannot be reached"),
+            (&Scenario::Timeout, false) => (1, "The service request timed out."),
+            _ => (0, "The service request is processed successfully."),
+        }
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = UnknownScenario;
+
+    fn from_str(name: &str) -> Result<Scenario, UnknownScenario> {
+        SCENARIOS.iter().find(|scenario| scenario.as_str() == name).cloned().ok_or_else(|| UnknownScenario(name.to_string()))
+    }
+}
+
+impl Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Display for UnknownScenario {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UnknownScenario -- {} is not a scenario", self.0)
+    }
+}
+
+impl Error for UnknownScenario {
+    fn description(&self) -> &str {
+        "unknown mock scenario"
+    }
+}
@@ -0,0 +1,205 @@
+//! Amounts of money in Kenyan shillings
+//!
+//! Most APIs only accept whole shillings, within limits that depend on the product:
+//!
+//! | Product     | Minimum | Maximum   |
+//! |-------------|---------|-----------|
+//! | STK push    | 1       | 250,000   |
+//! | B2C         | 10      | 250,000   |
+//! | C2B         | 1       | 250,000   |
+//! | B2B         | 1       |           |
+//!
+//! Requests with amounts outside those limits are rejected before they are sent, with the result code M-Pesa
+//! would have answered with. Amounts reported in callbacks may have cents, e.g. `"1.00"`, and are parsed as such.
+//!
+//! # Example
+//! ```
+//! # use mpesa::parameters::{Amount, ParameterError, ResultCodes};
+//! # use mpesa::api_products::Product;
+//! let amount: Amount = "1500.00".parse().unwrap();
+//! assert_eq!(amount, Amount::shillings(1500));
+//! assert_eq!(amount.to_string(), "1500");
+//!
+//! match Amount::shillings(5).check(Product::B2C) {
+//!     Err(ParameterError::AmountOutOfRange { result_code, .. }) => {
+//!         assert_eq!(result_code, ResultCodes::LessThanMinimumTransactionValue)
+//!     },
+//!     other => panic!("{:?}", other),
+//! }
+//! ```
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use api_products::Product;
+use super::{ParameterError, ResultCodes};
+
+/// Cents in a shilling
+const CENTS: u64 = 100;
+
+/// The most that can be paid with an STK push
+pub const STK_MAXIMUM: u64 = 250_000;
+
+/// The least that can be sent to a customer
+pub const B2C_MINIMUM: u64 = 10;
+
+/// The most that can be sent to a customer
+pub const B2C_MAXIMUM: u64 = 250_000;
+
+/// The most a customer can pay to a shortcode
+pub const C2B_MAXIMUM: u64 = 250_000;
+
+/// An amount of money, held in cents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount {
+    cents: u64,
+}
+
+impl Amount {
+    /// An amount of whole shillings. Amounts too large to count in cents are capped, so every product rejects them
+    pub fn shillings(shillings: u64) -> Amount {
+        let cents = shillings.saturating_mul(CENTS);
+        Amount { cents: cents - cents % CENTS }
+    }
+
+    /// The amount in cents
+    pub fn cents(&self) -> u64 {
+        self.cents
+    }
+
+    /// Whether the amount has no cents
+    pub fn is_whole(&self) -> bool {
+        self.cents % CENTS == 0
+    }
+
+    /// The amount in whole shillings, rounding cents down
+    pub fn whole_shillings(&self) -> u64 {
+        self.cents / CENTS
+    }
+
+    /// Reads an amount from a callback, where it may be a number or a string
+    pub fn from_value(value: &Value) -> Option<Amount> {
+        match value {
+            &Value::String(ref amount) => amount.parse().ok(),
+            &Value::Number(ref amount) => amount.to_string().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The least and, if there is one, the most that can be sent with `product`
+    pub fn limits(product: Product) -> (Amount, Option<Amount>) {
+        match product {
+            Product::StkPush => (Amount::shillings(1), Some(Amount::shillings(STK_MAXIMUM))),
+            Product::B2C => (Amount::shillings(B2C_MINIMUM), Some(Amount::shillings(B2C_MAXIMUM))),
+            Product::C2BSimulate => (Amount::shillings(1), Some(Amount::shillings(C2B_MAXIMUM))),
+            _ => (Amount::shillings(1), None),
+        }
+    }
+
+    /// Checks that the amount is in whole shillings and within the limits of `product`
+    pub fn check(&self, product: Product) -> Result<(), ParameterError> {
+        if !self.is_whole() {
+            return Err(ParameterError::Amount(self.to_string()));
+        }
+
+        let (minimum, maximum) = Amount::limits(product);
+        if *self < minimum {
+            return Err(ParameterError::AmountOutOfRange {
+                amount: *self,
+                result_code: ResultCodes::LessThanMinimumTransactionValue,
+            });
+        }
+        match maximum {
+            Some(maximum) if *self > maximum => Err(ParameterError::AmountOutOfRange {
+                amount: *self,
+                result_code: ResultCodes::MoreThanMaximumTransactionValue,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ParameterError;
+
+    /// Parses whole shillings, e.g. `100`, or shillings and cents, e.g. `100.5` or `100.50`
+    fn from_str(s: &str) -> Result<Amount, ParameterError> {
+        let invalid = || ParameterError::Amount(s.to_string());
+        let s = s.trim();
+        let (shillings, cents) = match s.find('.') {
+            Some(point) => (&s[..point], &s[point + 1..]),
+            None => (s, ""),
+        };
+        if shillings.is_empty() || !shillings.chars().all(|c| c.is_ascii_digit()) ||
+            cents.len() > 2 || !cents.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let shillings: u64 = shillings.parse().map_err(|_| invalid())?;
+        let cents: u64 = match cents.len() {
+            0 => 0,
+            1 => cents.parse::<u64>().map_err(|_| invalid())? * 10,
+            _ => cents.parse().map_err(|_| invalid())?,
+        };
+        shillings.checked_mul(CENTS)
+            .and_then(|total| total.checked_add(cents))
+            .map(|cents| Amount { cents: cents })
+            .ok_or_else(invalid)
+    }
+}
+
+impl Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_whole() {
+            write!(f, "{}", self.whole_shillings())
+        } else {
+            write!(f, "{}.{:02}", self.whole_shillings(), self.cents % CENTS)
+        }
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Amount, D::Error> {
+        deserializer.deserialize_any(AmountVisitor)
+    }
+}
+
+/// Reads amounts sent either as numbers or as strings
+struct AmountVisitor;
+
+impl<'de> Visitor<'de> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an amount of money")
+    }
+
+    fn visit_u64<E: de::Error>(self, amount: u64) -> Result<Amount, E> {
+        amount.checked_mul(CENTS).map(|cents| Amount { cents: cents }).ok_or_else(|| E::custom("the amount is too large"))
+    }
+
+    fn visit_i64<E: de::Error>(self, amount: i64) -> Result<Amount, E> {
+        if amount < 0 {
+            return Err(E::custom(ParameterError::Amount(amount.to_string())));
+        }
+        self.visit_u64(amount as u64)
+    }
+
+    fn visit_f64<E: de::Error>(self, amount: f64) -> Result<Amount, E> {
+        format!("{:.2}", amount).parse().map_err(E::custom)
+    }
+
+    fn visit_str<E: de::Error>(self, amount: &str) -> Result<Amount, E> {
+        amount.parse().map_err(E::custom)
+    }
+}
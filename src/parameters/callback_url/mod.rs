@@ -0,0 +1,115 @@
+//! Validation of the urls M-Pesa posts callbacks to
+//!
+//! M-Pesa silently drops callbacks to urls it does not like, so a misconfigured `ResultURL` only shows up as results
+//! that never arrive. The rules are checked before a request is sent instead:
+//! * the url must be an absolute `http` or `https` url
+//! * production urls must use `https`
+//! * production urls must name a host rather than an IP address
+//! * no url may contain the keywords `mpesa`, `safaricom`, `exec` or `cmd`, in any case
+//!
+//! # Example
+//! ```
+//! # use mpesa::parameters::{check_callback_url, Environment, UrlRule};
+//! assert_eq!(check_callback_url("https://example.com/callbacks/result", Environment::Production), Ok(()));
+//! assert_eq!(check_callback_url("http://example.com/callbacks/result", Environment::Production), Err(UrlRule::Https));
+//! assert_eq!(check_callback_url("http://example.com/callbacks/result", Environment::Sandbox), Ok(()));
+//! assert_eq!(check_callback_url("https://example.com/mpesa/result", Environment::Sandbox), Err(UrlRule::Keyword("mpesa")));
+//! ```
+
+use std::fmt::{self, Display};
+use std::net::Ipv4Addr;
+
+use super::ParameterError;
+
+/// Keywords M-Pesa rejects callback urls for
+pub const FORBIDDEN_KEYWORDS: &[&str] = &["mpesa", "safaricom", "exec", "cmd"];
+
+/// The M-Pesa environment requests are sent to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    /// The sandbox (testing) environment
+    Sandbox,
+    /// The production environment
+    Production,
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::Sandbox
+    }
+}
+
+/// A rule a callback url breaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlRule {
+    /// The url is not an absolute `http` or `https` url
+    Malformed,
+    /// The url does not use `https` in production
+    Https,
+    /// The url names an IP address rather than a host in production
+    IpLiteral,
+    /// The url contains a forbidden keyword
+    Keyword(&'static str),
+}
+
+/// Checks `url` against the rules of `environment`, returning the first rule it breaks
+pub fn check_callback_url(url: &str, environment: Environment) -> Result<(), UrlRule> {
+    let lowercase = url.trim().to_lowercase();
+    let (scheme, rest) = match lowercase.find("://") {
+        Some(separator) => (&lowercase[..separator], &lowercase[separator + 3..]),
+        None => return Err(UrlRule::Malformed),
+    };
+    if scheme != "http" && scheme != "https" {
+        return Err(UrlRule::Malformed);
+    }
+
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+    let host = if authority.starts_with('[') {
+        // IPv6 literals are enclosed in brackets
+        match authority.find(']') {
+            Some(end) => &authority[..end + 1],
+            None => return Err(UrlRule::Malformed),
+        }
+    } else {
+        authority.split(':').next().unwrap_or("")
+    };
+    if host.is_empty() || authority.contains('@') || url.chars().any(char::is_whitespace) {
+        return Err(UrlRule::Malformed);
+    }
+
+    if environment == Environment::Production {
+        if scheme != "https" {
+            return Err(UrlRule::Https);
+        }
+        if host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok() {
+            return Err(UrlRule::IpLiteral);
+        }
+    }
+    match FORBIDDEN_KEYWORDS.iter().find(|keyword| lowercase.contains(*keyword)) {
+        Some(keyword) => Err(UrlRule::Keyword(keyword)),
+        None => Ok(()),
+    }
+}
+
+/// Checks the callback urls of a request, naming the field of the first one that breaks a rule
+pub fn check_callback_urls(urls: &[(&str, &str)], environment: Environment) -> Result<(), ParameterError> {
+    for &(field, url) in urls {
+        check_callback_url(url, environment).map_err(|rule| ParameterError::CallbackUrl {
+            field: field.to_string(),
+            url: url.to_string(),
+            rule: rule,
+        })?;
+    }
+    Ok(())
+}
+
+impl Display for UrlRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &UrlRule::Malformed => write!(f, "it is not an absolute http or https url"),
+            &UrlRule::Https => write!(f, "production urls must use https"),
+            &UrlRule::IpLiteral => write!(f, "production urls must name a host rather than an IP address"),
+            &UrlRule::Keyword(keyword) => write!(f, "it contains the forbidden keyword \"{}\"", keyword),
+        }
+    }
+}
@@ -2,7 +2,24 @@
 //! and handling responses received.
 //! The enum variants should always be used with the `to_String()` method or `format!()` macro so as to get the correct value as defined in the [official Mpesa Api Documenation](https://developer.safaricom.com)
 
+pub mod amount;
+pub mod callback_url;
+pub mod msisdn;
+pub mod party;
+
+use std::error::Error;
 use std::fmt::{Display, self};
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+
+use api_products::Product;
+
+pub use self::amount::Amount;
+pub use self::callback_url::{check_callback_url, check_callback_urls, Environment, UrlRule};
+pub use self::msisdn::Msisdn;
+pub use self::party::{Party, Paybill, ShortCode, TillNumber};
 
 
 /// Reprersenattion of the `Command Ids` used to identify the various API Products to be invoked
@@ -17,7 +34,16 @@ use std::fmt::{Display, self};
 /// 
 /// assert_eq!(String::from("TransactionReversal"), command);
 /// ```
-#[derive(Debug)]
+///
+/// Command ids can be parsed back and checked against the product they are sent with
+/// ```
+/// # use mpesa::parameters::*;
+/// # use mpesa::api_products::Product;
+///
+/// assert_eq!(CommandIds::check("SalaryPayment", Product::B2C), Ok(CommandIds::SalaryPayment));
+/// assert!(CommandIds::check("TransactionReversal", Product::B2C).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CommandIds {
     /// Reversal for an erroneous C2B transaction
     TransactionReversal,
@@ -44,7 +70,97 @@ pub enum CommandIds {
     /// Transferring funds from one paybills MMF to another paybills MMF account
     BusinessToBusinessTransfer,
     /// Transferring funds from paybills MMF to another paybills utility account.
-    BusinessTransferFromMMFToUtility
+    BusinessTransferFromMMFToUtility,
+    /// Used to simulate a payment to a till number or to initiate one on behalf of the customer (STK Push)
+    CustomerBuyGoodsOnline,
+    /// Transferring funds from one merchant till to another
+    MerchantToMerchantTransfer,
+    /// Transferring funds from a merchant account to its working account
+    MerchantTransferFromMerchantToWorking,
+    /// Transferring funds from a merchant account to its MMF account
+    MerchantServicesMMFAccountTransfer,
+    /// Advancing float to an agent
+    AgencyFloatAdvance,
+    /// Sending funds from a paybill to a bulk payment account
+    BusinessPayToBulk,
+}
+
+const COMMAND_IDS: &[CommandIds] = &[
+    CommandIds::TransactionReversal,
+    CommandIds::SalaryPayment,
+    CommandIds::BusinessPayment,
+    CommandIds::PromotionPayment,
+    CommandIds::AccountBalance,
+    CommandIds::CustomerPayBillOnline,
+    CommandIds::TransactionStatusQuery,
+    CommandIds::CheckIdentity,
+    CommandIds::BusinessPayBill,
+    CommandIds::BusinessBuyGoods,
+    CommandIds::DisburseFundsToBusiness,
+    CommandIds::BusinessToBusinessTransfer,
+    CommandIds::BusinessTransferFromMMFToUtility,
+    CommandIds::CustomerBuyGoodsOnline,
+    CommandIds::MerchantToMerchantTransfer,
+    CommandIds::MerchantTransferFromMerchantToWorking,
+    CommandIds::MerchantServicesMMFAccountTransfer,
+    CommandIds::AgencyFloatAdvance,
+    CommandIds::BusinessPayToBulk,
+];
+
+impl CommandIds {
+    /// Whether the command can be sent with `product`
+    pub fn is_compatible(&self, product: Product) -> bool {
+        use self::CommandIds::*;
+
+        match (product, *self) {
+            (Product::B2C, SalaryPayment) | (Product::B2C, BusinessPayment) | (Product::B2C, PromotionPayment) => true,
+            (Product::B2B, BusinessPayBill) | (Product::B2B, BusinessBuyGoods) | (Product::B2B, DisburseFundsToBusiness) |
+            (Product::B2B, BusinessToBusinessTransfer) | (Product::B2B, BusinessTransferFromMMFToUtility) |
+            (Product::B2B, MerchantToMerchantTransfer) | (Product::B2B, MerchantTransferFromMerchantToWorking) |
+            (Product::B2B, MerchantServicesMMFAccountTransfer) | (Product::B2B, AgencyFloatAdvance) |
+            (Product::B2B, BusinessPayToBulk) => true,
+            (Product::StkPush, CustomerPayBillOnline) | (Product::StkPush, CustomerBuyGoodsOnline) => true,
+            (Product::C2BSimulate, CustomerPayBillOnline) | (Product::C2BSimulate, CustomerBuyGoodsOnline) => true,
+            (Product::Reversal, TransactionReversal) => true,
+            (Product::TransactionStatus, TransactionStatusQuery) => true,
+            (Product::AccountBalance, AccountBalance) => true,
+            _ => false,
+        }
+    }
+
+    /// Parses `command_id` and checks that it can be sent with `product`
+    pub fn check(command_id: &str, product: Product) -> Result<CommandIds, ParameterError> {
+        let command: CommandIds = command_id.parse()?;
+        if command.is_compatible(product) {
+            Ok(command)
+        } else {
+            Err(ParameterError::IncompatibleCommand { command: command, product: product })
+        }
+    }
+}
+
+impl FromStr for CommandIds {
+    type Err = ParameterError;
+
+    fn from_str(s: &str) -> Result<CommandIds, ParameterError> {
+        COMMAND_IDS.iter()
+            .find(|command| command.to_string() == s.trim())
+            .cloned()
+            .ok_or_else(|| ParameterError::CommandId(s.to_string()))
+    }
+}
+
+impl Serialize for CommandIds {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandIds {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CommandIds, D::Error> {
+        let command = String::deserialize(deserializer)?;
+        command.parse().map_err(de::Error::custom)
+    }
 }
 
 /// Identifier types - both sender and receiver - identify an M-Pesa transaction’s sending and receiving party as either
@@ -59,8 +175,7 @@ pub enum CommandIds {
 /// 
 /// assert_eq!(String::from("4"), identifier);
 /// ```
-#[derive(Debug)]
-#[derive(Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Identifiers {
     MSISDN = 1,
     TillNumber,
@@ -68,7 +183,10 @@ pub enum Identifiers {
 }
 
 /// Representation of reponses expected from the API after a call.
-/// 
+///
+/// Covers the result codes of the B2C, B2B, reversal, balance and status products, the STK push results and the
+/// `C2B000xx` codes a validation URL rejects C2B payments with. Codes not listed here are kept as `Unknown`.
+///
 /// The enum implememts the `Display` trait so you can use the `toString()` method on its variants or use the `format!() macro`
 /// # Example
 /// ```
@@ -78,10 +196,21 @@ pub enum Identifiers {
 /// 
 /// assert_eq!(String::from("15"), result_code);
 /// ```
-#[derive(Debug)]
-#[derive(Clone)]
+///
+/// Codes can be read back from their numeric or string form and classified
+/// ```
+/// # use mpesa::parameters::*;
+///
+/// let result_code: ResultCodes = "1032".parse().unwrap();
+///
+/// assert_eq!(ResultCodes::RequestCancelledByUser, result_code);
+/// assert_eq!(ResultClass::UserCaused, result_code.class());
+/// assert_eq!(ResultCodes::Unknown(42), ResultCodes::from(42));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ResultCodes {
     Success,
+    /// 1 - also reported when the customer has too little money for an STK push
     InsufficientFunds,
     LessThanMinimumTransactionValue,
     MoreThanMaximumTransactionValue,
@@ -90,15 +219,214 @@ pub enum ResultCodes {
     UnresolvedPrimaryParty,
     UnresolvedReceiverParty,
     WouldExceedMaximumBalance,
-    DebitAccountInvalid = 11,
+    DebitAccountInvalid,
     CreditAccountInvalid,
     UnresolvedDebitAccount,
     UnresolvedCreditAccount,
     DuplicateDetected,
-    InternalFailure = 17,
-    UnresolvedInitiator = 20,
-    TrafficBlockingConditionInPlace = 26
+    InternalFailure,
+    UnresolvedInitiator,
+    TrafficBlockingConditionInPlace,
+    /// 1001 - another transaction is already in process for the subscriber
+    SubscriberLocked,
+    /// 1019 - the transaction expired before the customer responded
+    TransactionExpired,
+    /// 1025 - the push request could not be sent
+    PushRequestFailed,
+    /// 1032 - the customer cancelled the STK push
+    RequestCancelledByUser,
+    /// 1037 - the customer's phone could not be reached
+    SubscriberUnreachable,
+    /// 2001 - the initiator information is invalid, e.g. the customer entered the wrong PIN
+    InvalidInitiatorInformation,
+    /// 9999 - an error occurred while sending the push request
+    PushRequestError,
+    /// C2B00011
+    InvalidMsisdn,
+    /// C2B00012
+    InvalidAccountNumber,
+    /// C2B00013
+    InvalidAmount,
+    /// C2B00014
+    InvalidKycDetails,
+    /// C2B00015
+    InvalidShortcode,
+    /// C2B00016
+    OtherError,
+    /// A numeric code not listed above
+    Unknown(i64),
+}
+
+/// How a `ResultCodes` should be acted upon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultClass {
+    /// The transaction went through
+    Success,
+    /// The customer caused the failure, e.g. by cancelling or having too little money. It may be asked again
+    UserCaused,
+    /// The failure is temporary. The same request may be sent again later
+    Retryable,
+    /// The request will keep failing until it is changed
+    Permanent,
+}
 
+/// A parameter could not be read or is not valid where it is used
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterError {
+    /// Not a known result code
+    ResultCode(String),
+    /// Not a known command id
+    CommandId(String),
+    /// The command id cannot be used with the API product
+    IncompatibleCommand { command: CommandIds, product: Product },
+    /// Not a Safaricom phone number
+    Msisdn(String),
+    /// Not an amount of money, or not in whole shillings where they are expected
+    Amount(String),
+    /// The amount is outside the limits of the product. Holds the result code M-Pesa would reject it with
+    AmountOutOfRange { amount: Amount, result_code: ResultCodes },
+    /// Not a shortcode
+    ShortCode(String),
+    /// Not a till number
+    TillNumber(String),
+    /// Not a paybill number
+    Paybill(String),
+    /// The party cannot be used where it is, or does not match the identifier type sent along with it
+    Party { party: String, identifier: Identifiers },
+    /// The callback url in `field` breaks `rule`
+    CallbackUrl { field: String, url: String, rule: UrlRule },
+}
+
+const RESULT_CODES: &[(ResultCodes, i64)] = &[
+    (ResultCodes::Success, 0),
+    (ResultCodes::InsufficientFunds, 1),
+    (ResultCodes::LessThanMinimumTransactionValue, 2),
+    (ResultCodes::MoreThanMaximumTransactionValue, 3),
+    (ResultCodes::WouldExceedDailyTransferLimit, 4),
+    (ResultCodes::WouldExceedMinimumBalance, 5),
+    (ResultCodes::UnresolvedPrimaryParty, 6),
+    (ResultCodes::UnresolvedReceiverParty, 7),
+    (ResultCodes::WouldExceedMaximumBalance, 8),
+    (ResultCodes::DebitAccountInvalid, 11),
+    (ResultCodes::CreditAccountInvalid, 12),
+    (ResultCodes::UnresolvedDebitAccount, 13),
+    (ResultCodes::UnresolvedCreditAccount, 14),
+    (ResultCodes::DuplicateDetected, 15),
+    (ResultCodes::InternalFailure, 17),
+    (ResultCodes::UnresolvedInitiator, 20),
+    (ResultCodes::TrafficBlockingConditionInPlace, 26),
+    (ResultCodes::SubscriberLocked, 1001),
+    (ResultCodes::TransactionExpired, 1019),
+    (ResultCodes::PushRequestFailed, 1025),
+    (ResultCodes::RequestCancelledByUser, 1032),
+    (ResultCodes::SubscriberUnreachable, 1037),
+    (ResultCodes::InvalidInitiatorInformation, 2001),
+    (ResultCodes::PushRequestError, 9999),
+];
+
+const C2B_RESULT_CODES: &[(ResultCodes, &str)] = &[
+    (ResultCodes::InvalidMsisdn, "C2B00011"),
+    (ResultCodes::InvalidAccountNumber, "C2B00012"),
+    (ResultCodes::InvalidAmount, "C2B00013"),
+    (ResultCodes::InvalidKycDetails, "C2B00014"),
+    (ResultCodes::InvalidShortcode, "C2B00015"),
+    (ResultCodes::OtherError, "C2B00016"),
+];
+
+impl ResultCodes {
+    /// The numeric code, `None` for the `C2B000xx` codes
+    pub fn code(&self) -> Option<i64> {
+        if let &ResultCodes::Unknown(code) = self {
+            return Some(code);
+        }
+        RESULT_CODES.iter().find(|&&(result_code, _)| result_code == *self).map(|&(_, code)| code)
+    }
+
+    /// How the result should be acted upon
+    pub fn class(&self) -> ResultClass {
+        use self::ResultCodes::*;
+
+        match *self {
+            Success => ResultClass::Success,
+            InsufficientFunds | WouldExceedDailyTransferLimit | WouldExceedMaximumBalance |
+            RequestCancelledByUser | InvalidInitiatorInformation => ResultClass::UserCaused,
+            WouldExceedMinimumBalance | InternalFailure | TrafficBlockingConditionInPlace | SubscriberLocked |
+            TransactionExpired | PushRequestFailed | SubscriberUnreachable | PushRequestError => ResultClass::Retryable,
+            _ => ResultClass::Permanent,
+        }
+    }
+
+    /// Whether the transaction went through
+    pub fn is_success(&self) -> bool {
+        self.class() == ResultClass::Success
+    }
+
+    /// Whether the same request may be sent again later
+    pub fn is_retryable(&self) -> bool {
+        self.class() == ResultClass::Retryable
+    }
+}
+
+/// Never fails: codes missing from the catalogue become `ResultCodes::Unknown`
+impl From<i64> for ResultCodes {
+    fn from(code: i64) -> ResultCodes {
+        RESULT_CODES.iter()
+            .find(|&&(_, known)| known == code)
+            .map(|&(result_code, _)| result_code)
+            .unwrap_or(ResultCodes::Unknown(code))
+    }
+}
+
+impl FromStr for ResultCodes {
+    type Err = ParameterError;
+
+    fn from_str(s: &str) -> Result<ResultCodes, ParameterError> {
+        let s = s.trim();
+        if let Some(&(result_code, _)) = C2B_RESULT_CODES.iter().find(|&&(_, code)| code == s) {
+            return Ok(result_code);
+        }
+        s.parse::<i64>()
+            .map(ResultCodes::from)
+            .map_err(|_| ParameterError::ResultCode(s.to_string()))
+    }
+}
+
+impl Serialize for ResultCodes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.code() {
+            Some(code) => serializer.serialize_i64(code),
+            None => serializer.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ResultCodes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ResultCodes, D::Error> {
+        deserializer.deserialize_any(ResultCodeVisitor)
+    }
+}
+
+/// Reads result codes sent either as numbers or as strings, as the STK query does
+struct ResultCodeVisitor;
+
+impl<'de> Visitor<'de> for ResultCodeVisitor {
+    type Value = ResultCodes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a result code")
+    }
+
+    fn visit_i64<E: de::Error>(self, code: i64) -> Result<ResultCodes, E> {
+        Ok(ResultCodes::from(code))
+    }
+
+    fn visit_u64<E: de::Error>(self, code: u64) -> Result<ResultCodes, E> {
+        Ok(ResultCodes::from(code as i64))
+    }
+
+    fn visit_str<E: de::Error>(self, code: &str) -> Result<ResultCodes, E> {
+        code.parse().map_err(|error| E::custom(error))
+    }
 }
 
 /// Response codes are sent from the clients endpoints back to the gateway. This is done to acknowledge that the
@@ -147,6 +475,24 @@ pub enum MpesaRequestError {
     
 }
 
+impl MpesaRequestError {
+    /// Maps an HTTP status code returned by the API to the matching error, if any
+    pub fn from_status(status: u16) -> Option<MpesaRequestError> {
+        match status {
+            400 => Some(MpesaRequestError::BadRequest),
+            401 => Some(MpesaRequestError::Unauthorized),
+            403 => Some(MpesaRequestError::Forbidden),
+            404 => Some(MpesaRequestError::NotFound),
+            405 => Some(MpesaRequestError::MethodNotAllowed),
+            406 => Some(MpesaRequestError::NotAcceptable),
+            429 => Some(MpesaRequestError::TooManyRequests),
+            500 => Some(MpesaRequestError::InternalServerError),
+            503 => Some(MpesaRequestError::ServiceUnavailable),
+            _ => None,
+        }
+    }
+}
+
 impl Display for MpesaRequestError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     
@@ -182,8 +528,45 @@ impl Display for ResponseCodes {
 impl Display for ResultCodes {
 
    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-       let val = (*self).clone();
-        write!(f, "{}", val as usize )
+        match self.code() {
+            Some(code) => write!(f, "{}", code),
+            None => {
+                let code = C2B_RESULT_CODES.iter().find(|&&(result_code, _)| result_code == *self).map(|&(_, code)| code);
+                write!(f, "{}", code.unwrap_or_default())
+            },
+        }
+    }
+}
+
+impl Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ParameterError::ResultCode(ref code) => write!(f, "ParameterError::ResultCode -- {} is not a result code", code),
+            &ParameterError::CommandId(ref command) => write!(f, "ParameterError::CommandId -- {} is not a command id", command),
+            &ParameterError::IncompatibleCommand { command, product } => {
+                write!(f, "ParameterError::IncompatibleCommand -- {} cannot be used with {}", command, product.as_str())
+            },
+            &ParameterError::Msisdn(ref number) => write!(f, "ParameterError::Msisdn -- {} is not a Safaricom phone number", number),
+            &ParameterError::Amount(ref amount) => write!(f, "ParameterError::Amount -- {} is not an amount in whole shillings", amount),
+            &ParameterError::AmountOutOfRange { amount, result_code } => {
+                write!(f, "ParameterError::AmountOutOfRange -- {} is outside the limits of the product ({:?})", amount, result_code)
+            },
+            &ParameterError::ShortCode(ref number) => write!(f, "ParameterError::ShortCode -- {} is not a shortcode", number),
+            &ParameterError::TillNumber(ref number) => write!(f, "ParameterError::TillNumber -- {} is not a till number", number),
+            &ParameterError::Paybill(ref number) => write!(f, "ParameterError::Paybill -- {} is not a paybill number", number),
+            &ParameterError::Party { ref party, identifier } => {
+                write!(f, "ParameterError::Party -- {} cannot be used with identifier type {:?}", party, identifier)
+            },
+            &ParameterError::CallbackUrl { ref field, ref url, rule } => {
+                write!(f, "ParameterError::CallbackUrl -- the {} {} is rejected: {}", field, url, rule)
+            },
+        }
+    }
+}
+
+impl Error for ParameterError {
+    fn description(&self) -> &str {
+        "invalid parameter"
     }
 }
 
@@ -194,6 +577,12 @@ impl Display for Identifiers {
     }
 }
 
+impl Serialize for Identifiers {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl Display for CommandIds {
    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 
@@ -211,6 +600,12 @@ impl Display for CommandIds {
             &CommandIds::DisburseFundsToBusiness => write!(f, "DisburseFundsToBusiness"),
             &CommandIds::PromotionPayment => write!(f, "PromotionPayment"),
             &CommandIds::SalaryPayment => write!(f, "SalaryPayment"),
+            &CommandIds::CustomerBuyGoodsOnline => write!(f, "CustomerBuyGoodsOnline"),
+            &CommandIds::MerchantToMerchantTransfer => write!(f, "MerchantToMerchantTransfer"),
+            &CommandIds::MerchantTransferFromMerchantToWorking => write!(f, "MerchantTransferFromMerchantToWorking"),
+            &CommandIds::MerchantServicesMMFAccountTransfer => write!(f, "MerchantServicesMMFAccountTransfer"),
+            &CommandIds::AgencyFloatAdvance => write!(f, "AgencyFloatAdvance"),
+            &CommandIds::BusinessPayToBulk => write!(f, "BusinessPayToBulk"),
             
         }
     }
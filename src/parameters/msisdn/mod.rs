@@ -0,0 +1,92 @@
+//! Validated phone numbers
+//!
+//! M-Pesa expects phone numbers in the international format without the plus sign, e.g. `254708374149`.
+//! `Msisdn` accepts the ways Kenyan numbers are usually written and normalizes them to that format:
+//! * `0708374149` and `0110374149`
+//! * `+254708374149` and `254708374149`
+//! * any of the above with spaces or dashes, e.g. `0708 374 149` or `0708-374-149`
+//!
+//! Numbers outside the Safaricom ranges are rejected, and so are subscriber numbers without the country code or
+//! leading zero, e.g. `708374149`.
+//!
+//! # Example
+//! ```
+//! # use mpesa::parameters::Msisdn;
+//! let msisdn: Msisdn = "+254 708-374-149".parse().unwrap();
+//!
+//! assert_eq!(msisdn.as_str(), "254708374149");
+//! assert!(Msisdn::new("0208374149").is_err());
+//! ```
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+
+use super::ParameterError;
+
+/// A number as it may be written, capturing the subscriber number after the country code or leading zero.
+/// One of them is required: a bare subscriber number is as likely to be a mistyped number as a Kenyan one
+const MSISDN_PATTERN: &str = r"^(?:\+?254|0)([17]\d{8})$";
+
+/// The subscriber number ranges allocated to Safaricom
+const SAFARICOM_PATTERN: &str = r"^(?:7(?:[0-2]\d|4[0-68]|5[7-9]|6[89]|9\d)|11[0-5])\d{6}$";
+
+lazy_static! {
+    static ref MSISDN: Regex = Regex::new(MSISDN_PATTERN).expect("the MSISDN pattern is valid");
+    static ref SAFARICOM: Regex = Regex::new(SAFARICOM_PATTERN).expect("the Safaricom pattern is valid");
+}
+
+/// Country code of Kenya
+const COUNTRY_CODE: &str = "254";
+
+/// A Safaricom phone number in the format `2547XXXXXXXX` or `2541XXXXXXXX`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Msisdn(String);
+
+impl Msisdn {
+    /// Validates and normalizes `number`
+    pub fn new(number: &str) -> Result<Msisdn, ParameterError> {
+        let stripped: String = number.chars().filter(|c| !c.is_whitespace() && *c != '-').collect();
+        match MSISDN.captures(&stripped).and_then(|captures| captures.get(1)) {
+            Some(subscriber) if SAFARICOM.is_match(subscriber.as_str()) => {
+                Ok(Msisdn(format!("{}{}", COUNTRY_CODE, subscriber.as_str())))
+            },
+            _ => Err(ParameterError::Msisdn(number.to_string())),
+        }
+    }
+
+    /// The normalized number
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Msisdn {
+    type Err = ParameterError;
+
+    fn from_str(s: &str) -> Result<Msisdn, ParameterError> {
+        Msisdn::new(s)
+    }
+}
+
+impl Display for Msisdn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Serialize for Msisdn {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Msisdn {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Msisdn, D::Error> {
+        let number = String::deserialize(deserializer)?;
+        Msisdn::new(&number).map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,188 @@
+//! Validated identifiers of the parties to a transaction
+//!
+//! Businesses are identified by a `ShortCode`, a `Paybill` or a `TillNumber`, customers by an `Msisdn`.
+//! Where a request has to state what kind of party it names, e.g. the `RecieverIdentifierType` of a B2B payment,
+//! it is derived from the party through `Party::identifier()` rather than filled in by hand.
+//!
+//! # Example
+//! ```
+//! # use mpesa::parameters::{Identifiers, Party, TillNumber};
+//! let till: TillNumber = "5123456".parse().unwrap();
+//! let party = Party::from(till);
+//!
+//! assert_eq!(party.identifier(), Identifiers::TillNumber);
+//! assert_eq!(party.to_string(), "5123456");
+//! assert!("60-000".parse::<TillNumber>().is_err());
+//! ```
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use regex::Regex;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+
+use super::{Identifiers, Msisdn, ParameterError};
+
+/// Shortcodes, paybills and till numbers are 5 to 7 digits long and never start with a zero
+const BUSINESS_NUMBER_PATTERN: &str = r"^[1-9]\d{4,6}$";
+
+lazy_static! {
+    static ref BUSINESS_NUMBER: Regex = Regex::new(BUSINESS_NUMBER_PATTERN).expect("the business number pattern is valid");
+}
+
+/// Checks that `number` looks like a business number
+fn business_number(number: &str) -> Option<String> {
+    let number = number.trim();
+    if BUSINESS_NUMBER.is_match(number) {
+        Some(number.to_string())
+    } else {
+        None
+    }
+}
+
+macro_rules! business_number {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub struct $name(String);
+
+        impl $name {
+            /// Validates `number`
+            pub fn new(number: &str) -> Result<$name, ParameterError> {
+                business_number(number).map($name).ok_or_else(|| ParameterError::$name(number.to_string()))
+            }
+
+            /// The number as sent to M-Pesa
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParameterError;
+
+            fn from_str(s: &str) -> Result<$name, ParameterError> {
+                $name::new(s)
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.0)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                let number = String::deserialize(deserializer)?;
+                $name::new(&number).map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+business_number! {
+    /// The shortcode of an organization, e.g. the one B2C payments are made from
+    ShortCode
+}
+
+business_number! {
+    /// A Buy Goods till number
+    TillNumber
+}
+
+business_number! {
+    /// A Pay Bill number
+    Paybill
+}
+
+/// Any party to a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Party {
+    /// A customer
+    Msisdn(Msisdn),
+    /// An organization
+    ShortCode(ShortCode),
+    /// A Buy Goods till
+    TillNumber(TillNumber),
+    /// A Pay Bill account
+    Paybill(Paybill),
+}
+
+impl Party {
+    /// The identifier type M-Pesa expects alongside the party
+    pub fn identifier(&self) -> Identifiers {
+        match self {
+            &Party::Msisdn(_) => Identifiers::MSISDN,
+            &Party::TillNumber(_) => Identifiers::TillNumber,
+            &Party::ShortCode(_) | &Party::Paybill(_) => Identifiers::ShortCode,
+        }
+    }
+
+    /// Whether the party is a business rather than a customer
+    pub fn is_business(&self) -> bool {
+        self.identifier() != Identifiers::MSISDN
+    }
+
+    /// Checks that `identifier` is the identifier type of the party
+    pub fn check(&self, identifier: Identifiers) -> Result<(), ParameterError> {
+        if self.identifier() == identifier {
+            Ok(())
+        } else {
+            Err(ParameterError::Party { party: self.to_string(), identifier: identifier })
+        }
+    }
+
+    /// The number of the party as sent to M-Pesa
+    pub fn as_str(&self) -> &str {
+        match self {
+            &Party::Msisdn(ref msisdn) => msisdn.as_str(),
+            &Party::ShortCode(ref short_code) => short_code.as_str(),
+            &Party::TillNumber(ref till_number) => till_number.as_str(),
+            &Party::Paybill(ref paybill) => paybill.as_str(),
+        }
+    }
+}
+
+impl From<Msisdn> for Party {
+    fn from(msisdn: Msisdn) -> Party {
+        Party::Msisdn(msisdn)
+    }
+}
+
+impl From<ShortCode> for Party {
+    fn from(short_code: ShortCode) -> Party {
+        Party::ShortCode(short_code)
+    }
+}
+
+impl From<TillNumber> for Party {
+    fn from(till_number: TillNumber) -> Party {
+        Party::TillNumber(till_number)
+    }
+}
+
+impl From<Paybill> for Party {
+    fn from(paybill: Paybill) -> Party {
+        Party::Paybill(paybill)
+    }
+}
+
+impl Display for Party {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Serialize for Party {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
@@ -0,0 +1,312 @@
+//! Resolution of transactions whose outcome never arrived
+//!
+//! When the callback of a transaction never arrives, money may or may not have moved.
+//! A `Reconciler` periodically scans the `TransactionStore` for transactions that have been stuck waiting for their
+//! outcome for too long and looks them up:
+//! * STK pushes are looked up with an STK query on their `CheckoutRequestID`. Results that are not final, e.g. a
+//!   customer who could not be reached (`1037`) or did not answer in time (`1019`), leave the push pending
+//! * B2C and B2B payments and reversals are looked up with a Transaction Status query on their receipt or
+//!   `OriginatorConversationID`
+//!
+//! Lookups are spaced out to stay within the rate limits of the API. Transactions that cannot be resolved after
+//! a number of lookups are moved to `Escalated`, to be resolved by hand, and are not looked up again.
+//!
+//! The Transaction Status results arrive on the `ResultURL`, so the client given to the `Reconciler` must have a
+//! `Correlator` that is installed on the callback server.
+//!
+//! # Example
+//! ```no_run
+//! # extern crate mpesa;
+//! # use mpesa::callbacks::verify::VerificationConfig;
+//! # use mpesa::client::MpesaClient;
+//! # use mpesa::reconciliation::Reconciler;
+//! # use mpesa::store::TransactionStore;
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//! # fn main() {
+//! # let client: MpesaClient = unimplemented!();
+//! # let store: Arc<dyn TransactionStore> = unimplemented!();
+//! # let lookup: VerificationConfig = unimplemented!();
+//! Reconciler::new(client, store, lookup)
+//!     .stale_after(Duration::from_secs(15 * 60))
+//!     .on_escalation(|transaction| eprintln!("transaction {} needs to be looked into", transaction.id))
+//!     .spawn();
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{self, Utc};
+
+use api_products::lipa_na_mpesa_online_query_request::LipaNaMpesaOnlineQueryRequest;
+use callbacks::guard::AUDIT_TARGET;
+use callbacks::verify::VerificationConfig;
+use client::{MpesaClient, Resolution};
+use parameters::{ResultClass, ResultCodes};
+use store::{self, Product, StoreError, Transaction, TransactionState, TransactionStore};
+
+/// The states of transactions whose outcome is awaited
+const STUCK_STATES: &[TransactionState] = &[TransactionState::Submitted, TransactionState::Acknowledged, TransactionState::Unknown];
+
+/// The products whose transactions move money
+const RECONCILED_PRODUCTS: &[Product] = &[Product::B2C, Product::B2B, Product::StkPush, Product::Reversal];
+
+/// How long a transaction may wait for its outcome before it is looked up
+pub const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// How long to wait between scans of the store
+pub const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The least time between two lookups
+pub const DEFAULT_QUERY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How many lookups may fail to resolve a transaction before it is escalated
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// What a single scan of the store achieved
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReconciliationReport {
+    /// Transactions whose outcome was found
+    pub resolved: usize,
+    /// Transactions that could not be resolved this time
+    pub pending: usize,
+    /// Transactions given up on and escalated
+    pub escalated: usize,
+}
+
+/// The result of looking a transaction up
+enum Lookup {
+    /// The outcome of the transaction was found
+    Resolved { state: TransactionState, result_code: Option<i64>, result_desc: String, receipt: Option<String> },
+    /// The lookup failed or the outcome is not decided yet
+    Pending(String),
+    /// The transaction cannot be looked up at all
+    Impossible(String),
+}
+
+/// A background worker resolving stuck transactions
+pub struct Reconciler {
+    client: MpesaClient,
+    store: Arc<dyn TransactionStore>,
+    lookup: VerificationConfig,
+    stale_after: Duration,
+    scan_interval: Duration,
+    query_interval: Duration,
+    max_attempts: u32,
+    escalation: Option<Box<dyn Fn(&Transaction) + Send>>,
+    attempts: HashMap<String, u32>,
+    last_query: Option<Instant>,
+}
+
+impl Reconciler {
+    /// Creates a new reconciler resolving the transactions in `store` with `client`.
+    /// `lookup` holds the details needed to make the queries.
+    pub fn new(client: MpesaClient, store: Arc<dyn TransactionStore>, lookup: VerificationConfig) -> Reconciler {
+        Reconciler {
+            client: client,
+            store: store,
+            lookup: lookup,
+            stale_after: DEFAULT_STALE_AFTER,
+            scan_interval: DEFAULT_SCAN_INTERVAL,
+            query_interval: DEFAULT_QUERY_INTERVAL,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            escalation: None,
+            attempts: HashMap::new(),
+            last_query: None,
+        }
+    }
+
+    /// How long a transaction may wait for its outcome before it is looked up
+    pub fn stale_after(mut self, stale_after: Duration) -> Reconciler {
+        self.stale_after = stale_after;
+        self
+    }
+
+    /// How long to wait between scans of the store
+    pub fn scan_interval(mut self, scan_interval: Duration) -> Reconciler {
+        self.scan_interval = scan_interval;
+        self
+    }
+
+    /// The least time between two lookups
+    pub fn query_interval(mut self, query_interval: Duration) -> Reconciler {
+        self.query_interval = query_interval;
+        self
+    }
+
+    /// How many lookups may fail to resolve a transaction before it is escalated
+    pub fn max_attempts(mut self, max_attempts: u32) -> Reconciler {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// Calls `escalation` with every transaction given up on
+    pub fn on_escalation<F: Fn(&Transaction) + Send + 'static>(mut self, escalation: F) -> Reconciler {
+        self.escalation = Some(Box::new(escalation));
+        self
+    }
+
+    /// Scans the store every `scan_interval` on a background thread
+    pub fn spawn(mut self) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            match self.run_once() {
+                Ok(report) => if report != ReconciliationReport::default() {
+                    info!("reconciled transactions: {:?}", report);
+                },
+                Err(error) => error!("unable to scan for stuck transactions: {}", error),
+            }
+            thread::sleep(self.scan_interval);
+        })
+    }
+
+    /// Looks up every transaction that has been stuck for longer than `stale_after`
+    pub fn run_once(&mut self) -> Result<ReconciliationReport, StoreError> {
+        let stale_after = chrono::Duration::from_std(self.stale_after).unwrap_or_else(|_| chrono::Duration::zero());
+        let stale = self.store.find_stale(STUCK_STATES, Utc::now().naive_utc() - stale_after)?;
+        let mut report = ReconciliationReport::default();
+
+        for transaction in stale {
+            if !RECONCILED_PRODUCTS.contains(&transaction.product) {
+                continue;
+            }
+
+            match self.look_up(&transaction) {
+                Lookup::Resolved { state, result_code, result_desc, receipt } => {
+                    self.attempts.remove(&transaction.id);
+                    self.resolve(&transaction.id, state, result_code, &result_desc, receipt)?;
+                    report.resolved += 1;
+                },
+                Lookup::Pending(reason) => {
+                    let attempts = {
+                        let attempts = self.attempts.entry(transaction.id.clone()).or_insert(0);
+                        *attempts += 1;
+                        *attempts
+                    };
+                    if attempts >= self.max_attempts {
+                        self.escalate(&transaction.id, &reason)?;
+                        report.escalated += 1;
+                    } else {
+                        debug!("transaction {} is still unresolved after {} lookups: {}", transaction.id, attempts, reason);
+                        report.pending += 1;
+                    }
+                },
+                Lookup::Impossible(reason) => {
+                    self.escalate(&transaction.id, &reason)?;
+                    report.escalated += 1;
+                },
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn look_up(&mut self, transaction: &Transaction) -> Lookup {
+        match transaction.product {
+            Product::StkPush => self.stk_query(transaction),
+            _ => self.transaction_status(transaction),
+        }
+    }
+
+    fn stk_query(&mut self, transaction: &Transaction) -> Lookup {
+        let query = match transaction.checkout_request_id {
+            Some(ref checkout_request_id) => {
+                LipaNaMpesaOnlineQueryRequest::new(&self.lookup.stk_short_code, &self.lookup.stk_passkey, checkout_request_id)
+            },
+            None => return Lookup::Impossible(String::from("the STK push has no CheckoutRequestID to look it up by")),
+        };
+
+        self.throttle();
+        // pushes that are still being processed are rejected with the error 500.001.1001
+        let response = match self.client.stk_query(&query) {
+            Ok(response) => response,
+            Err(error) => return Lookup::Pending(format!("{}", error)),
+        };
+        let result_code = match response.ResultCode.parse::<ResultCodes>() {
+            Ok(result_code) => result_code,
+            Err(_) => return Lookup::Pending(format!("unexpected ResultCode {}", response.ResultCode)),
+        };
+        let state = match result_code.class() {
+            ResultClass::Success => TransactionState::Completed,
+            // e.g. the customer could not be reached or did not answer in time, which is not a final answer
+            ResultClass::Retryable => return Lookup::Pending(format!("{} {}", response.ResultCode, response.ResultDesc)),
+            ResultClass::UserCaused | ResultClass::Permanent => TransactionState::Failed,
+        };
+        Lookup::Resolved {
+            state: state,
+            result_code: result_code.code(),
+            result_desc: response.ResultDesc,
+            receipt: None,
+        }
+    }
+
+    fn transaction_status(&mut self, transaction: &Transaction) -> Lookup {
+        let receipt = transaction.receipt.clone().unwrap_or_default();
+        let originator_conversation_id = transaction.originator_conversation_id.clone().unwrap_or_default();
+        if receipt.is_empty() && originator_conversation_id.is_empty() {
+            return Lookup::Impossible(String::from("the transaction has no receipt or OriginatorConversationID to look it up by"));
+        }
+
+        self.throttle();
+        match self.client.look_up(&receipt, &originator_conversation_id, &self.lookup) {
+            Resolution::Undetermined(reason) => Lookup::Pending(reason),
+            resolution => match resolution.state() {
+                Some(state) => Lookup::Resolved {
+                    state: state,
+                    result_code: resolution.result_code(),
+                    result_desc: resolution.description(),
+                    receipt: resolution.receipt(),
+                },
+                None => Lookup::Pending(resolution.description()),
+            },
+        }
+    }
+
+    /// Waits until `query_interval` has passed since the last lookup
+    fn throttle(&mut self) {
+        if let Some(last_query) = self.last_query {
+            let elapsed = last_query.elapsed();
+            if elapsed < self.query_interval {
+                thread::sleep(self.query_interval - elapsed);
+            }
+        }
+        self.last_query = Some(Instant::now());
+    }
+
+    /// Records the outcome found for a transaction. Callbacks that arrived in the meantime take precedence.
+    fn resolve(&self, id: &str, state: TransactionState, result_code: Option<i64>, result_desc: &str, receipt: Option<String>) -> Result<(), StoreError> {
+        match store::modify(&self.store, id, |transaction| transaction.conclude(state, result_code, result_desc, receipt.clone())) {
+            Ok(_) => Ok(()),
+            Err(StoreError::IllegalTransition(error)) => {
+                warn!("not recording the reconciled outcome of transaction {}: {}", id, error);
+                Ok(())
+            },
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Gives up on a transaction, moving it to `Escalated`
+    fn escalate(&mut self, id: &str, reason: &str) -> Result<(), StoreError> {
+        self.attempts.remove(id);
+
+        let transaction = match store::modify(&self.store, id, |transaction| transaction.transition(TransactionState::Escalated)) {
+            Ok(Some(transaction)) => transaction,
+            Ok(None) => return Ok(()),
+            // the outcome arrived in the meantime
+            Err(StoreError::IllegalTransition(error)) => {
+                info!("not escalating transaction {}: {}", id, error);
+                return Ok(());
+            },
+            Err(error) => return Err(error),
+        };
+
+        warn!(target: AUDIT_TARGET, "the outcome of {} transaction {} could not be determined: {}",
+              transaction.product.as_str(), transaction.id, reason);
+        if let Some(ref escalation) = self.escalation {
+            escalation(&transaction);
+        }
+        Ok(())
+    }
+}
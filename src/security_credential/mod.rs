@@ -0,0 +1,86 @@
+//! Generation of the security credential of the API operator
+//!
+//! Requests made on behalf of an API operator (B2C, B2B, reversal, transaction status and account balance) carry a
+//! `SecurityCredential`: the password of the operator encrypted with the public key of the M-Pesa certificate of the
+//! environment, using RSA with PKCS #1 v1.5 padding, then base64 encoded.
+//!
+//! Safaricom publishes one certificate for the sandbox and one for production, both downloadable from the Daraja
+//! portal. A credential encrypted with the certificate of the other environment is rejected.
+//!
+//! # Example
+//! ```no_run
+//! # use std::path::Path;
+//! # use mpesa::security_credential::Certificate;
+//! let certificate = Certificate::from_file(Path::new("certs/sandbox.cer")).unwrap();
+//! let security_credential = certificate.encrypt("Safaricom999!*!").unwrap();
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use base64;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Padding;
+use openssl::x509::X509;
+
+/// The public key of an M-Pesa certificate
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    key: PKey<Public>,
+}
+
+/// Definition of possible errors when generating a security credential
+#[derive(Debug)]
+pub enum CredentialError {
+    /// The certificate file could not be read. Holds its path
+    Io(PathBuf, io::Error),
+    /// The certificate could not be parsed or holds no RSA key
+    Certificate(String),
+    /// The password could not be encrypted, e.g. because it is too long for the key
+    Encryption(String),
+}
+
+impl Certificate {
+    /// Reads a certificate in the PEM format M-Pesa distributes it in, or in DER
+    pub fn new(contents: &[u8]) -> Result<Certificate, CredentialError> {
+        let certificate = X509::from_pem(contents)
+            .or_else(|_| X509::from_der(contents))
+            .map_err(|error| CredentialError::Certificate(format!("{}", error)))?;
+        let key = certificate.public_key().map_err(|error| CredentialError::Certificate(format!("{}", error)))?;
+        Ok(Certificate { key: key })
+    }
+
+    /// Reads the certificate at `path`
+    pub fn from_file(path: &Path) -> Result<Certificate, CredentialError> {
+        let contents = fs::read(path).map_err(|error| CredentialError::Io(path.to_path_buf(), error))?;
+        Certificate::new(&contents)
+    }
+
+    /// Encrypts `password` into a security credential
+    pub fn encrypt(&self, password: &str) -> Result<String, CredentialError> {
+        let rsa = self.key.rsa().map_err(|error| CredentialError::Certificate(format!("{}", error)))?;
+        let mut encrypted = vec![0; rsa.size() as usize];
+        let length = rsa.public_encrypt(password.as_bytes(), &mut encrypted, Padding::PKCS1)
+            .map_err(|error| CredentialError::Encryption(format!("{}", error)))?;
+        Ok(base64::encode(&encrypted[..length]))
+    }
+}
+
+impl Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CredentialError::Io(ref path, ref error) => write!(f, "CredentialError::Io -- {}: {}", path.display(), error),
+            &CredentialError::Certificate(ref description) => write!(f, "CredentialError::Certificate -- {}", description),
+            &CredentialError::Encryption(ref description) => write!(f, "CredentialError::Encryption -- {}", description),
+        }
+    }
+}
+
+impl Error for CredentialError {
+    fn description(&self) -> &str {
+        "failed to generate the security credential"
+    }
+}
@@ -0,0 +1,147 @@
+//! The lifecycle every transaction goes through
+//!
+//! | From           | To                                                                         |
+//! |----------------|----------------------------------------------------------------------------|
+//! | `Created`      | `Submitted`, `Failed`                                                      |
+//! | `Submitted`    | `Acknowledged`, `Completed`, `Failed`, `TimedOut`, `Unknown`, `Escalated`  |
+//! | `Acknowledged` | `Completed`, `Failed`, `TimedOut`, `Unknown`, `Escalated`                  |
+//! | `TimedOut`     | `Completed`, `Failed`, `Unknown`                                           |
+//! | `Unknown`      | `Completed`, `Failed`, `TimedOut`, `Escalated`                             |
+//! | `Escalated`    | `Completed`, `Failed`, `TimedOut`                                          |
+//! | `Completed`    | `Reversed`                                                                 |
+//! | `Failed`       |                                                                            |
+//! | `Reversed`     |                                                                            |
+//!
+//! * The client moves a transaction from `Created` to `Submitted` when it sends the request, and on to `Acknowledged`,
+//!   `Failed` or `Unknown` depending on the response
+//! * `ResultURL` callbacks complete or fail it, `QueueTimeOutURL` callbacks time it out
+//! * A completed reversal moves the transaction it reversed to `Reversed`
+//! * The `Reconciler` moves a transaction it gave up looking up to `Escalated`, where it waits to be resolved by hand
+//!
+//! A transaction stays in its state when it is moved to the state it is already in, e.g. by a repeated callback.
+//! Every other move not shown above, e.g. `Completed -> Failed`, is rejected with an `IllegalTransition`.
+
+use std::fmt::{self, Display};
+use std::error::Error;
+use std::str::FromStr;
+
+use super::StoreError;
+
+/// The state a transaction is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionState {
+    /// The transaction has been recorded but the request not sent yet
+    Created,
+    /// The request has been sent but not acknowledged
+    Submitted,
+    /// The request has been acknowledged and the result is awaited
+    Acknowledged,
+    /// The result has arrived and the transaction went through
+    Completed,
+    /// The request was rejected or the result reports a failure
+    Failed,
+    /// The request timed out in the M-Pesa queue
+    TimedOut,
+    /// The transaction went through but has since been reversed
+    Reversed,
+    /// It is not known whether the transaction went through, e.g. because the connection dropped while sending it
+    Unknown,
+    /// The outcome could not be looked up and is left to be resolved by hand
+    Escalated,
+}
+
+/// A transaction was to be moved to a state it cannot reach from its current one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IllegalTransition {
+    /// The state the transaction is in
+    pub from: TransactionState,
+    /// The state it was to be moved to
+    pub to: TransactionState,
+}
+
+impl TransactionState {
+    /// Whether a transaction in this state may be moved to `next`
+    pub fn can_become(&self, next: TransactionState) -> bool {
+        use self::TransactionState::*;
+
+        if *self == next {
+            return true;
+        }
+        match (*self, next) {
+            (Created, Submitted) | (Created, Failed) => true,
+            (Submitted, Acknowledged) | (Submitted, Failed) | (Submitted, Unknown) => true,
+            // the callback may arrive before the acknowledgement is recorded
+            (Submitted, Completed) | (Submitted, TimedOut) => true,
+            (Acknowledged, Completed) | (Acknowledged, Failed) | (Acknowledged, TimedOut) | (Acknowledged, Unknown) => true,
+            (TimedOut, Completed) | (TimedOut, Failed) | (TimedOut, Unknown) => true,
+            (Unknown, Completed) | (Unknown, Failed) | (Unknown, TimedOut) => true,
+            (Submitted, Escalated) | (Acknowledged, Escalated) | (Unknown, Escalated) => true,
+            (Escalated, Completed) | (Escalated, Failed) | (Escalated, TimedOut) => true,
+            (Completed, Reversed) => true,
+            _ => false,
+        }
+    }
+
+    /// Moves to `next` if it can be reached from this state
+    pub fn transition(self, next: TransactionState) -> Result<TransactionState, IllegalTransition> {
+        if self.can_become(next) {
+            Ok(next)
+        } else {
+            Err(IllegalTransition { from: self, to: next })
+        }
+    }
+
+    /// Whether the outcome of the transaction is settled, i.e. it completed, failed or was reversed
+    pub fn is_resolved(&self) -> bool {
+        match *self {
+            TransactionState::Completed | TransactionState::Failed | TransactionState::Reversed => true,
+            _ => false,
+        }
+    }
+
+    /// The name the state is stored under
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &TransactionState::Created => "created",
+            &TransactionState::Submitted => "submitted",
+            &TransactionState::Acknowledged => "acknowledged",
+            &TransactionState::Completed => "completed",
+            &TransactionState::Failed => "failed",
+            &TransactionState::TimedOut => "timed_out",
+            &TransactionState::Reversed => "reversed",
+            &TransactionState::Unknown => "unknown",
+            &TransactionState::Escalated => "escalated",
+        }
+    }
+}
+
+impl FromStr for TransactionState {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<TransactionState, StoreError> {
+        match s {
+            "created" => Ok(TransactionState::Created),
+            "submitted" => Ok(TransactionState::Submitted),
+            "acknowledged" => Ok(TransactionState::Acknowledged),
+            "completed" => Ok(TransactionState::Completed),
+            "failed" => Ok(TransactionState::Failed),
+            "timed_out" => Ok(TransactionState::TimedOut),
+            "reversed" => Ok(TransactionState::Reversed),
+            "unknown" => Ok(TransactionState::Unknown),
+            "escalated" => Ok(TransactionState::Escalated),
+            other => Err(StoreError::Corrupt(format!("unknown transaction state {}", other))),
+        }
+    }
+}
+
+impl Display for IllegalTransition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "IllegalTransition -- a {} transaction cannot become {}", self.from.as_str(), self.to.as_str())
+    }
+}
+
+impl Error for IllegalTransition {
+    fn description(&self) -> &str {
+        "illegal transaction state transition"
+    }
+}
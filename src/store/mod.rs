@@ -0,0 +1,693 @@
+//! Persistent record of every transaction made through the client
+//!
+//! A `TransactionStore` keeps, for every request sent by the client:
+//! * the request itself, with the security credential and password redacted
+//! * the ids returned in the acknowledgement (`ConversationID`, `CheckoutRequestID`, ...)
+//! * every callback received for it
+//! * its final state, result code and receipt
+//!
+//! Give the store to the client with `MpesaClient::store()` to record requests and acknowledgements, and wrap your
+//! `CallbackHandler` in a `Recorder` to record callbacks. Besides the in-memory `MemoryTransactionStore`,
+//! Diesel backed stores for MySQL and SQLite are available with the `mysql` and `sqlite` features.
+//! They create their tables through the migrations in the `migrations` directory when opened.
+//!
+//! The states a transaction moves through are described in the `lifecycle` module.
+
+pub mod lifecycle;
+#[cfg(any(feature = "mysql", feature = "sqlite"))]
+pub mod sql;
+
+pub use api_products::Product;
+pub use self::lifecycle::{IllegalTransition, TransactionState};
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use chrono::{NaiveDateTime, Utc};
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use api_products::account_balance::AccountBalanceResponse;
+use api_products::b2b::B2BResponse;
+use api_products::b2c::B2CResponse;
+use api_products::lipa_na_mpesa_online_payment_request::LipaNaMpesaOnlinePaymentResponse;
+use api_products::reversal::ReversalResponse;
+use api_products::transaction_status::TransactionStatusResponse;
+use callbacks::{CallbackHandler, C2BTransaction, CallbackResponse, StkCallback, TransactionResult};
+use callbacks::verify::SecurityEvent;
+
+/// Length of generated transaction ids
+const ID_LENGTH: usize = 24;
+
+/// How many times `modify` applies a change to a transaction that keeps being moved on concurrently
+const MODIFY_ATTEMPTS: usize = 8;
+
+/// Request fields that are never written to the store
+const REDACTED_FIELDS: &[&str] = &["SecurityCredential", "Password"];
+
+/// A transaction as recorded in the store
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    /// Id generated by the store
+    pub id: String,
+    /// The API product invoked
+    pub product: Product,
+    /// The request as sent, in JSON
+    pub request: String,
+    /// `ConversationID` of the acknowledgement
+    pub conversation_id: Option<String>,
+    /// `OriginatorConversationID` of the acknowledgement
+    pub originator_conversation_id: Option<String>,
+    /// `CheckoutRequestID` of the acknowledgement of an STK push
+    pub checkout_request_id: Option<String>,
+    /// `MerchantRequestID` of the acknowledgement of an STK push
+    pub merchant_request_id: Option<String>,
+    /// The current state
+    pub state: TransactionState,
+    /// `ResultCode` of the result, or the status code the request was rejected with
+    pub result_code: Option<i64>,
+    /// `ResultDesc` of the result, or the body the request was rejected with
+    pub result_desc: Option<String>,
+    /// M-Pesa receipt number of the transaction
+    pub receipt: Option<String>,
+    /// When the request was made
+    pub created_at: NaiveDateTime,
+    /// When the record was last changed
+    pub updated_at: NaiveDateTime,
+    /// The key given by the caller to make sure the request is only sent once
+    pub idempotency_key: Option<String>,
+    /// The acknowledgement of the request, in JSON
+    pub acknowledgement: Option<String>,
+}
+
+/// The kinds of callbacks that are recorded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallbackKind {
+    Result,
+    Timeout,
+    Stk,
+    C2BValidation,
+    C2BConfirmation,
+}
+
+/// A callback as recorded in the store
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallbackRecord {
+    /// Id generated by the store
+    pub id: String,
+    /// The transaction the callback belongs to. C2B callbacks and unknown results have none
+    pub transaction_id: Option<String>,
+    /// Which url the callback was posted to
+    pub kind: CallbackKind,
+    /// The `ConversationID`, `CheckoutRequestID` or `TransID` of the callback
+    pub reference: String,
+    /// The callback in JSON
+    pub body: String,
+    /// When the callback was received
+    pub received_at: NaiveDateTime,
+}
+
+/// The ids returned in an acknowledgement
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AcknowledgementIds {
+    pub conversation_id: Option<String>,
+    pub originator_conversation_id: Option<String>,
+    pub checkout_request_id: Option<String>,
+    pub merchant_request_id: Option<String>,
+}
+
+/// Implemented by acknowledgements of requests that start a transaction
+pub trait Acknowledgement {
+    /// The ids the transaction is known by from now on
+    fn ids(&self) -> AcknowledgementIds;
+}
+
+impl Acknowledgement for B2CResponse {
+    fn ids(&self) -> AcknowledgementIds {
+        AcknowledgementIds {
+            conversation_id: Some(self.ConversationID.clone()),
+            originator_conversation_id: Some(self.OriginatorConversationID.clone()),
+            ..AcknowledgementIds::default()
+        }
+    }
+}
+
+impl Acknowledgement for B2BResponse {
+    fn ids(&self) -> AcknowledgementIds {
+        AcknowledgementIds {
+            conversation_id: Some(self.ConversationID.clone()),
+            originator_conversation_id: Some(self.OriginatorConversationID.clone()),
+            ..AcknowledgementIds::default()
+        }
+    }
+}
+
+impl Acknowledgement for ReversalResponse {
+    fn ids(&self) -> AcknowledgementIds {
+        AcknowledgementIds {
+            conversation_id: Some(self.ConversationID.clone()),
+            originator_conversation_id: Some(self.OriginatorConversationID.clone()),
+            ..AcknowledgementIds::default()
+        }
+    }
+}
+
+impl Acknowledgement for TransactionStatusResponse {
+    fn ids(&self) -> AcknowledgementIds {
+        AcknowledgementIds {
+            conversation_id: Some(self.ConversationID.clone()),
+            originator_conversation_id: Some(self.OriginatorConversationID.clone()),
+            ..AcknowledgementIds::default()
+        }
+    }
+}
+
+impl Acknowledgement for AccountBalanceResponse {
+    fn ids(&self) -> AcknowledgementIds {
+        AcknowledgementIds {
+            conversation_id: Some(self.ConversationID.clone()),
+            originator_conversation_id: Some(self.OriginatorConversationID.clone()),
+            ..AcknowledgementIds::default()
+        }
+    }
+}
+
+impl Acknowledgement for LipaNaMpesaOnlinePaymentResponse {
+    fn ids(&self) -> AcknowledgementIds {
+        AcknowledgementIds {
+            checkout_request_id: Some(self.CheckoutRequestID.clone()),
+            merchant_request_id: Some(self.MerchantRequestID.clone()),
+            ..AcknowledgementIds::default()
+        }
+    }
+}
+
+/// Definition of possible errors when accessing a `TransactionStore`
+#[derive(Debug)]
+pub enum StoreError {
+    /// The database could not be reached
+    Connection(String),
+    /// The tables could not be created or upgraded
+    Migration(String),
+    /// A query failed
+    Query(String),
+    /// A stored value could not be understood
+    Corrupt(String),
+    /// A transaction with the same idempotency key has been recorded already
+    DuplicateKey(String),
+    /// A transaction could not be moved to the state it was to be recorded in
+    IllegalTransition(IllegalTransition),
+}
+
+/// Durable storage of transactions and their callbacks
+pub trait TransactionStore: Send + Sync {
+    /// Records a new transaction
+    fn insert(&self, transaction: &Transaction) -> Result<(), StoreError>;
+
+    /// Overwrites a recorded transaction
+    fn update(&self, transaction: &Transaction) -> Result<(), StoreError>;
+
+    /// Overwrites a recorded transaction if it is still in the `expected` state.
+    /// Returns `false`, leaving it untouched, if it has been moved on since, e.g. by a callback.
+    fn update_from(&self, expected: TransactionState, transaction: &Transaction) -> Result<bool, StoreError>;
+
+    /// Looks a transaction up by its id
+    fn get(&self, id: &str) -> Result<Option<Transaction>, StoreError>;
+
+    /// Looks a transaction up by the idempotency key it was sent with
+    fn find_by_idempotency_key(&self, key: &str) -> Result<Option<Transaction>, StoreError>;
+
+    /// Looks a transaction up by any of the ids of its acknowledgement
+    fn find_by_reference(&self, reference: &str) -> Result<Option<Transaction>, StoreError>;
+
+    /// The transactions carrying the M-Pesa receipt number `receipt`
+    fn find_by_receipt(&self, receipt: &str) -> Result<Vec<Transaction>, StoreError>;
+
+    /// The transactions in any of `states` that have not changed since `updated_before`, least recently changed first
+    fn find_stale(&self, states: &[TransactionState], updated_before: NaiveDateTime) -> Result<Vec<Transaction>, StoreError>;
+
+    /// Records a callback
+    fn insert_callback(&self, callback: &CallbackRecord) -> Result<(), StoreError>;
+
+    /// The callbacks recorded for a transaction, oldest first
+    fn callbacks(&self, transaction_id: &str) -> Result<Vec<CallbackRecord>, StoreError>;
+}
+
+impl<S: TransactionStore + ?Sized> TransactionStore for Arc<S> {
+    fn insert(&self, transaction: &Transaction) -> Result<(), StoreError> {
+        (**self).insert(transaction)
+    }
+
+    fn update(&self, transaction: &Transaction) -> Result<(), StoreError> {
+        (**self).update(transaction)
+    }
+
+    fn update_from(&self, expected: TransactionState, transaction: &Transaction) -> Result<bool, StoreError> {
+        (**self).update_from(expected, transaction)
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Transaction>, StoreError> {
+        (**self).get(id)
+    }
+
+    fn find_by_idempotency_key(&self, key: &str) -> Result<Option<Transaction>, StoreError> {
+        (**self).find_by_idempotency_key(key)
+    }
+
+    fn find_by_reference(&self, reference: &str) -> Result<Option<Transaction>, StoreError> {
+        (**self).find_by_reference(reference)
+    }
+
+    fn find_by_receipt(&self, receipt: &str) -> Result<Vec<Transaction>, StoreError> {
+        (**self).find_by_receipt(receipt)
+    }
+
+    fn find_stale(&self, states: &[TransactionState], updated_before: NaiveDateTime) -> Result<Vec<Transaction>, StoreError> {
+        (**self).find_stale(states, updated_before)
+    }
+
+    fn insert_callback(&self, callback: &CallbackRecord) -> Result<(), StoreError> {
+        (**self).insert_callback(callback)
+    }
+
+    fn callbacks(&self, transaction_id: &str) -> Result<Vec<CallbackRecord>, StoreError> {
+        (**self).callbacks(transaction_id)
+    }
+}
+
+/// Generates a random id for a record
+fn new_id() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(ID_LENGTH).collect()
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+/// Serializes `request` with the `REDACTED_FIELDS` blanked out
+pub fn redacted_json<T: Serialize>(request: &T) -> String {
+    let mut value = serde_json::to_value(request).unwrap_or(Value::Null);
+    if let Value::Object(ref mut fields) = value {
+        for field in REDACTED_FIELDS {
+            if let Some(value) = fields.get_mut(*field) {
+                *value = Value::String(String::from("[redacted]"));
+            }
+        }
+    }
+    value.to_string()
+}
+
+impl Transaction {
+    /// Creates the record of a request that is about to be sent
+    pub fn new<T: Serialize>(product: Product, request: &T) -> Transaction {
+        let now = now();
+        Transaction {
+            id: new_id(),
+            product: product,
+            request: redacted_json(request),
+            conversation_id: None,
+            originator_conversation_id: None,
+            checkout_request_id: None,
+            merchant_request_id: None,
+            state: TransactionState::Created,
+            result_code: None,
+            result_desc: None,
+            receipt: None,
+            created_at: now,
+            updated_at: now,
+            idempotency_key: None,
+            acknowledgement: None,
+        }
+    }
+
+    /// Moves the transaction to `state`, see the `lifecycle` module for the moves allowed
+    pub fn transition(&mut self, state: TransactionState) -> Result<(), IllegalTransition> {
+        self.state = self.state.transition(state)?;
+        self.updated_at = now();
+        Ok(())
+    }
+
+    /// Records the ids of the acknowledgement.
+    /// A transaction whose outcome arrived before its acknowledgement keeps that outcome.
+    pub fn acknowledge(&mut self, ids: AcknowledgementIds) -> Result<(), IllegalTransition> {
+        match self.state {
+            TransactionState::Created | TransactionState::Submitted => self.transition(TransactionState::Acknowledged)?,
+            _ => (),
+        }
+        self.conversation_id = ids.conversation_id;
+        self.originator_conversation_id = ids.originator_conversation_id;
+        self.checkout_request_id = ids.checkout_request_id;
+        self.merchant_request_id = ids.merchant_request_id;
+        Ok(())
+    }
+
+    /// Records the outcome of the transaction.
+    /// A transaction reported as reversed is completed first if it has not been already.
+    pub fn conclude(&mut self, state: TransactionState, result_code: Option<i64>, result_desc: &str, receipt: Option<String>) -> Result<(), IllegalTransition> {
+        if state == TransactionState::Reversed && self.state.can_become(TransactionState::Completed) {
+            self.transition(TransactionState::Completed)?;
+        }
+        self.transition(state)?;
+        self.result_code = result_code;
+        self.result_desc = Some(result_desc.to_string());
+        if receipt.is_some() {
+            self.receipt = receipt;
+        }
+        Ok(())
+    }
+
+    /// Whether `reference` is one of the ids of the acknowledgement
+    pub fn has_reference(&self, reference: &str) -> bool {
+        [&self.conversation_id, &self.originator_conversation_id, &self.checkout_request_id, &self.merchant_request_id]
+            .iter()
+            .any(|id| id.as_ref().map(|id| id == reference).unwrap_or(false))
+    }
+}
+
+impl CallbackRecord {
+    /// Creates the record of a callback
+    pub fn new<T: Serialize>(transaction_id: Option<String>, kind: CallbackKind, reference: &str, body: &T) -> CallbackRecord {
+        CallbackRecord {
+            id: new_id(),
+            transaction_id: transaction_id,
+            kind: kind,
+            reference: reference.to_string(),
+            body: serde_json::to_string(body).unwrap_or_default(),
+            received_at: now(),
+        }
+    }
+}
+
+/// Applies `change` to the recorded transaction `id` and writes it back. If the transaction is moved on in between,
+/// e.g. by a callback, `change` is applied again to the transaction as it is now.
+/// Returns the transaction as written, or `None` if there is no transaction `id`.
+pub fn modify<S, F>(store: &S, id: &str, mut change: F) -> Result<Option<Transaction>, StoreError>
+    where S: TransactionStore + ?Sized, F: FnMut(&mut Transaction) -> Result<(), IllegalTransition>
+{
+    for _ in 0..MODIFY_ATTEMPTS {
+        let mut transaction = match store.get(id)? {
+            Some(transaction) => transaction,
+            None => return Ok(None),
+        };
+        let expected = transaction.state;
+        change(&mut transaction).map_err(StoreError::IllegalTransition)?;
+        if store.update_from(expected, &transaction)? {
+            return Ok(Some(transaction));
+        }
+    }
+    Err(StoreError::Query(format!("transaction {} kept changing while being updated", id)))
+}
+
+/// A `TransactionStore` that keeps everything in memory. Useful for tests.
+#[derive(Debug, Default)]
+pub struct MemoryTransactionStore {
+    transactions: Mutex<HashMap<String, Transaction>>,
+    callbacks: Mutex<Vec<CallbackRecord>>,
+}
+
+impl MemoryTransactionStore {
+    /// Creates an empty store
+    pub fn new() -> MemoryTransactionStore {
+        MemoryTransactionStore::default()
+    }
+}
+
+impl TransactionStore for MemoryTransactionStore {
+    fn insert(&self, transaction: &Transaction) -> Result<(), StoreError> {
+        let mut transactions = self.transactions.lock().unwrap();
+        if transactions.contains_key(&transaction.id) {
+            return Err(StoreError::Query(format!("transaction {} already exists", transaction.id)));
+        }
+        if let Some(ref key) = transaction.idempotency_key {
+            if transactions.values().any(|stored| stored.idempotency_key.as_ref() == Some(key)) {
+                return Err(StoreError::DuplicateKey(key.clone()));
+            }
+        }
+        transactions.insert(transaction.id.clone(), transaction.clone());
+        Ok(())
+    }
+
+    fn update(&self, transaction: &Transaction) -> Result<(), StoreError> {
+        match self.transactions.lock().unwrap().get_mut(&transaction.id) {
+            Some(stored) => {
+                *stored = transaction.clone();
+                Ok(())
+            },
+            None => Err(StoreError::Query(format!("transaction {} does not exist", transaction.id))),
+        }
+    }
+
+    fn update_from(&self, expected: TransactionState, transaction: &Transaction) -> Result<bool, StoreError> {
+        match self.transactions.lock().unwrap().get_mut(&transaction.id) {
+            Some(ref mut stored) if stored.state != expected => Ok(false),
+            Some(stored) => {
+                *stored = transaction.clone();
+                Ok(true)
+            },
+            None => Err(StoreError::Query(format!("transaction {} does not exist", transaction.id))),
+        }
+    }
+
+    fn get(&self, id: &str) -> Result<Option<Transaction>, StoreError> {
+        Ok(self.transactions.lock().unwrap().get(id).cloned())
+    }
+
+    fn find_by_idempotency_key(&self, key: &str) -> Result<Option<Transaction>, StoreError> {
+        Ok(self.transactions.lock().unwrap().values()
+            .find(|transaction| transaction.idempotency_key.as_ref().map(|stored| stored == key).unwrap_or(false))
+            .cloned())
+    }
+
+    fn find_by_reference(&self, reference: &str) -> Result<Option<Transaction>, StoreError> {
+        Ok(self.transactions.lock().unwrap().values()
+            .filter(|transaction| transaction.has_reference(reference))
+            .max_by_key(|transaction| transaction.created_at)
+            .cloned())
+    }
+
+    fn find_by_receipt(&self, receipt: &str) -> Result<Vec<Transaction>, StoreError> {
+        Ok(self.transactions.lock().unwrap().values()
+            .filter(|transaction| transaction.receipt.as_ref().map(|id| id == receipt).unwrap_or(false))
+            .cloned()
+            .collect())
+    }
+
+    fn find_stale(&self, states: &[TransactionState], updated_before: NaiveDateTime) -> Result<Vec<Transaction>, StoreError> {
+        let mut stale: Vec<Transaction> = self.transactions.lock().unwrap().values()
+            .filter(|transaction| states.contains(&transaction.state) && transaction.updated_at < updated_before)
+            .cloned()
+            .collect();
+        stale.sort_by_key(|transaction| transaction.updated_at);
+        Ok(stale)
+    }
+
+    fn insert_callback(&self, callback: &CallbackRecord) -> Result<(), StoreError> {
+        self.callbacks.lock().unwrap().push(callback.clone());
+        Ok(())
+    }
+
+    fn callbacks(&self, transaction_id: &str) -> Result<Vec<CallbackRecord>, StoreError> {
+        Ok(self.callbacks.lock().unwrap().iter()
+            .filter(|callback| callback.transaction_id.as_ref().map(|id| id == transaction_id).unwrap_or(false))
+            .cloned()
+            .collect())
+    }
+}
+
+/// A `CallbackHandler` that records every callback, and the outcome it reports, before passing it on to `handler`
+pub struct Recorder<H, S> {
+    handler: H,
+    store: S,
+}
+
+impl<H: CallbackHandler, S: TransactionStore> Recorder<H, S> {
+    /// Creates a new recorder in front of `handler`, recording to `store`
+    pub fn new(handler: H, store: S) -> Recorder<H, S> {
+        Recorder {
+            handler: handler,
+            store: store,
+        }
+    }
+
+    /// Finds the transaction known by any of `references`
+    fn find(&self, references: &[&str]) -> Option<Transaction> {
+        for reference in references.iter().filter(|reference| !reference.is_empty()) {
+            match self.store.find_by_reference(reference) {
+                Ok(Some(transaction)) => return Some(transaction),
+                Ok(None) => (),
+                Err(error) => error!("unable to look up transaction {}: {}", reference, error),
+            }
+        }
+        None
+    }
+
+    /// Records a callback and, if it belongs to a known transaction, the outcome it reports.
+    /// Returns the transaction the callback belongs to.
+    fn record<T: Serialize>(&self, kind: CallbackKind, references: &[&str], body: &T, outcome: Option<(TransactionState, i64, &str, Option<String>)>) -> Option<Transaction> {
+        let mut transaction = self.find(references);
+
+        if let (Some(found), Some((state, result_code, result_desc, receipt))) = (transaction.clone(), outcome) {
+            match modify(&self.store, &found.id, |transaction| transaction.conclude(state, Some(result_code), result_desc, receipt.clone())) {
+                Ok(concluded) => transaction = concluded.or(transaction),
+                Err(StoreError::IllegalTransition(error)) => warn!("ignoring the {:?} callback of transaction {}: {}", kind, found.id, error),
+                Err(error) => error!("unable to record the outcome of transaction {}: {}", found.id, error),
+            }
+        }
+
+        let callback = CallbackRecord::new(transaction.as_ref().map(|transaction| transaction.id.clone()), kind, references[0], body);
+        if let Err(error) = self.store.insert_callback(&callback) {
+            error!("unable to record {:?} callback {}: {}", kind, callback.reference, error);
+        }
+        transaction
+    }
+
+    /// Marks the transaction undone by a completed `reversal` as reversed
+    fn reverse(&self, reversal: &Transaction) {
+        let request: Value = serde_json::from_str(&reversal.request).unwrap_or(Value::Null);
+        let receipt = match request.get("TransactionID").and_then(|receipt| receipt.as_str()) {
+            Some(receipt) => receipt,
+            None => return,
+        };
+
+        let reversed = match self.store.find_by_receipt(receipt) {
+            Ok(transactions) => transactions,
+            Err(error) => {
+                error!("unable to look up transaction {}: {}", receipt, error);
+                return;
+            },
+        };
+        for transaction in reversed.into_iter().filter(|transaction| transaction.product != Product::Reversal) {
+            match modify(&self.store, &transaction.id, |transaction| transaction.transition(TransactionState::Reversed)) {
+                Ok(_) => (),
+                Err(StoreError::IllegalTransition(error)) => warn!("unable to reverse transaction {}: {}", transaction.id, error),
+                Err(error) => error!("unable to record the reversal of transaction {}: {}", transaction.id, error),
+            }
+        }
+    }
+}
+
+/// The state a result with `result_code` leaves a transaction in
+fn concluded(result_code: i64) -> TransactionState {
+    if result_code == 0 {
+        TransactionState::Completed
+    } else {
+        TransactionState::Failed
+    }
+}
+
+impl<H: CallbackHandler, S: TransactionStore> CallbackHandler for Recorder<H, S> {
+    fn on_result(&self, result: TransactionResult) {
+        let receipt = if result.TransactionID.is_empty() { None } else { Some(result.TransactionID.clone()) };
+        let transaction = self.record(CallbackKind::Result,
+                                      &[&result.ConversationID, &result.OriginatorConversationID],
+                                      &result,
+                                      Some((concluded(result.ResultCode), result.ResultCode, &result.ResultDesc, receipt)));
+
+        if let Some(transaction) = transaction {
+            if transaction.product == Product::Reversal && transaction.state == TransactionState::Completed {
+                self.reverse(&transaction);
+            }
+        }
+        self.handler.on_result(result)
+    }
+
+    fn on_timeout(&self, result: TransactionResult) {
+        self.record(CallbackKind::Timeout,
+                    &[&result.ConversationID, &result.OriginatorConversationID],
+                    &result,
+                    Some((TransactionState::TimedOut, result.ResultCode, &result.ResultDesc, None)));
+        self.handler.on_timeout(result)
+    }
+
+    fn on_stk_callback(&self, callback: StkCallback) {
+        let receipt = callback.item("MpesaReceiptNumber").and_then(|receipt| receipt.as_str()).map(String::from);
+        self.record(CallbackKind::Stk,
+                    &[&callback.CheckoutRequestID, &callback.MerchantRequestID],
+                    &callback,
+                    Some((concluded(callback.ResultCode), callback.ResultCode, &callback.ResultDesc, receipt)));
+        self.handler.on_stk_callback(callback)
+    }
+
+    fn on_c2b_validation(&self, transaction: C2BTransaction) -> CallbackResponse {
+        self.record(CallbackKind::C2BValidation, &[&transaction.TransID], &transaction, None);
+        self.handler.on_c2b_validation(transaction)
+    }
+
+    fn on_c2b_confirmation(&self, transaction: C2BTransaction) {
+        self.record(CallbackKind::C2BConfirmation, &[&transaction.TransID], &transaction, None);
+        self.handler.on_c2b_confirmation(transaction)
+    }
+
+    fn on_security_event(&self, event: SecurityEvent) {
+        self.handler.on_security_event(event)
+    }
+}
+
+impl FromStr for Product {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Product, StoreError> {
+        match s {
+            "b2c" => Ok(Product::B2C),
+            "b2b" => Ok(Product::B2B),
+            "stk_push" => Ok(Product::StkPush),
+            "reversal" => Ok(Product::Reversal),
+            "transaction_status" => Ok(Product::TransactionStatus),
+            "account_balance" => Ok(Product::AccountBalance),
+            "c2b_simulate" => Ok(Product::C2BSimulate),
+            other => Err(StoreError::Corrupt(format!("unknown product {}", other))),
+        }
+    }
+}
+
+impl CallbackKind {
+    /// The name the kind is stored under
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &CallbackKind::Result => "result",
+            &CallbackKind::Timeout => "timeout",
+            &CallbackKind::Stk => "stk",
+            &CallbackKind::C2BValidation => "c2b_validation",
+            &CallbackKind::C2BConfirmation => "c2b_confirmation",
+        }
+    }
+}
+
+impl FromStr for CallbackKind {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<CallbackKind, StoreError> {
+        match s {
+            "result" => Ok(CallbackKind::Result),
+            "timeout" => Ok(CallbackKind::Timeout),
+            "stk" => Ok(CallbackKind::Stk),
+            "c2b_validation" => Ok(CallbackKind::C2BValidation),
+            "c2b_confirmation" => Ok(CallbackKind::C2BConfirmation),
+            other => Err(StoreError::Corrupt(format!("unknown callback kind {}", other))),
+        }
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &StoreError::Connection(ref description) => write!(f, "StoreError::Connection -- {}", description),
+            &StoreError::Migration(ref description) => write!(f, "StoreError::Migration -- {}", description),
+            &StoreError::Query(ref description) => write!(f, "StoreError::Query -- {}", description),
+            &StoreError::Corrupt(ref description) => write!(f, "StoreError::Corrupt -- {}", description),
+            &StoreError::DuplicateKey(ref key) => write!(f, "StoreError::DuplicateKey -- {} has been used already", key),
+            &StoreError::IllegalTransition(ref error) => write!(f, "StoreError::IllegalTransition -- {}", error),
+        }
+    }
+}
+
+impl Error for StoreError {
+    fn description(&self) -> &str {
+        "failed to access the transaction store"
+    }
+}
@@ -0,0 +1,338 @@
+//! Diesel backed `TransactionStore`s
+//!
+//! * `MysqlTransactionStore` - available with the `mysql` feature
+//! * `SqliteTransactionStore` - available with the `sqlite` feature
+//!
+//! Both run the migrations for their database, found under `migrations/mysql` and `migrations/sqlite`,
+//! when opened so the `mpesa_transactions` and `mpesa_callbacks` tables are always up to date.
+
+use chrono::NaiveDateTime;
+use diesel;
+
+use super::{CallbackRecord, StoreError, Transaction};
+
+table! {
+    mpesa_transactions (id) {
+        id -> Varchar,
+        product -> Varchar,
+        request -> Text,
+        conversation_id -> Nullable<Varchar>,
+        originator_conversation_id -> Nullable<Varchar>,
+        checkout_request_id -> Nullable<Varchar>,
+        merchant_request_id -> Nullable<Varchar>,
+        state -> Varchar,
+        result_code -> Nullable<BigInt>,
+        result_desc -> Nullable<Text>,
+        receipt -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        idempotency_key -> Nullable<Varchar>,
+        acknowledgement -> Nullable<Text>,
+    }
+}
+
+table! {
+    mpesa_callbacks (id) {
+        id -> Varchar,
+        transaction_id -> Nullable<Varchar>,
+        kind -> Varchar,
+        reference -> Varchar,
+        body -> Text,
+        received_at -> Timestamp,
+    }
+}
+
+/// A row of `mpesa_transactions`
+#[derive(Queryable, Insertable, AsChangeset)]
+#[table_name = "mpesa_transactions"]
+#[changeset_options(treat_none_as_null = "true")]
+struct TransactionRow {
+    id: String,
+    product: String,
+    request: String,
+    conversation_id: Option<String>,
+    originator_conversation_id: Option<String>,
+    checkout_request_id: Option<String>,
+    merchant_request_id: Option<String>,
+    state: String,
+    result_code: Option<i64>,
+    result_desc: Option<String>,
+    receipt: Option<String>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+    idempotency_key: Option<String>,
+    acknowledgement: Option<String>,
+}
+
+/// A row of `mpesa_callbacks`
+#[derive(Queryable, Insertable)]
+#[table_name = "mpesa_callbacks"]
+struct CallbackRow {
+    id: String,
+    transaction_id: Option<String>,
+    kind: String,
+    reference: String,
+    body: String,
+    received_at: NaiveDateTime,
+}
+
+impl<'a> From<&'a Transaction> for TransactionRow {
+    fn from(transaction: &'a Transaction) -> TransactionRow {
+        TransactionRow {
+            id: transaction.id.clone(),
+            product: transaction.product.as_str().to_string(),
+            request: transaction.request.clone(),
+            conversation_id: transaction.conversation_id.clone(),
+            originator_conversation_id: transaction.originator_conversation_id.clone(),
+            checkout_request_id: transaction.checkout_request_id.clone(),
+            merchant_request_id: transaction.merchant_request_id.clone(),
+            state: transaction.state.as_str().to_string(),
+            result_code: transaction.result_code,
+            result_desc: transaction.result_desc.clone(),
+            receipt: transaction.receipt.clone(),
+            created_at: transaction.created_at,
+            updated_at: transaction.updated_at,
+            idempotency_key: transaction.idempotency_key.clone(),
+            acknowledgement: transaction.acknowledgement.clone(),
+        }
+    }
+}
+
+impl TransactionRow {
+    fn into_transaction(self) -> Result<Transaction, StoreError> {
+        Ok(Transaction {
+            id: self.id,
+            product: self.product.parse()?,
+            request: self.request,
+            conversation_id: self.conversation_id,
+            originator_conversation_id: self.originator_conversation_id,
+            checkout_request_id: self.checkout_request_id,
+            merchant_request_id: self.merchant_request_id,
+            state: self.state.parse()?,
+            result_code: self.result_code,
+            result_desc: self.result_desc,
+            receipt: self.receipt,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            idempotency_key: self.idempotency_key,
+            acknowledgement: self.acknowledgement,
+        })
+    }
+}
+
+impl<'a> From<&'a CallbackRecord> for CallbackRow {
+    fn from(callback: &'a CallbackRecord) -> CallbackRow {
+        CallbackRow {
+            id: callback.id.clone(),
+            transaction_id: callback.transaction_id.clone(),
+            kind: callback.kind.as_str().to_string(),
+            reference: callback.reference.clone(),
+            body: callback.body.clone(),
+            received_at: callback.received_at,
+        }
+    }
+}
+
+impl CallbackRow {
+    fn into_callback(self) -> Result<CallbackRecord, StoreError> {
+        Ok(CallbackRecord {
+            id: self.id,
+            transaction_id: self.transaction_id,
+            kind: self.kind.parse()?,
+            reference: self.reference,
+            body: self.body,
+            received_at: self.received_at,
+        })
+    }
+}
+
+impl From<diesel::result::Error> for StoreError {
+    fn from(error: diesel::result::Error) -> Self {
+        StoreError::Query(format!("{}", error))
+    }
+}
+
+/// Implements a `TransactionStore` over a Diesel connection type.
+/// Expects the `embedded_migrations` of the database to be in scope.
+macro_rules! diesel_store {
+    ($(#[$attr:meta])* $store:ident, $connection:ty) => {
+        $(#[$attr])*
+        pub struct $store {
+            connection: Mutex<$connection>,
+        }
+
+        impl $store {
+            /// Connects to the database at `database_url` and brings its tables up to date
+            pub fn new(database_url: &str) -> Result<$store, StoreError> {
+                let connection = <$connection>::establish(database_url)
+                    .map_err(|error| StoreError::Connection(format!("{}", error)))?;
+                embedded_migrations::run(&connection)
+                    .map_err(|error| StoreError::Migration(format!("{}", error)))?;
+
+                Ok($store {
+                    connection: Mutex::new(connection),
+                })
+            }
+        }
+
+        impl TransactionStore for $store {
+            fn insert(&self, transaction: &Transaction) -> Result<(), StoreError> {
+                let connection = self.connection.lock().unwrap();
+                diesel::insert_into(mpesa_transactions::table)
+                    .values(&TransactionRow::from(transaction))
+                    .execute(&*connection)
+                    .map_err(|error| match error {
+                        // the id is random, so the idempotency key is the one that clashed
+                        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) if transaction.idempotency_key.is_some() => {
+                            StoreError::DuplicateKey(transaction.idempotency_key.clone().unwrap_or_default())
+                        },
+                        error => StoreError::from(error),
+                    })?;
+                Ok(())
+            }
+
+            fn update(&self, transaction: &Transaction) -> Result<(), StoreError> {
+                let connection = self.connection.lock().unwrap();
+                let updated = diesel::update(mpesa_transactions::table.find(&transaction.id))
+                    .set(&TransactionRow::from(transaction))
+                    .execute(&*connection)?;
+                if updated == 0 {
+                    return Err(StoreError::Query(format!("transaction {} does not exist", transaction.id)));
+                }
+                Ok(())
+            }
+
+            fn update_from(&self, expected: TransactionState, transaction: &Transaction) -> Result<bool, StoreError> {
+                let connection = self.connection.lock().unwrap();
+                let updated = diesel::update(mpesa_transactions::table.find(&transaction.id)
+                        .filter(mpesa_transactions::state.eq(expected.as_str())))
+                    .set(&TransactionRow::from(transaction))
+                    .execute(&*connection)?;
+                Ok(updated == 1)
+            }
+
+            fn get(&self, id: &str) -> Result<Option<Transaction>, StoreError> {
+                let connection = self.connection.lock().unwrap();
+                mpesa_transactions::table.find(id)
+                    .first::<TransactionRow>(&*connection)
+                    .optional()?
+                    .map(TransactionRow::into_transaction)
+                    .map_or(Ok(None), |transaction| transaction.map(Some))
+            }
+
+            fn find_by_idempotency_key(&self, key: &str) -> Result<Option<Transaction>, StoreError> {
+                let connection = self.connection.lock().unwrap();
+                mpesa_transactions::table
+                    .filter(mpesa_transactions::idempotency_key.eq(key))
+                    .first::<TransactionRow>(&*connection)
+                    .optional()?
+                    .map(TransactionRow::into_transaction)
+                    .map_or(Ok(None), |transaction| transaction.map(Some))
+            }
+
+            fn find_by_reference(&self, reference: &str) -> Result<Option<Transaction>, StoreError> {
+                let connection = self.connection.lock().unwrap();
+                mpesa_transactions::table
+                    .filter(mpesa_transactions::conversation_id.eq(reference)
+                        .or(mpesa_transactions::originator_conversation_id.eq(reference))
+                        .or(mpesa_transactions::checkout_request_id.eq(reference))
+                        .or(mpesa_transactions::merchant_request_id.eq(reference)))
+                    .order(mpesa_transactions::created_at.desc())
+                    .first::<TransactionRow>(&*connection)
+                    .optional()?
+                    .map(TransactionRow::into_transaction)
+                    .map_or(Ok(None), |transaction| transaction.map(Some))
+            }
+
+            fn find_by_receipt(&self, receipt: &str) -> Result<Vec<Transaction>, StoreError> {
+                let connection = self.connection.lock().unwrap();
+                mpesa_transactions::table
+                    .filter(mpesa_transactions::receipt.eq(receipt))
+                    .load::<TransactionRow>(&*connection)?
+                    .into_iter()
+                    .map(TransactionRow::into_transaction)
+                    .collect()
+            }
+
+            fn find_stale(&self, states: &[TransactionState], updated_before: NaiveDateTime) -> Result<Vec<Transaction>, StoreError> {
+                let states: Vec<&str> = states.iter().map(TransactionState::as_str).collect();
+                let connection = self.connection.lock().unwrap();
+                mpesa_transactions::table
+                    .filter(mpesa_transactions::state.eq_any(states))
+                    .filter(mpesa_transactions::updated_at.lt(updated_before))
+                    .order(mpesa_transactions::updated_at.asc())
+                    .load::<TransactionRow>(&*connection)?
+                    .into_iter()
+                    .map(TransactionRow::into_transaction)
+                    .collect()
+            }
+
+            fn insert_callback(&self, callback: &CallbackRecord) -> Result<(), StoreError> {
+                let connection = self.connection.lock().unwrap();
+                diesel::insert_into(mpesa_callbacks::table)
+                    .values(&CallbackRow::from(callback))
+                    .execute(&*connection)?;
+                Ok(())
+            }
+
+            fn callbacks(&self, transaction_id: &str) -> Result<Vec<CallbackRecord>, StoreError> {
+                let connection = self.connection.lock().unwrap();
+                mpesa_callbacks::table
+                    .filter(mpesa_callbacks::transaction_id.eq(transaction_id))
+                    .order(mpesa_callbacks::received_at.asc())
+                    .load::<CallbackRow>(&*connection)?
+                    .into_iter()
+                    .map(CallbackRow::into_callback)
+                    .collect()
+            }
+        }
+    };
+}
+
+#[cfg(feature = "mysql")]
+pub use self::mysql::MysqlTransactionStore;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteTransactionStore;
+
+#[cfg(feature = "mysql")]
+mod mysql {
+    use std::sync::Mutex;
+
+    use chrono::NaiveDateTime;
+    use diesel;
+    use diesel::prelude::*;
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    use diesel::mysql::MysqlConnection;
+
+    use super::{mpesa_callbacks, mpesa_transactions, CallbackRow, TransactionRow};
+    use super::super::{CallbackRecord, StoreError, Transaction, TransactionState, TransactionStore};
+
+    embed_migrations!("migrations/mysql");
+
+    diesel_store! {
+        /// A `TransactionStore` backed by a MySQL database
+        MysqlTransactionStore, MysqlConnection
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::sync::Mutex;
+
+    use chrono::NaiveDateTime;
+    use diesel;
+    use diesel::prelude::*;
+    use diesel::result::{DatabaseErrorKind, Error as DieselError};
+    use diesel::sqlite::SqliteConnection;
+
+    use super::{mpesa_callbacks, mpesa_transactions, CallbackRow, TransactionRow};
+    use super::super::{CallbackRecord, StoreError, Transaction, TransactionState, TransactionStore};
+
+    embed_migrations!("migrations/sqlite");
+
+    diesel_store! {
+        /// A `TransactionStore` backed by a SQLite database.
+        /// `database_url` is the path of the database file.
+        SqliteTransactionStore, SqliteConnection
+    }
+}
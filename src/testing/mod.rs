@@ -0,0 +1,280 @@
+//! Sample requests and callbacks for contract tests
+//!
+//! Every API product has a sample request built from fixed sandbox values, and a golden file holding the JSON the
+//! API documents for it. A change to the serialization of a request shows up as a difference from its golden file.
+//!
+//! The callbacks are the payloads M-Pesa documents, along with the variants it actually sends: failures without
+//! `ResultParameters` or `CallbackMetadata`, a lone `ResultParameter` instead of a list, codes sent as strings and
+//! C2B amounts, phone numbers and times sent as numbers. Downstream crates can run their own handlers against the
+//! same payloads.
+//!
+//! The files live in `src/testing/samples`.
+//!
+//! # Example
+//! ```
+//! # extern crate mpesa;
+//! # extern crate serde_json;
+//! # use mpesa::callbacks::{C2BTransaction, ResultCallback, StkCallbackBody};
+//! # use mpesa::testing::{self, C2B_TRANSACTIONS, RESULTS, STK_CALLBACKS};
+//! # fn main() {
+//! for sample in testing::requests() {
+//!     assert_eq!(sample.body, sample.expected(), "{} does not match its golden file", sample.endpoint);
+//! }
+//!
+//! for payload in STK_CALLBACKS {
+//!     let callback: StkCallbackBody = serde_json::from_str(payload).unwrap();
+//! }
+//! for payload in RESULTS {
+//!     let result: ResultCallback = serde_json::from_str(payload).unwrap();
+//! }
+//! for payload in C2B_TRANSACTIONS {
+//!     let transaction: C2BTransaction = serde_json::from_str(payload).unwrap();
+//! }
+//! # }
+//! ```
+
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use api_products::{account_balance, b2b, b2c, c2b, lipa_na_mpesa_online_payment_request, lipa_na_mpesa_online_query_request,
+                   reversal, transaction_status};
+use api_products::account_balance::AccountBalance;
+use api_products::b2b::B2B;
+use api_products::b2c::B2C;
+use api_products::c2b::{RegisterUrl, SimulateTransaction};
+use api_products::lipa_na_mpesa_online_payment_request::{password, LipaNaMpesaOnlinePaymentRequest};
+use api_products::lipa_na_mpesa_online_query_request::LipaNaMpesaOnlineQueryRequest;
+use api_products::reversal::Reversal;
+use api_products::transaction_status::TransactionSatus;
+use parameters::{Amount, CommandIds, Msisdn, Party, ShortCode};
+
+/// Shortcode of the sandbox organization
+pub const SHORT_CODE: &str = "600000";
+/// Shortcode of the sandbox Lipa na M-Pesa Online paybill
+pub const STK_SHORT_CODE: &str = "174379";
+/// Passkey of the sandbox Lipa na M-Pesa Online paybill
+pub const STK_PASSKEY: &str = "bfb279f9aa9bdbcf158e97dd71a467cd2e0c893059b10f78e6b72ada1ed2c919";
+/// Timestamp of the sample STK requests
+pub const TIMESTAMP: &str = "20180701120000";
+/// The sandbox test customer
+pub const MSISDN: &str = "254708374149";
+/// The sandbox initiator
+pub const INITIATOR: &str = "testapi";
+/// Stands in for an encrypted security credential
+pub const SECURITY_CREDENTIAL: &str = "Safaricom999!*!";
+/// `ResultURL` of the sample requests
+pub const RESULT_URL: &str = "https://example.com/payments/result";
+/// `QueueTimeOutURL` of the sample requests
+pub const QUEUE_TIMEOUT_URL: &str = "https://example.com/payments/timeout";
+/// `CallBackURL` of the sample STK push
+pub const CALLBACK_URL: &str = "https://example.com/payments/stk";
+/// `ValidationURL` of the sample url registration
+pub const VALIDATION_URL: &str = "https://example.com/payments/validation";
+/// `ConfirmationURL` of the sample url registration
+pub const CONFIRMATION_URL: &str = "https://example.com/payments/confirmation";
+/// Receipt of the transaction the sample reversal and transaction status refer to
+pub const RECEIPT: &str = "LKXXXX1234";
+/// `CheckoutRequestID` of the sample STK query
+pub const CHECKOUT_REQUEST_ID: &str = "ws_CO_DMZ_123212312_2342347678234";
+
+/// Golden file of the B2C request
+pub const B2C_REQUEST: &str = include_str!("samples/requests/b2c.json");
+/// Golden file of the B2B request
+pub const B2B_REQUEST: &str = include_str!("samples/requests/b2b.json");
+/// Golden file of the reversal request
+pub const REVERSAL_REQUEST: &str = include_str!("samples/requests/reversal.json");
+/// Golden file of the transaction status request
+pub const TRANSACTION_STATUS_REQUEST: &str = include_str!("samples/requests/transaction_status.json");
+/// Golden file of the account balance request
+pub const ACCOUNT_BALANCE_REQUEST: &str = include_str!("samples/requests/account_balance.json");
+/// Golden file of the STK push request
+pub const STK_PUSH_REQUEST: &str = include_str!("samples/requests/stk_push.json");
+/// Golden file of the STK query request
+pub const STK_QUERY_REQUEST: &str = include_str!("samples/requests/stk_query.json");
+/// Golden file of the C2B url registration request
+pub const REGISTER_URL_REQUEST: &str = include_str!("samples/requests/register_url.json");
+/// Golden file of the C2B simulation request
+pub const SIMULATE_REQUEST: &str = include_str!("samples/requests/simulate.json");
+
+/// The customer paid the STK push
+pub const STK_CALLBACK_PAID: &str = include_str!("samples/callbacks/stk_paid.json");
+/// The customer cancelled the STK push. Failures carry no `CallbackMetadata`
+pub const STK_CALLBACK_CANCELLED: &str = include_str!("samples/callbacks/stk_cancelled.json");
+/// The customer could not afford the STK push
+pub const STK_CALLBACK_INSUFFICIENT_FUNDS: &str = include_str!("samples/callbacks/stk_insufficient_funds.json");
+/// A paid STK push with its code and every item sent as strings
+pub const STK_CALLBACK_STRINGS: &str = include_str!("samples/callbacks/stk_strings.json");
+
+/// A successful B2C payment
+pub const B2C_RESULT: &str = include_str!("samples/callbacks/b2c_result.json");
+/// A B2C payment refused for a wrong initiator. Failures carry no `ResultParameters`
+pub const B2C_RESULT_FAILED: &str = include_str!("samples/callbacks/b2c_result_failed.json");
+/// A B2C result with a lone `ResultParameter` and its codes sent as strings
+pub const B2C_RESULT_SINGLE_PARAMETER: &str = include_str!("samples/callbacks/b2c_result_single_parameter.json");
+/// A successful B2B payment
+pub const B2B_RESULT: &str = include_str!("samples/callbacks/b2b_result.json");
+/// A successful reversal
+pub const REVERSAL_RESULT: &str = include_str!("samples/callbacks/reversal_result.json");
+/// The status of a completed transaction
+pub const TRANSACTION_STATUS_RESULT: &str = include_str!("samples/callbacks/transaction_status_result.json");
+/// The balances of an organization
+pub const ACCOUNT_BALANCE_RESULT: &str = include_str!("samples/callbacks/account_balance_result.json");
+/// A request that timed out in the queue, as posted to the `QueueTimeOutURL`
+pub const QUEUE_TIMEOUT: &str = include_str!("samples/callbacks/queue_timeout.json");
+
+/// A C2B payment to validate
+pub const C2B_VALIDATION: &str = include_str!("samples/callbacks/c2b_validation.json");
+/// A completed C2B payment
+pub const C2B_CONFIRMATION: &str = include_str!("samples/callbacks/c2b_confirmation.json");
+/// A completed C2B payment with its amount, shortcode, phone number, time and balance sent as numbers
+pub const C2B_CONFIRMATION_NUMBERS: &str = include_str!("samples/callbacks/c2b_confirmation_numbers.json");
+
+/// Every payload posted to an STK `CallBackURL`
+pub const STK_CALLBACKS: &[&str] = &[STK_CALLBACK_PAID, STK_CALLBACK_CANCELLED, STK_CALLBACK_INSUFFICIENT_FUNDS, STK_CALLBACK_STRINGS];
+
+/// Every payload posted to a `ResultURL` or `QueueTimeOutURL`
+pub const RESULTS: &[&str] = &[
+    B2C_RESULT, B2C_RESULT_FAILED, B2C_RESULT_SINGLE_PARAMETER, B2B_RESULT, REVERSAL_RESULT, TRANSACTION_STATUS_RESULT,
+    ACCOUNT_BALANCE_RESULT, QUEUE_TIMEOUT,
+];
+
+/// Every payload posted to a C2B `ValidationURL` or `ConfirmationURL`
+pub const C2B_TRANSACTIONS: &[&str] = &[C2B_VALIDATION, C2B_CONFIRMATION, C2B_CONFIRMATION_NUMBERS];
+
+/// A sample request, serialized, next to its golden file
+#[derive(Debug, Clone)]
+pub struct RequestSample {
+    /// Path of the API product the request is sent to
+    pub endpoint: &'static str,
+    /// The request as serialized by the crate
+    pub body: Value,
+    /// The golden file of the request
+    pub golden: &'static str,
+}
+
+impl RequestSample {
+    fn new<T: Serialize>(endpoint: &'static str, request: &T, golden: &'static str) -> RequestSample {
+        RequestSample {
+            endpoint: endpoint,
+            body: serde_json::to_value(request).expect("sample requests are serializable"),
+            golden: golden,
+        }
+    }
+
+    /// The JSON of the golden file
+    pub fn expected(&self) -> Value {
+        serde_json::from_str(self.golden).expect("golden files are JSON")
+    }
+}
+
+/// The sample request of every API product
+pub fn requests() -> Vec<RequestSample> {
+    vec![
+        RequestSample::new(b2c::ENDPOINT, &b2c(), B2C_REQUEST),
+        RequestSample::new(b2b::ENDPOINT, &b2b(), B2B_REQUEST),
+        RequestSample::new(reversal::ENDPOINT, &reversal(), REVERSAL_REQUEST),
+        RequestSample::new(transaction_status::ENDPOINT, &transaction_status(), TRANSACTION_STATUS_REQUEST),
+        RequestSample::new(account_balance::ENDPOINT, &account_balance(), ACCOUNT_BALANCE_REQUEST),
+        RequestSample::new(lipa_na_mpesa_online_payment_request::ENDPOINT, &stk_push(), STK_PUSH_REQUEST),
+        RequestSample::new(lipa_na_mpesa_online_query_request::ENDPOINT, &stk_query(), STK_QUERY_REQUEST),
+        RequestSample::new(c2b::REGISTER_URL_ENDPOINT, &register_url(), REGISTER_URL_REQUEST),
+        RequestSample::new(c2b::SIMULATE_ENDPOINT, &simulate(), SIMULATE_REQUEST),
+    ]
+}
+
+/// A salary payment of 100 shillings to the test customer
+pub fn b2c() -> B2C {
+    B2C {
+        InitiatorName: INITIATOR.to_string(),
+        SecurityCredential: SECURITY_CREDENTIAL.to_string(),
+        CommandID: CommandIds::SalaryPayment.to_string(),
+        Amount: Amount::shillings(100),
+        PartyA: short_code(),
+        PartyB: msisdn(),
+        Remarks: String::from("Salary"),
+        QueueTimeOutURL: QUEUE_TIMEOUT_URL.to_string(),
+        ResultURL: RESULT_URL.to_string(),
+        Occasion: String::from("July"),
+        OriginatorConversationID: String::new(),
+    }
+}
+
+/// A payment of 500 shillings to the paybill of the STK samples
+pub fn b2b() -> B2B {
+    let receiver = ShortCode::new(STK_SHORT_CODE).expect("the sample paybill is valid");
+    B2B::new(CommandIds::BusinessPayBill, Amount::shillings(500), short_code(), receiver)
+        .initiator(INITIATOR, SECURITY_CREDENTIAL)
+        .urls(RESULT_URL, QUEUE_TIMEOUT_URL)
+        .remarks("Supplies")
+        .account_reference("INV-1")
+}
+
+/// The reversal of the transaction `RECEIPT`
+pub fn reversal() -> Reversal {
+    Reversal::new(RECEIPT, short_code())
+        .initiator(INITIATOR, SECURITY_CREDENTIAL)
+        .urls(RESULT_URL, QUEUE_TIMEOUT_URL)
+        .remarks("Wrong recipient")
+}
+
+/// The status of the transaction `RECEIPT`
+pub fn transaction_status() -> TransactionSatus {
+    TransactionSatus::new(RECEIPT, short_code())
+        .initiator(INITIATOR, SECURITY_CREDENTIAL)
+        .urls(RESULT_URL, QUEUE_TIMEOUT_URL)
+        .remarks("Status")
+}
+
+/// The balance of the sandbox organization
+pub fn account_balance() -> AccountBalance {
+    AccountBalance::new(short_code())
+        .initiator(INITIATOR, SECURITY_CREDENTIAL)
+        .urls(RESULT_URL, QUEUE_TIMEOUT_URL)
+        .remarks("Balance")
+}
+
+/// A payment of 1 shilling from the test customer to the sandbox paybill
+pub fn stk_push() -> LipaNaMpesaOnlinePaymentRequest {
+    LipaNaMpesaOnlinePaymentRequest {
+        BusinessShortCode: ShortCode::new(STK_SHORT_CODE).expect("the sample paybill is valid"),
+        Password: password(STK_SHORT_CODE, STK_PASSKEY, TIMESTAMP),
+        Timestamp: TIMESTAMP.to_string(),
+        TransactionType: CommandIds::CustomerPayBillOnline.to_string(),
+        Amount: Amount::shillings(1),
+        PartyA: msisdn(),
+        PartyB: Party::ShortCode(ShortCode::new(STK_SHORT_CODE).expect("the sample paybill is valid")),
+        PhoneNumber: msisdn(),
+        CallBackURL: CALLBACK_URL.to_string(),
+        AccountReference: String::from("INV-1"),
+        TransactionDesc: String::from("Payment of INV-1"),
+    }
+}
+
+/// A query of the STK push `CHECKOUT_REQUEST_ID`, made at `TIMESTAMP`
+pub fn stk_query() -> LipaNaMpesaOnlineQueryRequest {
+    LipaNaMpesaOnlineQueryRequest {
+        BusinessShortCode: STK_SHORT_CODE.to_string(),
+        Password: password(STK_SHORT_CODE, STK_PASSKEY, TIMESTAMP),
+        Timestamp: TIMESTAMP.to_string(),
+        CheckoutRequestID: CHECKOUT_REQUEST_ID.to_string(),
+    }
+}
+
+/// The registration of the sample validation and confirmation urls
+pub fn register_url() -> RegisterUrl {
+    RegisterUrl::new(short_code(), VALIDATION_URL, CONFIRMATION_URL, "Completed")
+}
+
+/// A payment of 10 shillings from the test customer to the sandbox organization
+pub fn simulate() -> SimulateTransaction {
+    SimulateTransaction::new(short_code(), msisdn(), Amount::shillings(10))
+        .bill_ref_number("account")
+}
+
+fn short_code() -> ShortCode {
+    ShortCode::new(SHORT_CODE).expect("the sample shortcode is valid")
+}
+
+fn msisdn() -> Msisdn {
+    Msisdn::new(MSISDN).expect("the sample customer is valid")
+}
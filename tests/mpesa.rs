@@ -1,13 +1,45 @@
 extern crate mpesa;
+extern crate chrono;
+extern crate futures;
+extern crate base64;
+extern crate openssl;
+#[macro_use]
+extern crate serde_json;
 use mpesa::access_token::*;
 use mpesa::parameters::*;
+use mpesa::callbacks::*;
+use mpesa::callbacks::correlation::*;
+use mpesa::callbacks::dedup::*;
+use mpesa::callbacks::guard::*;
+use mpesa::callbacks::server::CallbackPaths;
+use mpesa::store::*;
+#[cfg(feature = "sqlite")]
+use mpesa::store::sql::SqliteTransactionStore;
+use mpesa::reconciliation::*;
+use mpesa::config::*;
+use mpesa::bulk::*;
+use mpesa::mock::MockServer;
+use mpesa::mock::daraja::{Callback, Daraja, Reply};
+use mpesa::mock::scenario::Scenario;
+use mpesa::client::{MpesaClient, MpesaClientError, Resolution, SANDBOX_URL};
+use mpesa::client::transport::*;
+use mpesa::client::polling::{StkOutcome, StkPolling};
+use mpesa::testing;
+use mpesa::api_products::b2b::B2B;
+use mpesa::api_products::b2c::{B2C, B2CResponse};
+use mpesa::api_products::c2b::SimulateTransaction;
+use mpesa::api_products::lipa_na_mpesa_online_payment_request::LipaNaMpesaOnlinePaymentRequest;
+use mpesa::api_products::reversal::Reversal;
+use mpesa::callbacks::verify::{Field, SecurityEvent, VerificationConfig, Verifier};
+use mpesa::security_credential::Certificate;
+use futures::Future;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[test]
+#[ignore] // calls the sandbox with the credentials from .env, MPESA_CONSUMER_KEY and MPESA_CONSUMER_SECRET or mpesa.toml
 fn test_access_token() {
-    let consumer_key = String::from("0SKHgBn66azzyz5Y22ZufBhP6m5JwmQT");
-    let consumer_secret = String::from("GUm27XVgi697SUfE");
-    let mut access_token = AccessToken::new(consumer_key, consumer_secret);
-    
+    let mut access_token = Config::load(None).and_then(|config| config.access_token()).expect("No credentials configured");
 
     match access_token.token() {
         Ok(token) => println!("Here is the token {}", token ),
@@ -21,4 +53,1503 @@ fn test_enum_conversion(){
     let string1 = CommandIds::TransactionReversal;
 
     
-}
\ No newline at end of file
+}
+
+struct Unmatched(std::sync::Mutex<Vec<String>>);
+
+impl CallbackHandler for Unmatched {
+    fn on_result(&self, result: TransactionResult) {
+        self.0.lock().unwrap().push(result.ConversationID);
+    }
+}
+
+fn result_callback(conversation_id: &str, originator_conversation_id: &str) -> TransactionResult {
+    let json = format!(r#"{{"Result": {{
+        "ResultType": 0, "ResultCode": 0, "ResultDesc": "The service request is processed successfully.",
+        "OriginatorConversationID": "{}", "ConversationID": "{}", "TransactionID": "LGR219G3EY",
+        "ResultParameters": {{"ResultParameter": {{"Key": "TransactionAmount", "Value": 10}}}}}}}}"#,
+        originator_conversation_id, conversation_id);
+    serde_json::from_str::<ResultCallback>(&json).unwrap().Result
+}
+
+#[test]
+fn test_correlator_delivers_matching_result() {
+    let correlator = Correlator::new(Unmatched(Default::default()));
+    let pending: PendingResult<TransactionResult> = correlator.register(&["AG_1", "10571-7910404-1"], Duration::from_secs(60));
+
+    correlator.on_result(result_callback("", "10571-7910404-1"));
+
+    let result = pending.wait().unwrap();
+    assert_eq!(result.TransactionID, "LGR219G3EY");
+    assert_eq!(result.parameter("TransactionAmount").and_then(|value| value.as_i64()), Some(10));
+    assert_eq!(correlator.pending(), 0);
+}
+
+#[test]
+fn test_correlator_routes_unmatched_to_fallback() {
+    let fallback = Arc::new(Unmatched(Default::default()));
+    let correlator = Correlator::new(fallback.clone());
+    let _pending: PendingResult<TransactionResult> = correlator.register(&["AG_1"], Duration::from_secs(60));
+
+    correlator.on_result(result_callback("AG_2", "10571-7910404-2"));
+
+    assert_eq!(*fallback.0.lock().unwrap(), vec![String::from("AG_2")]);
+    assert_eq!(correlator.pending(), 1);
+}
+
+#[test]
+fn test_correlator_times_out() {
+    let correlator = Correlator::new(Unmatched(Default::default()));
+    let pending: PendingResult<TransactionResult> = correlator.register(&["AG_1"], Duration::from_millis(10));
+
+    match pending.wait() {
+        Err(AwaitError::TimedOut) => (),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_correlator_holds_results_arriving_before_the_acknowledgement() {
+    let fallback = Arc::new(Unmatched(Default::default()));
+    let correlator = Correlator::new(fallback.clone());
+
+    let in_flight = correlator.in_flight();
+    correlator.on_result(result_callback("AG_1", "10571-7910404-1"));
+    correlator.on_result(result_callback("AG_2", "10571-7910404-2"));
+    assert!(fallback.0.lock().unwrap().is_empty());
+
+    let pending: PendingResult<TransactionResult> = in_flight.register(&["AG_1"], Duration::from_secs(60));
+    assert_eq!(pending.wait().unwrap().ConversationID, "AG_1");
+    assert_eq!(*fallback.0.lock().unwrap(), vec![String::from("AG_2")]);
+    assert_eq!(correlator.pending(), 0);
+}
+
+#[test]
+fn test_deduplicator_suppresses_repeated_results() {
+    let handler = Arc::new(Unmatched(Default::default()));
+    let deduplicator = Deduplicator::new(handler.clone(), MemoryStore::new(100, Duration::from_secs(60)));
+
+    deduplicator.on_result(result_callback("AG_1", "10571-7910404-1"));
+    deduplicator.on_result(result_callback("AG_1", "10571-7910404-1"));
+    deduplicator.on_result(result_callback("AG_2", "10571-7910404-2"));
+
+    assert_eq!(*handler.0.lock().unwrap(), vec![String::from("AG_1"), String::from("AG_2")]);
+}
+
+struct Failing(std::sync::atomic::AtomicUsize);
+
+impl CallbackHandler for Failing {
+    fn on_result(&self, _result: TransactionResult) {
+        if self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+            panic!("the first delivery fails");
+        }
+    }
+}
+
+#[test]
+fn test_deduplicator_passes_on_redelivery_after_handler_panic() {
+    let handler = Arc::new(Failing(Default::default()));
+    let deduplicator = Deduplicator::new(handler.clone(), MemoryStore::new(100, Duration::from_secs(60)));
+
+    let first = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| deduplicator.on_result(result_callback("AG_1", "10571-7910404-1"))));
+    assert!(first.is_err());
+    deduplicator.on_result(result_callback("AG_1", "10571-7910404-1"));
+    deduplicator.on_result(result_callback("AG_1", "10571-7910404-1"));
+
+    assert_eq!(handler.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_memory_store_forgets_least_recently_seen() {
+    let store = MemoryStore::new(2, Duration::from_secs(60));
+
+    assert!(store.first_seen("a").unwrap());
+    assert!(store.first_seen("b").unwrap());
+    assert!(!store.first_seen("a").unwrap());
+    assert!(store.first_seen("c").unwrap());
+
+    assert!(!store.first_seen("a").unwrap());
+    assert!(store.first_seen("b").unwrap());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_store_remembers_callbacks_until_forgotten() {
+    let store = SqliteStore::new(":memory:").unwrap();
+
+    assert!(store.first_seen("stk:ws_CO_1").unwrap());
+    assert!(!store.first_seen("stk:ws_CO_1").unwrap());
+    assert!(store.first_seen("c2b:LGR219G3EY").unwrap());
+
+    store.forget("stk:ws_CO_1").unwrap();
+    assert!(store.first_seen("stk:ws_CO_1").unwrap());
+    assert_eq!(store.purge(1).unwrap(), 0);
+    assert!(!store.first_seen("c2b:LGR219G3EY").unwrap());
+}
+
+#[test]
+fn test_guard_checks_source_behind_trusted_proxy() {
+    let allowlist = SourceAllowlist::safaricom().trusted_proxy("10.0.0.0/8".parse().unwrap());
+    let guard = CallbackGuard::new().allowlist(allowlist);
+    let proxy = Some("10.1.2.3".parse().unwrap());
+
+    assert_eq!(guard.check("/callbacks/result", proxy, Some("196.201.214.200"), None), Ok(()));
+    assert_eq!(guard.check("/callbacks/result", proxy, Some("196.201.214.200, 41.90.1.1"), None),
+               Err(Rejection::SourceNotAllowed("41.90.1.1".parse().unwrap())));
+    // X-Forwarded-For is ignored on connections that do not come from a trusted proxy
+    assert_eq!(guard.check("/callbacks/result", Some("41.90.1.1".parse().unwrap()), Some("196.201.214.200"), None),
+               Err(Rejection::SourceNotAllowed("41.90.1.1".parse().unwrap())));
+    assert_eq!(guard.check("/callbacks/result", None, None, None), Err(Rejection::UnknownSource));
+}
+
+#[test]
+fn test_guard_checks_path_tokens() {
+    let tokens = TokenRegistry::new();
+    let guard = CallbackGuard::new().tokens(tokens.clone());
+    let urls = CallbackUrls::new("https://example.com/", CallbackPaths::default(), tokens.clone()).issue();
+
+    assert_eq!(urls.stk_url, format!("https://example.com/callbacks/stk/{}", urls.token));
+    assert_eq!(guard.check("/callbacks/stk", None, None, Some(&urls.token)), Ok(()));
+    assert_eq!(guard.check("/callbacks/stk", None, None, Some("guessed")), Err(Rejection::InvalidToken));
+
+    tokens.revoke(&urls.token);
+    assert_eq!(guard.check("/callbacks/stk", None, None, Some(&urls.token)), Err(Rejection::InvalidToken));
+}
+
+#[test]
+fn test_cidr_parsing() {
+    let cidr: Cidr = "196.201.212.0/23".parse().unwrap();
+
+    assert!(cidr.contains(&"196.201.213.44".parse().unwrap()));
+    assert!(!cidr.contains(&"196.201.214.200".parse().unwrap()));
+    assert!(cidr.contains(&"::ffff:196.201.212.69".parse().unwrap()));
+    assert_eq!("196.201.212.0/33".parse::<Cidr>(), Err(CidrParseError::InvalidPrefix(String::from("33"))));
+}
+
+#[test]
+fn test_recorder_concludes_acknowledged_transaction() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    let recorder = Recorder::new(Unmatched(Default::default()), store.clone());
+
+    let mut transaction = Transaction::new(Product::B2C, &json!({"SecurityCredential": "secret", "Amount": "10"}));
+    store.insert(&transaction).unwrap();
+    transaction.transition(TransactionState::Submitted).unwrap();
+    transaction.acknowledge(AcknowledgementIds {
+        conversation_id: Some(String::from("AG_1")),
+        originator_conversation_id: Some(String::from("10571-7910404-1")),
+        ..AcknowledgementIds::default()
+    }).unwrap();
+    store.update(&transaction).unwrap();
+
+    recorder.on_result(result_callback("AG_1", "10571-7910404-1"));
+
+    let recorded = store.get(&transaction.id).unwrap().unwrap();
+    assert_eq!(recorded.state, TransactionState::Completed);
+    assert_eq!(recorded.receipt, Some(String::from("LGR219G3EY")));
+    assert!(!recorded.request.contains("secret"));
+    assert_eq!(store.callbacks(&transaction.id).unwrap().len(), 1);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_store_records_transactions_and_callbacks() {
+    let store = SqliteTransactionStore::new(":memory:").unwrap();
+
+    let mut transaction = Transaction::new(Product::B2C, &json!({"SecurityCredential": "secret", "Amount": "10"}));
+    store.insert(&transaction).unwrap();
+    assert_eq!(store.get(&transaction.id).unwrap(), Some(transaction.clone()));
+    assert!(store.insert(&transaction).is_err());
+
+    transaction.conversation_id = Some(String::from("AG_1"));
+    transaction.originator_conversation_id = Some(String::from("10571-7910404-1"));
+    store.update(&transaction).unwrap();
+    assert_eq!(store.find_by_reference("AG_1").unwrap(), Some(transaction.clone()));
+    assert_eq!(store.find_by_reference("10571-7910404-1").unwrap(), Some(transaction.clone()));
+    assert_eq!(store.find_by_reference("AG_2").unwrap(), None);
+    assert!(!store.get(&transaction.id).unwrap().unwrap().request.contains("secret"));
+
+    let callback = CallbackRecord::new(Some(transaction.id.clone()), CallbackKind::Result, "AG_1", &json!({"Result": {}}));
+    store.insert_callback(&callback).unwrap();
+    assert_eq!(store.callbacks(&transaction.id).unwrap(), vec![callback]);
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_store_updates_only_from_the_expected_state() {
+    let store = SqliteTransactionStore::new(":memory:").unwrap();
+    let mut transaction = Transaction::new(Product::B2C, &json!({"Amount": "10"}));
+    store.insert(&transaction).unwrap();
+
+    transaction.transition(TransactionState::Submitted).unwrap();
+    assert!(store.update_from(TransactionState::Created, &transaction).unwrap());
+    // a response recorded after the callback does not overwrite its outcome
+    let mut concluded = transaction.clone();
+    concluded.conclude(TransactionState::Completed, Some(0), "processed", Some(String::from("LGR219G3EY"))).unwrap();
+    assert!(store.update_from(TransactionState::Submitted, &concluded).unwrap());
+    transaction.acknowledge(AcknowledgementIds { conversation_id: Some(String::from("AG_1")), ..AcknowledgementIds::default() }).unwrap();
+    assert!(!store.update_from(TransactionState::Submitted, &transaction).unwrap());
+
+    assert_eq!(store.get(&transaction.id).unwrap(), Some(concluded));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_store_keeps_idempotency_keys_unique() {
+    let store = SqliteTransactionStore::new(":memory:").unwrap();
+    let transaction = Transaction {
+        idempotency_key: Some(String::from("payroll-2018-08-1")),
+        ..Transaction::new(Product::B2C, &json!({"Amount": "10"}))
+    };
+    store.insert(&transaction).unwrap();
+    store.insert(&Transaction::new(Product::B2C, &json!({"Amount": "10"}))).unwrap();
+
+    assert_eq!(store.find_by_idempotency_key("payroll-2018-08-1").unwrap(), Some(transaction.clone()));
+    assert_eq!(store.find_by_idempotency_key("payroll-2018-08-2").unwrap(), None);
+    match store.insert(&Transaction { id: String::from("other"), ..transaction }) {
+        Err(StoreError::DuplicateKey(key)) => assert_eq!(key, "payroll-2018-08-1"),
+        other => panic!("expected the key to be taken, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_lifecycle_rejects_illegal_transitions() {
+    let mut transaction = Transaction::new(Product::B2C, &json!({}));
+
+    assert_eq!(transaction.transition(TransactionState::Completed),
+               Err(IllegalTransition { from: TransactionState::Created, to: TransactionState::Completed }));
+    transaction.transition(TransactionState::Submitted).unwrap();
+    transaction.transition(TransactionState::Unknown).unwrap();
+    transaction.conclude(TransactionState::Completed, Some(0), "processed", Some(String::from("LGR219G3EY"))).unwrap();
+    // repeated callbacks leave the transaction as it is
+    transaction.transition(TransactionState::Completed).unwrap();
+
+    assert!(transaction.conclude(TransactionState::Failed, Some(1), "failed", None).is_err());
+    assert_eq!(transaction.state, TransactionState::Completed);
+    assert_eq!(transaction.result_code, Some(0));
+}
+
+#[test]
+fn test_recorder_marks_reversed_transactions() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    let recorder = Recorder::new(Unmatched(Default::default()), store.clone());
+
+    let mut payment = Transaction::new(Product::B2C, &json!({}));
+    payment.transition(TransactionState::Submitted).unwrap();
+    payment.conclude(TransactionState::Completed, Some(0), "processed", Some(String::from("LGR219G3EY"))).unwrap();
+    store.insert(&payment).unwrap();
+
+    let mut reversal = Transaction::new(Product::Reversal, &json!({"TransactionID": "LGR219G3EY"}));
+    reversal.transition(TransactionState::Submitted).unwrap();
+    reversal.acknowledge(AcknowledgementIds {
+        conversation_id: Some(String::from("AG_R")),
+        ..AcknowledgementIds::default()
+    }).unwrap();
+    store.insert(&reversal).unwrap();
+
+    let mut result = result_callback("AG_R", "10571-7910404-3");
+    result.TransactionID = String::from("LKA31H5T1Z");
+    recorder.on_result(result);
+
+    assert_eq!(store.get(&reversal.id).unwrap().unwrap().state, TransactionState::Completed);
+    assert_eq!(store.get(&payment.id).unwrap().unwrap().state, TransactionState::Reversed);
+}
+
+/// Makes the transaction known by `reference` look like it has been waiting for its outcome for a while
+fn backdate(store: &MemoryTransactionStore, reference: &str) -> String {
+    let mut transaction = store.find_by_reference(reference).unwrap().unwrap();
+    transaction.updated_at = transaction.updated_at - chrono::Duration::minutes(30);
+    store.update(&transaction).unwrap();
+    transaction.id
+}
+
+#[test]
+fn test_reconciler_escalates_transactions_it_cannot_look_up() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    let mut transaction = Transaction::new(Product::B2C, &json!({}));
+    transaction.transition(TransactionState::Submitted).unwrap();
+    transaction.updated_at = transaction.updated_at - chrono::Duration::minutes(30);
+    store.insert(&transaction).unwrap();
+
+    let client = || MpesaClient::new(AccessToken::new(String::from("key"), String::from("secret")), SANDBOX_URL);
+    let lookup = VerificationConfig {
+        initiator: String::from("testapi"),
+        security_credential: String::from("credential"),
+        short_code: Party::from(ShortCode::new("600000").unwrap()),
+        result_url: String::from("https://example.com/callbacks/result"),
+        timeout_url: String::from("https://example.com/callbacks/timeout"),
+        stk_short_code: String::from("174379"),
+        stk_passkey: String::from("passkey"),
+    };
+    let escalated = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = escalated.clone();
+    let mut reconciler = Reconciler::new(client(), store.clone(), lookup.clone())
+        .on_escalation(move |transaction| sink.lock().unwrap().push(transaction.id.clone()));
+
+    let report = reconciler.run_once().unwrap();
+
+    assert_eq!(report, ReconciliationReport { resolved: 0, pending: 0, escalated: 1 });
+    assert_eq!(*escalated.lock().unwrap(), vec![transaction.id.clone()]);
+    assert_eq!(store.get(&transaction.id).unwrap().unwrap().state, TransactionState::Escalated);
+    // escalated transactions are not looked up again, not even after a restart
+    assert_eq!(reconciler.run_once().unwrap(), ReconciliationReport::default());
+    assert_eq!(Reconciler::new(client(), store.clone(), lookup).run_once().unwrap(), ReconciliationReport::default());
+}
+
+#[test]
+fn test_reconciler_resolves_stuck_transactions_with_the_api() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    // the callbacks are kept by the mock, so the transactions are left waiting for them
+    let mock = Delivering::new(Daraja::new());
+    let mut client = mock.client().store(store.clone());
+    let paid = client.send_stk_push(&testing::stk_push()).unwrap();
+    let cancelled = LipaNaMpesaOnlinePaymentRequest { PhoneNumber: "254700000002".parse().unwrap(), ..testing::stk_push() };
+    let cancelled = client.send_stk_push(&cancelled).unwrap();
+    let unreached = LipaNaMpesaOnlinePaymentRequest { PhoneNumber: "254700000004".parse().unwrap(), ..testing::stk_push() };
+    let unreached = client.send_stk_push(&unreached).unwrap();
+    let payment = client.send_b2c(&b2c_request()).unwrap();
+    let paid = backdate(&store, &paid.CheckoutRequestID);
+    let cancelled = backdate(&store, &cancelled.CheckoutRequestID);
+    let unreached = backdate(&store, &unreached.CheckoutRequestID);
+    let payment = backdate(&store, &payment.ConversationID);
+
+    let mut reconciler = Reconciler::new(mock.client(), store.clone(), verification_config()).query_interval(Duration::from_millis(0));
+
+    assert_eq!(reconciler.run_once().unwrap(), ReconciliationReport { resolved: 3, pending: 1, escalated: 0 });
+    let paid = store.get(&paid).unwrap().unwrap();
+    assert_eq!((paid.state, paid.result_code), (TransactionState::Completed, Some(0)));
+    let cancelled = store.get(&cancelled).unwrap().unwrap();
+    assert_eq!((cancelled.state, cancelled.result_code), (TransactionState::Failed, Some(1032)));
+    // an unreachable customer is not a final answer
+    assert_eq!(store.get(&unreached).unwrap().unwrap().state, TransactionState::Acknowledged);
+    let payment = store.get(&payment).unwrap().unwrap();
+    assert_eq!((payment.state, payment.result_code), (TransactionState::Completed, Some(0)));
+    assert_eq!(reconciler.run_once().unwrap(), ReconciliationReport { resolved: 0, pending: 1, escalated: 0 });
+}
+
+fn status_result(result_code: i64, status: &str) -> TransactionResult {
+    let json = format!(r#"{{"Result": {{
+        "ResultType": 0, "ResultCode": {}, "ResultDesc": "The service request is processed successfully.",
+        "OriginatorConversationID": "10571-7910404-1", "ConversationID": "AG_1", "TransactionID": "LGR219G3EY",
+        "ResultParameters": {{"ResultParameter": [
+            {{"Key": "ReceiptNo", "Value": "LGR219G3EY"}}, {{"Key": "TransactionStatus", "Value": "{}"}}]}}}}}}"#,
+        result_code, status);
+    serde_json::from_str::<ResultCallback>(&json).unwrap().Result
+}
+
+#[test]
+fn test_resolution_from_transaction_status() {
+    let completed = Resolution::from_status(status_result(0, "Completed"));
+    assert_eq!(completed.state(), Some(TransactionState::Completed));
+    assert_eq!(completed.receipt(), Some(String::from("LGR219G3EY")));
+
+    assert_eq!(Resolution::from_status(status_result(0, "Declined")).state(), Some(TransactionState::Failed));
+    assert_eq!(Resolution::from_status(status_result(0, "Pending")).state(), None);
+    match Resolution::from_status(status_result(2001, "")) {
+        Resolution::Rejected(_) => (),
+        other => panic!("expected the query to be rejected, got {:?}", other),
+    }
+}
+
+fn b2c_request() -> B2C {
+    B2C {
+        InitiatorName: String::from("testapi"),
+        SecurityCredential: String::from("credential"),
+        CommandID: CommandIds::SalaryPayment.to_string(),
+        Amount: Amount::shillings(100),
+        PartyA: ShortCode::new("600000").unwrap(),
+        PartyB: "0708374149".parse().unwrap(),
+        Remarks: String::from("August salary"),
+        QueueTimeOutURL: String::from("https://example.com/callbacks/timeout"),
+        ResultURL: String::from("https://example.com/callbacks/result"),
+        Occasion: String::new(),
+        OriginatorConversationID: String::new(),
+    }
+}
+
+#[test]
+fn test_idempotency_key_is_not_sent_twice() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    let mut acknowledged = Transaction::new(Product::B2C, &b2c_request());
+    acknowledged.idempotency_key = Some(String::from("payroll-2018-08-1"));
+    acknowledged.acknowledgement = Some(String::from(r#"{"ConversationID": "AG_1", "OriginatorConversationID": "10571-7910404-1",
+        "ResponseCode": "0", "ResponseDescription": "Accept the service request successfully."}"#));
+    store.insert(&acknowledged).unwrap();
+
+    let mut in_flight = Transaction::new(Product::B2C, &b2c_request());
+    in_flight.idempotency_key = Some(String::from("payroll-2018-08-2"));
+    in_flight.transition(TransactionState::Submitted).unwrap();
+    store.insert(&in_flight).unwrap();
+
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL).store(store.clone());
+
+    let acknowledgement = client.send_b2c_idempotent("payroll-2018-08-1", &b2c_request()).unwrap();
+    assert_eq!(acknowledgement.ConversationID, "AG_1");
+    match client.send_b2c_idempotent("payroll-2018-08-2", &b2c_request()) {
+        Err(MpesaClientError::Duplicate(transaction)) => assert_eq!(transaction.state, TransactionState::Submitted),
+        other => panic!("expected a duplicate, got {:?}", other),
+    }
+    assert!(store.insert(&Transaction { id: String::from("other"), ..in_flight.clone() }).is_err());
+}
+
+#[test]
+fn test_idempotency_keys_are_tied_to_their_request() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    let transport = Arc::new(InProcess(std::sync::Mutex::new(Daraja::new().credentials("key", "secret"))));
+    let client = |secret: &str| {
+        let access_token = AccessToken::new(String::from("key"), String::from(secret));
+        MpesaClient::new(access_token, SANDBOX_URL).transport(transport.clone()).store(store.clone())
+    };
+
+    // never sent, so sending it again with the key goes through
+    match client("wrong").send_b2c_idempotent("payroll-2018-08-1", &b2c_request()) {
+        Err(MpesaClientError::AccessToken(_)) => (),
+        other => panic!("expected no access token, got {:?}", other),
+    }
+    let mut client = client("secret");
+    let acknowledgement = client.send_b2c_idempotent("payroll-2018-08-1", &b2c_request()).unwrap();
+    assert_eq!(client.send_b2c_idempotent("payroll-2018-08-1", &b2c_request()).unwrap().ConversationID, acknowledgement.ConversationID);
+
+    // recorded by an attempt that stopped before sending it
+    let recorded = Transaction { idempotency_key: Some(String::from("payroll-2018-08-2")), ..Transaction::new(Product::B2C, &b2c_request()) };
+    store.insert(&recorded).unwrap();
+    let acknowledgement = client.send_b2c_idempotent("payroll-2018-08-2", &b2c_request()).unwrap();
+    let sent = store.get(&recorded.id).unwrap().unwrap();
+    assert_eq!((sent.state, sent.conversation_id), (TransactionState::Acknowledged, Some(acknowledgement.ConversationID)));
+
+    let different = B2C { Amount: Amount::shillings(200), ..b2c_request() };
+    match client.send_b2c_idempotent("payroll-2018-08-1", &different) {
+        Err(MpesaClientError::IdempotencyMismatch(key)) => assert_eq!(key, "payroll-2018-08-1"),
+        other => panic!("expected the key to be refused, got {:?}", other),
+    }
+}
+
+/// Answers requests with the mock API in-process and records results straight away, before the request is acknowledged
+struct Recording {
+    daraja: std::sync::Mutex<Daraja>,
+    recorder: Recorder<Unmatched, Arc<MemoryTransactionStore>>,
+}
+
+impl std::fmt::Debug for Recording {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Recording")
+    }
+}
+
+impl Transport for Recording {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        let reply = answer(&mut self.daraja.lock().unwrap(), request);
+        for callback in reply.callbacks {
+            if let Ok(result) = serde_json::from_value::<ResultCallback>(callback.body) {
+                self.recorder.on_result(result.Result);
+            }
+        }
+        Ok(HttpResponse { status: reply.status, body: reply.body.to_string() })
+    }
+}
+
+#[test]
+fn test_results_arriving_before_the_acknowledgement_are_kept() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    let transport = Recording {
+        daraja: std::sync::Mutex::new(Daraja::new().credentials("key", "secret")),
+        recorder: Recorder::new(Unmatched(Default::default()), store.clone()),
+    };
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL).transport(Arc::new(transport)).store(store.clone());
+
+    let acknowledgement = client.send_b2c(&b2c_request()).unwrap();
+
+    let transaction = store.find_by_reference(&acknowledgement.ConversationID).unwrap().unwrap();
+    assert_eq!(transaction.state, TransactionState::Completed);
+    assert!(transaction.receipt.is_some());
+    assert_eq!(store.callbacks(&transaction.id).unwrap().len(), 1);
+}
+
+/// Issues tokens with the mock API but fails every other request with an internal server error
+#[derive(Debug)]
+struct FailingServer(std::sync::Mutex<Daraja>);
+
+impl Transport for FailingServer {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        if request.method == "GET" {
+            let reply = answer(&mut self.0.lock().unwrap(), request);
+            return Ok(HttpResponse { status: reply.status, body: reply.body.to_string() });
+        }
+        Ok(HttpResponse { status: 500, body: String::from(r#"{"errorCode": "500.003.02", "errorMessage": "Internal Server Error"}"#) })
+    }
+}
+
+#[test]
+fn test_server_errors_leave_the_outcome_of_payments_unknown() {
+    let store = Arc::new(MemoryTransactionStore::new());
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let transport = FailingServer(std::sync::Mutex::new(Daraja::new()));
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL).transport(Arc::new(transport)).store(store.clone());
+
+    let originator_conversation_id = match client.send_b2c(&b2c_request()) {
+        Err(MpesaClientError::OutcomeUnknown { originator_conversation_id: Some(originator_conversation_id), .. }) => originator_conversation_id,
+        other => panic!("expected the outcome to be unknown, got {:?}", other),
+    };
+    let transaction = store.find_by_reference(&originator_conversation_id).unwrap().unwrap();
+    assert_eq!(transaction.state, TransactionState::Unknown);
+
+    // the B2B API assigns the id in the acknowledgement, so there is none to look the payment up with
+    let request = B2B::new(CommandIds::BusinessPayBill, Amount::shillings(100), ShortCode::new("600000").unwrap(),
+                           ShortCode::new("600001").unwrap())
+        .initiator("testapi", "credential")
+        .urls("https://example.com/callbacks/result", "https://example.com/callbacks/timeout")
+        .account_reference("INV-1");
+    match client.send_b2b(&request) {
+        Err(MpesaClientError::OutcomeUnknown { originator_conversation_id: None, .. }) => (),
+        other => panic!("expected the outcome to be unknown, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_result_codes_round_trip() {
+    assert_eq!("1032".parse::<ResultCodes>().unwrap(), ResultCodes::RequestCancelledByUser);
+    assert_eq!("C2B00012".parse::<ResultCodes>().unwrap(), ResultCodes::InvalidAccountNumber);
+    assert_eq!(ResultCodes::InvalidAccountNumber.to_string(), "C2B00012");
+    assert_eq!(ResultCodes::from(424242), ResultCodes::Unknown(424242));
+    assert!("not a code".parse::<ResultCodes>().is_err());
+
+    let codes: Vec<ResultCodes> = serde_json::from_str(r#"[0, "1", "C2B00016", 2001]"#).unwrap();
+    assert_eq!(codes, vec![ResultCodes::Success, ResultCodes::InsufficientFunds, ResultCodes::OtherError,
+                           ResultCodes::InvalidInitiatorInformation]);
+    assert_eq!(serde_json::to_string(&codes).unwrap(), r#"[0,1,"C2B00016",2001]"#);
+
+    assert_eq!(ResultCodes::Success.class(), ResultClass::Success);
+    assert_eq!(ResultCodes::RequestCancelledByUser.class(), ResultClass::UserCaused);
+    assert!(ResultCodes::SubscriberLocked.is_retryable());
+    assert!(ResultCodes::TransactionExpired.is_retryable());
+    assert_eq!(ResultCodes::DuplicateDetected.class(), ResultClass::Permanent);
+}
+
+#[test]
+fn test_command_ids_are_checked_against_products() {
+    assert_eq!("BusinessPayToBulk".parse::<CommandIds>().unwrap(), CommandIds::BusinessPayToBulk);
+    assert!("PayEveryone".parse::<CommandIds>().is_err());
+    assert_eq!(serde_json::to_string(&CommandIds::CustomerBuyGoodsOnline).unwrap(), r#""CustomerBuyGoodsOnline""#);
+    assert_eq!(serde_json::from_str::<CommandIds>(r#""AgencyFloatAdvance""#).unwrap(), CommandIds::AgencyFloatAdvance);
+
+    assert!(CommandIds::MerchantToMerchantTransfer.is_compatible(Product::B2B));
+    assert!(!CommandIds::SalaryPayment.is_compatible(Product::B2B));
+
+    let request = B2C { CommandID: CommandIds::TransactionReversal.to_string(), ..b2c_request() };
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL);
+    match client.send_b2c(&request) {
+        Err(MpesaClientError::InvalidRequest(ParameterError::IncompatibleCommand { command, product })) => {
+            assert_eq!(command, CommandIds::TransactionReversal);
+            assert_eq!(product, Product::B2C);
+        },
+        other => panic!("expected the command to be rejected, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_msisdn_normalization() {
+    for number in &["0708374149", "0708 374 149", "0708-374-149", "+254708374149", "254708374149"] {
+        assert_eq!(number.parse::<Msisdn>().unwrap().as_str(), "254708374149");
+    }
+    assert_eq!(Msisdn::new("0110374149").unwrap().as_str(), "254110374149");
+    // Airtel, a landline, too short, not a number and without the country code or leading zero
+    for number in &["0733374149", "0208374149", "070837414", "07O8374149", "708374149", "+708374149"] {
+        assert!(Msisdn::new(number).is_err(), "{} should be rejected", number);
+    }
+
+    let body = serde_json::to_value(&b2c_request()).unwrap();
+    assert_eq!(body["PartyB"], "254708374149");
+}
+
+#[test]
+fn test_amounts_are_checked_against_product_limits() {
+    assert_eq!("10.5".parse::<Amount>().unwrap().cents(), 1050);
+    assert_eq!("10.05".parse::<Amount>().unwrap().to_string(), "10.05");
+    for amount in &["", "-10", "10.005", "1,000", "ten"] {
+        assert!(amount.parse::<Amount>().is_err(), "{} should be rejected", amount);
+    }
+
+    let out_of_range = |amount: u64, product: Product| match Amount::shillings(amount).check(product) {
+        Err(ParameterError::AmountOutOfRange { result_code, .. }) => Some(result_code),
+        _ => None,
+    };
+    assert_eq!(out_of_range(9, Product::B2C), Some(ResultCodes::LessThanMinimumTransactionValue));
+    assert_eq!(out_of_range(250_001, Product::StkPush), Some(ResultCodes::MoreThanMaximumTransactionValue));
+    assert_eq!(out_of_range(1, Product::StkPush), None);
+    assert_eq!(out_of_range(std::u64::MAX, Product::StkPush), Some(ResultCodes::MoreThanMaximumTransactionValue));
+    assert!("99.50".parse::<Amount>().unwrap().check(Product::StkPush).is_err());
+
+    let callback: StkCallback = serde_json::from_str(r#"{"MerchantRequestID": "1", "CheckoutRequestID": "ws_CO_1", "ResultCode": 0,
+        "ResultDesc": "ok", "CallbackMetadata": {"Item": [{"Name": "Amount", "Value": 1.00}]}}"#).unwrap();
+    assert_eq!(callback.amount(), Some(Amount::shillings(1)));
+    assert_eq!(serde_json::to_value(&b2c_request()).unwrap()["Amount"], "100");
+}
+
+#[test]
+fn test_party_identifiers_are_derived() {
+    assert!(ShortCode::new("600000").is_ok());
+    for number in &["06000", "6000", "60000000", "600 000"] {
+        assert!(ShortCode::new(number).is_err(), "{} should be rejected", number);
+    }
+
+    let reversal = Reversal::new("LKXXXX1234", Paybill::new("600000").unwrap());
+    assert_eq!(reversal.RecieverIdentifierType, Identifiers::ShortCode);
+    let body = serde_json::to_value(&reversal).unwrap();
+    assert_eq!(body["ReceiverParty"], "600000");
+    assert_eq!(body["RecieverIdentifierType"], "4");
+    assert!(reversal.validate().is_ok());
+
+    let mismatched = Reversal { RecieverIdentifierType: Identifiers::TillNumber, ..reversal };
+    assert!(mismatched.validate().is_err());
+
+    let request = B2B::new(CommandIds::BusinessPayBill, Amount::shillings(100), ShortCode::new("600000").unwrap(),
+                           "0708374149".parse::<Msisdn>().unwrap());
+    assert_eq!(request.RecieverIdentifierType, Identifiers::MSISDN);
+    assert!(request.validate().is_err());
+}
+
+#[test]
+fn test_callback_urls_are_checked_before_sending() {
+    assert_eq!(check_callback_url("https://196.201.214.200/callbacks", Environment::Production), Err(UrlRule::IpLiteral));
+    assert_eq!(check_callback_url("https://196.201.214.200/callbacks", Environment::Sandbox), Ok(()));
+    assert_eq!(check_callback_url("https://example.com/callbacks?run=CMD", Environment::Sandbox), Err(UrlRule::Keyword("cmd")));
+    assert_eq!(check_callback_url("example.com/callbacks", Environment::Sandbox), Err(UrlRule::Malformed));
+    assert_eq!(check_callback_url("ftp://example.com/callbacks", Environment::Sandbox), Err(UrlRule::Malformed));
+
+    let request = B2C { ResultURL: String::from("http://example.com/callbacks/result"), ..b2c_request() };
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL).environment(Environment::Production);
+    match client.send_b2c(&request) {
+        Err(MpesaClientError::InvalidRequest(ParameterError::CallbackUrl { field, rule, .. })) => {
+            assert_eq!(field, "ResultURL");
+            assert_eq!(rule, UrlRule::Https);
+        },
+        other => panic!("expected the url to be rejected, got {:?}", other),
+    }
+
+    let urls = CallbackUrls::new("http://example.com", CallbackPaths::default(), TokenRegistry::new());
+    assert!(urls.check_urls(Environment::Sandbox).is_ok());
+    match urls.check_urls(Environment::Production) {
+        Err(ParameterError::CallbackUrl { field, rule, .. }) => assert_eq!((field.as_str(), rule), ("ResultURL", UrlRule::Https)),
+        other => panic!("expected the urls to be rejected, got {:?}", other),
+    }
+    let config = Config::from_toml("[sandbox]\nconsumer_key = \"key\"\nconsumer_secret = \"secret\"\ntimeout_url = \"https://example.com/cmd\"", "sandbox").unwrap();
+    match config.client() {
+        Err(ConfigError::Invalid(name, _)) => assert_eq!(name, "timeout_url"),
+        other => panic!("expected the timeout url to be rejected, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_c2b_simulation_command_follows_receiver() {
+    let msisdn: Msisdn = "0708374149".parse().unwrap();
+    let request = SimulateTransaction::new(TillNumber::new("174379").unwrap(), msisdn.clone(), Amount::shillings(10));
+    assert_eq!(request.CommandID, "CustomerBuyGoodsOnline");
+    assert!(request.validate().is_ok());
+
+    let request = SimulateTransaction::new(Paybill::new("600000").unwrap(), msisdn.clone(), Amount::shillings(10)).bill_ref_number("account");
+    assert_eq!(request.CommandID, "CustomerPayBillOnline");
+    assert_eq!(serde_json::to_value(&request).unwrap()["BillRefNumber"], "account");
+
+    let request = SimulateTransaction::new(msisdn.clone(), msisdn, Amount::shillings(10));
+    assert!(request.validate().is_err());
+}
+
+#[test]
+fn test_requests_match_golden_files() {
+    for sample in testing::requests() {
+        assert_eq!(sample.body, sample.expected(), "{} does not match its golden file", sample.endpoint);
+    }
+}
+
+#[test]
+fn test_documented_callbacks_parse() {
+    let paid = serde_json::from_str::<StkCallbackBody>(testing::STK_CALLBACK_PAID).unwrap().Body.stk_callback;
+    assert_eq!((paid.ResultCode, paid.amount()), (0, Some(Amount::shillings(1))));
+    assert_eq!(paid.item("MpesaReceiptNumber").unwrap(), "NLJ7RT61SV");
+    let strings = serde_json::from_str::<StkCallbackBody>(testing::STK_CALLBACK_STRINGS).unwrap().Body.stk_callback;
+    assert_eq!((strings.ResultCode, strings.amount()), (0, Some(Amount::shillings(1))));
+    let cancelled = serde_json::from_str::<StkCallbackBody>(testing::STK_CALLBACK_CANCELLED).unwrap().Body.stk_callback;
+    assert_eq!(cancelled.ResultCode, 1032);
+    assert!(cancelled.CallbackMetadata.is_none() && cancelled.amount().is_none());
+    for payload in testing::STK_CALLBACKS {
+        assert!(serde_json::from_str::<StkCallbackBody>(payload).is_ok(), "{}", payload);
+    }
+
+    let paid = serde_json::from_str::<ResultCallback>(testing::B2C_RESULT).unwrap().Result;
+    assert_eq!(paid.amount(), Some(Amount::shillings(10)));
+    assert_eq!(paid.parameter("TransactionReceipt").unwrap(), "NLJ41HAY6Q");
+    let failed = serde_json::from_str::<ResultCallback>(testing::B2C_RESULT_FAILED).unwrap().Result;
+    assert_eq!(failed.ResultCode, 2001);
+    assert!(failed.ResultParameters.is_none() && failed.amount().is_none());
+    let single = serde_json::from_str::<ResultCallback>(testing::B2C_RESULT_SINGLE_PARAMETER).unwrap().Result;
+    assert_eq!((single.ResultType, single.ResultCode, single.amount()), (0, 0, Some(Amount::shillings(10))));
+    for payload in testing::RESULTS {
+        assert!(serde_json::from_str::<ResultCallback>(payload).is_ok(), "{}", payload);
+    }
+
+    let confirmation: C2BTransaction = serde_json::from_str(testing::C2B_CONFIRMATION_NUMBERS).unwrap();
+    assert_eq!((confirmation.TransAmount.as_str(), confirmation.amount()), ("10", Some(Amount::shillings(10))));
+    assert_eq!((confirmation.BusinessShortCode.as_str(), confirmation.MSISDN.as_str()), ("600000", "254708374149"));
+    assert_eq!(confirmation.TransTime, "20191122063845");
+    for payload in testing::C2B_TRANSACTIONS {
+        assert!(serde_json::from_str::<C2BTransaction>(payload).is_ok(), "{}", payload);
+    }
+}
+
+#[test]
+fn test_config_profiles() {
+    let file = r#"
+        [sandbox]
+        consumer_key = "key"
+        consumer_secret = "secret"
+        short_code = 600000
+        result_url = "https://example.com/callbacks/result"
+
+        [prod]
+        environment = "production"
+        base_url = "https://gateway.example.com/"
+    "#;
+
+    let config = Config::from_toml(file, "sandbox").unwrap();
+    assert_eq!(config.environment, Environment::Sandbox);
+    assert_eq!(config.base_url(), SANDBOX_URL);
+    assert_eq!(config.short_code, Some(ShortCode::new("600000").unwrap()));
+    assert!(config.access_token().is_ok());
+
+    let mut config = Config::from_toml(file, "prod").unwrap();
+    assert_eq!(config.environment, Environment::Production);
+    assert_eq!(config.base_url(), "https://gateway.example.com/");
+    match config.client() {
+        Err(ConfigError::Missing(name)) => assert_eq!(name, "consumer_key"),
+        other => panic!("expected the consumer key to be missing, got {:?}", other.map(|_| ())),
+    }
+    config.set("consumer_key", "key").unwrap();
+    config.set("consumer_secret", "secret").unwrap();
+    assert!(config.client().is_ok());
+
+    assert!(config.set("short_code", "12").is_err());
+    assert!(config.set("environment", "staging").is_err());
+    match Config::from_toml(file, "staging") {
+        Err(ConfigError::UnknownProfile(profile)) => assert_eq!(profile, "staging"),
+        other => panic!("expected the profile to be unknown, got {:?}", other.map(|_| ())),
+    }
+    assert!(Config::from_toml("[sandbox]\nconsumer_kee = \"key\"", "sandbox").is_err());
+}
+
+/// A self-signed certificate standing in for the M-Pesa one
+const TEST_CERTIFICATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certificates/test.cer");
+
+/// Decrypts a security credential with the private key of the test certificate
+fn decrypt(security_credential: &str) -> String {
+    let key = openssl::rsa::Rsa::private_key_from_pem(include_bytes!("certificates/test.key")).unwrap();
+    let encrypted = base64::decode(security_credential).unwrap();
+    let mut decrypted = vec![0; key.size() as usize];
+    let length = key.private_decrypt(&encrypted, &mut decrypted, openssl::rsa::Padding::PKCS1).unwrap();
+    String::from_utf8(decrypted[..length].to_vec()).unwrap()
+}
+
+#[test]
+fn test_config_generates_the_security_credential() {
+    let file = format!("[sandbox]\ninitiator_password = \"Safaricom999!*!\"\ncertificate_path = '{}'\n", TEST_CERTIFICATE);
+    let mut config = Config::from_toml(&file, "sandbox").unwrap();
+    let security_credential = config.credential().unwrap();
+    assert_eq!(decrypt(&security_credential), "Safaricom999!*!");
+    // the padding is random, so every encryption differs
+    assert_ne!(config.credential().unwrap(), security_credential);
+
+    config.set("security_credential", "encrypted beforehand").unwrap();
+    assert_eq!(config.credential().unwrap(), "encrypted beforehand");
+
+    let config = Config::from_toml("[sandbox]\ninitiator_password = \"secret\"\ncertificate_path = \"missing.cer\"", "sandbox").unwrap();
+    match config.credential() {
+        Err(ConfigError::Invalid(name, _)) => assert_eq!(name, "certificate_path"),
+        other => panic!("expected the certificate to be missing, got {:?}", other),
+    }
+    match Config::from_toml("[sandbox]\ncertificate_path = \"missing.cer\"", "sandbox").unwrap().credential() {
+        Err(ConfigError::Missing(name)) => assert_eq!(name, "initiator_password"),
+        other => panic!("expected the password to be missing, got {:?}", other),
+    }
+}
+
+/// Acknowledges every payment, failing the ones to `unknown` as if the connection dropped
+struct FakeSender {
+    sent: Arc<std::sync::Mutex<Vec<String>>>,
+    unknown: Msisdn,
+}
+
+impl PaymentSender for FakeSender {
+    fn send_b2c(&mut self, request: &B2C) -> Result<B2CResponse, MpesaClientError> {
+        self.sent.lock().unwrap().push(request.OriginatorConversationID.clone());
+        if request.PartyB == self.unknown {
+            return Err(MpesaClientError::OutcomeUnknown {
+                originator_conversation_id: Some(request.OriginatorConversationID.clone()),
+                cause: String::from("connection dropped"),
+            });
+        }
+        Ok(B2CResponse {
+            ConversationID: format!("AG_{}", request.OriginatorConversationID),
+            OriginatorConversationID: request.OriginatorConversationID.clone(),
+            ResponseCode: String::from("0"),
+            ResponseDescription: String::from("Accept the service request successfully."),
+        })
+    }
+}
+
+#[test]
+fn test_bulk_payouts_are_validated_up_front() {
+    let csv = "msisdn,amount,command_id,remarks\n0708374149,100,SalaryPayment,ok\n123,100,,bad msisdn\n0708374150,5,,too little\n0708374151,100,CustomerPayBillOnline,wrong command\n";
+    match read_payouts(csv.as_bytes()) {
+        Err(BulkError::InvalidRows(rows)) => assert_eq!(rows.iter().map(|row| row.0).collect::<Vec<_>>(), vec![3, 4, 5]),
+        other => panic!("expected the invalid rows, got {:?}", other),
+    }
+
+    let payouts = read_payouts("MSISDN,Amount\n0708374149, 100\n".as_bytes()).unwrap();
+    assert_eq!(payouts[0].line, 2);
+    assert_eq!(payouts[0].command_id, CommandIds::BusinessPayment);
+}
+
+#[test]
+fn test_bulk_payouts_resume_without_paying_twice() {
+    let state_path = std::env::temp_dir().join(format!("mpesa-bulk-{}.state", std::process::id()));
+    let _ = std::fs::remove_file(&state_path);
+    let csv = "msisdn,amount,command_id,remarks\n0708374149,100,,a\n0708374150,200,,b\n0708374151,300,,c\n";
+    let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sender = || FakeSender { sent: sent.clone(), unknown: "0708374151".parse().unwrap() };
+
+    let bulk = BulkPayout::new(b2c_request(), read_payouts(csv.as_bytes()).unwrap(), &state_path).unwrap().concurrency(2).rate_limit(100);
+    let states = bulk.run(&sender).unwrap();
+    assert_eq!(sent.lock().unwrap().len(), 3);
+    assert_eq!(states.iter().map(|state| state.state).collect::<Vec<_>>(),
+               vec![TransactionState::Acknowledged, TransactionState::Acknowledged, TransactionState::Unknown]);
+
+    let first = states[0].originator_conversation_id.clone().unwrap();
+    bulk.results().on_result(result_callback("", &first));
+    assert!(!bulk.wait(Duration::from_millis(10)));
+
+    // an interrupted run started again sends nothing twice
+    let resumed = BulkPayout::new(b2c_request(), read_payouts(csv.as_bytes()).unwrap(), &state_path).unwrap();
+    let states = resumed.run(&sender).unwrap();
+    assert_eq!(sent.lock().unwrap().len(), 3);
+    assert_eq!(states[0].state, TransactionState::Completed);
+    assert_eq!(states[0].receipt, Some(String::from("LGR219G3EY")));
+
+    let mut results = Vec::new();
+    resumed.write_results(&mut results).unwrap();
+    let results = String::from_utf8(results).unwrap();
+    assert!(results.starts_with("line,msisdn,amount,command_id,remarks,state,"));
+    assert!(results.contains("LGR219G3EY"));
+    assert_eq!(results.lines().count(), 4);
+
+    let other = "msisdn,amount\n0708374149,999\n";
+    match BulkPayout::new(b2c_request(), read_payouts(other.as_bytes()).unwrap(), &state_path) {
+        Err(BulkError::StateMismatch(line)) => assert_eq!(line, 2),
+        other => panic!("expected the state file to be refused, got {:?}", other.map(|_| ())),
+    }
+    let _ = std::fs::remove_file(&state_path);
+}
+
+/// Answers payments with the scripted answers, one per request, a `None` acknowledging the payment as do all
+/// requests once they are used up
+struct Refusing {
+    sent: Arc<std::sync::Mutex<Vec<String>>>,
+    answers: Arc<std::sync::Mutex<std::collections::VecDeque<Option<MpesaClientError>>>>,
+}
+
+impl PaymentSender for Refusing {
+    fn send_b2c(&mut self, request: &B2C) -> Result<B2CResponse, MpesaClientError> {
+        self.sent.lock().unwrap().push(request.OriginatorConversationID.clone());
+        if let Some(Some(error)) = self.answers.lock().unwrap().pop_front() {
+            return Err(error);
+        }
+        Ok(B2CResponse {
+            ConversationID: format!("AG_{}", request.OriginatorConversationID),
+            OriginatorConversationID: request.OriginatorConversationID.clone(),
+            ResponseCode: String::from("0"),
+            ResponseDescription: String::from("Accept the service request successfully."),
+        })
+    }
+}
+
+#[test]
+fn test_bulk_payouts_retry_throttled_requests_and_resume_the_ones_never_sent() {
+    let state_path = std::env::temp_dir().join(format!("mpesa-bulk-refused-{}.state", std::process::id()));
+    let _ = std::fs::remove_file(&state_path);
+    let csv = "msisdn,amount\n0708374149,100\n0708374150,200\n0708374151,300\n";
+    let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let answers = Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new()));
+    let sender = || Refusing { sent: sent.clone(), answers: answers.clone() };
+    let script = |error| answers.lock().unwrap().push_back(error);
+    let throttled = || MpesaClientError::Request(MpesaRequestError::TooManyRequests, String::from("429"));
+    let bulk = || BulkPayout::new(b2c_request(), read_payouts(csv.as_bytes()).unwrap(), &state_path).unwrap()
+        .retries(2, Duration::from_millis(1));
+
+    // the first row goes through on its last retry, the token expires on the second and the third is never tried
+    script(Some(throttled()));
+    script(Some(throttled()));
+    script(None);
+    script(Some(MpesaClientError::AccessToken(MpesaAccessTokenError::EmptyAccessToken)));
+    let states = bulk().run(&sender).unwrap();
+    assert_eq!(sent.lock().unwrap().len(), 4);
+    assert_eq!(states.iter().map(|state| state.state).collect::<Vec<_>>(),
+               vec![TransactionState::Acknowledged, TransactionState::Created, TransactionState::Created]);
+    assert_eq!(states[1].originator_conversation_id, None);
+    assert!(states[1].result_desc.is_some());
+
+    // refused more often than retried, the second row is left for the next run again
+    for _ in 0..3 {
+        script(Some(MpesaClientError::Request(MpesaRequestError::ServiceUnavailable, String::from("503"))));
+    }
+    let states = bulk().run(&sender).unwrap();
+    assert_eq!(sent.lock().unwrap().len(), 7);
+    assert_eq!(states[1].state, TransactionState::Created);
+    assert_eq!(states[2].state, TransactionState::Created);
+
+    let states = bulk().run(&sender).unwrap();
+    assert_eq!(sent.lock().unwrap().len(), 9);
+    assert!(states.iter().all(|state| state.state == TransactionState::Acknowledged));
+    let _ = std::fs::remove_file(&state_path);
+
+    // urls M-Pesa would reject stop the batch before anything is recorded or sent
+    let template = B2C { ResultURL: String::from("http://example.com/callbacks/result"), ..b2c_request() };
+    let bulk = BulkPayout::new(template, read_payouts(csv.as_bytes()).unwrap(), &state_path).unwrap()
+        .environment(Environment::Production);
+    match bulk.run(&sender) {
+        Err(BulkError::Template(ParameterError::CallbackUrl { ref field, .. })) => assert_eq!(field, "ResultURL"),
+        other => panic!("expected the template to be refused, got {:?}", other),
+    }
+    assert_eq!(sent.lock().unwrap().len(), 9);
+    assert!(bulk.states().iter().all(|state| state.state == TransactionState::Created));
+    let _ = std::fs::remove_file(&state_path);
+}
+
+/// Posts the result of every payment before acknowledging it, with the ids M-Pesa assigned
+struct ResultFirst {
+    results: BulkResults,
+}
+
+impl PaymentSender for ResultFirst {
+    fn send_b2c(&mut self, request: &B2C) -> Result<B2CResponse, MpesaClientError> {
+        let originator_conversation_id = format!("29112-{}-1", &request.PartyB.as_str()[4..]);
+        self.results.on_result(result_callback("AG_1", &originator_conversation_id));
+        Ok(B2CResponse {
+            ConversationID: String::from("AG_1"),
+            OriginatorConversationID: originator_conversation_id,
+            ResponseCode: String::from("0"),
+            ResponseDescription: String::from("Accept the service request successfully."),
+        })
+    }
+}
+
+#[test]
+fn test_bulk_payouts_hold_back_early_results_and_mark_interrupted_rows_unknown() {
+    let state_path = std::env::temp_dir().join(format!("mpesa-bulk-early-{}.state", std::process::id()));
+    let _ = std::fs::remove_file(&state_path);
+    let csv = "msisdn,amount\n0708374149,100\n";
+
+    // results arriving before the acknowledgements are recorded once the acknowledgements are
+    let bulk = BulkPayout::new(b2c_request(), read_payouts(csv.as_bytes()).unwrap(), &state_path).unwrap();
+    let states = bulk.run(|| ResultFirst { results: bulk.results() }).unwrap();
+    assert_eq!(states[0].state, TransactionState::Completed);
+    assert_eq!(states[0].originator_conversation_id, Some(String::from("29112-08374149-1")));
+    assert_eq!(states[0].receipt, Some(String::from("LGR219G3EY")));
+
+    // a payout left submitted by a crash may have been paid
+    let mut interrupted = states[0].clone();
+    interrupted.state = TransactionState::Submitted;
+    std::fs::write(&state_path, format!("{}\n", serde_json::to_string(&interrupted).unwrap())).unwrap();
+    let sent = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let resumed = BulkPayout::new(b2c_request(), read_payouts(csv.as_bytes()).unwrap(), &state_path).unwrap();
+    assert_eq!(resumed.states()[0].state, TransactionState::Unknown);
+    resumed.run(|| FakeSender { sent: sent.clone(), unknown: "0708374151".parse().unwrap() }).unwrap();
+    assert!(sent.lock().unwrap().is_empty());
+    let _ = std::fs::remove_file(&state_path);
+}
+
+#[test]
+fn test_mock_daraja_acknowledges_and_calls_back() {
+    let mut daraja = Daraja::new().credentials("key", "secret").callback_delay(Duration::from_secs(60));
+    let reply = daraja.token(Some("Basic d3Jvbmc6d3Jvbmc="), "grant_type=client_credentials");
+    assert_eq!((reply.status, reply.body["errorCode"].as_str()), (400, Some("400.008.01")));
+    let reply = daraja.token(Some("Basic a2V5OnNlY3JldA=="), "grant_type=client_credentials");
+    assert_eq!(reply.body["expires_in"], "3599");
+    let authorization = format!("Bearer {}", reply.body["access_token"].as_str().unwrap());
+    let authorization = Some(authorization.as_str());
+
+    let request = serde_json::to_value(&b2c_request()).unwrap();
+    let reply = daraja.handle(mpesa::api_products::b2c::ENDPOINT, authorization, &request);
+    let acknowledgement: B2CResponse = serde_json::from_value(reply.body).unwrap();
+    assert_eq!(acknowledgement.ResponseCode, "0");
+    assert_eq!(reply.callbacks[0].url, "https://example.com/callbacks/result");
+    let result: ResultCallback = serde_json::from_value(reply.callbacks[0].body.clone()).unwrap();
+    assert_eq!(result.Result.ConversationID, acknowledgement.ConversationID);
+    assert_eq!(result.Result.amount(), Some(Amount::shillings(100)));
+
+    // only version 3 of the B2C API takes the id from the caller, the others assign it
+    let b2c = B2C { OriginatorConversationID: String::from("payroll-2018-08-1"), ..b2c_request() };
+    let reply = daraja.handle(mpesa::api_products::b2c::ENDPOINT, authorization, &serde_json::to_value(&b2c).unwrap());
+    assert_eq!(reply.body["OriginatorConversationID"], "payroll-2018-08-1");
+    let b2b = B2B::new(CommandIds::BusinessPayBill, Amount::shillings(100), ShortCode::new("600000").unwrap(),
+                       ShortCode::new("600001").unwrap())
+        .initiator("testapi", "credential")
+        .urls("https://example.com/callbacks/result", "https://example.com/callbacks/timeout");
+    let mut b2b = serde_json::to_value(&b2b).unwrap();
+    b2b["OriginatorConversationID"] = json!("payroll-2018-08-1");
+    let reply = daraja.handle(mpesa::api_products::b2b::ENDPOINT, authorization, &b2b);
+    let id = reply.body["OriginatorConversationID"].as_str().unwrap();
+    let parts: Vec<&str> = id.split('-').collect();
+    assert_eq!(parts.iter().map(|part| part.len()).collect::<Vec<_>>(), vec![5, 8, 1], "{}", id);
+    assert!(id.chars().all(|c| c.is_ascii_digit() || c == '-'));
+    let result: ResultCallback = serde_json::from_value(reply.callbacks[0].body.clone()).unwrap();
+    assert_eq!(result.Result.OriginatorConversationID, id);
+
+    let mut incomplete = request.clone();
+    incomplete.as_object_mut().unwrap().remove("ResultURL");
+    let reply = daraja.handle(mpesa::api_products::b2c::ENDPOINT, authorization, &incomplete);
+    assert_eq!((reply.status, reply.body["errorMessage"].as_str()), (400, Some("Bad Request - Invalid ResultURL")));
+    let reply = daraja.handle(mpesa::api_products::b2c::ENDPOINT, Some("Bearer expired"), &request);
+    assert_eq!((reply.status, reply.body["errorCode"].as_str()), (404, Some("404.001.03")));
+
+    // the customer has not answered the push before the callback delay is over
+    let push = json!({
+        "BusinessShortCode": "174379", "Password": "cGFzc3dvcmQ=", "Timestamp": "20180701120000",
+        "TransactionType": "CustomerPayBillOnline", "Amount": "10", "PartyA": "254708374149", "PartyB": "174379",
+        "PhoneNumber": "254708374149", "CallBackURL": "https://example.com/callbacks/stk", "AccountReference": "invoice",
+    });
+    let reply = daraja.handle("/mpesa/stkpush/v1/processrequest", authorization, &push);
+    let callback: StkCallbackBody = serde_json::from_value(reply.callbacks[0].body.clone()).unwrap();
+    assert_eq!(callback.Body.stk_callback.amount(), Some(Amount::shillings(10)));
+    let query = json!({
+        "BusinessShortCode": "174379", "Password": "cGFzc3dvcmQ=", "Timestamp": "20180701120000",
+        "CheckoutRequestID": reply.body["CheckoutRequestID"],
+    });
+    let reply = daraja.handle("/mpesa/stkpushquery/v1/query", authorization, &query);
+    assert_eq!((reply.status, reply.body["errorCode"].as_str()), (500, Some("500.001.1001")));
+
+    let registration = json!({
+        "ShortCode": "600000", "ResponseType": "Completed",
+        "ConfirmationURL": "https://example.com/callbacks/c2b/confirmation", "ValidationURL": "https://example.com/callbacks/c2b/validation",
+    });
+    assert_eq!(daraja.handle("/mpesa/c2b/v1/registerurl", authorization, &registration).status, 200);
+    let payment = SimulateTransaction::new(Paybill::new("600000").unwrap(), "0708374149".parse().unwrap(), Amount::shillings(10));
+    let reply = daraja.handle("/mpesa/c2b/v1/simulate", authorization, &serde_json::to_value(&payment).unwrap());
+    assert!(reply.callbacks[0].validation);
+    let transaction: C2BTransaction = serde_json::from_value(reply.callbacks[1].body.clone()).unwrap();
+    assert_eq!((transaction.TransactionType.as_str(), transaction.amount()), ("Pay Bill", Some(Amount::shillings(10))));
+}
+
+/// Answers every request posted to `listener` with an acceptance and hands its path and body to `posted`
+fn receive_callbacks(listener: std::net::TcpListener, posted: std::sync::mpsc::Sender<(String, serde_json::Value)>) {
+    use std::io::{BufRead, Read, Write};
+
+    for stream in listener.incoming() {
+        let mut stream = stream.unwrap();
+        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            if parts.next().unwrap().trim().eq_ignore_ascii_case("content-length") {
+                length = parts.next().unwrap().trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let answer = r#"{"ResultCode":0,"ResultDesc":"Accepted"}"#;
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               answer.len(), answer).unwrap();
+        if posted.send((path, serde_json::from_slice(&body).unwrap())).is_err() {
+            return;
+        }
+    }
+}
+
+#[test]
+fn test_mock_server_serves_the_api_over_http_and_posts_callbacks_after_the_delay() {
+    let receiver = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let callbacks = format!("http://{}", receiver.local_addr().unwrap());
+    let (posted, received) = std::sync::mpsc::channel();
+    std::thread::spawn(move || receive_callbacks(receiver, posted));
+
+    // a port that was free a moment ago
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let delay = Duration::from_millis(500);
+    std::thread::spawn(move || {
+        MockServer::new().credentials("key", "secret").callback_delay(delay).run(address).expect("unable to start the mock server")
+    });
+    let started = std::time::Instant::now();
+    while std::net::TcpStream::connect(address).is_err() {
+        assert!(started.elapsed() < Duration::from_secs(10), "the mock server did not start");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let base_url = format!("http://{}", address);
+
+    let mut wrong = AccessToken::new(String::from("key"), String::from("wrong")).base_url(&base_url);
+    assert!(wrong.token().is_err());
+
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let mut client = MpesaClient::new(access_token, &base_url);
+    let request = B2C {
+        ResultURL: format!("{}/result", callbacks),
+        QueueTimeOutURL: format!("{}/timeout", callbacks),
+        ..b2c_request()
+    };
+    let sent_at = std::time::Instant::now();
+    let acknowledgement = client.send_b2c(&request).unwrap();
+    assert_eq!(acknowledgement.ResponseCode, "0");
+
+    let (path, body) = received.recv_timeout(Duration::from_secs(10)).expect("the result was not posted");
+    assert!(sent_at.elapsed() >= delay);
+    assert_eq!(path, "/result");
+    let result: ResultCallback = serde_json::from_value(body).unwrap();
+    assert_eq!(result.Result.ConversationID, acknowledgement.ConversationID);
+    assert_eq!(result.Result.ResultCode, 0);
+    assert!(received.try_recv().is_err());
+}
+
+#[test]
+fn test_mock_scenarios() {
+    assert_eq!(Scenario::from_convention(Some("0700000002"), None), Some(Scenario::Cancelled));
+    assert_eq!(Scenario::from_convention(Some("254708374149"), Some(Amount::shillings(9908))), Some(Scenario::TooManyRequests));
+    assert_eq!(Scenario::from_convention(Some("254708374149"), Some(Amount::shillings(100))), None);
+
+    let mut daraja = Daraja::new();
+    let token = daraja.token(Some("Basic a2V5OnNlY3JldA=="), "grant_type=client_credentials");
+    let authorization = format!("Bearer {}", token.body["access_token"].as_str().unwrap());
+    let authorization = Some(authorization.as_str());
+    let b2c = mpesa::api_products::b2c::ENDPOINT;
+    let payout = |amount: u64| serde_json::to_value(&B2C { Amount: Amount::shillings(amount), ..b2c_request() }).unwrap();
+
+    let reply = daraja.handle(b2c, authorization, &payout(9901));
+    let result: ResultCallback = serde_json::from_value(reply.callbacks[0].body.clone()).unwrap();
+    assert_eq!((result.Result.ResultCode, result.Result.ResultParameters.is_none()), (1, true));
+    let reply = daraja.handle(b2c, authorization, &payout(9905));
+    assert_eq!(reply.callbacks.len(), 2);
+
+    // scripted for another customer, so it does not apply
+    assert_eq!(daraja.admin(&json!({ "scenario": "timeout", "msisdn": "254700000099", "times": 2 })).status, 200);
+    assert_eq!(daraja.admin(&json!({ "scenario": "flood" })).status, 400);
+    let reply = daraja.handle(b2c, authorization, &payout(100));
+    assert_eq!(reply.callbacks[0].url, "https://example.com/callbacks/result");
+    daraja.script(Scenario::Timeout, None, 1);
+    let reply = daraja.handle(b2c, authorization, &payout(100));
+    assert_eq!(reply.callbacks[0].url, "https://example.com/callbacks/timeout");
+    daraja.clear_scripts();
+
+    daraja.script(Scenario::ServiceUnavailable, None, 1);
+    assert_eq!(daraja.handle(b2c, authorization, &payout(100)).status, 503);
+    assert_eq!(daraja.handle(b2c, authorization, &payout(9908)).status, 429);
+    assert_eq!(daraja.handle(b2c, authorization, &payout(100)).status, 200);
+    assert_eq!(daraja.handle(b2c, authorization, &payout(9910)).body["errorCode"], "404.001.03");
+    assert_eq!(daraja.handle(b2c, authorization, &payout(100)).body["errorCode"], "404.001.03");
+
+    // a cancelled push is reported by the query once the customer has answered
+    let token = daraja.token(Some("Basic a2V5OnNlY3JldA=="), "grant_type=client_credentials");
+    let authorization = format!("Bearer {}", token.body["access_token"].as_str().unwrap());
+    let authorization = Some(authorization.as_str());
+    let push = json!({
+        "BusinessShortCode": "174379", "Password": "cGFzc3dvcmQ=", "Timestamp": "20180701120000",
+        "TransactionType": "CustomerPayBillOnline", "Amount": 10, "PartyA": "254700000002", "PartyB": "174379",
+        "PhoneNumber": "254700000002", "CallBackURL": "https://example.com/callbacks/stk", "AccountReference": "invoice",
+    });
+    let reply = daraja.handle("/mpesa/stkpush/v1/processrequest", authorization, &push);
+    let callback: StkCallbackBody = serde_json::from_value(reply.callbacks[0].body.clone()).unwrap();
+    assert_eq!(callback.Body.stk_callback.ResultCode, 1032);
+    assert!(callback.Body.stk_callback.CallbackMetadata.is_none());
+    let query = json!({
+        "BusinessShortCode": "174379", "Password": "cGFzc3dvcmQ=", "Timestamp": "20180701120000",
+        "CheckoutRequestID": reply.body["CheckoutRequestID"],
+    });
+    let reply = daraja.handle("/mpesa/stkpushquery/v1/query", authorization, &query);
+    assert_eq!(reply.body["ResultCode"], "1032");
+}
+
+/// Answers requests with the mock API in-process
+#[derive(Debug)]
+struct InProcess(std::sync::Mutex<Daraja>);
+
+impl Transport for InProcess {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        let reply = answer(&mut self.0.lock().unwrap(), request);
+        Ok(HttpResponse { status: reply.status, body: reply.body.to_string() })
+    }
+}
+
+fn answer(daraja: &mut Daraja, request: &HttpRequest) -> Reply {
+    let authorization = request.headers.get("Authorization").map(String::as_str);
+    let mut url = request.url.trim_start_matches(SANDBOX_URL).splitn(2, '?');
+    let (path, query) = (url.next().unwrap(), url.next().unwrap_or(""));
+    match request.method.as_str() {
+        "GET" => daraja.token(authorization, query),
+        _ => daraja.handle(path, authorization, &serde_json::from_str(request.body.as_ref().unwrap()).unwrap()),
+    }
+}
+
+/// Answers requests with the mock API in-process and hands results to a correlator straight away,
+/// before the request is acknowledged. Other callbacks are kept
+#[derive(Clone)]
+struct Delivering {
+    daraja: Arc<std::sync::Mutex<Daraja>>,
+    correlator: Correlator,
+    posted: Arc<std::sync::Mutex<Vec<Callback>>>,
+}
+
+impl std::fmt::Debug for Delivering {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Delivering")
+    }
+}
+
+impl Delivering {
+    fn new(daraja: Daraja) -> Delivering {
+        Delivering {
+            daraja: Arc::new(std::sync::Mutex::new(daraja)),
+            correlator: Correlator::new(Unmatched(Default::default())),
+            posted: Default::default(),
+        }
+    }
+
+    fn client(&self) -> MpesaClient {
+        let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+        MpesaClient::new(access_token, SANDBOX_URL).transport(Arc::new(self.clone())).correlator(self.correlator.clone())
+    }
+
+    /// The body of the last callback posted to `url`
+    fn posted(&self, url: &str) -> serde_json::Value {
+        self.posted.lock().unwrap().iter().rev().find(|callback| callback.url == url).unwrap().body.clone()
+    }
+}
+
+impl Transport for Delivering {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        let reply = answer(&mut self.daraja.lock().unwrap(), request);
+        for callback in reply.callbacks {
+            match serde_json::from_value::<ResultCallback>(callback.body.clone()) {
+                Ok(result) if callback.url.contains("timeout") => self.correlator.on_timeout(result.Result),
+                Ok(result) => self.correlator.on_result(result.Result),
+                Err(_) => self.posted.lock().unwrap().push(callback),
+            }
+        }
+        Ok(HttpResponse { status: reply.status, body: reply.body.to_string() })
+    }
+}
+
+fn verification_config() -> VerificationConfig {
+    VerificationConfig {
+        initiator: testing::INITIATOR.to_string(),
+        security_credential: testing::SECURITY_CREDENTIAL.to_string(),
+        short_code: Party::from(ShortCode::new(testing::SHORT_CODE).unwrap()),
+        result_url: testing::RESULT_URL.to_string(),
+        timeout_url: testing::QUEUE_TIMEOUT_URL.to_string(),
+        stk_short_code: testing::STK_SHORT_CODE.to_string(),
+        stk_passkey: testing::STK_PASSKEY.to_string(),
+    }
+}
+
+/// Reports the payments passed on by a `Verifier` and the security events it raises
+struct Verified(std::sync::Mutex<std::sync::mpsc::Sender<Result<String, SecurityEvent>>>);
+
+impl CallbackHandler for Verified {
+    fn on_stk_callback(&self, callback: StkCallback) {
+        self.0.lock().unwrap().send(Ok(callback.CheckoutRequestID)).unwrap();
+    }
+
+    fn on_c2b_confirmation(&self, transaction: C2BTransaction) {
+        self.0.lock().unwrap().send(Ok(transaction.TransID)).unwrap();
+    }
+
+    fn on_security_event(&self, event: SecurityEvent) {
+        self.0.lock().unwrap().send(Err(event)).unwrap();
+    }
+}
+
+#[test]
+fn test_verifier_confirms_payments_with_the_api() {
+    let mock = Delivering::new(Daraja::new());
+    let mut payer = mock.client();
+    payer.register_urls(&testing::register_url()).unwrap();
+    payer.simulate_c2b(&testing::simulate()).unwrap();
+    payer.send_stk_push(&testing::stk_push()).unwrap();
+    let confirmation: C2BTransaction = serde_json::from_value(mock.posted(testing::CONFIRMATION_URL)).unwrap();
+    let stk = serde_json::from_value::<StkCallbackBody>(mock.posted(testing::CALLBACK_URL)).unwrap().Body.stk_callback;
+
+    let (sender, verified) = std::sync::mpsc::channel();
+    let verifier = Verifier::new(Verified(std::sync::Mutex::new(sender)), mock.client(), verification_config());
+    let next = || verified.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    verifier.on_c2b_confirmation(confirmation.clone());
+    assert_eq!(next().unwrap(), confirmation.TransID);
+    verifier.on_stk_callback(stk.clone());
+    assert_eq!(next().unwrap(), stk.CheckoutRequestID);
+
+    let claims = vec![
+        (C2BTransaction { TransAmount: String::from("1000"), ..confirmation.clone() }, Field::Amount),
+        // amounts are compared in cents, not rounded to them
+        (C2BTransaction { TransAmount: String::from("10.004"), ..confirmation.clone() }, Field::Amount),
+        (C2BTransaction { MSISDN: String::from("254711111111"), ..confirmation.clone() }, Field::Msisdn),
+    ];
+    for (forged, field) in claims {
+        verifier.on_c2b_confirmation(forged);
+        match next() {
+            Err(SecurityEvent::Discrepancy { ref reference, ref discrepancies }) => {
+                assert_eq!(reference, &confirmation.TransID);
+                assert_eq!(discrepancies.iter().map(|discrepancy| discrepancy.field).collect::<Vec<_>>(), vec![field]);
+            },
+            other => panic!("expected a discrepancy in {:?}, got {:?}", field, other),
+        }
+    }
+
+    mock.daraja.lock().unwrap().script(Scenario::ServiceUnavailable, None, 1);
+    verifier.on_c2b_confirmation(confirmation.clone());
+    match next() {
+        Err(SecurityEvent::VerificationFailed { reference, description }) => {
+            assert_eq!(reference, confirmation.TransID);
+            assert!(description.contains("503"), "{}", description);
+        },
+        other => panic!("expected the lookup to fail, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_record_and_replay_exchanges() {
+    let path = std::env::temp_dir().join(format!("mpesa-session-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let access_token = || AccessToken::new(String::from("key"), String::from("secret"));
+
+    let recorder = RecordingTransport::create(&path, InProcess(std::sync::Mutex::new(Daraja::new()))).unwrap();
+    let mut client = MpesaClient::new(access_token(), SANDBOX_URL).transport(Arc::new(recorder));
+    let recorded = client.send_b2c(&b2c_request()).unwrap();
+
+    let session = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(session.lines().count(), 2);
+    assert!(!session.contains("a2V5OnNlY3JldA==") && !session.contains("\"credential\""));
+    assert!(session.contains("Basic [REDACTED]") && session.contains("Bearer [REDACTED]"));
+
+    let replay = Arc::new(ReplayTransport::open(&path).unwrap());
+    let mut client = MpesaClient::new(access_token(), "http://localhost:8000").transport(replay.clone());
+    let replayed = client.send_b2c(&b2c_request()).unwrap();
+    assert_eq!(replayed.ConversationID, recorded.ConversationID);
+    assert_eq!(replay.remaining(), 0);
+    match client.send_b2c(&b2c_request()) {
+        Err(MpesaClientError::OutcomeUnknown { .. }) => panic!("a missing recording is not a dropped connection"),
+        Err(MpesaClientError::Transport(TransportError::NotRecorded(request))) => assert!(request.starts_with("POST ")),
+        other => panic!("expected the request not to be recorded, got {:?}", other.map(|response| response.ConversationID)),
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_stk_push_is_polled_to_its_outcome() {
+    let daraja = Daraja::new().callback_delay(Duration::from_millis(100));
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL).transport(Arc::new(InProcess(std::sync::Mutex::new(daraja))));
+    let polling = StkPolling::new().delays(Duration::from_millis(20), Duration::from_millis(50)).window(Duration::from_secs(5));
+
+    let outcome = client.stk_push_and_wait(&testing::stk_push(), testing::STK_PASSKEY, &polling).unwrap();
+    assert!(outcome.is_paid(), "{}", outcome.description());
+
+    let cancelled = LipaNaMpesaOnlinePaymentRequest { PhoneNumber: "254700000002".parse().unwrap(), ..testing::stk_push() };
+    match client.stk_push_and_wait(&cancelled, testing::STK_PASSKEY, &polling).unwrap() {
+        StkOutcome::Cancelled(response) => assert_eq!(response.ResultCode, "1032"),
+        other => panic!("expected the push to be cancelled, got {}", other.description()),
+    }
+
+    let timed_out = LipaNaMpesaOnlinePaymentRequest { PhoneNumber: "254700000004".parse().unwrap(), ..testing::stk_push() };
+    match client.stk_push_and_wait(&timed_out, testing::STK_PASSKEY, &polling).unwrap() {
+        StkOutcome::Timeout(response) => assert_eq!(response.ResultCode, "1037"),
+        other => panic!("expected the push to time out, got {}", other.description()),
+    }
+
+    let broke = LipaNaMpesaOnlinePaymentRequest { PhoneNumber: "254700000001".parse().unwrap(), ..testing::stk_push() };
+    match client.stk_push_and_wait(&broke, testing::STK_PASSKEY, &polling).unwrap() {
+        StkOutcome::InsufficientFunds(response) => assert_eq!(response.ResultCode, "1"),
+        other => panic!("expected the funds to be insufficient, got {}", other.description()),
+    }
+
+    let mistyped = LipaNaMpesaOnlinePaymentRequest { PhoneNumber: "254700000003".parse().unwrap(), ..testing::stk_push() };
+    match client.stk_push_and_wait(&mistyped, testing::STK_PASSKEY, &polling).unwrap() {
+        StkOutcome::WrongPin(response) => assert_eq!(response.ResultCode, "2001"),
+        other => panic!("expected a wrong PIN, got {}", other.description()),
+    }
+
+    let unanswered = LipaNaMpesaOnlinePaymentRequest { PhoneNumber: "254700000007".parse().unwrap(), ..testing::stk_push() };
+    let polling = polling.window(Duration::from_millis(300));
+    match client.stk_push_and_wait(&unanswered, testing::STK_PASSKEY, &polling).unwrap() {
+        StkOutcome::Undetermined(reason) => assert!(reason.contains("500.001.1001"), "{}", reason),
+        other => panic!("expected no outcome, got {}", other.description()),
+    }
+}
+
+/// Answers requests with the mock API in-process, keeping the bodies of the requests
+#[derive(Debug)]
+struct Capturing(std::sync::Mutex<Daraja>, std::sync::Mutex<Vec<serde_json::Value>>);
+
+impl Transport for Capturing {
+    fn send(&self, request: &HttpRequest) -> Result<HttpResponse, TransportError> {
+        if let Some(ref body) = request.body {
+            self.1.lock().unwrap().push(serde_json::from_str(body).unwrap());
+        }
+        let reply = answer(&mut self.0.lock().unwrap(), request);
+        Ok(HttpResponse { status: reply.status, body: reply.body.to_string() })
+    }
+}
+
+#[test]
+fn test_security_credentials_are_generated_for_requests_without_one() {
+    let transport = Arc::new(Capturing(std::sync::Mutex::new(Daraja::new().credentials("key", "secret")), Default::default()));
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let certificate = Certificate::from_file(std::path::Path::new(TEST_CERTIFICATE)).unwrap();
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL).transport(transport.clone())
+        .initiator_password("Safaricom999!*!", certificate);
+
+    let request = B2C { SecurityCredential: String::new(), ..b2c_request() };
+    client.send_b2c(&request).unwrap();
+    client.send_b2c(&b2c_request()).unwrap();
+    let rendered = client.dry_run().render_b2c(&request).unwrap();
+    let rendered: serde_json::Value = serde_json::from_str(&rendered.body.unwrap()).unwrap();
+    assert_eq!(rendered["SecurityCredential"], "[REDACTED]");
+
+    let bodies = transport.1.lock().unwrap();
+    assert_eq!(bodies.len(), 2);
+    assert_eq!(decrypt(bodies[0]["SecurityCredential"].as_str().unwrap()), "Safaricom999!*!");
+    assert_eq!(bodies[1]["SecurityCredential"], "credential");
+}
+
+#[test]
+fn test_dry_run_builds_requests_without_sending() {
+    let nothing = Arc::new(ReplayTransport::from_reader(std::io::Cursor::new("")).unwrap());
+    let store = Arc::new(MemoryTransactionStore::new());
+    let access_token = AccessToken::new(String::from("key"), String::from("secret"));
+    let mut client = MpesaClient::new(access_token, SANDBOX_URL).transport(nothing).store(store.clone());
+
+    let request = client.dry_run().render_b2c(&b2c_request()).unwrap();
+    assert_eq!((request.method.as_str(), request.url.as_str()), ("POST", "https://sandbox.safaricom.co.ke/mpesa/b2c/v3/paymentrequest"));
+    assert_eq!(request.headers["Authorization"], "Bearer [REDACTED]");
+    let body: serde_json::Value = serde_json::from_str(&request.body.unwrap()).unwrap();
+    assert_eq!((body["SecurityCredential"].as_str(), body["Amount"].as_str()), (Some("[REDACTED]"), Some("100")));
+    assert!(!body["OriginatorConversationID"].as_str().unwrap().is_empty());
+    let tomorrow = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+    assert!(store.find_stale(&[TransactionState::Created, TransactionState::Submitted], tomorrow).unwrap().is_empty());
+
+    let invalid = B2C { CommandID: CommandIds::TransactionReversal.to_string(), ..b2c_request() };
+    match client.dry_run().render_b2c(&invalid) {
+        Err(MpesaClientError::InvalidRequest(_)) => (),
+        other => panic!("expected the request to be rejected, got {:?}", other.map(|request| request.url)),
+    }
+
+    // retrieving the token is the only exchange, and there is none to replay
+    match client.dry_run().acquire_token(true).render_b2c(&b2c_request()) {
+        Err(MpesaClientError::AccessToken(_)) => (),
+        other => panic!("expected the token to be retrieved, got {:?}", other.map(|request| request.url)),
+    }
+}